@@ -1,74 +1,863 @@
-use anyhow::{Context, Result};
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use regex::Regex;
+use serde::Serialize;
 use tracing;
 
-use super::providers::ProviderRegistry;
+use super::{
+    groups,
+    lifetime::{SnapshotWatch, UsageProbe},
+    preflight::preflight,
+    providers::{Provider, ProviderRegistry, Skipped},
+};
 use crate::{
     AppCtx,
-    tooling::pbs::BackupItem,
+    config::{
+        ActiveVolumes, Backup, BackupOrder, BlackoutAction, Config, DEFAULT_CHANGED_ONLY_MAX_AGE,
+        DEFAULT_CHANGED_ONLY_MAX_RUNS, Node, Repo, Restore, SnapshotAgeAction,
+    },
+    events::Event,
+    manifest::{BackupManifest, CarriedArchive, ClaimRecord, MANIFEST_ARCHIVE, PvRecord},
+    tooling::{
+        Toolbox,
+        fs::PortFile,
+        kube::PvClaim,
+        pbs::{BackupItem, BackupOpts, PbsSnapshot, UploadStats, snapshot_path},
+    },
     ui,
-    utils::{exec_policy::with_dry_run_enabled, lock::LockGuard},
+    utils::{
+        blackout::Blackout,
+        exec_policy::{self, is_dry_run, with_dry_run_enabled},
+        failure::Failure,
+        lock::{LockSet, Resource},
+        process::StepTimedOut,
+        signal,
+        ssh::SshRunner,
+        time::{current_epoch, local_week_minute},
+    },
     volume::{Volume, VolumeSliceExt},
 };
 
-pub fn backup(ctx: &AppCtx, target: Option<&str>, dry_run: bool) -> Result<()> {
-    let _lock = LockGuard::try_acquire("pvtool-backup")?;
+const BLACKOUT_POLL_SECS: u32 = 30;
+
+pub struct NodeResult {
+    pub node: String,
+    pub backup_id: String,
+    pub secs: u64,
+    pub error: Option<String>,
+}
+
+/// How one volume fared in a backup run, for the summary table and the `backup_summary` event.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VolumeOutcome {
+    pub storage: String,
+    pub disk: String,
+    pub archive: String,
+    /// Size of the volume as discovery saw it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size_bytes: Option<u64>,
+    /// Bytes read from the device, as reported by proxmox-backup-client.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Compressed bytes of the chunks the datastore did not have yet: what the volume
+    /// costs in storage after deduplication.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stored: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secs: Option<f64>,
+    pub status: VolumeStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VolumeStatus {
+    Ok,
+    Failed,
+    Skipped,
+}
+
+/// What became of the selected volumes so far; reported however the run ends.
+#[derive(Default)]
+struct Report {
+    volumes: Vec<Volume>,
+    skipped: Vec<Skipped>,
+    failed: Vec<Skipped>,
+    stats: Vec<UploadStats>,
+}
+
+impl Report {
+    fn outcomes(&self, error: Option<&str>) -> Vec<VolumeOutcome> {
+        let find = |list: &[Skipped], v: &Volume| {
+            list.iter()
+                .find(|s| s.archive == v.archive)
+                .map(|s| s.reason.clone())
+        };
+        self.volumes
+            .iter()
+            .map(|v| {
+                let (status, reason) = if let Some(r) = find(&self.failed, v) {
+                    (VolumeStatus::Failed, Some(r))
+                } else if let Some(r) = find(&self.skipped, v) {
+                    (VolumeStatus::Skipped, Some(r))
+                } else if let Some(e) = error {
+                    (VolumeStatus::Failed, Some(e.to_string()))
+                } else {
+                    (VolumeStatus::Ok, None)
+                };
+                let stats = self.stats.iter().find(|s| s.archive == v.archive);
+                VolumeOutcome {
+                    storage: v.storage.clone(),
+                    disk: v.disk.clone(),
+                    archive: v.archive.clone(),
+                    size_bytes: v.size_bytes,
+                    size: stats.map(|s| s.size),
+                    stored: stats.map(|s| s.compressed),
+                    secs: stats.map(|s| s.secs),
+                    status,
+                    reason,
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Default)]
+pub struct RunOpts {
+    pub target: Option<String>,
+    pub dry_run: bool,
+    pub emit_script: Option<PathBuf>,
+    pub ignore_blackout: bool,
+    pub changed_only: bool,
+    pub only: Vec<String>,
+    pub include_active: bool,
+    pub force: bool,
+}
+
+impl From<&super::BackupRunArgs> for RunOpts {
+    fn from(value: &super::BackupRunArgs) -> Self {
+        Self {
+            target: value.target.clone(),
+            dry_run: value.dry_run,
+            emit_script: value.emit_script.clone(),
+            ignore_blackout: value.ignore_blackout,
+            changed_only: value.changed_only,
+            only: value.only.clone(),
+            include_active: value.include_active,
+            force: value.force,
+        }
+    }
+}
+
+pub fn backup(ctx: &AppCtx, opts: RunOpts) -> Result<()> {
+    let RunOpts {
+        target,
+        dry_run,
+        emit_script,
+        ignore_blackout,
+        changed_only,
+        only,
+        include_active,
+        force,
+    } = opts;
+    let only = parse_only(&only)?;
+    exec_policy::emitting_script(emit_script.as_deref(), "pvtools backup run", || {
+        let repo = ctx.cfg.resolve_backup_repo(target.as_deref())?;
+        if ignore_blackout {
+            if let Some(w) = active_blackout(&ctx.cfg.backup)? {
+                tracing::warn!("inside blackout window '{w}', running anyway (--ignore-blackout)");
+            }
+        } else if !dry_run {
+            wait_out_blackout(&ctx.cfg.backup)?;
+        }
+        run(
+            ctx,
+            repo,
+            dry_run,
+            changed_only,
+            &only,
+            include_active,
+            force,
+        )
+    })
+}
+
+/// `--only` patterns, anchored so a plain archive or disk name selects just that volume.
+fn parse_only(patterns: &[String]) -> Result<Vec<Regex>> {
+    patterns
+        .iter()
+        .map(|p| Regex::new(&format!("^(?:{p})$")).with_context(|| format!("bad --only '{p}'")))
+        .collect()
+}
+
+fn is_selected(v: &Volume, only: &[Regex]) -> bool {
+    only.is_empty()
+        || only
+            .iter()
+            .any(|re| re.is_match(&v.archive) || re.is_match(&v.disk))
+}
+
+/// Sorts `volumes` into `[backup] order`; ties and volumes of unknown size keep discovery order.
+fn order_volumes(volumes: &mut [Volume], order: BackupOrder) {
+    match order {
+        BackupOrder::Config => {}
+        BackupOrder::Alphabetical => {
+            volumes.sort_by(|a, b| (&a.disk, &a.archive).cmp(&(&b.disk, &b.archive)))
+        }
+        BackupOrder::SizeAsc => volumes.sort_by_key(|v| (v.size_bytes.is_none(), v.size_bytes)),
+        BackupOrder::SizeDesc => {
+            volumes.sort_by_key(|v| (v.size_bytes.is_none(), Reverse(v.size_bytes)))
+        }
+    }
+}
+
+fn run(
+    ctx: &AppCtx,
+    repo: &Repo,
+    dry_run: bool,
+    changed_only: bool,
+    only: &[Regex],
+    include_active: bool,
+    force: bool,
+) -> Result<()> {
+    if !ctx.cfg.nodes.is_empty() {
+        return backup_nodes(
+            ctx,
+            repo,
+            dry_run,
+            changed_only,
+            only,
+            include_active,
+            force,
+        );
+    }
+    let mut resources = source_resources(&ctx.cfg);
+    resources.push(Resource::Repo(repo.url.clone()));
+    let _lock = LockSet::try_acquire(resources)?;
 
     with_dry_run_enabled(dry_run, || {
-        let repo = ctx.cfg.resolve_backup_repo(target)?;
-        let ns_opt = ctx.cfg.pbs.ns.as_deref();
-        let registry = ProviderRegistry::new(ctx);
-        let mut providers = registry.build();
-        let mut volumes: Vec<Volume> = Vec::new();
+        ctx.events.emit(Event::RunStarted {
+            command: "backup",
+            backup_id: &ctx.cfg.pbs.backup_id,
+            repo: &repo.url,
+            dry_run,
+        });
 
-        for p in providers.iter_mut() {
-            let mut v = p
-                .discover()
-                .with_context(|| format!("collect from provider {}", p.name()))?;
-            volumes.append(&mut v);
+        let res = run_backup(
+            ctx,
+            repo,
+            run_deadline(ctx),
+            changed_only,
+            only,
+            include_active,
+            force,
+        );
+        ctx.events.emit(Event::RunFinished {
+            command: "backup",
+            ok: res.is_ok(),
+            error: res.as_ref().err().map(|e| format!("{e:#}")),
+        });
+        res
+    })
+}
+
+fn active_blackout(cfg: &Backup) -> Result<Option<&Blackout>> {
+    if cfg.blackout.is_empty() {
+        return Ok(None);
+    }
+    let (day, minute) = local_week_minute(current_epoch())?;
+    Ok(cfg.blackout.iter().find(|w| w.contains(day, minute)))
+}
+
+/// Refuses to start inside a blackout window, or with `blackout_action = "wait"` sleeps
+/// until no window applies any more.
+fn wait_out_blackout(cfg: &Backup) -> Result<()> {
+    let mut waiting = false;
+    while let Some(w) = active_blackout(cfg)? {
+        match cfg.blackout_action {
+            BlackoutAction::Refuse => {
+                bail!("inside blackout window '{w}'; pass --ignore-blackout to run anyway")
+            }
+            BlackoutAction::Wait => {
+                if !waiting {
+                    tracing::info!("inside blackout window '{w}', waiting for it to end");
+                    waiting = true;
+                }
+                for _ in 0..BLACKOUT_POLL_SECS {
+                    std::thread::sleep(Duration::from_secs(1));
+                    signal::check()?;
+                }
+            }
         }
+    }
+    if waiting {
+        tracing::info!("blackout window over, starting backup");
+    }
+    Ok(())
+}
 
-        if volumes.is_empty() {
+/// Backs up every `[nodes.<name>]` in turn; one failing node does not stop the others.
+fn backup_nodes(
+    ctx: &AppCtx,
+    repo: &Repo,
+    dry_run: bool,
+    changed_only: bool,
+    only: &[Regex],
+    include_active: bool,
+    force: bool,
+) -> Result<()> {
+    let _lock = LockSet::try_acquire([Resource::Repo(repo.url.clone())])?;
+
+    with_dry_run_enabled(dry_run, || {
+        ctx.events.emit(Event::RunStarted {
+            command: "backup",
+            backup_id: &ctx.cfg.pbs.backup_id,
+            repo: &repo.url,
+            dry_run,
+        });
+
+        let deadline = run_deadline(ctx);
+        let mut results = Vec::with_capacity(ctx.cfg.nodes.len());
+        for (name, node) in &ctx.cfg.nodes {
+            let started = Instant::now();
+            let res = if deadline.is_some_and(|d| started >= d) {
+                Err(anyhow!("skipped: run_timeout exceeded"))
+            } else {
+                tracing::info!("node {name}: backup as {}", node.backup_id);
+                LockSet::try_acquire([Resource::Node(name.clone())])
+                    .and_then(|_lock| {
+                        run_backup(
+                            &node_ctx(ctx, node)?,
+                            repo,
+                            deadline,
+                            changed_only,
+                            only,
+                            include_active,
+                            force,
+                        )
+                    })
+                    .with_context(|| format!("node {name}"))
+            };
+            if let Err(e) = &res {
+                tracing::error!("{e:#}");
+            }
+            results.push(NodeResult {
+                node: name.clone(),
+                backup_id: node.backup_id.clone(),
+                secs: started.elapsed().as_secs(),
+                error: res.err().map(|e| format!("{e:#}")),
+            });
+            if signal::interrupted().is_some() {
+                break;
+            }
+        }
+
+        ui::log_node_results(&results, ctx.cfg.nodes.len());
+        let failed = ctx.cfg.nodes.len() - results.iter().filter(|r| r.error.is_none()).count();
+        let res = if failed > 0 {
+            Err(Failure::Partial.with(format!(
+                "{failed} of {} nodes failed to back up",
+                ctx.cfg.nodes.len()
+            )))
+        } else {
+            Ok(())
+        };
+        ctx.events.emit(Event::RunFinished {
+            command: "backup",
+            ok: res.is_ok(),
+            error: res.as_ref().err().map(|e| format!("{e:#}")),
+        });
+        res
+    })
+}
+
+/// Context of a single node: its sources and backup group, with every command sent over ssh.
+pub(crate) fn node_ctx(ctx: &AppCtx, node: &Node) -> Result<AppCtx> {
+    let mut cfg = ctx.cfg.clone();
+    cfg.pbs.backup_id = node.backup_id.clone();
+    cfg.backup.sources = node.sources.clone();
+    cfg.backup.ssh = Some(node.ssh.clone());
+    cfg.restore = Restore::default();
+    cfg.nodes.clear();
+
+    let runner = Arc::new(SshRunner::new(node.ssh.clone()));
+    let tools = Toolbox::over_ssh(&cfg, runner.clone())?;
+    Ok(AppCtx {
+        debug: ctx.debug,
+        cfg,
+        runner,
+        tools,
+        events: ctx.events.clone(),
+    })
+}
+
+fn run_deadline(ctx: &AppCtx) -> Option<Instant> {
+    ctx.cfg
+        .backup
+        .run_timeout
+        .filter(|_| !is_dry_run())
+        .map(|t| Instant::now() + t)
+}
+
+fn run_backup(
+    ctx: &AppCtx,
+    repo: &Repo,
+    deadline: Option<Instant>,
+    changed_only: bool,
+    only: &[Regex],
+    include_active: bool,
+    force: bool,
+) -> Result<()> {
+    if !force
+        && let Some(min) = ctx.cfg.backup.min_interval
+        && let Some(age) = recent_snapshot(ctx, repo, min)?
+    {
+        tracing::info!(
+            "recent snapshot exists ({age}s old, min_interval {}s), nothing to do; \
+             pass --force to back up anyway",
+            min.as_secs()
+        );
+        return Ok(());
+    }
+    preflight(ctx, repo)?;
+    let mut report = Report::default();
+    let res = backup_volumes(
+        ctx,
+        repo,
+        deadline,
+        changed_only,
+        only,
+        include_active,
+        &mut report,
+    );
+    if !report.volumes.is_empty() {
+        let outcomes = report.outcomes(res.as_ref().err().map(|e| format!("{e:#}")).as_deref());
+        ui::log_backup_summary(&outcomes);
+        ctx.events.emit(Event::BackupSummary { volumes: &outcomes });
+    }
+    res
+}
+
+/// Age of the group's latest snapshot in `repo`, if it is younger than `min`.
+fn recent_snapshot(ctx: &AppCtx, repo: &Repo, min: Duration) -> Result<Option<u64>> {
+    let snaps = repo_snapshots(ctx, repo)?;
+    Ok(younger_than(
+        &snaps,
+        &ctx.cfg.pbs.backup_id,
+        current_epoch(),
+        min,
+    ))
+}
+
+/// Snapshots in `repo` and its namespace; none while the namespace does not exist yet.
+fn repo_snapshots(ctx: &AppCtx, repo: &Repo) -> Result<Vec<PbsSnapshot>> {
+    let pbs = ctx.tools.pbs();
+    let ns = repo.ns.as_deref();
+    if let Some(ns) = ns
+        && !pbs.ns_exists(repo, ns)?
+    {
+        return Ok(Vec::new());
+    }
+    pbs.snapshots(repo, ns)
+}
+
+/// Backup time of the snapshot of the group that holds `archive` from an earlier upload, if
+/// it may stand in for a new one: fewer than `max_runs` later snapshots left the archive out
+/// and it is younger than `max_age`. Otherwise why the archive has to be uploaded again.
+fn carry_source(
+    snaps: &[PbsSnapshot],
+    backup_id: &str,
+    archive: &str,
+    now: u64,
+    max_runs: u32,
+    max_age: Duration,
+) -> std::result::Result<u64, String> {
+    let group: Vec<&PbsSnapshot> = snaps.iter().filter(|s| s.backup_id == backup_id).collect();
+    // PBS lists the archives the backup recorded with a `.fidx` suffix.
+    let holder = group
+        .iter()
+        .filter(|s| {
+            s.files
+                .iter()
+                .any(|f| f.filename.strip_suffix(".fidx").unwrap_or(&f.filename) == archive)
+        })
+        .map(|s| s.backup_time)
+        .max()
+        .ok_or_else(|| "no snapshot of the group holds it".to_string())?;
+    let runs = group.iter().filter(|s| s.backup_time > holder).count();
+    if runs >= max_runs as usize {
+        return Err(format!("the last {runs} snapshot(s) left it out"));
+    }
+    let age = now.saturating_sub(holder);
+    if age >= max_age.as_secs() {
+        return Err(format!("its last upload is {age}s old"));
+    }
+    Ok(holder)
+}
+
+fn younger_than(snaps: &[PbsSnapshot], backup_id: &str, now: u64, min: Duration) -> Option<u64> {
+    let latest = snaps
+        .iter()
+        .filter(|s| s.backup_id == backup_id)
+        .map(|s| s.backup_time)
+        .max()?;
+    let age = now.saturating_sub(latest);
+    (age < min.as_secs()).then_some(age)
+}
+
+fn backup_volumes(
+    ctx: &AppCtx,
+    repo: &Repo,
+    deadline: Option<Instant>,
+    changed_only: bool,
+    only: &[Regex],
+    include_active: bool,
+    report: &mut Report,
+) -> Result<()> {
+    let ns_opt = repo.ns.as_deref();
+    let registry = ProviderRegistry::new(ctx);
+    let mut providers = registry.build();
+    let mut volumes: Vec<Volume> = Vec::new();
+
+    for p in providers.iter_mut() {
+        let mut v = p
+            .discover()
+            .with_context(|| format!("collect from provider {}", p.name()))?;
+        volumes.append(&mut v);
+    }
+    let discovered = volumes.len();
+    volumes.retain(|v| is_selected(v, only));
+    order_volumes(&mut volumes, ctx.cfg.backup.order);
+
+    if volumes.is_empty() {
+        if discovered > 0 {
+            tracing::warn!("--only matches none of the {discovered} volumes, nothing to backup");
+        } else {
             tracing::info!("nothing to backup");
-            return Ok(());
         }
+        return Ok(());
+    }
 
-        volumes.ensure_unique_archive_names()?;
+    volumes.ensure_unique_archive_names()?;
+    report.volumes = volumes.clone();
+    for v in &volumes {
+        ctx.events.emit(Event::VolumeDiscovered {
+            storage: &v.storage,
+            disk: &v.disk,
+            archive: &v.archive,
+        });
+    }
 
-        ui::log_pbs_info(repo, ns_opt, &ctx.cfg.pbs.backup_id, None);
-        ui::log_archives(&volumes);
+    let mut carried = Vec::new();
+    if changed_only {
+        let mut skipped = Vec::new();
+        for p in &providers {
+            skipped.append(&mut p.unchanged(&volumes)?);
+        }
+        if !skipped.is_empty() {
+            let snaps = repo_snapshots(ctx, repo)?;
+            let max_runs = ctx
+                .cfg
+                .backup
+                .changed_only_max_runs
+                .unwrap_or(DEFAULT_CHANGED_ONLY_MAX_RUNS);
+            let max_age = ctx
+                .cfg
+                .backup
+                .changed_only_max_age
+                .unwrap_or(DEFAULT_CHANGED_ONLY_MAX_AGE);
+            let now = current_epoch();
+            skipped.retain(|s| {
+                let backup_id = &ctx.cfg.pbs.backup_id;
+                match carry_source(&snaps, backup_id, &s.archive, now, max_runs, max_age) {
+                    Ok(backup_time) => {
+                        carried.push(CarriedArchive {
+                            archive: s.archive.clone(),
+                            backup_time,
+                        });
+                        true
+                    }
+                    Err(why) => {
+                        tracing::info!("{}: unchanged, but {why}; uploading it again", s.archive);
+                        false
+                    }
+                }
+            });
+        }
+        for s in &skipped {
+            tracing::info!("{}: unchanged, skipped", s.archive);
+            ctx.events.emit(Event::VolumeSkipped {
+                archive: &s.archive,
+                reason: &s.reason,
+            });
+        }
+        report.skipped.append(&mut skipped);
+        volumes.retain(|v| !report.skipped.iter().any(|s| s.archive == v.archive));
+        if volumes.is_empty() {
+            tracing::info!("nothing changed since the last backup");
+            return Ok(());
+        }
+    }
 
-        if let Some(ns) = ns_opt {
-            ctx.tools.pbs().ns_ensure(repo, ns)?;
+    let claims = pv_claims(ctx);
+    let check_active = ctx.cfg.backup.active_volumes;
+    if check_active != ActiveVolumes::Ignore {
+        let active = active_volumes(ctx, &providers, &volumes, &claims)?;
+        let skip = check_active == ActiveVolumes::Skip && !include_active;
+        for s in &active {
+            if skip {
+                tracing::warn!(
+                    "{}: in use ({}), skipped; pass --include-active to back it up",
+                    s.archive,
+                    s.reason
+                );
+                ctx.events.emit(Event::VolumeSkipped {
+                    archive: &s.archive,
+                    reason: &format!("in use: {}", s.reason),
+                });
+            } else {
+                tracing::warn!(
+                    "{}: in use ({}), its snapshot is only crash-consistent",
+                    s.archive,
+                    s.reason
+                );
+            }
         }
+        if skip && !active.is_empty() {
+            report.skipped.extend(active.into_iter().map(|s| Skipped {
+                reason: format!("in use: {}", s.reason),
+                archive: s.archive,
+            }));
+            volumes.retain(|v| !report.skipped.iter().any(|s| s.archive == v.archive));
+            if volumes.is_empty() {
+                tracing::info!("every selected volume is in use, nothing to backup");
+                return Ok(());
+            }
+        }
+    }
+
+    ui::log_pbs_info(&repo.url, ns_opt, &ctx.cfg.pbs.backup_id, None);
+    ui::log_archives(&volumes);
+
+    if let Some(ns) = ns_opt {
+        ctx.tools.pbs().ns_ensure(repo, ns)?;
+    }
 
+    if deadline.is_some_and(|d| Instant::now() >= d) {
+        report.skipped.append(&mut skip_all(
+            ctx,
+            &volumes,
+            "run_timeout exceeded before snapshots",
+        ));
+        bail!("run_timeout exceeded before any volume was snapshotted");
+    }
+
+    let snapshots_taken = Instant::now();
+    let mut failed = groups::snapshot_groups(ctx, &mut providers, &volumes, &claims)?;
+    volumes.retain(|v| !failed.iter().any(|s| s.archive == v.archive));
+    if ctx.cfg.backup.snapshot_barrier {
         for p in providers.iter_mut() {
-            p.prepare(&volumes)?;
+            failed.append(&mut p.snapshot(&volumes)?);
         }
+        volumes.retain(|v| !failed.iter().any(|s| s.archive == v.archive));
+        tracing::info!(
+            "snapshots taken in {:.1}s",
+            snapshots_taken.elapsed().as_secs_f64()
+        );
+    }
+    for p in providers.iter_mut() {
+        failed.append(&mut p.prepare(&volumes)?);
+    }
+    if !failed.is_empty() {
+        for s in &failed {
+            ctx.events.emit(Event::VolumeSkipped {
+                archive: &s.archive,
+                reason: &s.reason,
+            });
+        }
+        volumes.retain(|v| !failed.iter().any(|s| s.archive == v.archive));
+        report.failed.append(&mut failed);
+        if volumes.is_empty() {
+            tracing::info!("nothing left to backup");
+            return Ok(());
+        }
+    }
+    if deadline.is_some_and(|d| Instant::now() >= d) {
+        report.skipped.append(&mut skip_all(
+            ctx,
+            &volumes,
+            "run_timeout exceeded while preparing",
+        ));
+        bail!("run_timeout exceeded before the upload started");
+    }
 
-        let keyfile = ctx.cfg.pbs.keyfile.as_deref();
-        let items: Vec<BackupItem> = volumes
-            .iter()
-            .map(|v| BackupItem {
-                archive: v.archive.as_str(),
-                device: v.device.as_path(),
-            })
-            .collect();
-        ctx.tools
-            .pbs()
-            .backup(repo, ns_opt, &ctx.cfg.pbs.backup_id, keyfile, &items)?;
+    let max_age = ctx.cfg.backup.snapshot_max_age.filter(|_| !is_dry_run());
+    let (_watch, age_budget) = match (max_age, ctx.cfg.backup.snapshot_age_action) {
+        (Some(age), SnapshotAgeAction::Warn) => {
+            (Some(SnapshotWatch::start(age, usage_probes(ctx))), None)
+        }
+        (Some(age), SnapshotAgeAction::Abort) => {
+            (None, Some(age.saturating_sub(snapshots_taken.elapsed())))
+        }
+        (None, _) => (None, None),
+    };
+    let volume_timeout = ctx.cfg.backup.volume_timeout.filter(|_| !is_dry_run());
+    let run_budget = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+    let upload_limit = upload_limit(&[
+        (age_budget, "snapshot_max_age"),
+        (run_budget, "run_timeout"),
+    ]);
 
-        if let Ok(ts) = latest_backup_time(ctx, repo, ns_opt, &ctx.cfg.pbs.backup_id) {
-            ui::log_pbs_info(repo, ns_opt, &ctx.cfg.pbs.backup_id, Some(ts));
-        } else {
-            tracing::info!("Backup finished, but latest snapshot time is not visible yet.");
+    let storage = providers.iter().flat_map(|p| p.storage_status()).collect();
+    let mut manifest = BackupManifest::new(&ctx.cfg.pbs.backup_id, storage);
+    manifest.claims = claim_records(&claims, &volumes);
+    manifest.carried = carried;
+    // A remote PBS client cannot read a local temp file, so stage it on that host instead.
+    let (_local_manifest, _remote_manifest, manifest_path) = if ctx.tools.is_remote() {
+        let path = PathBuf::from(format!(
+            "/tmp/pvtools-manifest-{}-{}.json",
+            ctx.cfg.pbs.backup_id,
+            current_epoch()
+        ));
+        let f = PortFile::write(ctx.tools.fs(), path, &manifest.to_json()?)?;
+        let path = f.path().to_path_buf();
+        (None, Some(f), path)
+    } else {
+        let f = manifest.write_temp()?;
+        let path = f.path().to_path_buf();
+        (Some(f), None, path)
+    };
+
+    let mut items: Vec<BackupItem> = volumes
+        .iter()
+        .map(|v| BackupItem {
+            archive: v.archive.as_str(),
+            device: v.device.as_path(),
+        })
+        .collect();
+    items.push(BackupItem {
+        archive: MANIFEST_ARCHIVE,
+        device: manifest_path.as_path(),
+    });
+    let upload_started = Instant::now();
+    let uploaded = ctx
+        .tools
+        .pbs()
+        .backup(
+            repo,
+            ns_opt,
+            &ctx.cfg.pbs.backup_id,
+            &items,
+            BackupOpts {
+                timeout: upload_limit.map(|(t, _)| t),
+                archive_timeout: volume_timeout,
+                priority: ctx.cfg.backup.priority,
+                ..BackupOpts::default()
+            },
+        )
+        .with_context(|| match upload_limit {
+            Some((t, limit)) => {
+                format!("upload failed or exceeded {limit} ({}s left)", t.as_secs())
+            }
+            None => "upload failed".to_string(),
+        });
+    let cancelled = match &uploaded {
+        Err(e) if e.chain().any(|c| c.is::<StepTimedOut>()) => Some("volume_timeout"),
+        Err(_) => upload_limit
+            .filter(|&(t, _)| upload_started.elapsed() >= t)
+            .map(|(_, limit)| limit),
+        Ok(_) => None,
+    };
+    if let Some(limit) = cancelled {
+        report.skipped.append(&mut skip_all(
+            ctx,
+            &volumes,
+            &format!("upload cancelled: {limit} exceeded"),
+        ));
+    }
+    let stats = uploaded?;
+    if changed_only {
+        for p in providers.iter_mut() {
+            if let Err(e) = p.keep_baseline(&volumes) {
+                tracing::warn!("{}: baseline for --changed-only not kept: {e:#}", p.name());
+            }
         }
-        tracing::info!("Done");
-        Ok(())
-    })
+    }
+    for v in &volumes {
+        ctx.events.emit(Event::ArchiveUploaded {
+            archive: &v.archive,
+            stats: stats.iter().find(|s| s.archive == v.archive),
+        });
+    }
+
+    let latest = latest_backup_time(ctx, repo, ns_opt, &ctx.cfg.pbs.backup_id);
+    if let Ok(ts) = latest {
+        ui::log_pbs_info(&repo.url, ns_opt, &ctx.cfg.pbs.backup_id, Some(ts));
+    } else {
+        tracing::info!("Backup finished, but latest snapshot time is not visible yet.");
+    }
+    if !stats.is_empty() {
+        ui::log_upload_stats(&stats);
+    }
+    if let Ok(ts) = latest
+        && !is_dry_run()
+    {
+        let snapshot_stats = snapshot_path(&ctx.cfg.pbs.backup_id, ts)
+            .and_then(|snap| ctx.tools.pbs().snapshot_stats(repo, ns_opt, &snap));
+        match snapshot_stats {
+            Ok(Some(s)) => ui::log_snapshot_stats(&s),
+            Ok(None) => tracing::debug!("no chunk statistics in the snapshot's index.json"),
+            Err(e) => tracing::warn!("deduplication statistics not read: {e:#}"),
+        }
+    }
+    report.stats = stats;
+    tracing::info!("Done");
+    Ok(())
+}
+
+/// Tightest of the upload limits that apply, with the config key it comes from.
+fn upload_limit(limits: &[(Option<Duration>, &'static str)]) -> Option<(Duration, &'static str)> {
+    limits
+        .iter()
+        .filter_map(|&(t, key)| t.map(|t| (t, key)))
+        .min_by_key(|&(t, _)| t)
+}
+
+fn skip_all(ctx: &AppCtx, volumes: &[Volume], reason: &str) -> Vec<Skipped> {
+    volumes
+        .iter()
+        .map(|v| {
+            ctx.events.emit(Event::VolumeSkipped {
+                archive: &v.archive,
+                reason,
+            });
+            Skipped {
+                archive: v.archive.clone(),
+                reason: reason.to_string(),
+            }
+        })
+        .collect()
 }
 
 pub fn list_archives(ctx: &AppCtx) -> Result<()> {
-    let _lock = LockGuard::try_acquire("pvtool-backup")?;
+    for (name, node) in &ctx.cfg.nodes {
+        tracing::info!("node {name}:");
+        let _lock = LockSet::try_acquire([Resource::Node(name.clone())])?;
+        list_archives(&node_ctx(ctx, node)?).with_context(|| format!("node {name}"))?;
+    }
+    if !ctx.cfg.nodes.is_empty() {
+        return Ok(());
+    }
+
+    let _lock = LockSet::try_acquire(source_resources(&ctx.cfg))?;
     let registry = ProviderRegistry::new(ctx);
     let mut providers = registry.build();
     let mut volumes: Vec<Volume> = Vec::new();
@@ -86,13 +875,163 @@ pub fn list_archives(ctx: &AppCtx) -> Result<()> {
     }
 
     volumes.ensure_unique_archive_names()?;
+    order_volumes(&mut volumes, ctx.cfg.backup.order);
 
     ui::log_archives(&volumes);
 
     Ok(())
 }
 
-fn latest_backup_time(ctx: &AppCtx, repo: &str, ns: Option<&str>, backup_id: &str) -> Result<u64> {
+/// Bound PVCs, used to group volumes and recorded in the manifest so restores can select
+/// archives by claim name. A failed lookup only costs that, so it does not fail the backup.
+fn pv_claims(ctx: &AppCtx) -> Vec<PvClaim> {
+    let Some(kube) = ctx.tools.kube() else {
+        return Vec::new();
+    };
+    kube.claims().unwrap_or_else(|e| {
+        tracing::warn!("kubernetes: PVC lookup failed, no claims are matched or recorded: {e:#}");
+        Vec::new()
+    })
+}
+
+fn pv_attachments(ctx: &AppCtx) -> BTreeMap<String, String> {
+    let Some(kube) = ctx.tools.kube() else {
+        return BTreeMap::new();
+    };
+    kube.attachments().unwrap_or_else(|e| {
+        tracing::warn!("kubernetes: volume attachment lookup failed: {e:#}");
+        BTreeMap::new()
+    })
+}
+
+/// Node the PV backed by `disk` is attached to.
+fn attached_node<'a>(
+    attached: &'a BTreeMap<String, String>,
+    claims: &[PvClaim],
+    disk: &str,
+) -> Option<&'a str> {
+    claims
+        .iter()
+        .filter(|c| c.matches_disk(disk))
+        .find_map(|c| attached.get(&c.pv))
+        .map(String::as_str)
+}
+
+/// `active_volumes`: volumes open on the host, as their provider sees it, or whose PV is
+/// attached to a Kubernetes node, with what holds them.
+fn active_volumes(
+    ctx: &AppCtx,
+    providers: &[Box<dyn Provider + '_>],
+    volumes: &[Volume],
+    claims: &[PvClaim],
+) -> Result<Vec<Skipped>> {
+    let mut active = Vec::new();
+    for p in providers {
+        active.append(
+            &mut p
+                .in_use(volumes)
+                .with_context(|| format!("check which {} volumes are in use", p.name()))?,
+        );
+    }
+    let attached = pv_attachments(ctx);
+    for v in volumes {
+        if let Some(node) = attached_node(&attached, claims, &v.disk)
+            && !active.iter().any(|s| s.archive == v.archive)
+        {
+            active.push(Skipped {
+                archive: v.archive.clone(),
+                reason: format!("attached to node {node}"),
+            });
+        }
+    }
+    Ok(active)
+}
+
+fn claim_records(claims: &[PvClaim], volumes: &[Volume]) -> Vec<ClaimRecord> {
+    volumes
+        .iter()
+        .filter_map(|v| {
+            let c = claims.iter().find(|c| c.matches_disk(&v.disk))?;
+            Some(ClaimRecord {
+                archive: v.archive.clone(),
+                namespace: c.namespace.clone(),
+                name: c.name.clone(),
+                pv: Some(PvRecord {
+                    name: c.pv.clone(),
+                    volume_handle: c.volume_handle.clone(),
+                    details: c.details.clone(),
+                }),
+            })
+        })
+        .collect()
+}
+
+/// Prints every volume the configured providers consider, with why each one is left out.
+pub fn discover(ctx: &AppCtx) -> Result<()> {
+    for (name, node) in &ctx.cfg.nodes {
+        tracing::info!("node {name}:");
+        discover(&node_ctx(ctx, node)?).with_context(|| format!("node {name}"))?;
+    }
+    if !ctx.cfg.nodes.is_empty() {
+        return Ok(());
+    }
+
+    let registry = ProviderRegistry::new(ctx);
+    let mut candidates = Vec::new();
+    for p in registry.build() {
+        let mut c = p
+            .candidates()
+            .with_context(|| format!("discover from provider {}", p.name()))?;
+        candidates.append(&mut c);
+    }
+    let claims = pv_claims(ctx);
+    let attached = pv_attachments(ctx);
+    for c in candidates.iter_mut().filter(|c| c.in_use.is_none()) {
+        let disk = c.name.rsplit('/').next().unwrap_or(&c.name);
+        if let Some(node) = attached_node(&attached, &claims, disk) {
+            c.in_use = Some(format!("attached to node {node}"));
+        }
+    }
+    ui::log_candidates(&candidates);
+    Ok(())
+}
+
+fn usage_probes(ctx: &AppCtx) -> Vec<UsageProbe> {
+    let mut out = Vec::new();
+    if let (Some(z), Some(zfs)) = (&ctx.cfg.backup.sources.zfs, ctx.tools.zfs()) {
+        for pool in &z.pools {
+            let pool = pool.split('/').next().unwrap_or(pool).to_string();
+            let zfs = zfs.clone();
+            out.push(UsageProbe {
+                label: format!("zpool {pool}"),
+                probe: Box::new(move || zfs.pool_usage(&pool)),
+            });
+        }
+    }
+    if let (Some(l), Some(lvm)) = (&ctx.cfg.backup.sources.lvmthin, ctx.tools.lvm()) {
+        for vg in &l.vgs {
+            let vg = vg.clone();
+            let lvm = lvm.clone();
+            out.push(UsageProbe {
+                label: format!("vg {vg}"),
+                probe: Box::new(move || lvm.thinpool_usage(&vg)),
+            });
+        }
+    }
+    if let (Some(l), Some(lvm)) = (&ctx.cfg.backup.sources.lvm, ctx.tools.lvm()) {
+        for vg in &l.vgs {
+            let vg = vg.clone();
+            let lvm = lvm.clone();
+            out.push(UsageProbe {
+                label: format!("vg {vg} snapshots"),
+                probe: Box::new(move || lvm.cow_snapshot_usage(&vg)),
+            });
+        }
+    }
+    out
+}
+
+fn latest_backup_time(ctx: &AppCtx, repo: &Repo, ns: Option<&str>, backup_id: &str) -> Result<u64> {
     let snaps = ctx.tools.pbs().snapshots(repo, ns)?;
     snaps
         .iter()
@@ -101,3 +1040,167 @@ fn latest_backup_time(ctx: &AppCtx, repo: &str, ns: Option<&str>, backup_id: &st
         .max()
         .context("no snapshot visible after backup with given backup-id")
 }
+
+/// Pools and VGs the configured backup sources snapshot.
+pub(crate) fn source_resources(cfg: &Config) -> Vec<Resource> {
+    let mut out = Vec::new();
+    if let Some(zfs) = &cfg.backup.sources.zfs {
+        out.extend(zfs.pools.iter().map(|p| Resource::zfs(p)));
+    }
+    if let Some(lvm) = &cfg.backup.sources.lvmthin {
+        out.extend(lvm.vgs.iter().map(|vg| Resource::Vg(vg.clone())));
+    }
+    if let Some(lvm) = &cfg.backup.sources.lvm {
+        out.extend(lvm.vgs.iter().map(|vg| Resource::Vg(vg.clone())));
+    }
+    out.extend(
+        cfg.backup
+            .sources
+            .external
+            .keys()
+            .map(|name| Resource::External(name.clone())),
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_classifies_every_selected_volume() {
+        let vol = |disk: &str| Volume {
+            storage: "local-zfs".to_string(),
+            disk: disk.to_string(),
+            archive: format!("zfs_{disk}_noext_aaaa1111.img"),
+            device: PathBuf::from(format!("/dev/zvol/tank/{disk}")),
+            size_bytes: Some(8 << 30),
+            meta: None,
+        };
+        let skip = |v: &Volume, reason: &str| Skipped {
+            archive: v.archive.clone(),
+            reason: reason.to_string(),
+        };
+        let volumes = vec![vol("vm-1-disk-0"), vol("vm-1-disk-1"), vol("vm-2-disk-0")];
+        let report = Report {
+            skipped: vec![skip(&volumes[1], "unchanged")],
+            failed: vec![skip(&volumes[2], "snapshot failed")],
+            stats: vec![UploadStats {
+                archive: volumes[0].archive.clone(),
+                size: 1 << 20,
+                uploaded: 0,
+                compressed: 0,
+                secs: 2.0,
+            }],
+            volumes,
+        };
+
+        let ok = report.outcomes(None);
+        assert_eq!(
+            ok.iter().map(|o| o.status).collect::<Vec<_>>(),
+            [
+                VolumeStatus::Ok,
+                VolumeStatus::Skipped,
+                VolumeStatus::Failed
+            ]
+        );
+        assert_eq!((ok[0].size, ok[0].secs), (Some(1 << 20), Some(2.0)));
+        assert_eq!(ok[1].size_bytes, Some(8 << 30));
+        assert_eq!(ok[2].reason.as_deref(), Some("snapshot failed"));
+
+        let failed = report.outcomes(Some("upload failed"));
+        assert_eq!(failed[0].status, VolumeStatus::Failed);
+        assert_eq!(failed[0].reason.as_deref(), Some("upload failed"));
+        assert_eq!(failed[1].status, VolumeStatus::Skipped);
+    }
+
+    #[test]
+    fn min_interval_looks_at_the_latest_snapshot_of_the_group() {
+        let snap = |backup_id: &str, backup_time| PbsSnapshot {
+            backup_id: backup_id.to_string(),
+            backup_time,
+            files: Vec::new(),
+        };
+        let snaps = [
+            snap("pve1", 1_000),
+            snap("pve1", 50_000),
+            snap("pve2", 90_000),
+        ];
+        let min = Duration::from_secs(20 * 3600);
+
+        assert_eq!(younger_than(&snaps, "pve1", 60_000, min), Some(10_000));
+        assert_eq!(younger_than(&snaps, "pve1", 50_000 + 20 * 3600, min), None);
+        assert_eq!(younger_than(&snaps, "pve3", 60_000, min), None);
+    }
+
+    #[test]
+    fn unchanged_archives_are_carried_until_too_many_runs_or_too_old() {
+        let snap = |backup_id: &str, backup_time, files: &[&str]| PbsSnapshot {
+            backup_id: backup_id.to_string(),
+            backup_time,
+            files: files
+                .iter()
+                .map(|f| crate::tooling::pbs::PbsFile {
+                    filename: f.to_string(),
+                    size: 0,
+                    crypt_mode: None,
+                })
+                .collect(),
+        };
+        let a = "zfs_vm-1-disk-0_raw_aaaa1111.img";
+        let snaps = [
+            snap("pve1", 1_000, &["zfs_vm-1-disk-0_raw_aaaa1111.img.fidx"]),
+            snap("pve1", 2_000, &[]),
+            snap("pve1", 3_000, &[]),
+            snap("pve2", 4_000, &["zfs_vm-1-disk-0_raw_aaaa1111.img.fidx"]),
+        ];
+        let day = Duration::from_secs(86_400);
+
+        assert_eq!(carry_source(&snaps, "pve1", a, 5_000, 3, day), Ok(1_000));
+        assert!(carry_source(&snaps, "pve1", a, 5_000, 2, day).is_err());
+        assert!(carry_source(&snaps, "pve1", a, 1_000 + 86_400, 3, day).is_err());
+        assert!(carry_source(&snaps, "pve1", "zfs_other_raw_bbbb2222.img", 5_000, 3, day).is_err());
+        assert_eq!(carry_source(&snaps, "pve2", a, 5_000, 3, day), Ok(4_000));
+    }
+
+    #[test]
+    fn orders_volumes_by_name_or_size() {
+        let vol = |(disk, size_bytes): (&str, Option<u64>)| Volume {
+            storage: "local-zfs".to_string(),
+            disk: disk.to_string(),
+            archive: format!("zfs_{disk}_noext_aaaa1111.img"),
+            device: PathBuf::from(format!("/dev/zvol/tank/{disk}")),
+            size_bytes,
+            meta: None,
+        };
+        let ordered = |order| {
+            let mut volumes = [
+                ("vm-3-disk-0", Some(10)),
+                ("vm-2-disk-0", None),
+                ("vm-1-disk-0", Some(30)),
+                ("vm-4-disk-0", Some(20)),
+            ]
+            .map(vol)
+            .to_vec();
+            order_volumes(&mut volumes, order);
+            volumes.into_iter().map(|v| v.disk).collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            ordered(BackupOrder::Config),
+            ["vm-3-disk-0", "vm-2-disk-0", "vm-1-disk-0", "vm-4-disk-0"]
+        );
+        assert_eq!(
+            ordered(BackupOrder::Alphabetical),
+            ["vm-1-disk-0", "vm-2-disk-0", "vm-3-disk-0", "vm-4-disk-0"]
+        );
+        assert_eq!(
+            ordered(BackupOrder::SizeAsc),
+            ["vm-3-disk-0", "vm-4-disk-0", "vm-1-disk-0", "vm-2-disk-0"]
+        );
+        assert_eq!(
+            ordered(BackupOrder::SizeDesc),
+            ["vm-1-disk-0", "vm-4-disk-0", "vm-3-disk-0", "vm-2-disk-0"]
+        );
+    }
+}
@@ -0,0 +1,172 @@
+use std::slice;
+
+use anyhow::{Context, Result, bail};
+use regex::Regex;
+
+use crate::{
+    AppCtx,
+    commands::backup::providers::{Provider, Skipped},
+    config::VolumeGroup,
+    tooling::kube::PvClaim,
+    utils::process::{CmdSpec, Pipeline},
+    volume::Volume,
+};
+
+/// The volumes of one group, in snapshot order.
+pub(super) struct Members<'a> {
+    pub group: &'a VolumeGroup,
+    pub volumes: Vec<&'a Volume>,
+}
+
+/// Puts each volume in the first group with a pattern matching its disk name or PVC; within a
+/// group, volumes are ordered by the first pattern they match.
+pub(super) fn assign<'a>(
+    groups: &'a [VolumeGroup],
+    volumes: &'a [Volume],
+    claims: &[PvClaim],
+) -> Result<Vec<Members<'a>>> {
+    let patterns = groups
+        .iter()
+        .map(|g| {
+            g.volumes
+                .iter()
+                .map(|re| Regex::new(re).with_context(|| format!("group {}: regex {re}", g.name)))
+                .collect::<Result<Vec<_>>>()
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut out: Vec<Vec<(usize, &Volume)>> = vec![Vec::new(); groups.len()];
+    for v in volumes {
+        let claim = claims
+            .iter()
+            .find(|c| c.matches_disk(&v.disk))
+            .map(|c| format!("{}/{}", c.namespace, c.name));
+        let hit = patterns.iter().enumerate().find_map(|(g, res)| {
+            res.iter()
+                .position(|re| {
+                    re.is_match(&v.disk) || claim.as_deref().is_some_and(|c| re.is_match(c))
+                })
+                .map(|i| (g, i))
+        });
+        if let Some((g, i)) = hit {
+            out[g].push((i, v));
+        }
+    }
+    Ok(groups
+        .iter()
+        .zip(out)
+        .map(|(group, mut vols)| {
+            vols.sort_by_key(|(i, _)| *i);
+            Members {
+                group,
+                volumes: vols.into_iter().map(|(_, v)| v).collect(),
+            }
+        })
+        .collect())
+}
+
+/// Snapshots every group between its hooks. A failing `pre` hook skips the group; `post`
+/// runs regardless, and its failure fails the run, as the application may still be frozen.
+pub(super) fn snapshot_groups(
+    ctx: &AppCtx,
+    providers: &mut [Box<dyn Provider + '_>],
+    volumes: &[Volume],
+    claims: &[PvClaim],
+) -> Result<Vec<Skipped>> {
+    let mut skipped = Vec::new();
+    for m in assign(&ctx.cfg.backup.groups, volumes, claims)? {
+        let name = &m.group.name;
+        if m.volumes.is_empty() {
+            tracing::debug!("group {name}: no volumes");
+            continue;
+        }
+        tracing::info!("group {name}: snapshotting {} volume(s)", m.volumes.len());
+
+        let snapped = match run_hook(ctx, &m.group.pre) {
+            Ok(()) => snapshot_in_order(providers, &m.volumes),
+            Err(e) => {
+                tracing::warn!("group {name}: pre hook failed, skipping: {e:#}");
+                skipped.extend(m.volumes.iter().map(|v| Skipped {
+                    archive: v.archive.clone(),
+                    reason: format!("group {name}: pre hook failed: {e:#}"),
+                }));
+                Ok(Vec::new())
+            }
+        };
+        let post = run_hook(ctx, &m.group.post);
+        skipped.append(&mut snapped.with_context(|| format!("group {name}"))?);
+        if let Err(e) = post {
+            bail!("group {name}: post hook failed: {e:#}");
+        }
+    }
+    Ok(skipped)
+}
+
+fn snapshot_in_order(
+    providers: &mut [Box<dyn Provider + '_>],
+    volumes: &[&Volume],
+) -> Result<Vec<Skipped>> {
+    let mut skipped = Vec::new();
+    for v in volumes {
+        for p in providers.iter_mut() {
+            skipped.append(&mut p.snapshot(slice::from_ref(*v))?);
+        }
+    }
+    Ok(skipped)
+}
+
+fn run_hook(ctx: &AppCtx, argv: &[String]) -> Result<()> {
+    let Some((cmd, args)) = argv.split_first() else {
+        return Ok(());
+    };
+    ctx.runner
+        .run(&Pipeline::new().cmd(CmdSpec::new(cmd).args(args)))
+        .with_context(|| format!("run {}", argv.join(" ")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn vol(disk: &str) -> Volume {
+        Volume {
+            storage: "local-zfs".to_string(),
+            disk: disk.to_string(),
+            archive: format!("zfs_{disk}_noext_aaaa1111.img"),
+            device: PathBuf::from(format!("/dev/zvol/tank/{disk}")),
+            size_bytes: None,
+            meta: None,
+        }
+    }
+
+    #[test]
+    fn assigns_volumes_in_pattern_order() {
+        let groups = vec![VolumeGroup {
+            name: "pg".to_string(),
+            volumes: vec!["^db/wal-".to_string(), "^db/data-".to_string()],
+            pre: Vec::new(),
+            post: Vec::new(),
+        }];
+        let volumes = vec![vol("vm-9999-pvc-data"), vol("vm-9999-pvc-wal"), vol("vm-1")];
+        let claim = |pv: &str, name: &str| PvClaim {
+            pv: pv.to_string(),
+            volume_handle: None,
+            namespace: "db".to_string(),
+            name: name.to_string(),
+            details: Default::default(),
+        };
+        let claims = vec![claim("pvc-data", "data-pg-0"), claim("pvc-wal", "wal-pg-0")];
+
+        let members = assign(&groups, &volumes, &claims).unwrap();
+        assert_eq!(
+            members[0]
+                .volumes
+                .iter()
+                .map(|v| v.disk.as_str())
+                .collect::<Vec<_>>(),
+            ["vm-9999-pvc-wal", "vm-9999-pvc-data"]
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use std::{
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::Result;
+
+const WARN_REPEAT: Duration = Duration::from_secs(15 * 60);
+
+pub struct UsageProbe {
+    pub label: String,
+    pub probe: Box<dyn Fn() -> Result<String> + Send>,
+}
+
+/// Warns (with pool usage) while pvtools snapshots outlive `max_age`.
+pub struct SnapshotWatch {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl SnapshotWatch {
+    pub fn start(max_age: Duration, probes: Vec<UsageProbe>) -> Self {
+        Self::start_with_repeat(max_age, WARN_REPEAT, probes)
+    }
+
+    fn start_with_repeat(max_age: Duration, repeat: Duration, probes: Vec<UsageProbe>) -> Self {
+        let (tx, rx) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            let mut wait = max_age;
+            let mut age = Duration::ZERO;
+            while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(wait) {
+                age += wait;
+                tracing::warn!(
+                    "pvtools snapshots have been held for {}s (max {}s)",
+                    age.as_secs(),
+                    max_age.as_secs()
+                );
+                for p in &probes {
+                    match (p.probe)() {
+                        Ok(usage) => tracing::warn!("  {}: {usage}", p.label),
+                        Err(e) => tracing::warn!("  {}: usage unavailable: {e:#}", p.label),
+                    }
+                }
+                wait = repeat;
+            }
+        });
+        Self {
+            stop: Some(tx),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for SnapshotWatch {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(h) = self.handle.take() {
+            let _ = h.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+
+    #[test]
+    fn probes_run_after_max_age_and_stop_on_drop() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let c = calls.clone();
+        let watch = SnapshotWatch::start_with_repeat(
+            Duration::from_millis(20),
+            Duration::from_secs(3600),
+            vec![UsageProbe {
+                label: "tank".to_string(),
+                probe: Box::new(move || {
+                    c.fetch_add(1, Ordering::SeqCst);
+                    Ok("tank 10G 9G 1G 90% 12%".to_string())
+                }),
+            }],
+        );
+        thread::sleep(Duration::from_millis(200));
+        drop(watch);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn drop_before_max_age_is_silent() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let c = calls.clone();
+        let watch = SnapshotWatch::start(
+            Duration::from_secs(3600),
+            vec![UsageProbe {
+                label: "pve".to_string(),
+                probe: Box::new(move || {
+                    c.fetch_add(1, Ordering::SeqCst);
+                    Ok(String::new())
+                }),
+            }],
+        );
+        drop(watch);
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+    }
+}
@@ -1,10 +1,21 @@
+use std::path::PathBuf;
+
 use anyhow::Result;
 use clap::{Args, Subcommand};
 
-use crate::AppCtx;
+use crate::{AppCtx, commands::NsArg};
 
 mod executor;
-mod providers;
+mod groups;
+mod lifetime;
+mod preflight;
+pub mod providers;
+
+pub use executor::{
+    NodeResult, RunOpts, VolumeOutcome, VolumeStatus, backup, discover, list_archives,
+};
+pub(crate) use executor::{node_ctx, source_resources};
+pub use providers::{Candidate, Skipped};
 
 #[derive(Debug, Args)]
 pub struct BackupArgs {
@@ -23,14 +34,44 @@ pub struct BackupRunArgs {
     #[arg(long)]
     pub target: Option<String>,
 
+    #[command(flatten)]
+    pub ns: NsArg,
+
     #[arg(long)]
     pub dry_run: bool,
+
+    /// Write the commands the dry run would execute to this file as a shell script
+    #[arg(long, requires = "dry_run")]
+    pub emit_script: Option<PathBuf>,
+
+    /// Run even inside a configured `backup.blackout` window
+    #[arg(long)]
+    pub ignore_blackout: bool,
+
+    /// Skip ZFS volumes with nothing written since their last `--changed-only` backup
+    #[arg(long)]
+    pub changed_only: bool,
+
+    /// Back up only volumes whose archive or disk name fully matches this regex (can be repeated)
+    #[arg(long, value_name = "NAME|REGEX")]
+    pub only: Vec<String>,
+
+    /// Back up volumes in use too when `backup.active_volumes = "skip"`
+    #[arg(long)]
+    pub include_active: bool,
+
+    /// Run even if the latest snapshot of the group is younger than `backup.min_interval`
+    #[arg(long)]
+    pub force: bool,
 }
 
 #[derive(Args, Debug)]
 pub struct ListArchivesArgs {
     #[arg(long)]
     pub target: Option<String>,
+
+    #[command(flatten)]
+    pub ns: NsArg,
 }
 
 #[derive(Debug, Subcommand)]
@@ -40,9 +81,17 @@ pub enum BackupCmd {
 }
 
 impl BackupCmd {
+    /// The `--ns` given to the subcommand.
+    pub fn ns(&self) -> Option<&str> {
+        match self {
+            BackupCmd::Run(args) => args.ns.get(),
+            BackupCmd::ListArchives(args) => args.ns.get(),
+        }
+    }
+
     pub fn run(&self, ctx: &AppCtx) -> Result<()> {
         match self {
-            BackupCmd::Run(args) => executor::backup(ctx, args.target.as_deref(), args.dry_run),
+            BackupCmd::Run(args) => executor::backup(ctx, executor::RunOpts::from(args)),
             BackupCmd::ListArchives(_args) => executor::list_archives(ctx),
         }
     }
@@ -0,0 +1,318 @@
+use std::{collections::BTreeSet, path::Path};
+
+use anyhow::{Context, Result, bail};
+
+use crate::{
+    AppCtx,
+    config::{Config, NsCreate, Repo},
+    tooling::PbsPort,
+    utils::{
+        exec_policy::is_dry_run,
+        process::{CmdSpec, Pipeline, ProcessRunner, Runner, StdioSpec},
+    },
+};
+
+const CAP_DAC_READ_SEARCH: u32 = 2;
+const CAP_SYS_ADMIN: u32 = 21;
+
+/// Checks privileges, state directories and the PBS repo before anything is snapshotted, so
+/// a run started as the wrong user fails up front with every problem listed instead of
+/// midway. Dry runs only warn.
+pub(super) fn preflight(ctx: &AppCtx, repo: &Repo) -> Result<()> {
+    let problems = check(
+        &ctx.cfg,
+        ctx.runner.as_ref(),
+        &ProcessRunner::new(),
+        ctx.tools.pbs().as_ref(),
+        repo,
+    );
+    if problems.is_empty() {
+        return Ok(());
+    }
+    if is_dry_run() {
+        for p in &problems {
+            tracing::warn!("[preflight] {p}");
+        }
+        return Ok(());
+    }
+    bail!("preflight checks failed:\n  - {}", problems.join("\n  - "))
+}
+
+/// `runner` runs the backup commands, `local` checks files of this host.
+fn check(
+    cfg: &Config,
+    runner: &dyn Runner,
+    local: &dyn Runner,
+    pbs: &dyn PbsPort,
+    repo: &Repo,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let s = &cfg.backup.sources;
+    if s.zfs.is_some() || s.lvmthin.is_some() || s.lvm.is_some() {
+        match missing_caps(runner) {
+            Ok(None) => {}
+            Ok(Some((uid, caps))) => problems.push(format!(
+                "running as uid {uid} without {}, which zfs/lvm snapshots and reading their \
+                 device nodes need; run as root",
+                caps.join(", ")
+            )),
+            Err(e) => problems.push(format!("{e:#}")),
+        }
+    }
+
+    let mut local_dirs = BTreeSet::from([cfg.state.dir.as_path()]);
+    if cfg.events.history_keep > 0
+        && let Some(dir) = cfg.events.history.parent()
+    {
+        local_dirs.insert(dir);
+    }
+    for dir in local_dirs {
+        if !writable(local, dir) {
+            problems.push(format!("state directory {} is not writable", dir.display()));
+        }
+    }
+    if let Some(z) = &s.zfs
+        && !z.send_pools.is_empty()
+        && !writable(runner, &z.staging_dir)
+    {
+        problems.push(format!(
+            "zfs send staging_dir {} is not writable",
+            z.staging_dir.display()
+        ));
+    }
+
+    // `namespace list` answers for any namespace, so it also checks reachability and login.
+    let url = repo.url.as_str();
+    let ns = repo.ns.as_deref();
+    match pbs.ns_exists(repo, ns.unwrap_or_default()) {
+        Ok(false) if ns.is_some() && cfg.pbs.create_ns != NsCreate::Auto => {
+            problems.push(format!(
+                "namespace '{}' does not exist on {url} and create_ns = \"{}\" does not allow \
+                 creating it",
+                ns.unwrap_or_default(),
+                cfg.pbs.create_ns
+            ));
+        }
+        // A namespace still to be created has no groups to check.
+        Ok(false) if ns.is_some() => {}
+        Ok(_) => {
+            if let Some(p) = group_problem(pbs, repo, ns, &cfg.pbs.backup_id) {
+                problems.push(p);
+            }
+        }
+        Err(e) if denied(&e) => problems.push(missing_privilege(url, None)),
+        Err(e) => problems.push(format!("PBS repo {url} not reachable: {e:#}")),
+    }
+    problems
+}
+
+/// Listing groups needs Datastore.Backup (or Audit) on the namespace, and a backup into an
+/// existing group needs to own it, so this catches both before anything is snapshotted.
+fn group_problem(
+    pbs: &dyn PbsPort,
+    repo: &Repo,
+    ns: Option<&str>,
+    backup_id: &str,
+) -> Option<String> {
+    let url = repo.url.as_str();
+    let auth = auth_id(url);
+    match pbs.group_owner(repo, ns, backup_id) {
+        Ok(Some(owner)) if !may_back_up_into(&owner, auth) => Some(format!(
+            "backup group host/{backup_id} on {url} is owned by {owner}, not {auth}; change it \
+             with `proxmox-backup-client change-owner host/{backup_id} {auth}` or back up as \
+             {owner}"
+        )),
+        Ok(_) => None,
+        Err(e) if denied(&e) => Some(missing_privilege(url, ns)),
+        Err(e) => Some(format!("PBS repo {url}: {e:#}")),
+    }
+}
+
+fn denied(e: &anyhow::Error) -> bool {
+    format!("{e:#}").contains("permission check failed")
+}
+
+fn missing_privilege(url: &str, ns: Option<&str>) -> String {
+    let store = url.rsplit_once(':').map_or(url, |(_, s)| s);
+    let path = match ns {
+        Some(ns) => format!("/datastore/{store}/{ns}"),
+        None => format!("/datastore/{store}"),
+    };
+    format!(
+        "{} lacks Datastore.Backup on {path}; grant it the DatastoreBackup role there",
+        auth_id(url)
+    )
+}
+
+/// The user or API token of a `[[auth-id@]server[:port]:]datastore` repository string.
+fn auth_id(url: &str) -> &str {
+    url.rsplit_once(':')
+        .and_then(|(rest, _)| rest.rsplit_once('@'))
+        .map_or("root@pam", |(auth, _)| auth)
+}
+
+/// PBS lets a user back up into its own groups and those of its API tokens.
+fn may_back_up_into(owner: &str, auth: &str) -> bool {
+    owner == auth || owner.split_once('!').is_some_and(|(user, _)| user == auth)
+}
+
+/// Effective uid and the missing capabilities, if any, of commands `runner` starts.
+fn missing_caps(runner: &dyn Runner) -> Result<Option<(u32, Vec<&'static str>)>> {
+    let cmd = CmdSpec::new("cat")
+        .arg("/proc/self/status")
+        .stderr(StdioSpec::Pipe);
+    let status = runner
+        .run_capture(&Pipeline::new().cmd(cmd))
+        .context("read process privileges")?;
+    let field = |name: &str| {
+        status
+            .lines()
+            .find_map(|l| l.strip_prefix(name))
+            .map(str::trim)
+            .with_context(|| format!("no {name} in /proc/self/status"))
+    };
+    let uid = field("Uid:")?
+        .split_whitespace()
+        .nth(1)
+        .and_then(|u| u.parse().ok())
+        .context("bad Uid: in /proc/self/status")?;
+    let eff =
+        u64::from_str_radix(field("CapEff:")?, 16).context("bad CapEff: in /proc/self/status")?;
+    let missing: Vec<&str> = [
+        (CAP_SYS_ADMIN, "CAP_SYS_ADMIN"),
+        (CAP_DAC_READ_SEARCH, "CAP_DAC_READ_SEARCH"),
+    ]
+    .into_iter()
+    .filter(|(bit, _)| eff & (1 << bit) == 0)
+    .map(|(_, name)| name)
+    .collect();
+    Ok((!missing.is_empty()).then_some((uid, missing)))
+}
+
+/// Whether `dir`, or the closest existing directory above it, accepts new files.
+fn writable(runner: &dyn Runner, dir: &Path) -> bool {
+    let cmd = CmdSpec::new("sh")
+        .args([
+            "-c",
+            r#"d=$1; while [ ! -e "$d" ]; do d=$(dirname "$d"); done; [ -d "$d" ] && [ -w "$d" ]"#,
+            "sh",
+        ])
+        .arg(dir.display().to_string())
+        .stdout(StdioSpec::Null)
+        .stderr(StdioSpec::Null);
+    runner.run(&Pipeline::new().cmd(cmd)).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc};
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{config::Pbs, tooling::PbsCli, utils::process::ScriptedRunner};
+
+    const STATUS: &str = "Name:\tcat\nUid:\t1000\t1000\t1000\t1000\nCapEff:\t0000000000000004\n";
+
+    #[test]
+    fn reports_every_problem_at_once() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("config.toml");
+        fs::write(
+            &path,
+            "[pbs]\nbackup_id = \"b\"\nns = \"k8s\"\ncreate_ns = \"never\"\n\
+             [pbs.repos]\nnas = \"root@pam@pbs:store\"\n\
+             [events]\nhistory = \"/var/lib/pvtools/history.jsonl\"\n\
+             [backup.sources.zfs]\npools = [\"tank\"]\nsend_pools = [\"tank\"]\n\
+             staging_dir = \"/srv/staging\"\n",
+        )
+        .unwrap();
+        let cfg = Config::load(&path).unwrap();
+        let repo = cfg.resolve_backup_repo(Some("nas")).unwrap().clone();
+
+        let runner = Arc::new(
+            ScriptedRunner::new()
+                .on("/proc/self/status", STATUS)
+                .fail("/srv/staging", "not writable")
+                .on("namespace list", "other\n"),
+        );
+        let local = ScriptedRunner::new().fail("/var/lib/pvtools", "not writable");
+        let pbs = PbsCli::new(runner.clone(), Arc::new(cfg.pbs.clone()));
+
+        let problems = check(&cfg, runner.as_ref(), &local, &pbs, &repo);
+        assert_eq!(problems.len(), 4, "{problems:#?}");
+        assert!(
+            problems[0].contains("uid 1000 without CAP_SYS_ADMIN,"),
+            "{}",
+            problems[0]
+        );
+        assert!(
+            !problems[0].contains("CAP_DAC_READ_SEARCH"),
+            "{}",
+            problems[0]
+        );
+        assert!(problems[1].contains("/var/lib/pvtools"), "{}", problems[1]);
+        assert!(problems[2].contains("/srv/staging"), "{}", problems[2]);
+        assert!(problems[3].contains("namespace 'k8s'"), "{}", problems[3]);
+
+        let root = Arc::new(
+            ScriptedRunner::new()
+                .on(
+                    "/proc/self/status",
+                    "Uid:\t0\t0\t0\t0\nCapEff:\t000001ffffffffff\n",
+                )
+                .on("namespace list", "k8s\n")
+                .on("client list", "[]"),
+        );
+        let pbs = PbsCli::new(root.clone(), Arc::new(cfg.pbs.clone()));
+        assert!(check(&cfg, root.as_ref(), root.as_ref(), &pbs, &repo).is_empty());
+    }
+
+    #[test]
+    fn reports_missing_privilege_and_foreign_group_owner() {
+        let pbs_for = |runner: ScriptedRunner| {
+            let pbs = Pbs {
+                repos: Default::default(),
+                keyfile: None,
+                password: None,
+                key_passphrase: None,
+                ns: None,
+                create_ns: Default::default(),
+                backup_id: "b".to_string(),
+            };
+            PbsCli::new(Arc::new(runner), Arc::new(pbs))
+        };
+        let repo = |url: &str| Repo {
+            url: url.to_string(),
+            ns: None,
+            fingerprint: None,
+            trusted_ca: false,
+            keyfile: None,
+            password: None,
+            key_passphrase: None,
+        };
+        let url = repo("backup@pbs!pvtools@pbs:store");
+        let denied = pbs_for(ScriptedRunner::new().fail(
+            "client list",
+            "Error: permission check failed - missing Datastore.Audit|Datastore.Backup",
+        ));
+        let p = group_problem(&denied, &url, Some("k8s"), "b").unwrap();
+        assert!(
+            p.starts_with("backup@pbs!pvtools lacks Datastore.Backup on /datastore/store/k8s"),
+            "{p}"
+        );
+
+        let groups = r#"[{"backup-type":"host","backup-id":"b","owner":"root@pam"},
+                         {"backup-type":"host","backup-id":"c","owner":"backup@pbs!pvtools"}]"#;
+        let listed = pbs_for(ScriptedRunner::new().on("client list", groups));
+        let p = group_problem(&listed, &url, None, "b").unwrap();
+        assert!(
+            p.contains("owned by root@pam, not backup@pbs!pvtools"),
+            "{p}"
+        );
+        assert!(group_problem(&listed, &url, None, "c").is_none());
+        assert!(group_problem(&listed, &url, None, "new").is_none());
+        assert!(group_problem(&listed, &repo("backup@pbs@pbs:store"), None, "c").is_none());
+    }
+}
@@ -0,0 +1,378 @@
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    commands::backup::providers::{Candidate, Provider, Reject, Skipped},
+    config::{Backup, Config, DEFAULT_EXTERNAL_TIMEOUT, External},
+    manifest::StorageStatus,
+    utils::{
+        exec_policy,
+        process::{CmdSpec, EnvValue, Pipeline, Runner, StdioSpec},
+        signal,
+        time::current_epoch,
+    },
+    volume::Volume,
+};
+
+/// Version of the request/response JSON; bumped on incompatible changes.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// A volume as the external command reports it and gets it back.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct WireVolume {
+    /// Name of the volume; becomes the disk part of the archive name.
+    disk: String,
+    /// Stable alphanumeric ID, the last part of the archive name.
+    id: String,
+    /// Block device to read once `prepare` has run.
+    device: PathBuf,
+    /// PVE storage ID shown in listings; defaults to the provider name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    storage: Option<String>,
+    /// Size of the volume in bytes, shown in plans and used by `[backup] order`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size_bytes: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct Request<'a> {
+    version: u32,
+    run_ts: u64,
+    volumes: &'a [WireVolume],
+}
+
+#[derive(Deserialize)]
+struct DiscoverResponse {
+    volumes: Vec<WireVolume>,
+}
+
+#[derive(Deserialize, Default)]
+struct PrepareResponse {
+    #[serde(default)]
+    skipped: Vec<WireSkip>,
+}
+
+#[derive(Deserialize)]
+struct WireSkip {
+    disk: String,
+    reason: String,
+}
+
+#[derive(Debug, Clone)]
+struct ExternalMeta {
+    provider: String,
+    wire: WireVolume,
+}
+
+/// Runs `<command> [args..] <discover|prepare|cleanup>` with the request JSON in
+/// `PVTOOLS_REQUEST` and reads the response JSON from stdout.
+pub struct ExternalProvider<'a> {
+    name: String,
+    source: &'a External,
+    backup: &'a Backup,
+    run_ts: u64,
+    runner: Arc<dyn Runner + Send + Sync>,
+    prepared: Vec<WireVolume>,
+}
+
+impl<'a> ExternalProvider<'a> {
+    pub fn new(cfg: &'a Config, name: &str, runner: Arc<dyn Runner + Send + Sync>) -> Self {
+        let source = cfg
+            .backup
+            .sources
+            .external
+            .get(name)
+            .expect("[external] missing in config (provider disabled)");
+        Self {
+            name: name.to_string(),
+            source,
+            backup: &cfg.backup,
+            run_ts: current_epoch(),
+            runner,
+            prepared: Vec::new(),
+        }
+    }
+
+    fn cmd(&self, verb: &str, volumes: &[WireVolume]) -> Result<CmdSpec> {
+        let req = serde_json::to_string(&Request {
+            version: PROTOCOL_VERSION,
+            run_ts: self.run_ts,
+            volumes,
+        })
+        .context("serialize external provider request")?;
+        Ok(CmdSpec::new(&self.source.command)
+            .args(&self.source.args)
+            .arg(verb)
+            .env("PVTOOLS_REQUEST", EnvValue::Plain(req))
+            .with_timeout(self.source.timeout.unwrap_or(DEFAULT_EXTERNAL_TIMEOUT))
+            .stdout(StdioSpec::Pipe)
+            .stderr(StdioSpec::Pipe))
+    }
+
+    fn call(&self, verb: &str, volumes: &[WireVolume]) -> Result<String> {
+        let cmd = self.cmd(verb, volumes)?;
+        self.runner
+            .run_capture(&Pipeline::new().cmd(cmd))
+            .with_context(|| format!("external provider {} {verb}", self.name))
+    }
+
+    fn wire_volumes(&self) -> Result<Vec<WireVolume>> {
+        let out = self.call("discover", &[])?;
+        let resp: DiscoverResponse = serde_json::from_str(&out)
+            .with_context(|| format!("parse {} discover response", self.name))?;
+        for v in &resp.volumes {
+            if v.disk.is_empty() || v.disk.contains('/') {
+                bail!("{}: bad disk name '{}'", self.name, v.disk);
+            }
+            if v.id.is_empty() || !v.id.bytes().all(|b| b.is_ascii_alphanumeric()) {
+                bail!("{}: id of {} must be alphanumeric", self.name, v.disk);
+            }
+        }
+        Ok(resp.volumes)
+    }
+
+    fn run_cleanup(&mut self) {
+        if self.prepared.is_empty() {
+            return;
+        }
+        let prepared = std::mem::take(&mut self.prepared);
+        signal::shielded(|| {
+            if exec_policy::is_dry_run() {
+                match self.cmd("cleanup", &prepared) {
+                    Ok(cmd) => exec_policy::skip(&cmd.render()),
+                    Err(e) => tracing::warn!("[cleanup] {e:#}"),
+                }
+            } else if let Err(e) = self.call("cleanup", &prepared) {
+                tracing::warn!("[cleanup] {e:#}");
+            }
+        });
+    }
+}
+
+impl Provider for ExternalProvider<'_> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn discover(&self) -> Result<Vec<Volume>> {
+        let mut out = Vec::new();
+        for v in self.wire_volumes()? {
+            if let Some(denial) = self.backup.pv_denial(&v.disk) {
+                tracing::debug!("skip {}: {}", v.disk, Reject::pv(denial));
+                continue;
+            }
+            out.push(Volume {
+                storage: v.storage.clone().unwrap_or_else(|| self.name.clone()),
+                disk: v.disk.clone(),
+                archive: self
+                    .backup
+                    .archive_names
+                    .archive_name(&self.name, &v.disk, &v.id)?,
+                device: v.device.clone(),
+                size_bytes: v.size_bytes,
+                meta: Some(Arc::new(ExternalMeta {
+                    provider: self.name.clone(),
+                    wire: v,
+                })),
+            });
+        }
+        if out.is_empty() {
+            tracing::debug!("{}: no candidate volumes", self.name);
+        }
+        Ok(out)
+    }
+
+    fn candidates(&self) -> Result<Vec<Candidate>> {
+        Ok(self
+            .wire_volumes()?
+            .into_iter()
+            .map(|v| Candidate {
+                provider: self.name.clone(),
+                rejected: self
+                    .backup
+                    .pv_denial(&v.disk)
+                    .map(|d| Reject::pv(d).to_string()),
+                name: v.disk,
+                in_use: None,
+            })
+            .collect())
+    }
+
+    fn prepare(&mut self, volumes: &[Volume]) -> Result<Vec<Skipped>> {
+        let mine: Vec<(&Volume, &WireVolume)> = volumes
+            .iter()
+            .filter_map(|v| {
+                let m = v.meta::<ExternalMeta>()?;
+                (m.provider == self.name).then_some((v, &m.wire))
+            })
+            .collect();
+        if mine.is_empty() {
+            return Ok(Vec::new());
+        }
+        let wire: Vec<WireVolume> = mine.iter().map(|(_, w)| (*w).clone()).collect();
+        // Cleanup gets every volume sent to prepare, even if prepare fails halfway.
+        self.prepared = wire.clone();
+        if exec_policy::is_dry_run() {
+            exec_policy::skip(&self.cmd("prepare", &wire)?.render());
+            return Ok(Vec::new());
+        }
+        let out = self.call("prepare", &wire)?;
+        let resp: PrepareResponse = if out.trim().is_empty() {
+            PrepareResponse::default()
+        } else {
+            serde_json::from_str(&out)
+                .with_context(|| format!("parse {} prepare response", self.name))?
+        };
+
+        let mut skipped = Vec::new();
+        for s in resp.skipped {
+            let Some((v, _)) = mine.iter().find(|(_, w)| w.disk == s.disk) else {
+                bail!("{}: prepare skipped unknown disk '{}'", self.name, s.disk);
+            };
+            tracing::warn!("skip {}: {}", s.disk, s.reason);
+            skipped.push(Skipped {
+                archive: v.archive.clone(),
+                reason: s.reason,
+            });
+        }
+        Ok(skipped)
+    }
+
+    fn storage_status(&self) -> Vec<StorageStatus> {
+        Vec::new()
+    }
+}
+
+impl Drop for ExternalProvider<'_> {
+    fn drop(&mut self) {
+        self.run_cleanup();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt, time::Duration};
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{
+        config::{BackupSources, Events, Pbs, Pve, Restore},
+        utils::process::ProcessRunner,
+    };
+
+    /// A provider script that logs each call and answers with canned JSON.
+    fn script(dir: &TempDir) -> String {
+        let path = dir.path().join("provider");
+        let log = dir.path().join("calls");
+        fs::write(
+            &path,
+            format!(
+                r#"#!/bin/sh
+echo "$1 $PVTOOLS_REQUEST" >> {log}
+case "$1" in
+  discover) echo '{{"volumes": [
+      {{"disk": "lun-1", "id": "0a1b2c3d", "device": "/dev/mapper/lun-1-snap", "storage": "san-a"}},
+      {{"disk": "lun-2", "id": "0a1b2c3e", "device": "/dev/mapper/lun-2-snap"}},
+      {{"disk": "tmp-3", "id": "0a1b2c3f", "device": "/dev/mapper/tmp-3-snap"}}]}}' ;;
+  prepare) echo '{{"skipped": [{{"disk": "lun-2", "reason": "LUN offline"}}]}}' ;;
+esac
+"#,
+                log = log.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path.display().to_string()
+    }
+
+    fn cfg(command: String) -> Config {
+        let mut sources = BackupSources::default();
+        sources.external.insert(
+            "san".to_string(),
+            External {
+                command,
+                args: Vec::new(),
+                timeout: None,
+            },
+        );
+        Config {
+            pbs: Pbs {
+                repos: Default::default(),
+                keyfile: None,
+                password: None,
+                key_passphrase: None,
+                create_ns: Default::default(),
+                ns: None,
+                backup_id: "test".to_string(),
+            },
+            pve: Pve::default(),
+            events: Events::default(),
+            log: Default::default(),
+            state: Default::default(),
+            backup: Backup {
+                sources,
+                pv_prefixes: vec!["lun-".to_string()],
+                ..Backup::default()
+            },
+            restore: Restore::default(),
+            nodes: Default::default(),
+        }
+    }
+
+    #[test]
+    fn runs_discover_prepare_and_cleanup() {
+        let tmp = TempDir::new().unwrap();
+        let cfg = cfg(script(&tmp));
+        let mut p = ExternalProvider::new(&cfg, "san", Arc::new(ProcessRunner::new()));
+
+        let vols = p.discover().unwrap();
+        assert_eq!(
+            vols.iter().map(|v| v.archive.as_str()).collect::<Vec<_>>(),
+            [
+                "san_lun-1_noext_0a1b2c3d.img",
+                "san_lun-2_noext_0a1b2c3e.img"
+            ]
+        );
+        assert_eq!(
+            (vols[0].storage.as_str(), vols[1].storage.as_str()),
+            ("san-a", "san")
+        );
+
+        let skipped = p.prepare(&vols).unwrap();
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].archive, "san_lun-2_noext_0a1b2c3e.img");
+        drop(p);
+
+        let calls = fs::read_to_string(tmp.path().join("calls")).unwrap();
+        let verbs: Vec<&str> = calls
+            .lines()
+            .map(|l| l.split_once(' ').unwrap().0)
+            .collect();
+        assert_eq!(verbs, ["discover", "prepare", "cleanup"]);
+        assert!(calls.lines().last().unwrap().contains(r#""disk":"lun-1""#));
+    }
+
+    #[test]
+    fn hung_calls_time_out() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("provider");
+        fs::write(
+            &path,
+            "#!/bin/sh
+exec sleep 30
+",
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        let mut cfg = cfg(path.display().to_string());
+        cfg.backup.sources.external.get_mut("san").unwrap().timeout =
+            Some(Duration::from_millis(200));
+        let p = ExternalProvider::new(&cfg, "san", Arc::new(ProcessRunner::new()));
+
+        let err = p.discover().unwrap_err();
+        assert!(format!("{err:#}").contains("timed out"), "{err:#}");
+    }
+}
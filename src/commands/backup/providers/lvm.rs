@@ -0,0 +1,467 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result, anyhow};
+use tracing;
+
+use super::lvmthin::{Cleanup, build_lvm_names};
+use crate::{
+    commands::backup::providers::{Candidate, LV_OPEN, Provider, Reject, Skipped, open_lvs},
+    config::{Backup, Config},
+    manifest::StorageStatus,
+    tooling::{
+        BlockPort, LvmPort, PveshPort,
+        lvm::LvInfo,
+        pvesh::{Storage, fallback_storage_id},
+    },
+    utils::{naming::PVTOOLS_SUFFIX, time::current_epoch},
+    volume::Volume,
+};
+
+const CLONE_SUFFIX: &str = PVTOOLS_SUFFIX;
+
+#[derive(Debug, Clone)]
+struct LvmClassicMeta {
+    vg: String,
+    lv: String,
+    run_ts: u64,
+}
+
+pub struct LvmProvider<'a> {
+    vgs_set: HashSet<String>,
+    snapshot_size: &'a str,
+    storage_map: &'a BTreeMap<String, String>,
+    backup: &'a Backup,
+    run_ts: u64,
+    cleanup: Cleanup,
+    /// Archives whose snapshot is taken.
+    snapped: HashSet<String>,
+    lvm: Arc<dyn LvmPort>,
+    block: Arc<dyn BlockPort>,
+    pvesh: Arc<dyn PveshPort>,
+}
+
+impl<'a> LvmProvider<'a> {
+    pub fn new(
+        cfg: &'a Config,
+        lvm: Arc<dyn LvmPort>,
+        block: Arc<dyn BlockPort>,
+        pvesh: Arc<dyn PveshPort>,
+    ) -> Self {
+        let l = cfg
+            .backup
+            .sources
+            .lvm
+            .as_ref()
+            .expect("[lvm] missing in config (provider disabled)");
+
+        Self {
+            vgs_set: l.vgs.iter().map(|s| s.trim().to_string()).collect(),
+            snapshot_size: &l.snapshot_size,
+            storage_map: &cfg.pve.storage_map,
+            backup: &cfg.backup,
+            run_ts: current_epoch(),
+            cleanup: Cleanup::new(lvm.clone()),
+            snapped: HashSet::new(),
+            lvm,
+            block,
+            pvesh,
+        }
+    }
+
+    /// Snapshots the LV of `v` unless done already; a vanished LV is skipped.
+    fn take_snapshot(&mut self, v: &Volume, meta: &LvmClassicMeta) -> Result<Option<Skipped>> {
+        if self.snapped.contains(&v.archive) {
+            return Ok(None);
+        }
+        if let Err(e) = self.lvm.lv_name(&meta.vg, &meta.lv) {
+            tracing::warn!(
+                "skip {}/{}: LV disappeared since discovery",
+                meta.vg,
+                meta.lv
+            );
+            return Ok(Some(Skipped {
+                archive: v.archive.clone(),
+                reason: format!("{e:#}"),
+            }));
+        }
+        let names = build_lvm_names(&meta.vg, &meta.lv, CLONE_SUFFIX, meta.run_ts);
+        self.lvm
+            .lvcreate_cow_snapshot(&meta.vg, &meta.lv, &names.snap, self.snapshot_size)
+            .with_context(|| format!("lv snapshot on {}", names.snap))?;
+        self.cleanup.add(names.snap_fq);
+        self.snapped.insert(v.archive.clone());
+        Ok(None)
+    }
+
+    fn accept_lv<'b>(&self, lv: &'b LvInfo) -> std::result::Result<(), Reject<'b>> {
+        if !matches!(lv.segtype.as_deref(), Some("linear" | "striped")) {
+            return Err(Reject::NotClassic);
+        }
+        // A classic snapshot reports the segtype of its COW area.
+        if lv.origin.is_some() {
+            return Err(Reject::Snapshot);
+        }
+        if !self.vgs_set.contains(&lv.vg_name) {
+            return Err(Reject::VgNotAllowed(&lv.vg_name));
+        }
+        if let Some(denial) = self.backup.pv_denial(&lv.lv_name) {
+            return Err(Reject::pv(denial));
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Provider for LvmProvider<'a> {
+    fn name(&self) -> &str {
+        "lvm"
+    }
+
+    fn discover(&self) -> Result<Vec<Volume>> {
+        let mut out = Vec::<Volume>::new();
+        let rows = self.lvm.list_lvs().context("run lvs and parse JSON")?;
+        let storages = self.pvesh.get_storage()?;
+        let mut storage_ids: HashMap<String, String> = HashMap::new();
+
+        for lv in rows {
+            match self.accept_lv(&lv) {
+                Ok(()) => {
+                    let name = format!("{}/{}", lv.vg_name, lv.lv_name);
+                    let id8 = self
+                        .lvm
+                        .lv_uuid_short8(&lv.vg_name, &lv.lv_name)
+                        .with_context(|| format!("get lv_uuid short8 for {name}"))?;
+                    let archive =
+                        self.backup
+                            .archive_names
+                            .archive_name("lvm", &lv.lv_name, &id8)?;
+
+                    let names =
+                        build_lvm_names(&lv.vg_name, &lv.lv_name, CLONE_SUFFIX, self.run_ts);
+
+                    let storage_id = storage_ids.entry(lv.vg_name.clone()).or_insert_with(|| {
+                        find_storage(&storages, &lv.vg_name)
+                            .map(str::to_string)
+                            .unwrap_or_else(|e| {
+                                fallback_storage_id(self.storage_map, &lv.vg_name, e)
+                            })
+                    });
+
+                    out.push(Volume {
+                        storage: storage_id.clone(),
+                        disk: lv.lv_name.clone(),
+                        archive,
+                        device: names.device.clone(),
+                        size_bytes: lv.size,
+                        meta: Some(Arc::new(LvmClassicMeta {
+                            vg: lv.vg_name.clone(),
+                            lv: lv.lv_name.clone(),
+                            run_ts: self.run_ts,
+                        })),
+                    });
+                }
+                Err(r) => tracing::debug!("skip {}/{}: {r}", lv.vg_name, lv.lv_name),
+            }
+        }
+
+        if out.is_empty() {
+            tracing::debug!("lvm: no candidate volumes");
+        }
+
+        Ok(out)
+    }
+
+    fn candidates(&self) -> Result<Vec<Candidate>> {
+        let rows = self.lvm.list_lvs().context("run lvs and parse JSON")?;
+        Ok(rows
+            .iter()
+            .map(|lv| Candidate {
+                provider: self.name().to_string(),
+                name: format!("{}/{}", lv.vg_name, lv.lv_name),
+                rejected: self.accept_lv(lv).err().map(|r| r.to_string()),
+                in_use: lv.open.then(|| LV_OPEN.to_string()),
+            })
+            .collect())
+    }
+
+    fn in_use(&self, volumes: &[Volume]) -> Result<Vec<Skipped>> {
+        open_lvs(self.lvm.as_ref(), volumes, |v| {
+            v.meta::<LvmClassicMeta>()
+                .map(|m| (m.vg.as_str(), m.lv.as_str()))
+        })
+    }
+
+    fn snapshot(&mut self, volumes: &[Volume]) -> Result<Vec<Skipped>> {
+        let mut skipped = Vec::new();
+        for v in volumes {
+            if let Some(meta) = v.meta::<LvmClassicMeta>() {
+                skipped.extend(self.take_snapshot(v, meta)?);
+            }
+        }
+        Ok(skipped)
+    }
+
+    fn prepare(&mut self, volumes: &[Volume]) -> Result<Vec<Skipped>> {
+        let mut skipped = Vec::new();
+        for v in volumes {
+            let meta = match v.meta::<LvmClassicMeta>() {
+                Some(m) => m,
+                None => continue,
+            };
+
+            if let Some(s) = self.take_snapshot(v, meta)? {
+                skipped.push(s);
+                continue;
+            }
+
+            let names = build_lvm_names(&meta.vg, &meta.lv, CLONE_SUFFIX, meta.run_ts);
+            self.block.wait_for_block(&names.device)?;
+        }
+
+        Ok(skipped)
+    }
+
+    fn storage_status(&self) -> Vec<StorageStatus> {
+        let mut vgs: Vec<&String> = self.vgs_set.iter().collect();
+        vgs.sort_unstable();
+        vgs.into_iter()
+            .map(|vg| {
+                StorageStatus::capture(
+                    self.name(),
+                    vg,
+                    &format!("vgs -o all --reportformat json {vg}"),
+                    self.lvm.vg_report(vg),
+                )
+            })
+            .collect()
+    }
+}
+
+#[inline]
+fn find_storage<'a>(storages: &'a [Storage], vg_name: &str) -> Result<&'a str> {
+    storages
+        .iter()
+        .find_map(|s| match *s {
+            Storage::Lvm {
+                ref id,
+                vgname: ref storage_name,
+                ..
+            } if storage_name.as_str() == vg_name => Some(id.as_str()),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("LVM storage with vgname='{vg_name}' not found"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::Path, sync::Mutex, time::Duration};
+
+    use super::*;
+    use crate::config::{BackupSources, Events, Lvm, Pbs, Pve, Restore};
+
+    #[derive(Default)]
+    struct MockLvm {
+        lvs: Vec<LvInfo>,
+        created: Mutex<Vec<String>>,
+    }
+
+    impl LvmPort for MockLvm {
+        fn list_lvs(&self) -> Result<Vec<LvInfo>> {
+            Ok(self
+                .lvs
+                .iter()
+                .map(|lv| LvInfo {
+                    lv_name: lv.lv_name.clone(),
+                    vg_name: lv.vg_name.clone(),
+                    segtype: lv.segtype.clone(),
+                    origin: lv.origin.clone(),
+                    open: lv.open,
+                    size: lv.size,
+                })
+                .collect())
+        }
+        fn lv_uuid_short8(&self, _vg: &str, _lv: &str) -> Result<String> {
+            Ok("abcd1234".to_string())
+        }
+        fn lv_tags(&self, _lv_fq: &str) -> Result<Vec<String>> {
+            Ok(Vec::new())
+        }
+        fn lvchange_tags(&self, _lv_fq: &str, _add: &[String], _del: &[String]) -> Result<()> {
+            Ok(())
+        }
+        fn lvcreate_snapshot(&self, _vg: &str, _lv: &str, _snap: &str) -> Result<String> {
+            unreachable!("classic LVs need a sized snapshot")
+        }
+        fn lvcreate_cow_snapshot(
+            &self,
+            vg: &str,
+            lv: &str,
+            snap: &str,
+            size: &str,
+        ) -> Result<String> {
+            self.created
+                .lock()
+                .unwrap()
+                .push(format!("{vg}/{lv} -> {snap} ({size})"));
+            Ok(format!("{vg}/{snap}"))
+        }
+        fn lvchange_activate(&self, _lv_fq: &str) -> Result<()> {
+            Ok(())
+        }
+        fn lvremove_force(&self, _lv_fq: &str) -> Result<()> {
+            Ok(())
+        }
+        fn lv_name(&self, _vg: &str, leaf: &str) -> Result<String> {
+            Ok(leaf.to_string())
+        }
+        fn lvcreate_thin(
+            &self,
+            _vg: &str,
+            _thinpool: &str,
+            _name: &str,
+            _size_bytes: u64,
+            _extra_args: &[String],
+        ) -> Result<()> {
+            Ok(())
+        }
+        fn lvcreate_linear(
+            &self,
+            _vg: &str,
+            _name: &str,
+            _size_bytes: u64,
+            _extra_args: &[String],
+        ) -> Result<()> {
+            Ok(())
+        }
+        fn vg_report(&self, _vg: &str) -> Result<String> {
+            Ok(String::new())
+        }
+        fn thinpool_usage(&self, _vg: &str) -> Result<String> {
+            Ok(String::new())
+        }
+        fn thin_pools(&self, _vg: &str) -> Result<Vec<crate::tooling::lvm::ThinPoolUsage>> {
+            Ok(Vec::new())
+        }
+        fn cow_snapshot_usage(&self, _vg: &str) -> Result<String> {
+            Ok(String::new())
+        }
+    }
+
+    struct MockBlock;
+    impl BlockPort for MockBlock {
+        fn wait_for_block(&self, _path: &Path) -> Result<()> {
+            Ok(())
+        }
+        fn wait_for_block_with(
+            &self,
+            _dev: &Path,
+            _timeout: Duration,
+            _delay: Duration,
+        ) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    struct MockPveSh;
+    impl PveshPort for MockPveSh {
+        fn get_storage(&self) -> Result<Vec<Storage>> {
+            Ok(vec![Storage::Lvm {
+                id: "local-lvm-plain".to_string(),
+                vgname: "data".to_string(),
+                content: vec!["images".to_string()],
+            }])
+        }
+        fn cluster_storage(&self) -> Result<Vec<crate::tooling::pvesh::ClusterStorage>> {
+            Ok(Vec::new())
+        }
+    }
+
+    fn test_config() -> Config {
+        Config {
+            pbs: Pbs {
+                repos: HashMap::new(),
+                keyfile: None,
+                password: None,
+                key_passphrase: None,
+                create_ns: Default::default(),
+                ns: None,
+                backup_id: "test".to_string(),
+            },
+            pve: Pve::default(),
+            events: Events::default(),
+            log: Default::default(),
+            state: Default::default(),
+            backup: Backup {
+                sources: BackupSources {
+                    lvm: Some(Lvm {
+                        vgs: vec!["data".to_string()],
+                        storage_map: BTreeMap::new(),
+                        snapshot_size: "2G".to_string(),
+                    }),
+                    ..BackupSources::default()
+                },
+                pv_prefixes: vec!["vm-".to_string()],
+                ..Backup::default()
+            },
+            restore: Restore::default(),
+            nodes: BTreeMap::new(),
+        }
+    }
+
+    fn lv(name: &str, segtype: &str, origin: Option<&str>) -> LvInfo {
+        LvInfo {
+            lv_name: name.to_string(),
+            vg_name: "data".to_string(),
+            segtype: Some(segtype.to_string()),
+            origin: origin.map(str::to_string),
+            open: false,
+            size: None,
+        }
+    }
+
+    #[test]
+    fn discover_takes_only_classic_origin_lvs() {
+        let cfg = test_config();
+        let lvm = Arc::new(MockLvm {
+            lvs: vec![
+                lv("vm-1-disk-0", "linear", None),
+                lv("vm-2-disk-0", "striped", None),
+                lv("vm-3-disk-0", "thin", None),
+                lv("vm-1-disk-0-pvtools-1", "linear", Some("vm-1-disk-0")),
+            ],
+            ..MockLvm::default()
+        });
+        let provider = LvmProvider::new(&cfg, lvm, Arc::new(MockBlock), Arc::new(MockPveSh));
+
+        let vols = provider.discover().unwrap();
+        let archives: Vec<&str> = vols.iter().map(|v| v.archive.as_str()).collect();
+        assert_eq!(
+            archives,
+            vec![
+                "lvm_vm-1-disk-0_noext_abcd1234.img",
+                "lvm_vm-2-disk-0_noext_abcd1234.img"
+            ]
+        );
+        assert_eq!(vols[0].storage, "local-lvm-plain");
+    }
+
+    #[test]
+    fn prepare_uses_configured_snapshot_size() {
+        let cfg = test_config();
+        let lvm = Arc::new(MockLvm {
+            lvs: vec![lv("vm-1-disk-0", "linear", None)],
+            ..MockLvm::default()
+        });
+        let mut provider =
+            LvmProvider::new(&cfg, lvm.clone(), Arc::new(MockBlock), Arc::new(MockPveSh));
+
+        let vols = provider.discover().unwrap();
+        crate::utils::exec_policy::with_dry_run_enabled(true, || provider.prepare(&vols)).unwrap();
+
+        let created = lvm.created.lock().unwrap();
+        assert_eq!(created.len(), 1);
+        assert!(created[0].ends_with("(2G)"), "{created:?}");
+    }
+}
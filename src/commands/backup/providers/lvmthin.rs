@@ -1,23 +1,26 @@
-use std::{collections::HashSet, path::PathBuf, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    path::PathBuf,
+    sync::Arc,
+};
 
-use anyhow::{Context, Result, anyhow};
+use anyhow::{Context, Result, anyhow, bail};
 use tracing;
 
 use crate::{
-    commands::backup::providers::Provider,
-    config::{Backup, Config},
-    tooling::{BlockPort, LvmPort, PveshPort, lvm::LvInfo, pvesh::Storage},
-    utils::{exec_policy, naming::create_archive_name, time::current_epoch},
+    commands::backup::providers::{Candidate, LV_OPEN, Provider, Reject, Skipped, open_lvs},
+    config::{Backup, Config, PoolUsageAction},
+    manifest::StorageStatus,
+    tooling::{
+        BlockPort, LvmPort, PveshPort,
+        lvm::LvInfo,
+        pvesh::{Storage, fallback_storage_id},
+    },
+    utils::{naming::PVTOOLS_SUFFIX, signal, time::current_epoch},
     volume::Volume,
 };
 
-enum Reject<'a> {
-    NotThin,
-    VgNotAllowed(&'a str),
-    PvDenied,
-}
-
-const CLONE_SUFFIX: &str = "pvtools";
+const CLONE_SUFFIX: &str = PVTOOLS_SUFFIX;
 
 #[derive(Debug, Clone)]
 struct LvmMeta {
@@ -28,9 +31,14 @@ struct LvmMeta {
 
 pub struct LvmThinProvider<'a> {
     vgs_set: HashSet<String>,
+    max_pool_usage: u8,
+    pool_usage_action: PoolUsageAction,
+    storage_map: &'a BTreeMap<String, String>,
     backup: &'a Backup,
     run_ts: u64,
     cleanup: Cleanup,
+    /// Archives whose snapshot is taken.
+    snapped: HashSet<String>,
     lvm: Arc<dyn LvmPort>,
     block: Arc<dyn BlockPort>,
     pvesh: Arc<dyn PveshPort>,
@@ -52,15 +60,44 @@ impl<'a> LvmThinProvider<'a> {
 
         Self {
             vgs_set: l.vgs.iter().map(|s| s.trim().to_string()).collect(),
+            max_pool_usage: l.max_pool_usage,
+            pool_usage_action: l.pool_usage_action,
+            storage_map: &cfg.pve.storage_map,
             backup: &cfg.backup,
             run_ts: current_epoch(),
             cleanup: Cleanup::new(lvm.clone()),
+            snapped: HashSet::new(),
             lvm,
             block,
             pvesh,
         }
     }
 
+    /// Snapshots the LV of `v` unless done already; a vanished LV is skipped.
+    fn take_snapshot(&mut self, v: &Volume, meta: &LvmMeta) -> Result<Option<Skipped>> {
+        if self.snapped.contains(&v.archive) {
+            return Ok(None);
+        }
+        if let Err(e) = self.lvm.lv_name(&meta.vg, &meta.lv) {
+            tracing::warn!(
+                "skip {}/{}: LV disappeared since discovery",
+                meta.vg,
+                meta.lv
+            );
+            return Ok(Some(Skipped {
+                archive: v.archive.clone(),
+                reason: format!("{e:#}"),
+            }));
+        }
+        let names = build_lvm_names(&meta.vg, &meta.lv, CLONE_SUFFIX, meta.run_ts);
+        self.lvm
+            .lvcreate_snapshot(&meta.vg, &meta.lv, &names.snap)
+            .with_context(|| format!("lv snapshot on {}", names.snap))?;
+        self.cleanup.add(names.snap_fq);
+        self.snapped.insert(v.archive.clone());
+        Ok(None)
+    }
+
     fn accept_lv<'b>(&self, lv: &'b LvInfo) -> std::result::Result<(), Reject<'b>> {
         if !matches!(lv.segtype.as_deref(), Some("thin")) {
             return Err(Reject::NotThin);
@@ -68,15 +105,42 @@ impl<'a> LvmThinProvider<'a> {
         if !self.vgs_set.contains(&lv.vg_name) {
             return Err(Reject::VgNotAllowed(&lv.vg_name));
         }
-        if !self.backup.pv_allows(&lv.lv_name) {
-            return Err(Reject::PvDenied);
+        if let Some(denial) = self.backup.pv_denial(&lv.lv_name) {
+            return Err(Reject::pv(denial));
+        }
+        Ok(())
+    }
+
+    /// Snapshotting in a nearly full thin pool risks filling it, which stalls every thin
+    /// volume in it, so pools above max_pool_usage are checked before the first snapshot.
+    fn check_pool_usage(&self, volumes: &[Volume]) -> Result<()> {
+        let vgs: BTreeSet<&str> = volumes
+            .iter()
+            .filter_map(|v| v.meta::<LvmMeta>())
+            .map(|m| m.vg.as_str())
+            .collect();
+        for vg in vgs {
+            for pool in self.lvm.thin_pools(vg)? {
+                let usage = pool.data_percent.max(pool.metadata_percent);
+                if usage <= f64::from(self.max_pool_usage) {
+                    continue;
+                }
+                let msg = format!(
+                    "thin pool {vg}/{} is {:.1}% data / {:.1}% metadata full, above max_pool_usage {}%",
+                    pool.name, pool.data_percent, pool.metadata_percent, self.max_pool_usage
+                );
+                match self.pool_usage_action {
+                    PoolUsageAction::Warn => tracing::warn!("{msg}"),
+                    PoolUsageAction::Abort => bail!("{msg}; refusing to snapshot"),
+                }
+            }
         }
         Ok(())
     }
 }
 
 impl<'a> Provider for LvmThinProvider<'a> {
-    fn name(&self) -> &'static str {
+    fn name(&self) -> &str {
         "lvmthin"
     }
 
@@ -84,6 +148,7 @@ impl<'a> Provider for LvmThinProvider<'a> {
         let mut out = Vec::<Volume>::new();
         let rows = self.lvm.list_lvs().context("run lvs and parse JSON")?;
         let storages = self.pvesh.get_storage()?;
+        let mut storage_ids: HashMap<String, String> = HashMap::new();
 
         for lv in rows {
             match self.accept_lv(&lv) {
@@ -93,18 +158,28 @@ impl<'a> Provider for LvmThinProvider<'a> {
                         .lvm
                         .lv_uuid_short8(&lv.vg_name, &lv.lv_name)
                         .with_context(|| format!("get lv_uuid short8 for {name}"))?;
-                    let archive = create_archive_name("lvmthin", &lv.lv_name, &id8)?;
+                    let archive =
+                        self.backup
+                            .archive_names
+                            .archive_name("lvmthin", &lv.lv_name, &id8)?;
 
                     let names =
                         build_lvm_names(&lv.vg_name, &lv.lv_name, CLONE_SUFFIX, self.run_ts);
 
-                    let storage_id = find_storage(&storages, &lv.vg_name)?;
+                    let storage_id = storage_ids.entry(lv.vg_name.clone()).or_insert_with(|| {
+                        find_storage(&storages, &lv.vg_name)
+                            .map(str::to_string)
+                            .unwrap_or_else(|e| {
+                                fallback_storage_id(self.storage_map, &lv.vg_name, e)
+                            })
+                    });
 
                     out.push(Volume {
-                        storage: storage_id.to_string(),
+                        storage: storage_id.clone(),
                         disk: lv.lv_name.clone(),
                         archive,
                         device: names.device.clone(),
+                        size_bytes: lv.size,
                         meta: Some(Arc::new(LvmMeta {
                             vg: lv.vg_name.clone(),
                             lv: lv.lv_name.clone(),
@@ -112,11 +187,7 @@ impl<'a> Provider for LvmThinProvider<'a> {
                         })),
                     });
                 }
-                Err(Reject::NotThin) => tracing::debug!("skip {}: segtype != thin", lv.lv_name),
-                Err(Reject::VgNotAllowed(vg)) => {
-                    tracing::debug!("skip {}: vg '{}' not allowed", lv.lv_name, vg)
-                }
-                Err(Reject::PvDenied) => tracing::debug!("skip {}: pv_allows=false", lv.lv_name),
+                Err(r) => tracing::debug!("skip {}/{}: {r}", lv.vg_name, lv.lv_name),
             }
         }
 
@@ -127,46 +198,94 @@ impl<'a> Provider for LvmThinProvider<'a> {
         Ok(out)
     }
 
-    fn prepare(&mut self, volumes: &[Volume]) -> Result<()> {
+    fn candidates(&self) -> Result<Vec<Candidate>> {
+        let rows = self.lvm.list_lvs().context("run lvs and parse JSON")?;
+        Ok(rows
+            .iter()
+            .map(|lv| Candidate {
+                provider: self.name().to_string(),
+                name: format!("{}/{}", lv.vg_name, lv.lv_name),
+                rejected: self.accept_lv(lv).err().map(|r| r.to_string()),
+                in_use: lv.open.then(|| LV_OPEN.to_string()),
+            })
+            .collect())
+    }
+
+    fn in_use(&self, volumes: &[Volume]) -> Result<Vec<Skipped>> {
+        open_lvs(self.lvm.as_ref(), volumes, |v| {
+            v.meta::<LvmMeta>().map(|m| (m.vg.as_str(), m.lv.as_str()))
+        })
+    }
+
+    fn snapshot(&mut self, volumes: &[Volume]) -> Result<Vec<Skipped>> {
+        self.check_pool_usage(volumes)?;
+
+        let mut skipped = Vec::new();
+        for v in volumes {
+            if let Some(meta) = v.meta::<LvmMeta>() {
+                skipped.extend(self.take_snapshot(v, meta)?);
+            }
+        }
+        Ok(skipped)
+    }
+
+    fn prepare(&mut self, volumes: &[Volume]) -> Result<Vec<Skipped>> {
+        if self.snapped.is_empty() {
+            self.check_pool_usage(volumes)?;
+        }
+
+        let mut skipped = Vec::new();
         for v in volumes {
             let meta = match v.meta::<LvmMeta>() {
                 Some(m) => m,
                 None => continue,
             };
 
-            let names = build_lvm_names(&meta.vg, &meta.lv, CLONE_SUFFIX, meta.run_ts);
+            if let Some(s) = self.take_snapshot(v, meta)? {
+                skipped.push(s);
+                continue;
+            }
 
-            self.lvm
-                .lvcreate_snapshot(&meta.vg, &meta.lv, &names.snap)
-                .with_context(|| format!("lv snapshot on {}", &names.snap))?;
+            let names = build_lvm_names(&meta.vg, &meta.lv, CLONE_SUFFIX, meta.run_ts);
             self.lvm
                 .lvchange_activate(&names.snap_fq)
-                .with_context(|| format!("lv change on {}", &names.snap))?;
-
-            if !exec_policy::is_dry_run() {
-                self.block.wait_for_block(&names.device)?;
-                self.cleanup.add(names.snap_fq);
-            }
+                .with_context(|| format!("lv change on {}", names.snap))?;
+            self.block.wait_for_block(&names.device)?;
         }
 
-        Ok(())
+        Ok(skipped)
+    }
+
+    fn storage_status(&self) -> Vec<StorageStatus> {
+        let mut vgs: Vec<&String> = self.vgs_set.iter().collect();
+        vgs.sort_unstable();
+        vgs.into_iter()
+            .map(|vg| {
+                StorageStatus::capture(
+                    self.name(),
+                    vg,
+                    &format!("vgs -o all --reportformat json {vg}"),
+                    self.lvm.vg_report(vg),
+                )
+            })
+            .collect()
     }
 }
 
-struct Cleanup {
+pub(super) struct Cleanup {
     snaps: Vec<String>,
     lvm: Option<Arc<dyn LvmPort>>,
 }
 
 impl Cleanup {
-    fn new(lvm: Arc<dyn LvmPort>) -> Self {
+    pub(super) fn new(lvm: Arc<dyn LvmPort>) -> Self {
         Self {
             snaps: Vec::new(),
             lvm: Some(lvm),
         }
     }
 
-    fn add(&mut self, snap_fq: String) {
+    pub(super) fn add(&mut self, snap_fq: String) {
         self.snaps.push(snap_fq);
     }
 }
@@ -174,11 +293,13 @@ impl Cleanup {
 impl Drop for Cleanup {
     fn drop(&mut self) {
         if let Some(lvm) = &self.lvm {
-            for s in self.snaps.drain(..) {
-                if let Err(e) = lvm.lvremove_force(&s) {
-                    tracing::warn!("[cleanup] lvremove -f {} failed: {e}", s);
+            signal::shielded(|| {
+                for s in self.snaps.drain(..) {
+                    if let Err(e) = lvm.lvremove_force(&s) {
+                        tracing::warn!("[cleanup] lvremove -f {} failed: {e}", s);
+                    }
                 }
-            }
+            });
         }
     }
 }
@@ -199,14 +320,14 @@ fn find_storage<'a>(storages: &'a [Storage], vg_name: &str) -> Result<&'a str> {
 }
 
 #[derive(Debug, Clone)]
-struct LvmNames {
-    snap: String,
-    snap_fq: String,
-    device: PathBuf,
+pub(super) struct LvmNames {
+    pub(super) snap: String,
+    pub(super) snap_fq: String,
+    pub(super) device: PathBuf,
 }
 
 #[inline]
-fn build_lvm_names(vg: &str, lv: &str, suffix: &str, ts: u64) -> LvmNames {
+pub(super) fn build_lvm_names(vg: &str, lv: &str, suffix: &str, ts: u64) -> LvmNames {
     let snap = format!("{lv}-{suffix}-{ts}");
     let snap_fq = format!("{vg}/{snap}");
     let device = PathBuf::from(format!("/dev/{snap_fq}"));
@@ -226,13 +347,20 @@ mod tests {
 
     use super::*;
     use crate::{
-        config::{Backup, BackupSources, BackupTarget, Config, LvmThin, Pbs, Restore},
-        tooling::{BlockPort, LvmPort, lvm::LvInfo},
+        config::{
+            Backup, BackupSources, BackupTarget, Config, Events, LvmThin, Pbs, PoolUsageAction,
+            Pve, Restore,
+        },
+        tooling::{
+            BlockPort, LvmPort,
+            lvm::{LvInfo, ThinPoolUsage},
+        },
         utils::process::ProcessRunner,
     };
 
     struct MockLvm {
         lvs: Vec<LvInfo>,
+        pools: Vec<ThinPoolUsage>,
     }
 
     impl LvmPort for MockLvm {
@@ -244,12 +372,21 @@ mod tests {
                     lv_name: lv.lv_name.clone(),
                     vg_name: lv.vg_name.clone(),
                     segtype: lv.segtype.clone(),
+                    origin: lv.origin.clone(),
+                    open: lv.open,
+                    size: lv.size,
                 })
                 .collect())
         }
         fn lv_uuid_short8(&self, _vg: &str, _lv: &str) -> Result<String> {
             Ok("abcd1234".to_string())
         }
+        fn lv_tags(&self, _lv_fq: &str) -> Result<Vec<String>> {
+            Ok(Vec::new())
+        }
+        fn lvchange_tags(&self, _lv_fq: &str, _add: &[String], _del: &[String]) -> Result<()> {
+            Ok(())
+        }
         fn lvcreate_snapshot(&self, _vg: &str, _lv: &str, _snap: &str) -> Result<String> {
             Ok("snap_path".to_string())
         }
@@ -268,9 +405,40 @@ mod tests {
             _thinpool: &str,
             _name: &str,
             _size_bytes: u64,
+            _extra_args: &[String],
+        ) -> Result<()> {
+            Ok(())
+        }
+        fn vg_report(&self, _vg: &str) -> Result<String> {
+            Ok(String::new())
+        }
+        fn thinpool_usage(&self, _vg: &str) -> Result<String> {
+            Ok(String::new())
+        }
+        fn lvcreate_cow_snapshot(
+            &self,
+            _vg: &str,
+            _lv: &str,
+            snap: &str,
+            _size: &str,
+        ) -> Result<String> {
+            Ok(snap.to_string())
+        }
+        fn lvcreate_linear(
+            &self,
+            _vg: &str,
+            _name: &str,
+            _size_bytes: u64,
+            _extra_args: &[String],
         ) -> Result<()> {
             Ok(())
         }
+        fn thin_pools(&self, _vg: &str) -> Result<Vec<ThinPoolUsage>> {
+            Ok(self.pools.clone())
+        }
+        fn cow_snapshot_usage(&self, _vg: &str) -> Result<String> {
+            Ok(String::new())
+        }
     }
 
     struct MockBlock;
@@ -298,6 +466,9 @@ mod tests {
                 content: vec!["".to_string()],
             }])
         }
+        fn cluster_storage(&self) -> Result<Vec<crate::tooling::pvesh::ClusterStorage>> {
+            Ok(Vec::new())
+        }
     }
 
     fn test_config() -> Config {
@@ -306,15 +477,26 @@ mod tests {
                 repos: HashMap::new(),
                 keyfile: None,
                 password: None,
+                key_passphrase: None,
+                create_ns: Default::default(),
                 ns: None,
                 backup_id: "test".to_string(),
             },
+            pve: Pve::default(),
+            events: Events::default(),
+            log: Default::default(),
+            state: Default::default(),
             backup: Backup {
                 sources: BackupSources {
                     zfs: None,
                     lvmthin: Some(LvmThin {
                         vgs: vec!["pve".to_string()],
+                        storage_map: BTreeMap::new(),
+                        max_pool_usage: 90,
+                        pool_usage_action: PoolUsageAction::Abort,
                     }),
+                    lvm: None,
+                    external: BTreeMap::new(),
                 },
                 target: BackupTarget {
                     repo: Some("nas".to_string()),
@@ -322,8 +504,10 @@ mod tests {
                 pv_prefixes: vec!["vm-".to_string()],
                 pv_exclude_re: None,
                 pv_exclude_re_src: None,
+                ..Backup::default()
             },
             restore: Restore::default(),
+            nodes: BTreeMap::new(),
         }
     }
 
@@ -341,7 +525,10 @@ mod tests {
     #[test]
     fn accept_lv_rejects_non_thin() {
         let cfg = test_config();
-        let lvm = Arc::new(MockLvm { lvs: vec![] });
+        let lvm = Arc::new(MockLvm {
+            lvs: vec![],
+            pools: vec![],
+        });
         let block = Arc::new(MockBlock);
         let pvesh = Arc::new(MockPveSh);
         let provider = LvmThinProvider::new(&cfg, lvm, block, pvesh);
@@ -350,6 +537,9 @@ mod tests {
             lv_name: "vm-123.raw".to_string(),
             vg_name: "pve".to_string(),
             segtype: Some("linear".to_string()),
+            origin: None,
+            open: false,
+            size: None,
         };
 
         let result = provider.accept_lv(&lv);
@@ -359,7 +549,10 @@ mod tests {
     #[test]
     fn accept_lv_rejects_wrong_vg() {
         let cfg = test_config();
-        let lvm = Arc::new(MockLvm { lvs: vec![] });
+        let lvm = Arc::new(MockLvm {
+            lvs: vec![],
+            pools: vec![],
+        });
         let block = Arc::new(MockBlock);
         let pvesh = Arc::new(MockPveSh);
         let provider = LvmThinProvider::new(&cfg, lvm, block, pvesh);
@@ -368,6 +561,9 @@ mod tests {
             lv_name: "vm-123.raw".to_string(),
             vg_name: "other".to_string(),
             segtype: Some("thin".to_string()),
+            origin: None,
+            open: false,
+            size: None,
         };
 
         let result = provider.accept_lv(&lv);
@@ -377,7 +573,10 @@ mod tests {
     #[test]
     fn accept_lv_rejects_non_pv() {
         let cfg = test_config();
-        let lvm = Arc::new(MockLvm { lvs: vec![] });
+        let lvm = Arc::new(MockLvm {
+            lvs: vec![],
+            pools: vec![],
+        });
         let block = Arc::new(MockBlock);
         let pvesh = Arc::new(MockPveSh);
         let provider = LvmThinProvider::new(&cfg, lvm, block, pvesh);
@@ -386,6 +585,9 @@ mod tests {
             lv_name: "other-123".to_string(),
             vg_name: "pve".to_string(),
             segtype: Some("thin".to_string()),
+            origin: None,
+            open: false,
+            size: None,
         };
 
         let result = provider.accept_lv(&lv);
@@ -395,7 +597,10 @@ mod tests {
     #[test]
     fn accept_lv_allows_valid() {
         let cfg = test_config();
-        let lvm = Arc::new(MockLvm { lvs: vec![] });
+        let lvm = Arc::new(MockLvm {
+            lvs: vec![],
+            pools: vec![],
+        });
         let block = Arc::new(MockBlock);
         let pvesh = Arc::new(MockPveSh);
         let provider = LvmThinProvider::new(&cfg, lvm, block, pvesh);
@@ -404,6 +609,9 @@ mod tests {
             lv_name: "vm-123.raw".to_string(),
             vg_name: "pve".to_string(),
             segtype: Some("thin".to_string()),
+            origin: None,
+            open: false,
+            size: None,
         };
 
         let result = provider.accept_lv(&lv);
@@ -416,10 +624,13 @@ mod tests {
             lv_name: "vm-123.raw".to_string(),
             vg_name: "pve".to_string(),
             segtype: Some("thin".to_string()),
+            origin: None,
+            open: false,
+            size: Some(32 << 30),
         }];
 
         let cfg = test_config();
-        let lvm = Arc::new(MockLvm { lvs });
+        let lvm = Arc::new(MockLvm { lvs, pools: vec![] });
         let block = Arc::new(MockBlock);
         let pvesh = Arc::new(MockPveSh);
         let provider = LvmThinProvider::new(&cfg, lvm, block, pvesh);
@@ -429,6 +640,69 @@ mod tests {
         assert_eq!(result[0].storage, "local-lvm");
         assert_eq!(result[0].disk, "vm-123.raw");
         assert_eq!(result[0].archive, "lvmthin_vm-123_raw_abcd1234.img");
+        assert_eq!(result[0].size_bytes, Some(32 << 30));
+    }
+
+    #[test]
+    fn open_lvs_are_in_use() {
+        let lv = |name: &str, open: bool| LvInfo {
+            lv_name: name.to_string(),
+            vg_name: "pve".to_string(),
+            segtype: Some("thin".to_string()),
+            origin: None,
+            open,
+            size: None,
+        };
+        let lvs = vec![lv("vm-1-disk-0", true), lv("vm-2-disk-0", false)];
+        let cfg = test_config();
+        let lvm = Arc::new(MockLvm { lvs, pools: vec![] });
+        let provider = LvmThinProvider::new(&cfg, lvm, Arc::new(MockBlock), Arc::new(MockPveSh));
+
+        let volumes = provider.discover().unwrap();
+        let in_use = provider.in_use(&volumes).unwrap();
+        assert_eq!(in_use.len(), 1);
+        assert_eq!(in_use[0].archive, volumes[0].archive);
+        let candidates = provider.candidates().unwrap();
+        assert_eq!(candidates[0].in_use.as_deref(), Some(LV_OPEN));
+        assert_eq!(candidates[1].in_use, None);
+    }
+
+    #[test]
+    fn candidates_report_every_lv() {
+        let lv = |name: &str, vg: &str, segtype: &str| LvInfo {
+            lv_name: name.to_string(),
+            vg_name: vg.to_string(),
+            segtype: Some(segtype.to_string()),
+            origin: None,
+            open: false,
+            size: None,
+        };
+        let lvs = vec![
+            lv("vm-1-disk-0", "pve", "thin"),
+            lv("vm-2-disk-0", "pve", "linear"),
+            lv("vm-3-disk-0", "other", "thin"),
+            lv("base-4-disk-0", "pve", "thin"),
+            lv("vm-5-cloudinit", "pve", "thin"),
+        ];
+
+        let mut cfg = test_config();
+        cfg.backup.pv_exclude_re = Some(regex::Regex::new("cloudinit").unwrap());
+        let lvm = Arc::new(MockLvm { lvs, pools: vec![] });
+        let provider = LvmThinProvider::new(&cfg, lvm, Arc::new(MockBlock), Arc::new(MockPveSh));
+
+        let reasons: Vec<(String, Option<String>)> = provider
+            .candidates()
+            .unwrap()
+            .into_iter()
+            .map(|c| (c.name, c.rejected))
+            .collect();
+        let reason = |i: usize| reasons[i].1.as_deref().unwrap_or("accept").to_string();
+        assert_eq!(reasons[0].0, "pve/vm-1-disk-0");
+        assert_eq!(reason(0), "accept");
+        assert!(reason(1).starts_with("NotThin"), "{}", reason(1));
+        assert!(reason(2).starts_with("VgNotAllowed"), "{}", reason(2));
+        assert!(reason(3).starts_with("PvDenied"), "{}", reason(3));
+        assert!(reason(4).starts_with("excluded-by-regex"), "{}", reason(4));
     }
 
     #[test]
@@ -441,4 +715,39 @@ mod tests {
         cleanup.add("pve/snap2".to_string());
         assert_eq!(cleanup.snaps.len(), 2);
     }
+
+    #[test]
+    fn prepare_refuses_nearly_full_thin_pool() {
+        let lvs = vec![LvInfo {
+            lv_name: "vm-123-disk-0".to_string(),
+            vg_name: "pve".to_string(),
+            segtype: Some("thin".to_string()),
+            origin: None,
+            open: false,
+            size: None,
+        }];
+        let pools = vec![ThinPoolUsage {
+            name: "data".to_string(),
+            data_percent: 42.0,
+            metadata_percent: 95.5,
+        }];
+        let mut cfg = test_config();
+        let lvm = Arc::new(MockLvm { lvs, pools });
+        let volumes =
+            LvmThinProvider::new(&cfg, lvm.clone(), Arc::new(MockBlock), Arc::new(MockPveSh))
+                .discover()
+                .unwrap();
+
+        let mut provider =
+            LvmThinProvider::new(&cfg, lvm.clone(), Arc::new(MockBlock), Arc::new(MockPveSh));
+        let err = provider.prepare(&volumes).unwrap_err();
+        assert!(format!("{err:#}").contains("pve/data"));
+
+        if let Some(l) = cfg.backup.sources.lvmthin.as_mut() {
+            l.pool_usage_action = PoolUsageAction::Warn;
+        }
+        let mut provider =
+            LvmThinProvider::new(&cfg, lvm, Arc::new(MockBlock), Arc::new(MockPveSh));
+        assert!(provider.prepare(&volumes).unwrap().is_empty());
+    }
 }
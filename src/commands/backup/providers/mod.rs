@@ -1,14 +1,125 @@
+pub mod external;
+pub mod lvm;
 pub mod lvmthin;
 pub mod zfs;
 
+use std::fmt;
+
 use anyhow::Result;
 
-use crate::{AppCtx, volume::Volume};
+use crate::{AppCtx, config::PvDenial, manifest::StorageStatus, tooling::LvmPort, volume::Volume};
+
+#[derive(Debug, Clone)]
+pub struct Skipped {
+    pub archive: String,
+    pub reason: String,
+}
+
+/// A volume a provider looked at during discovery, with the reason it was left out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub provider: String,
+    pub name: String,
+    pub rejected: Option<String>,
+    /// What holds the volume, when something does.
+    pub in_use: Option<String>,
+}
+
+pub(super) const LV_OPEN: &str = "LV device is open";
 
 pub trait Provider {
-    fn name(&self) -> &'static str;
+    fn name(&self) -> &str;
     fn discover(&self) -> Result<Vec<Volume>>;
-    fn prepare(&mut self, volumes: &[Volume]) -> Result<()>;
+    /// Every volume `discover` considers, accepted or not.
+    fn candidates(&self) -> Result<Vec<Candidate>>;
+    fn prepare(&mut self, volumes: &[Volume]) -> Result<Vec<Skipped>>;
+    fn storage_status(&self) -> Vec<StorageStatus>;
+
+    /// `snapshot_barrier`: takes only the snapshots of `volumes`, before any provider's
+    /// `prepare`; `prepare` then skips the snapshots already taken.
+    fn snapshot(&mut self, _volumes: &[Volume]) -> Result<Vec<Skipped>> {
+        Ok(Vec::new())
+    }
+
+    /// `--changed-only`: volumes with nothing written since their baseline snapshot.
+    fn unchanged(&self, _volumes: &[Volume]) -> Result<Vec<Skipped>> {
+        Ok(Vec::new())
+    }
+
+    /// `--changed-only`: keeps the snapshots of the uploaded `volumes` as their next baseline.
+    fn keep_baseline(&mut self, _volumes: &[Volume]) -> Result<()> {
+        Ok(())
+    }
+
+    /// `active_volumes`: volumes some process on the host holds open.
+    fn in_use(&self, _volumes: &[Volume]) -> Result<Vec<Skipped>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Volumes whose LV, `(vg, lv)` as `vg_lv` reads it from their meta, lvs reports open.
+pub(super) fn open_lvs<'v>(
+    lvm: &dyn LvmPort,
+    volumes: &'v [Volume],
+    vg_lv: impl Fn(&'v Volume) -> Option<(&'v str, &'v str)>,
+) -> Result<Vec<Skipped>> {
+    let open: Vec<(String, String)> = lvm
+        .list_lvs()?
+        .into_iter()
+        .filter(|lv| lv.open)
+        .map(|lv| (lv.vg_name, lv.lv_name))
+        .collect();
+    Ok(volumes
+        .iter()
+        .filter(|v| {
+            vg_lv(v).is_some_and(|(vg, lv)| open.iter().any(|(ovg, olv)| ovg == vg && olv == lv))
+        })
+        .map(|v| Skipped {
+            archive: v.archive.clone(),
+            reason: LV_OPEN.to_string(),
+        })
+        .collect())
+}
+
+/// Why a provider's discovery leaves a volume out.
+pub(super) enum Reject<'a> {
+    /// lvmthin: the LV is not a thin volume.
+    NotThin,
+    /// lvm: the LV is neither linear nor striped.
+    NotClassic,
+    /// lvm: the LV is a snapshot of another LV.
+    Snapshot,
+    VgNotAllowed(&'a str),
+    /// zfs: the dataset is a clone of `origin`.
+    NotBase(&'a str),
+    /// zfs: a filesystem dataset in a pool that is not in `send_pools`.
+    Filesystem,
+    PvDenied,
+    ExcludedByRegex,
+}
+
+impl Reject<'_> {
+    pub(super) fn pv(denial: PvDenial) -> Self {
+        match denial {
+            PvDenial::Prefix => Reject::PvDenied,
+            PvDenial::ExcludedByRegex => Reject::ExcludedByRegex,
+        }
+    }
+}
+
+impl fmt::Display for Reject<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reject::NotThin => write!(f, "NotThin: segtype is not thin"),
+            Reject::NotClassic => write!(f, "NotClassic: segtype is not linear/striped"),
+            Reject::Snapshot => write!(f, "Snapshot: LV is a snapshot"),
+            Reject::VgNotAllowed(vg) => write!(f, "VgNotAllowed: vg '{vg}' is not configured"),
+            Reject::NotBase(origin) => write!(f, "NotBase: clone of '{origin}'"),
+            Reject::Filesystem => write!(f, "Filesystem: pool is not in send_pools"),
+            Reject::PvDenied => write!(f, "PvDenied: no pv_prefixes match"),
+            Reject::ExcludedByRegex => write!(f, "excluded-by-regex: matches pv_exclude_re"),
+        }
+    }
 }
 
 pub struct ProviderRegistry<'a> {
@@ -32,6 +143,7 @@ impl<'a> ProviderRegistry<'a> {
                 zfs_port,
                 self.ctx.tools.block(),
                 self.ctx.tools.pvesh(),
+                self.ctx.tools.fs(),
             )));
         }
         if cfg.backup.sources.lvmthin.is_some() {
@@ -44,6 +156,23 @@ impl<'a> ProviderRegistry<'a> {
                 self.ctx.tools.pvesh(),
             )));
         }
+        if cfg.backup.sources.lvm.is_some() {
+            let lvm_port = self.ctx.tools.lvm().expect("lvm enabled");
+
+            out.push(Box::new(lvm::LvmProvider::new(
+                cfg,
+                lvm_port,
+                self.ctx.tools.block(),
+                self.ctx.tools.pvesh(),
+            )));
+        }
+        for name in cfg.backup.sources.external.keys() {
+            out.push(Box::new(external::ExternalProvider::new(
+                cfg,
+                name,
+                self.ctx.runner.clone(),
+            )));
+        }
 
         out
     }
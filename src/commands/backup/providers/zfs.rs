@@ -1,28 +1,58 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use anyhow::{Context, Result, anyhow};
+use anyhow::{Context, Result, anyhow, bail};
+use glob::Pattern;
 use tracing;
 
 use crate::{
-    commands::backup::providers::Provider,
+    commands::backup::providers::{Candidate, Provider, Reject, Skipped},
     config::{Backup, Config},
-    tooling::{BlockPort, PveshPort, ZfsPort, pvesh::Storage},
-    utils::{exec_policy, naming::create_archive_name, path::dataset_leaf, time::current_epoch},
+    manifest::StorageStatus,
+    tooling::{
+        BlockPort, FsPort, PveshPort, ZfsPort,
+        pvesh::{Storage, fallback_storage_id},
+        zfs::ZfsVolume,
+    },
+    utils::{
+        exec_policy,
+        naming::{PVTOOLS_SUFFIX, ZFS_SEND_EXT},
+        path::dataset_leaf,
+        signal,
+        time::current_epoch,
+    },
     volume::Volume,
 };
 
 const DEV_PREFIX: &str = "/dev/zvol/";
-const CLONE_SUFFIX: &str = "pvtools";
-
-enum Reject<'a> {
-    NotBase(&'a str),
-    PvDenied(&'a str),
-}
+const CLONE_SUFFIX: &str = PVTOOLS_SUFFIX;
+/// Snapshot `--changed-only` compares against: the one of the last upload of the dataset.
+const BASELINE_SNAP: &str = "pvtools-base";
 
 #[derive(Debug, Clone)]
 struct ZfsMeta {
     dataset: String,
     run_ts: u64,
+    /// Backed up as a `zfs send` stream staged at the volume's device path.
+    send: bool,
+    /// Read from the snapshot's own device node, without a clone.
+    snapdev: bool,
+    /// Pre-existing snapshot backed up instead of one of our own, with `use_existing_snapshot`.
+    existing: Option<String>,
+}
+
+impl ZfsMeta {
+    fn names(&self) -> ZfsNames {
+        let mut names = build_zfs_names(&self.dataset, CLONE_SUFFIX, self.run_ts);
+        if let Some(snap) = &self.existing {
+            names.snap_device = PathBuf::from(format!("{DEV_PREFIX}{snap}"));
+            names.snap = snap.clone();
+        }
+        names
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -30,16 +60,26 @@ struct ZfsNames {
     snap: String,
     clone: String,
     device: PathBuf,
+    snap_device: PathBuf,
 }
 
 pub struct ZfsProvider<'a> {
     pools: &'a [String],
+    send_pools: &'a [String],
+    staging_dir: &'a Path,
+    snapshot_devices: bool,
+    /// Snapshot names `use_existing_snapshot` matches.
+    use_existing: Option<Pattern>,
+    storage_map: &'a BTreeMap<String, String>,
     backup: &'a Backup,
     run_ts: u64,
     cleanup: Cleanup,
+    /// Archives whose snapshot is taken.
+    snapped: HashSet<String>,
     zfs: Arc<dyn ZfsPort>,
     block: Arc<dyn BlockPort>,
     pvesh: Arc<dyn PveshPort>,
+    fs: Arc<dyn FsPort>,
 }
 
 impl<'a> ZfsProvider<'a> {
@@ -48,20 +88,108 @@ impl<'a> ZfsProvider<'a> {
         zfs: Arc<dyn ZfsPort>,
         block: Arc<dyn BlockPort>,
         pvesh: Arc<dyn PveshPort>,
+        fs: Arc<dyn FsPort>,
     ) -> Self {
         let z = cfg.backup.sources.zfs.as_ref().expect("[zfs] missing");
 
         Self {
+            use_existing: z.use_existing_snapshot.as_deref().map(|p| {
+                Pattern::new(p).expect("use_existing_snapshot is checked when the config loads")
+            }),
             pools: &z.pools,
+            send_pools: &z.send_pools,
+            staging_dir: &z.staging_dir,
+            snapshot_devices: z.snapshot_devices,
+            storage_map: &cfg.pve.storage_map,
             backup: &cfg.backup,
             run_ts: current_epoch(),
-            cleanup: Cleanup::new(zfs.clone()),
+            cleanup: Cleanup::new(zfs.clone(), fs.clone()),
+            snapped: HashSet::new(),
             zfs,
             block,
             pvesh,
+            fs,
         }
     }
 
+    /// Fails unless `staging_dir` can hold the streams of `sends` at once, as they all stay
+    /// there until the single upload of the run is done.
+    fn check_staging_room(&self, sends: &[&Volume]) -> Result<()> {
+        if exec_policy::is_dry_run() {
+            return Ok(());
+        }
+        let mut need = 0u64;
+        for v in sends {
+            if let Some(meta) = v.meta::<ZfsMeta>() {
+                let snap = meta.names().snap;
+                need += self
+                    .zfs
+                    .send_size(&snap)
+                    .with_context(|| format!("estimate zfs send of {}", meta.dataset))?;
+            }
+        }
+        self.fs.ensure_dir(self.staging_dir)?;
+        let free = self.fs.free_bytes(self.staging_dir)?;
+        if need > free {
+            bail!(
+                "staging_dir {} has {free} bytes free, the {} zfs send stream(s) need about {need}",
+                self.staging_dir.display(),
+                sends.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// Datasets of `pool`: zvols, plus filesystems when the pool is backed up with `zfs send`.
+    fn datasets(&self, pool: &str) -> Result<Vec<ZfsVolume>> {
+        let mut out = self.zfs.list_volumes(pool)?;
+        if self.sends(pool) {
+            out.extend(self.zfs.list_filesystems(pool)?);
+        }
+        Ok(out)
+    }
+
+    /// Snapshots the dataset of `v` unless done already; a vanished dataset is skipped.
+    fn take_snapshot(&mut self, v: &Volume, meta: &ZfsMeta) -> Result<Option<Skipped>> {
+        if self.snapped.contains(&v.archive) {
+            return Ok(None);
+        }
+        if let Err(e) = self.zfs.assert_dataset_exists(&meta.dataset) {
+            tracing::warn!("skip {}: dataset disappeared since discovery", meta.dataset);
+            return Ok(Some(Skipped {
+                archive: v.archive.clone(),
+                reason: format!("{e:#}"),
+            }));
+        }
+        if self.use_existing.is_some() {
+            let Some(snap) = &meta.existing else {
+                tracing::warn!(
+                    "skip {}: no snapshot matches use_existing_snapshot",
+                    meta.dataset
+                );
+                return Ok(Some(Skipped {
+                    archive: v.archive.clone(),
+                    reason: "no existing snapshot matches use_existing_snapshot".to_string(),
+                }));
+            };
+            tracing::info!("{}: backing up existing snapshot {snap}", meta.dataset);
+            self.snapped.insert(v.archive.clone());
+            return Ok(None);
+        }
+        let snap = meta.names().snap;
+        self.zfs
+            .snapshot(&snap)
+            .with_context(|| format!("zfs snapshot on {}", meta.dataset))?;
+        self.cleanup.add_many([snap]);
+        self.snapped.insert(v.archive.clone());
+        Ok(None)
+    }
+
+    #[inline]
+    fn sends(&self, pool: &str) -> bool {
+        self.send_pools.iter().any(|p| p == pool)
+    }
+
     #[inline]
     fn accept_ds<'b>(
         &self,
@@ -71,16 +199,15 @@ impl<'a> ZfsProvider<'a> {
         if let Some(orig) = origin {
             return Err(Reject::NotBase(orig));
         }
-        let leaf = dataset_leaf(name);
-        if !self.backup.pv_allows(leaf) {
-            return Err(Reject::PvDenied(leaf));
+        if let Some(denial) = self.backup.pv_denial(dataset_leaf(name)) {
+            return Err(Reject::pv(denial));
         }
         Ok(())
     }
 }
 
 impl<'a> Provider for ZfsProvider<'a> {
-    fn name(&self) -> &'static str {
+    fn name(&self) -> &str {
         "zfs"
     }
 
@@ -89,9 +216,19 @@ impl<'a> Provider for ZfsProvider<'a> {
         let storages = self.pvesh.get_storage()?;
 
         for pool in self.pools {
-            let zfs_volumes = self.zfs.list_volumes(pool)?;
+            let send = self.sends(pool);
+            let zfs_volumes = self.datasets(pool)?;
             let guid_map = self.zfs.guid_map(pool)?;
-            let storage_id = find_storage(&storages, pool)?;
+            let snapdevs = if self.snapshot_devices && !send {
+                self.zfs.visible_snapdevs(pool)?
+            } else {
+                HashSet::new()
+            };
+            let existing = match &self.use_existing {
+                Some(pat) => newest_matching(self.zfs.list_snapshots(pool)?, pat),
+                None => HashMap::new(),
+            };
+            let mut storage_ids = BTreeMap::new();
 
             for v in zfs_volumes {
                 let name = &v.name;
@@ -99,31 +236,60 @@ impl<'a> Provider for ZfsProvider<'a> {
                 match self.accept_ds(name, origin) {
                     Ok(()) => {
                         let leaf = dataset_leaf(name);
+                        let parent = name.rsplit_once('/').map_or(name.as_str(), |(p, _)| p);
+                        let storage_id = storage_ids
+                            .entry(parent.to_string())
+                            .or_insert_with(|| {
+                                find_storage(&storages, parent)
+                                    .map(str::to_string)
+                                    .unwrap_or_else(|e| {
+                                        fallback_storage_id(self.storage_map, pool, e)
+                                    })
+                            })
+                            .clone();
                         let id8 = guid_map.get(name).ok_or_else(|| {
                             anyhow::anyhow!("guid not found for dataset {}", name)
                         })?;
-                        let archive = create_archive_name("zfs", leaf, id8)?;
-
-                        let names = build_zfs_names(name, CLONE_SUFFIX, self.run_ts);
-                        let device = names.device.clone();
+                        let scheme = self.backup.archive_names;
+                        let snapdev = snapdevs.contains(name);
+                        if self.snapshot_devices && !send && !snapdev {
+                            tracing::debug!("{name}: snapdev is hidden, reading from a clone");
+                        }
+                        let meta = ZfsMeta {
+                            dataset: name.to_string(),
+                            run_ts: self.run_ts,
+                            send,
+                            snapdev,
+                            existing: existing.get(name).cloned(),
+                        };
+                        let (archive, device) = if send {
+                            let archive = scheme.archive_name(
+                                "zfs",
+                                &format!("{leaf}.{ZFS_SEND_EXT}"),
+                                id8,
+                            )?;
+                            let file = format!("{archive}.{}", self.run_ts);
+                            (archive, self.staging_dir.join(file))
+                        } else {
+                            let names = meta.names();
+                            let device = if snapdev {
+                                names.snap_device
+                            } else {
+                                names.device
+                            };
+                            (scheme.archive_name("zfs", leaf, id8)?, device)
+                        };
 
                         out.push(Volume {
-                            storage: storage_id.to_string(),
+                            storage: storage_id,
                             disk: leaf.to_string(),
                             archive,
                             device,
-                            meta: Some(Arc::new(ZfsMeta {
-                                dataset: name.to_string(),
-                                run_ts: self.run_ts,
-                            })),
+                            size_bytes: v.size,
+                            meta: Some(Arc::new(meta)),
                         });
                     }
-                    Err(Reject::NotBase(orig)) => {
-                        tracing::debug!("skip {}: origin != '-' (origin='{}')", &name, orig)
-                    }
-                    Err(Reject::PvDenied(leaf)) => {
-                        tracing::debug!("skip {}: pv_allows(false) for leaf '{}'", &name, leaf)
-                    }
+                    Err(r) => tracing::debug!("skip {name}: {r}"),
                 }
             }
         }
@@ -135,44 +301,190 @@ impl<'a> Provider for ZfsProvider<'a> {
         Ok(out)
     }
 
-    fn prepare(&mut self, volumes: &[Volume]) -> Result<()> {
+    fn candidates(&self) -> Result<Vec<Candidate>> {
+        let mut out = Vec::new();
+        for pool in self.pools {
+            for v in self.datasets(pool)? {
+                let rejected = self
+                    .accept_ds(&v.name, v.origin.as_deref())
+                    .err()
+                    .map(|r| r.to_string());
+                out.push(Candidate {
+                    provider: self.name().to_string(),
+                    name: v.name,
+                    rejected,
+                    in_use: None,
+                });
+            }
+            // Filesystems only go out as send streams; show why the others are left out.
+            if !self.sends(pool) {
+                for v in self.zfs.list_filesystems(pool)? {
+                    out.push(Candidate {
+                        provider: self.name().to_string(),
+                        name: v.name,
+                        rejected: Some(Reject::Filesystem.to_string()),
+                        in_use: None,
+                    });
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    fn snapshot(&mut self, volumes: &[Volume]) -> Result<Vec<Skipped>> {
+        let mut skipped = Vec::new();
+        for v in volumes {
+            if let Some(meta) = v.meta::<ZfsMeta>() {
+                skipped.extend(self.take_snapshot(v, meta)?);
+            }
+        }
+        Ok(skipped)
+    }
+
+    fn prepare(&mut self, volumes: &[Volume]) -> Result<Vec<Skipped>> {
+        let mut skipped = Vec::new();
+        for v in volumes {
+            if let Some(meta) = v.meta::<ZfsMeta>()
+                && let Some(s) = self.take_snapshot(v, meta)?
+            {
+                skipped.push(s);
+            }
+        }
+        let sends: Vec<&Volume> = volumes
+            .iter()
+            .filter(|v| v.meta::<ZfsMeta>().is_some_and(|m| m.send))
+            .filter(|v| !skipped.iter().any(|s| s.archive == v.archive))
+            .collect();
+        if !sends.is_empty()
+            && let Err(e) = self.check_staging_room(&sends)
+        {
+            tracing::warn!("{e:#}");
+            skipped.extend(sends.iter().map(|v| Skipped {
+                archive: v.archive.clone(),
+                reason: format!("{e:#}"),
+            }));
+        }
+
         for v in volumes {
             let meta = match v.meta::<ZfsMeta>() {
                 Some(m) => m,
                 None => continue,
             };
+            if skipped.iter().any(|s| s.archive == v.archive) {
+                continue;
+            }
 
-            let names = build_zfs_names(&meta.dataset, CLONE_SUFFIX, meta.run_ts);
-
-            self.zfs
-                .snapshot(&names.snap)
-                .with_context(|| format!("zfs snapshot on {}", &meta.dataset))?;
+            let names = meta.names();
+            if meta.send {
+                self.fs.ensure_dir(self.staging_dir)?;
+                self.cleanup.files.push(v.device.clone());
+                self.zfs
+                    .send_to_file(
+                        &names.snap,
+                        &v.device,
+                        self.backup.priority,
+                        self.backup.volume_timeout,
+                    )
+                    .with_context(|| format!("stage zfs send of {}", meta.dataset))?;
+                continue;
+            }
+            if meta.snapdev {
+                self.block.wait_for_block(&names.snap_device)?;
+                continue;
+            }
             self.zfs
                 .clone_readonly_dev(&names.snap, &names.clone)
-                .with_context(|| format!("zfs clone on {}", &meta.dataset))?;
+                .with_context(|| format!("zfs clone on {}", meta.dataset))?;
+
+            self.cleanup.add_many([names.clone.clone()]);
+            self.block.wait_for_block(&names.device)?;
+        }
+
+        Ok(skipped)
+    }
 
-            if !exec_policy::is_dry_run() {
-                self.block.wait_for_block(&names.device)?;
-                self.cleanup
-                    .add_many([names.clone.clone(), names.snap.clone()]);
+    fn unchanged(&self, volumes: &[Volume]) -> Result<Vec<Skipped>> {
+        let mut out = Vec::new();
+        if self.use_existing.is_some() {
+            tracing::warn!("zfs: --changed-only does not apply to existing snapshots");
+            return Ok(out);
+        }
+        for v in volumes {
+            let Some(meta) = v.meta::<ZfsMeta>() else {
+                continue;
+            };
+            match self.zfs.written_since(&meta.dataset, BASELINE_SNAP)? {
+                Some(0) => out.push(Skipped {
+                    archive: v.archive.clone(),
+                    reason: "unchanged since the last backup".to_string(),
+                }),
+                Some(_) => {}
+                None => tracing::debug!("{}: no @{BASELINE_SNAP} yet", meta.dataset),
             }
         }
+        Ok(out)
+    }
 
+    fn keep_baseline(&mut self, volumes: &[Volume]) -> Result<()> {
+        // The snapshots belong to whatever took them.
+        if self.use_existing.is_some() {
+            return Ok(());
+        }
+        for v in volumes {
+            let Some(meta) = v.meta::<ZfsMeta>() else {
+                continue;
+            };
+            let snap = meta.names().snap;
+            let base = format!("{}@{BASELINE_SNAP}", meta.dataset);
+            if self
+                .zfs
+                .written_since(&meta.dataset, BASELINE_SNAP)?
+                .is_some()
+            {
+                self.zfs.destroy_recursive(&base)?;
+            }
+            self.zfs.rename(&snap, &base)?;
+            self.cleanup.tasks.retain(|t| *t != snap);
+        }
         Ok(())
     }
+
+    fn storage_status(&self) -> Vec<StorageStatus> {
+        let pools: BTreeSet<&str> = self
+            .pools
+            .iter()
+            .map(|p| p.split('/').next().unwrap_or(p))
+            .collect();
+        pools
+            .into_iter()
+            .map(|pool| {
+                StorageStatus::capture(
+                    self.name(),
+                    pool,
+                    &format!("zpool status -P {pool}"),
+                    self.zfs.pool_status(pool),
+                )
+            })
+            .collect()
+    }
 }
 
 #[derive(Default)]
 struct Cleanup {
     tasks: Vec<String>,
+    /// Staged `zfs send` streams.
+    files: Vec<PathBuf>,
     zfs: Option<Arc<dyn ZfsPort>>,
+    fs: Option<Arc<dyn FsPort>>,
 }
 
 impl Cleanup {
-    pub fn new(zfs: Arc<dyn ZfsPort>) -> Self {
+    pub fn new(zfs: Arc<dyn ZfsPort>, fs: Arc<dyn FsPort>) -> Self {
         Self {
             tasks: Vec::new(),
+            files: Vec::new(),
             zfs: Some(zfs),
+            fs: Some(fs),
         }
     }
 
@@ -186,11 +498,23 @@ impl Cleanup {
 impl Drop for Cleanup {
     fn drop(&mut self) {
         if let Some(zfs) = &self.zfs {
-            for s in self.tasks.drain(..) {
-                if let Err(e) = zfs.destroy_recursive(&s) {
-                    tracing::warn!("[cleanup] zfs destroy -r {} failed: {e}", s);
+            signal::shielded(|| {
+                // Newest first, so clones go before the snapshots they come from.
+                for s in self.tasks.drain(..).rev() {
+                    if let Err(e) = zfs.destroy_recursive(&s) {
+                        tracing::warn!("[cleanup] zfs destroy -r {} failed: {e}", s);
+                    }
                 }
-            }
+            });
+        }
+        if let Some(fs) = &self.fs {
+            signal::shielded(|| {
+                for f in self.files.drain(..) {
+                    if let Err(e) = fs.remove_file(&f) {
+                        tracing::warn!("[cleanup] rm {} failed: {e}", f.display());
+                    }
+                }
+            });
         }
     }
 }
@@ -200,71 +524,203 @@ fn build_zfs_names(ds: &str, suffix: &str, ts: u64) -> ZfsNames {
     let snap = format!("{ds}@{suffix}-{ts}");
     let clone = format!("{ds}-{suffix}-{ts}");
     let device = PathBuf::from(format!("{DEV_PREFIX}{clone}"));
+    let snap_device = PathBuf::from(format!("{DEV_PREFIX}{snap}"));
     ZfsNames {
         snap,
         clone,
         device,
+        snap_device,
     }
 }
 
-#[inline]
-fn find_storage<'a>(storages: &'a [Storage], pool: &str) -> Result<&'a str> {
+/// Newest snapshot of each dataset whose name matches `pat`, from `snaps` in creation order.
+fn newest_matching(snaps: Vec<String>, pat: &Pattern) -> HashMap<String, String> {
+    snaps
+        .into_iter()
+        .filter_map(|s| {
+            let (ds, name) = s.split_once('@')?;
+            pat.matches(name).then(|| (ds.to_string(), s.clone()))
+        })
+        .collect()
+}
+
+/// The storage whose pool is `dataset` or its closest ancestor.
+fn find_storage<'a>(storages: &'a [Storage], dataset: &str) -> Result<&'a str> {
     storages
         .iter()
-        .find_map(|s| match *s {
-            Storage::ZfsPool {
-                ref id,
-                pool: ref zpool_name,
-                ..
-            } if zpool_name.as_str() == pool => Some(id.as_str()),
+        .filter_map(|s| match s {
+            Storage::ZfsPool { id, pool, .. }
+                if dataset == pool
+                    || dataset
+                        .strip_prefix(pool.as_str())
+                        .is_some_and(|r| r.starts_with('/')) =>
+            {
+                Some((pool.len(), id.as_str()))
+            }
             _ => None,
         })
-        .ok_or_else(|| anyhow!("Zfs storage with pool='{pool}' not found"))
+        .max_by_key(|(len, _)| *len)
+        .map(|(_, id)| id)
+        .ok_or_else(|| anyhow!("Zfs storage with pool='{dataset}' not found"))
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{collections::HashMap, path::Path, sync::Arc, time::Duration};
+    use std::{collections::HashMap, path::Path, sync::Mutex, time::Duration};
 
     use anyhow::Result;
 
     use super::*;
     use crate::{
-        config::{Backup, BackupSources, BackupTarget, Config, Pbs, Restore, Zfs},
+        config::{Backup, BackupSources, BackupTarget, Config, Events, Pbs, Pve, Restore, Zfs},
         tooling::{BlockPort, ZfsPort, zfs::ZfsVolume},
-        utils::process::ProcessRunner,
+        utils::process::{CmdSpec, Priority, ProcessRunner},
     };
 
+    #[derive(Default)]
     struct MockZfs {
         volumes: Vec<ZfsVolume>,
         guid_map: HashMap<String, String>,
+        filesystems: Vec<ZfsVolume>,
+        sent: Mutex<Vec<(String, PathBuf)>>,
+        /// `written@pvtools-base` per dataset; datasets without one have no baseline.
+        written: HashMap<String, u64>,
+        zfs_calls: Mutex<Vec<String>>,
+        /// Snapshots and clones, in the order they were made.
+        made: Mutex<Vec<String>>,
+        snapdev: HashSet<String>,
+        snapshots: Vec<String>,
+        stream_size: u64,
     }
 
     impl ZfsPort for MockZfs {
         fn list_volumes(&self, _pool: &str) -> Result<Vec<ZfsVolume>> {
             Ok(self.volumes.clone())
         }
+        fn list_filesystems(&self, _pool: &str) -> Result<Vec<ZfsVolume>> {
+            Ok(self.filesystems.clone())
+        }
+        fn list_snapshots(&self, _pool: &str) -> Result<Vec<String>> {
+            Ok(self.snapshots.clone())
+        }
         fn guid_map(&self, _pool: &str) -> Result<HashMap<String, String>> {
             Ok(self.guid_map.clone())
         }
-        fn snapshot(&self, _name: &str) -> Result<()> {
+        fn visible_snapdevs(&self, _pool: &str) -> Result<HashSet<String>> {
+            Ok(self.snapdev.clone())
+        }
+        fn snapshot(&self, name: &str) -> Result<()> {
+            self.made.lock().unwrap().push(name.to_string());
             Ok(())
         }
-        fn clone_readonly_dev(&self, _snap: &str, _clone: &str) -> Result<()> {
+        fn clone_readonly_dev(&self, _snap: &str, clone: &str) -> Result<()> {
+            self.made.lock().unwrap().push(clone.to_string());
             Ok(())
         }
-        fn destroy_recursive(&self, _name: &str) -> Result<()> {
+        fn destroy_recursive(&self, name: &str) -> Result<()> {
+            self.zfs_calls
+                .lock()
+                .unwrap()
+                .push(format!("destroy -r {name}"));
             Ok(())
         }
-        fn assert_dataset_exists(&self, _dataset: &str) -> Result<()> {
+        fn rename(&self, from: &str, to: &str) -> Result<()> {
+            self.zfs_calls
+                .lock()
+                .unwrap()
+                .push(format!("rename {from} {to}"));
             Ok(())
         }
+        fn written_since(&self, dataset: &str, _snap: &str) -> Result<Option<u64>> {
+            Ok(self.written.get(dataset).copied())
+        }
+        fn assert_dataset_exists(&self, dataset: &str) -> Result<()> {
+            if self
+                .volumes
+                .iter()
+                .chain(&self.filesystems)
+                .any(|v| v.name == dataset)
+            {
+                Ok(())
+            } else {
+                anyhow::bail!("dataset does not exist: {dataset}")
+            }
+        }
         fn dataset_mountpoint(&self, _dataset: &str) -> Result<Option<String>> {
             Ok(None)
         }
-        fn create_zvol(&self, _dataset: &str, _size_bytes: u64) -> Result<()> {
+        fn user_property(&self, _dataset: &str, _prop: &str) -> Result<Option<String>> {
+            Ok(None)
+        }
+        fn set_user_property(
+            &self,
+            _dataset: &str,
+            _prop: &str,
+            _value: Option<&str>,
+        ) -> Result<()> {
+            Ok(())
+        }
+        fn create_zvol(
+            &self,
+            _dataset: &str,
+            _size_bytes: u64,
+            _volblocksize: Option<&str>,
+        ) -> Result<()> {
+            Ok(())
+        }
+        fn ensure_dataset(&self, _dataset: &str) -> Result<()> {
+            Ok(())
+        }
+        fn pool_status(&self, _pool: &str) -> Result<String> {
+            Ok(String::new())
+        }
+        fn pool_usage(&self, _pool: &str) -> Result<String> {
+            Ok(String::new())
+        }
+        fn send_size(&self, _snap: &str) -> Result<u64> {
+            Ok(self.stream_size)
+        }
+        fn send_to_file(
+            &self,
+            snap: &str,
+            path: &Path,
+            _priority: Priority,
+            _timeout: Option<Duration>,
+        ) -> Result<()> {
+            self.sent
+                .lock()
+                .unwrap()
+                .push((snap.to_string(), path.to_path_buf()));
+            Ok(())
+        }
+        fn receive(&self, _src: CmdSpec, _dataset: &str) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    struct MockFs;
+    impl FsPort for MockFs {
+        fn ensure_dir(&self, _dir: &Path) -> Result<()> {
+            Ok(())
+        }
+        fn ensure_parent_dir(&self, _path: &Path) -> Result<()> {
+            Ok(())
+        }
+        fn create_sparse_file(&self, _path: &Path, _size_bytes: u64) -> Result<()> {
+            Ok(())
+        }
+        fn write_file(&self, _path: &Path, _contents: &str) -> Result<()> {
             Ok(())
         }
+        fn remove_file(&self, _path: &Path) -> Result<()> {
+            Ok(())
+        }
+        fn copy_into(&self, _src: &Path, _dest: &Path) -> Result<()> {
+            Ok(())
+        }
+        fn free_bytes(&self, _dir: &Path) -> Result<u64> {
+            Ok(1 << 30)
+        }
     }
 
     struct MockBlock;
@@ -291,6 +747,9 @@ mod tests {
                 content: vec!["".to_string()],
             }])
         }
+        fn cluster_storage(&self) -> Result<Vec<crate::tooling::pvesh::ClusterStorage>> {
+            Ok(Vec::new())
+        }
     }
 
     fn test_config() -> Config {
@@ -299,22 +758,37 @@ mod tests {
                 repos: HashMap::new(),
                 keyfile: None,
                 password: None,
+                key_passphrase: None,
+                create_ns: Default::default(),
                 ns: None,
                 backup_id: "test".to_string(),
             },
+            pve: Pve::default(),
+            events: Events::default(),
+            log: Default::default(),
+            state: Default::default(),
             backup: Backup {
                 sources: BackupSources {
                     zfs: Some(Zfs {
                         pools: vec!["tank".to_string()],
+                        storage_map: BTreeMap::new(),
+                        send_pools: Vec::new(),
+                        staging_dir: PathBuf::from("/var/tmp"),
+                        snapshot_devices: false,
+                        use_existing_snapshot: None,
                     }),
                     lvmthin: None,
+                    lvm: None,
+                    external: BTreeMap::new(),
                 },
                 target: BackupTarget { repo: None },
                 pv_prefixes: vec!["vm-".to_string()],
                 pv_exclude_re: None,
                 pv_exclude_re_src: None,
+                ..Backup::default()
             },
             restore: Restore::default(),
+            nodes: BTreeMap::new(),
         }
     }
 
@@ -335,10 +809,11 @@ mod tests {
         let zfs = Arc::new(MockZfs {
             volumes: vec![],
             guid_map: HashMap::new(),
+            ..MockZfs::default()
         });
         let block = Arc::new(MockBlock);
         let pvesh = Arc::new(MockPveSh);
-        let provider = ZfsProvider::new(&cfg, zfs, block, pvesh);
+        let provider = ZfsProvider::new(&cfg, zfs, block, pvesh, Arc::new(MockFs));
 
         let result = provider.accept_ds("tank/vm-123", Some("tank/vm-base@snap"));
         assert!(matches!(result, Err(Reject::NotBase(_))));
@@ -350,13 +825,14 @@ mod tests {
         let zfs = Arc::new(MockZfs {
             volumes: vec![],
             guid_map: HashMap::new(),
+            ..MockZfs::default()
         });
         let block = Arc::new(MockBlock);
         let pvesh = Arc::new(MockPveSh);
-        let provider = ZfsProvider::new(&cfg, zfs, block, pvesh);
+        let provider = ZfsProvider::new(&cfg, zfs, block, pvesh, Arc::new(MockFs));
 
         let result = provider.accept_ds("tank/other-123", None);
-        assert!(matches!(result, Err(Reject::PvDenied(_))));
+        assert!(matches!(result, Err(Reject::PvDenied)));
     }
 
     #[test]
@@ -365,10 +841,11 @@ mod tests {
         let zfs = Arc::new(MockZfs {
             volumes: vec![],
             guid_map: HashMap::new(),
+            ..MockZfs::default()
         });
         let block = Arc::new(MockBlock);
         let pvesh = Arc::new(MockPveSh);
-        let provider = ZfsProvider::new(&cfg, zfs, block, pvesh);
+        let provider = ZfsProvider::new(&cfg, zfs, block, pvesh, Arc::new(MockFs));
 
         let result = provider.accept_ds("tank/vm-123", None);
         assert!(result.is_ok());
@@ -382,13 +859,18 @@ mod tests {
         let volumes = vec![ZfsVolume {
             name: "tank/vm-123.raw".to_string(),
             origin: None,
+            size: None,
         }];
 
         let cfg = test_config();
-        let zfs = Arc::new(MockZfs { volumes, guid_map });
+        let zfs = Arc::new(MockZfs {
+            volumes,
+            guid_map,
+            ..MockZfs::default()
+        });
         let block = Arc::new(MockBlock);
         let pvesh = Arc::new(MockPveSh);
-        let provider = ZfsProvider::new(&cfg, zfs, block, pvesh);
+        let provider = ZfsProvider::new(&cfg, zfs, block, pvesh, Arc::new(MockFs));
 
         let result = provider.discover().unwrap();
         assert_eq!(result.len(), 1);
@@ -397,11 +879,423 @@ mod tests {
         assert_eq!(result[0].archive, "zfs_vm-123_raw_abcd1234.img");
     }
 
+    #[test]
+    fn nested_source_maps_to_the_storage_of_its_ancestor() {
+        let name = "tank/k8s/pvs/vm-123.raw";
+        let mut cfg = test_config();
+        cfg.backup.sources.zfs.as_mut().unwrap().pools = vec!["tank/k8s/pvs".to_string()];
+        let zfs = Arc::new(MockZfs {
+            volumes: vec![ZfsVolume {
+                name: name.to_string(),
+                origin: None,
+                size: None,
+            }],
+            guid_map: HashMap::from([(name.to_string(), "abcd1234".to_string())]),
+            ..MockZfs::default()
+        });
+        let provider = ZfsProvider::new(
+            &cfg,
+            zfs,
+            Arc::new(MockBlock),
+            Arc::new(MockPveSh),
+            Arc::new(MockFs),
+        );
+
+        let result = provider.discover().unwrap();
+        assert_eq!(result[0].storage, "local-zfs");
+        assert_eq!(result[0].disk, "vm-123.raw");
+        assert_eq!(
+            find_storage(
+                &[
+                    Storage::ZfsPool {
+                        id: "local-zfs".to_string(),
+                        pool: "tank".to_string(),
+                        content: Vec::new(),
+                    },
+                    Storage::ZfsPool {
+                        id: "k8s".to_string(),
+                        pool: "tank/k8s".to_string(),
+                        content: Vec::new(),
+                    },
+                ],
+                "tank/k8s/pvs"
+            )
+            .unwrap(),
+            "k8s"
+        );
+        assert!(find_storage(&[], "tank/k8s").is_err());
+    }
+
+    #[test]
+    fn send_pools_stage_streams_including_filesystems() {
+        let mut cfg = test_config();
+        let z = cfg.backup.sources.zfs.as_mut().unwrap();
+        z.send_pools = vec!["tank".to_string()];
+        cfg.backup.pv_prefixes.push("subvol-".to_string());
+        let ds = |name: &str| ZfsVolume {
+            name: name.to_string(),
+            origin: None,
+            size: None,
+        };
+        let zfs = Arc::new(MockZfs {
+            volumes: vec![ds("tank/vm-1-disk-0")],
+            filesystems: vec![ds("tank/subvol-100-disk-0")],
+            guid_map: HashMap::from([
+                ("tank/vm-1-disk-0".to_string(), "aaaa1111".to_string()),
+                ("tank/subvol-100-disk-0".to_string(), "bbbb2222".to_string()),
+            ]),
+            ..MockZfs::default()
+        });
+        let mut provider = ZfsProvider::new(
+            &cfg,
+            zfs.clone(),
+            Arc::new(MockBlock),
+            Arc::new(MockPveSh),
+            Arc::new(MockFs),
+        );
+
+        let vols = provider.discover().unwrap();
+        assert_eq!(
+            vols.iter().map(|v| v.archive.as_str()).collect::<Vec<_>>(),
+            [
+                "zfs_vm-1-disk-0_zsend_aaaa1111.img",
+                "zfs_subvol-100-disk-0_zsend_bbbb2222.img"
+            ]
+        );
+        let ts = provider.run_ts;
+        assert_eq!(
+            vols[1].device,
+            PathBuf::from(format!(
+                "/var/tmp/zfs_subvol-100-disk-0_zsend_bbbb2222.img.{ts}"
+            ))
+        );
+
+        assert!(provider.prepare(&vols).unwrap().is_empty());
+        let sent = zfs.sent.lock().unwrap();
+        assert_eq!(sent[0].0, format!("tank/vm-1-disk-0@pvtools-{ts}"));
+        assert_eq!(sent[1].1, vols[1].device);
+        assert_eq!(provider.cleanup.files.len(), 2);
+    }
+
+    #[test]
+    fn send_streams_are_skipped_when_staging_dir_is_too_small() {
+        let mut cfg = test_config();
+        cfg.backup.sources.zfs.as_mut().unwrap().send_pools = vec!["tank".to_string()];
+        let zfs = Arc::new(MockZfs {
+            volumes: vec![ZfsVolume {
+                name: "tank/vm-1-disk-0".to_string(),
+                origin: None,
+                size: None,
+            }],
+            guid_map: HashMap::from([("tank/vm-1-disk-0".to_string(), "aaaa1111".to_string())]),
+            stream_size: 2 << 30,
+            ..MockZfs::default()
+        });
+        let mut provider = ZfsProvider::new(
+            &cfg,
+            zfs.clone(),
+            Arc::new(MockBlock),
+            Arc::new(MockPveSh),
+            Arc::new(MockFs),
+        );
+
+        let vols = provider.discover().unwrap();
+        let skipped = provider.prepare(&vols).unwrap();
+        assert_eq!(skipped.len(), 1);
+        assert!(skipped[0].reason.contains("staging_dir /var/tmp"));
+        assert!(zfs.sent.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn candidates_reject_filesystems_outside_send_pools() {
+        let cfg = test_config();
+        let ds = |name: &str| ZfsVolume {
+            name: name.to_string(),
+            origin: None,
+            size: None,
+        };
+        let provider = ZfsProvider::new(
+            &cfg,
+            Arc::new(MockZfs {
+                volumes: vec![ds("tank/vm-1-disk-0")],
+                filesystems: vec![ds("tank/pvc-0a1b")],
+                ..MockZfs::default()
+            }),
+            Arc::new(MockBlock),
+            Arc::new(MockPveSh),
+            Arc::new(MockFs),
+        );
+        let cands = provider.candidates().unwrap();
+        assert_eq!(cands.len(), 2);
+        assert_eq!(cands[1].name, "tank/pvc-0a1b");
+        assert_eq!(
+            cands[1].rejected.as_deref(),
+            Some("Filesystem: pool is not in send_pools")
+        );
+    }
+
+    #[test]
+    fn changed_only_skips_unwritten_volumes_and_keeps_baselines() {
+        let cfg = test_config();
+        let ds = |name: &str| ZfsVolume {
+            name: name.to_string(),
+            origin: None,
+            size: None,
+        };
+        let zfs = Arc::new(MockZfs {
+            volumes: vec![ds("tank/vm-1"), ds("tank/vm-2"), ds("tank/vm-3")],
+            guid_map: HashMap::from([
+                ("tank/vm-1".to_string(), "aaaa1111".to_string()),
+                ("tank/vm-2".to_string(), "bbbb2222".to_string()),
+                ("tank/vm-3".to_string(), "cccc3333".to_string()),
+            ]),
+            written: HashMap::from([
+                ("tank/vm-1".to_string(), 0),
+                ("tank/vm-2".to_string(), 8192),
+            ]),
+            ..MockZfs::default()
+        });
+        let mut provider = ZfsProvider::new(
+            &cfg,
+            zfs.clone(),
+            Arc::new(MockBlock),
+            Arc::new(MockPveSh),
+            Arc::new(MockFs),
+        );
+
+        let mut vols = provider.discover().unwrap();
+        let unchanged = provider.unchanged(&vols).unwrap();
+        assert_eq!(unchanged.len(), 1);
+        assert_eq!(unchanged[0].archive, "zfs_vm-1_noext_aaaa1111.img");
+
+        vols.remove(0);
+        provider.prepare(&vols).unwrap();
+        provider.keep_baseline(&vols).unwrap();
+        let ts = provider.run_ts;
+        assert_eq!(
+            *zfs.zfs_calls.lock().unwrap(),
+            [
+                "destroy -r tank/vm-2@pvtools-base".to_string(),
+                format!("rename tank/vm-2@pvtools-{ts} tank/vm-2@pvtools-base"),
+                format!("rename tank/vm-3@pvtools-{ts} tank/vm-3@pvtools-base"),
+            ]
+        );
+        assert_eq!(
+            provider.cleanup.tasks,
+            [
+                format!("tank/vm-2-pvtools-{ts}"),
+                format!("tank/vm-3-pvtools-{ts}")
+            ]
+        );
+    }
+
+    #[test]
+    fn snapshot_barrier_snapshots_everything_before_cloning() {
+        let cfg = test_config();
+        let ds = |name: &str| ZfsVolume {
+            name: name.to_string(),
+            origin: None,
+            size: None,
+        };
+        let zfs = Arc::new(MockZfs {
+            volumes: vec![ds("tank/vm-1"), ds("tank/vm-2")],
+            guid_map: HashMap::from([
+                ("tank/vm-1".to_string(), "aaaa1111".to_string()),
+                ("tank/vm-2".to_string(), "bbbb2222".to_string()),
+            ]),
+            ..MockZfs::default()
+        });
+        let mut provider = ZfsProvider::new(
+            &cfg,
+            zfs.clone(),
+            Arc::new(MockBlock),
+            Arc::new(MockPveSh),
+            Arc::new(MockFs),
+        );
+        let ts = provider.run_ts;
+
+        let vols = provider.discover().unwrap();
+        assert!(provider.snapshot(&vols).unwrap().is_empty());
+        assert!(provider.prepare(&vols).unwrap().is_empty());
+        assert_eq!(
+            *zfs.made.lock().unwrap(),
+            [
+                format!("tank/vm-1@pvtools-{ts}"),
+                format!("tank/vm-2@pvtools-{ts}"),
+                format!("tank/vm-1-pvtools-{ts}"),
+                format!("tank/vm-2-pvtools-{ts}"),
+            ]
+        );
+
+        drop(provider);
+        assert_eq!(
+            *zfs.zfs_calls.lock().unwrap(),
+            [
+                format!("destroy -r tank/vm-2-pvtools-{ts}"),
+                format!("destroy -r tank/vm-1-pvtools-{ts}"),
+                format!("destroy -r tank/vm-2@pvtools-{ts}"),
+                format!("destroy -r tank/vm-1@pvtools-{ts}"),
+            ]
+        );
+    }
+
+    #[test]
+    fn existing_snapshots_are_cloned_but_never_taken_or_destroyed() {
+        let mut cfg = test_config();
+        cfg.backup
+            .sources
+            .zfs
+            .as_mut()
+            .unwrap()
+            .use_existing_snapshot = Some("autosnap_????-*_hourly".to_string());
+        let ds = |name: &str| ZfsVolume {
+            name: name.to_string(),
+            origin: None,
+            size: None,
+        };
+        let zfs = Arc::new(MockZfs {
+            volumes: vec![ds("tank/vm-1"), ds("tank/vm-2")],
+            guid_map: HashMap::from([
+                ("tank/vm-1".to_string(), "aaaa1111".to_string()),
+                ("tank/vm-2".to_string(), "bbbb2222".to_string()),
+            ]),
+            snapshots: vec![
+                "tank/vm-1@autosnap_2025-01-01_10:00:00_hourly".to_string(),
+                "tank/vm-1@autosnap_2025-01-01_11:00:00_hourly".to_string(),
+                "tank/vm-1@autosnap_2025-01-01_00:00:00_daily".to_string(),
+                "tank/vm-2@autosnap_2025-01-01_00:00:00_daily".to_string(),
+            ],
+            ..MockZfs::default()
+        });
+        let mut provider = ZfsProvider::new(
+            &cfg,
+            zfs.clone(),
+            Arc::new(MockBlock),
+            Arc::new(MockPveSh),
+            Arc::new(MockFs),
+        );
+        let ts = provider.run_ts;
+
+        let vols = provider.discover().unwrap();
+        let skipped = provider.prepare(&vols).unwrap();
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].archive, vols[1].archive);
+        assert_eq!(
+            *zfs.made.lock().unwrap(),
+            [format!("tank/vm-1-pvtools-{ts}")]
+        );
+
+        provider.keep_baseline(&vols[..1]).unwrap();
+        drop(provider);
+        assert_eq!(
+            *zfs.zfs_calls.lock().unwrap(),
+            [format!("destroy -r tank/vm-1-pvtools-{ts}")]
+        );
+    }
+
+    #[test]
+    fn snapshot_devices_skip_the_clone_where_snapdev_is_visible() {
+        let mut cfg = test_config();
+        cfg.backup.sources.zfs.as_mut().unwrap().snapshot_devices = true;
+        let ds = |name: &str| ZfsVolume {
+            name: name.to_string(),
+            origin: None,
+            size: None,
+        };
+        let zfs = Arc::new(MockZfs {
+            volumes: vec![ds("tank/vm-1"), ds("tank/vm-2")],
+            guid_map: HashMap::from([
+                ("tank/vm-1".to_string(), "aaaa1111".to_string()),
+                ("tank/vm-2".to_string(), "bbbb2222".to_string()),
+            ]),
+            snapdev: HashSet::from(["tank/vm-1".to_string()]),
+            ..MockZfs::default()
+        });
+        let mut provider = ZfsProvider::new(
+            &cfg,
+            zfs.clone(),
+            Arc::new(MockBlock),
+            Arc::new(MockPveSh),
+            Arc::new(MockFs),
+        );
+        let ts = provider.run_ts;
+
+        let vols = provider.discover().unwrap();
+        assert_eq!(
+            vols[0].device,
+            PathBuf::from(format!("/dev/zvol/tank/vm-1@pvtools-{ts}"))
+        );
+        assert_eq!(
+            vols[1].device,
+            PathBuf::from(format!("/dev/zvol/tank/vm-2-pvtools-{ts}"))
+        );
+        assert!(provider.prepare(&vols).unwrap().is_empty());
+        assert_eq!(
+            *zfs.made.lock().unwrap(),
+            [
+                format!("tank/vm-1@pvtools-{ts}"),
+                format!("tank/vm-2@pvtools-{ts}"),
+                format!("tank/vm-2-pvtools-{ts}"),
+            ]
+        );
+    }
+
+    #[test]
+    fn prepare_skips_vanished_dataset() {
+        let mut guid_map = HashMap::new();
+        guid_map.insert("tank/vm-123.raw".to_string(), "abcd1234".to_string());
+        guid_map.insert("tank/vm-456.raw".to_string(), "efgh5678".to_string());
+        let volumes = vec![
+            ZfsVolume {
+                name: "tank/vm-123.raw".to_string(),
+                origin: None,
+                size: None,
+            },
+            ZfsVolume {
+                name: "tank/vm-456.raw".to_string(),
+                origin: None,
+                size: None,
+            },
+        ];
+
+        let cfg = test_config();
+        let found = ZfsProvider::new(
+            &cfg,
+            Arc::new(MockZfs {
+                volumes: volumes.clone(),
+                guid_map: guid_map.clone(),
+                ..MockZfs::default()
+            }),
+            Arc::new(MockBlock),
+            Arc::new(MockPveSh),
+            Arc::new(MockFs),
+        )
+        .discover()
+        .unwrap();
+
+        let mut provider = ZfsProvider::new(
+            &cfg,
+            Arc::new(MockZfs {
+                volumes: volumes[..1].to_vec(),
+                guid_map,
+                ..MockZfs::default()
+            }),
+            Arc::new(MockBlock),
+            Arc::new(MockPveSh),
+            Arc::new(MockFs),
+        );
+        let skipped = crate::utils::exec_policy::with_dry_run_enabled(true, || {
+            provider.prepare(&found).unwrap()
+        });
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].archive, "zfs_vm-456_raw_efgh5678.img");
+    }
+
     #[test]
     fn cleanup_adds_tasks() {
         let runner = Arc::new(ProcessRunner::new());
         let zfs = Arc::new(crate::tooling::ZfsCli::new(runner));
-        let mut cleanup = Cleanup::new(zfs);
+        let mut cleanup = Cleanup::new(zfs, Arc::new(MockFs));
 
         cleanup.add_many(vec!["snap1".to_string(), "snap2".to_string()]);
         assert_eq!(cleanup.tasks.len(), 2);
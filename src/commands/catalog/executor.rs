@@ -0,0 +1,233 @@
+use std::{collections::HashMap, fs, io::Write, path::PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use super::{CatalogFormat, ExportArgs};
+use crate::{
+    AppCtx,
+    commands::restore::fetch_manifest,
+    manifest::{BackupManifest, MANIFEST_ARCHIVE},
+    tooling::pbs::{FileClass, PbsSnapshot},
+    utils::{
+        naming::{parse_archive_name, send_stream_leaf},
+        time::fmt_utc,
+    },
+};
+
+pub struct ExportOpts {
+    pub source: Option<String>,
+    pub backup_id: Option<String>,
+    pub all_groups: bool,
+    pub format: CatalogFormat,
+    pub output: Option<PathBuf>,
+}
+
+impl From<&ExportArgs> for ExportOpts {
+    fn from(value: &ExportArgs) -> Self {
+        Self {
+            source: value.source.clone(),
+            backup_id: value.backup_id.as_ref().map(|id| id.trim().to_string()),
+            all_groups: value.all_groups,
+            format: value.format,
+            output: value.output.clone(),
+        }
+    }
+}
+
+/// One archive of one snapshot.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CatalogEntry {
+    pub backup_id: String,
+    pub backup_time: u64,
+    /// `backup_time` as RFC3339, UTC.
+    pub snapshot: String,
+    pub archive: String,
+    pub provider: String,
+    /// Disk name, or dataset name of a zfs send stream.
+    pub leaf: String,
+    pub size: u64,
+    /// `namespace/name` of the Kubernetes claim recorded in the manifest.
+    pub claim: Option<String>,
+    pub pv: Option<String>,
+}
+
+const CSV_HEADER: &str = "backup_id,backup_time,snapshot,archive,provider,leaf,size,claim,pv";
+
+/// Writes every archive of the selected backup group(s) with its snapshot, disk and size, and
+/// the Kubernetes claim where the snapshot's manifest records one.
+pub fn export(ctx: &AppCtx, opts: ExportOpts) -> Result<()> {
+    let repo = ctx.cfg.resolve_backup_repo(opts.source.as_deref())?;
+    let ns_opt = repo.ns.as_deref();
+    let group =
+        (!opts.all_groups).then(|| opts.backup_id.as_deref().unwrap_or(&ctx.cfg.pbs.backup_id));
+    let mut snaps: Vec<PbsSnapshot> = ctx
+        .tools
+        .pbs()
+        .snapshots(repo, ns_opt)?
+        .into_iter()
+        .filter(|s| group.is_none_or(|g| s.backup_id == g))
+        .collect();
+    snaps.sort_by(|a, b| (&a.backup_id, a.backup_time).cmp(&(&b.backup_id, b.backup_time)));
+
+    let manifest_blob = format!("{MANIFEST_ARCHIVE}.blob");
+    let mut entries = Vec::new();
+    for snap in &snaps {
+        let manifest = if snap.files.iter().any(|f| f.filename == manifest_blob) {
+            fetch_manifest(ctx, repo, ns_opt, snap)
+                .inspect_err(|e| {
+                    tracing::warn!(
+                        "{}/{}: no claims listed, manifest not read: {e:#}",
+                        snap.backup_id,
+                        snap.backup_time
+                    )
+                })
+                .ok()
+        } else {
+            None
+        };
+        entries.extend(catalog_entries(snap, manifest.as_ref())?);
+    }
+
+    let body = match opts.format {
+        CatalogFormat::Csv => to_csv(&entries),
+        CatalogFormat::Json => serde_json::to_string_pretty(&entries)? + "\n",
+    };
+    match &opts.output {
+        Some(path) => {
+            fs::write(path, body).with_context(|| format!("write {}", path.display()))?;
+            tracing::info!(
+                "wrote {} archive(s) of {} snapshot(s) to {}",
+                entries.len(),
+                snaps.len(),
+                path.display()
+            );
+        }
+        None => std::io::stdout()
+            .write_all(body.as_bytes())
+            .context("write catalog to stdout")?,
+    }
+    Ok(())
+}
+
+fn catalog_entries(
+    snap: &PbsSnapshot,
+    manifest: Option<&BackupManifest>,
+) -> Result<Vec<CatalogEntry>> {
+    let claims: HashMap<&str, _> = manifest
+        .map(|m| m.claims.iter().map(|c| (c.archive.as_str(), c)).collect())
+        .unwrap_or_default();
+    let snapshot = fmt_utc(snap.backup_time)?;
+    snap.files
+        .iter()
+        .filter(|f| f.class() == FileClass::Archive)
+        .map(|f| {
+            let (provider, leaf, _) = parse_archive_name(&f.filename)?;
+            let leaf = send_stream_leaf(&leaf).map_or(leaf.clone(), str::to_string);
+            // PBS lists the archives the backup recorded with a `.fidx` suffix.
+            let base = f.filename.strip_suffix(".fidx").unwrap_or(&f.filename);
+            let claim = claims.get(base);
+            Ok(CatalogEntry {
+                backup_id: snap.backup_id.clone(),
+                backup_time: snap.backup_time,
+                snapshot: snapshot.clone(),
+                archive: f.filename.clone(),
+                provider,
+                leaf,
+                size: f.size,
+                claim: claim.map(|c| format!("{}/{}", c.namespace, c.name)),
+                pv: claim.and_then(|c| c.pv.as_ref()).map(|pv| pv.name.clone()),
+            })
+        })
+        .collect()
+}
+
+fn to_csv(entries: &[CatalogEntry]) -> String {
+    let mut out = String::from(CSV_HEADER);
+    out.push('\n');
+    for e in entries {
+        let fields = [
+            e.backup_id.clone(),
+            e.backup_time.to_string(),
+            e.snapshot.clone(),
+            e.archive.clone(),
+            e.provider.clone(),
+            e.leaf.clone(),
+            e.size.to_string(),
+            e.claim.clone().unwrap_or_default(),
+            e.pv.clone().unwrap_or_default(),
+        ];
+        let row: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+        out.push_str(&row.join(","));
+        out.push('\n');
+    }
+    out
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        manifest::{ClaimRecord, PvDetails, PvRecord},
+        tooling::pbs::PbsFile,
+    };
+
+    #[test]
+    fn lists_archives_with_their_claims() {
+        let file = |name: &str, size| PbsFile {
+            filename: name.to_string(),
+            size,
+            crypt_mode: None,
+        };
+        let snap = PbsSnapshot {
+            backup_id: "pve1-backup".to_string(),
+            backup_time: 1_700_000_000,
+            files: vec![
+                file("zfs_vm-1-disk-0_raw_11111111.img.fidx", 10),
+                file("zfs_subvol-2-disk-0_zsend_22222222.img.fidx", 20),
+                file("index.json.blob", 1),
+            ],
+        };
+        let manifest = BackupManifest {
+            version: 1,
+            created: 1_700_000_000,
+            backup_id: "pve1-backup".to_string(),
+            storage: Vec::new(),
+            claims: vec![ClaimRecord {
+                archive: "zfs_vm-1-disk-0_raw_11111111.img".to_string(),
+                namespace: "db".to_string(),
+                name: "data, pg-0".to_string(),
+                pv: Some(PvRecord {
+                    name: "pvc-1".to_string(),
+                    volume_handle: None,
+                    details: PvDetails::default(),
+                }),
+            }],
+            carried: Vec::new(),
+        };
+
+        let entries = catalog_entries(&snap, Some(&manifest)).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].leaf, "vm-1-disk-0.raw");
+        assert_eq!(entries[0].claim.as_deref(), Some("db/data, pg-0"));
+        assert_eq!(entries[1].leaf, "subvol-2-disk-0");
+        assert_eq!(entries[1].claim, None);
+
+        let csv = to_csv(&entries);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], CSV_HEADER);
+        assert_eq!(
+            lines[1],
+            "pve1-backup,1700000000,2023-11-14T22:13:20Z,zfs_vm-1-disk-0_raw_11111111.img.fidx,\
+             zfs,vm-1-disk-0.raw,10,\"db/data, pg-0\",pvc-1"
+        );
+    }
+}
@@ -0,0 +1,54 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::{Args, Subcommand, ValueEnum};
+
+use crate::AppCtx;
+
+mod executor;
+
+pub use executor::{CatalogEntry, ExportOpts, export};
+
+#[derive(Debug, Args)]
+pub struct CatalogArgs {
+    #[command(subcommand)]
+    pub cmd: CatalogCmd,
+}
+
+impl CatalogArgs {
+    pub fn run(&self, ctx: &AppCtx) -> Result<()> {
+        match &self.cmd {
+            CatalogCmd::Export(args) => executor::export(ctx, ExportOpts::from(args)),
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CatalogCmd {
+    /// Write every archive of a backup group, across all its snapshots, as CSV or JSON
+    Export(ExportArgs),
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum CatalogFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ExportArgs {
+    /// Repo alias (defaults to [backup.target].repo)
+    #[arg(long)]
+    pub source: Option<String>,
+    /// Export this backup group instead of `[pbs].backup_id`
+    #[arg(long, conflicts_with = "all_groups")]
+    pub backup_id: Option<String>,
+    /// Export every backup group in the repo
+    #[arg(long)]
+    pub all_groups: bool,
+    #[arg(long, value_enum, default_value = "csv")]
+    pub format: CatalogFormat,
+    /// File to write the catalog to (default: stdout)
+    #[arg(long, short)]
+    pub output: Option<PathBuf>,
+}
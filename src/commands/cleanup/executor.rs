@@ -0,0 +1,166 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use crate::{
+    AppCtx,
+    commands::backup::{node_ctx, source_resources},
+    ui,
+    utils::{
+        exec_policy::with_dry_run_enabled,
+        failure::Failure,
+        lock::{LockSet, Resource},
+        naming::pvtools_leftover_ts,
+        time::{current_epoch, parse_duration},
+    },
+};
+
+#[derive(Clone)]
+pub struct CleanupOpts {
+    pub older_than: Duration,
+    pub dry_run: bool,
+}
+
+impl TryFrom<&super::CleanupArgs> for CleanupOpts {
+    type Error = anyhow::Error;
+    fn try_from(value: &super::CleanupArgs) -> Result<Self> {
+        Ok(Self {
+            older_than: parse_duration(&value.older_than)?,
+            dry_run: value.dry_run,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LeftoverKind {
+    ZfsClone,
+    ZfsSnapshot,
+    LvmSnapshot,
+}
+
+impl LeftoverKind {
+    pub fn label(&self) -> &'static str {
+        match self {
+            LeftoverKind::ZfsClone => "zfs clone",
+            LeftoverKind::ZfsSnapshot => "zfs snapshot",
+            LeftoverKind::LvmSnapshot => "lvm snapshot",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Leftover {
+    pub kind: LeftoverKind,
+    pub name: String,
+    pub created: u64,
+}
+
+pub fn cleanup(ctx: &AppCtx, opts: CleanupOpts) -> Result<()> {
+    for (name, node) in &ctx.cfg.nodes {
+        tracing::info!("node {name}:");
+        let _lock = LockSet::try_acquire([Resource::Node(name.clone())])?;
+        cleanup(&node_ctx(ctx, node)?, opts.clone()).with_context(|| format!("node {name}"))?;
+    }
+    if !ctx.cfg.nodes.is_empty() {
+        return Ok(());
+    }
+
+    // Same pool/VG locks as backup, so a running backup's snapshots are never touched.
+    let _lock = LockSet::try_acquire(source_resources(&ctx.cfg))?;
+    let cutoff = current_epoch().saturating_sub(opts.older_than.as_secs());
+
+    let leftovers = find_leftovers(ctx, cutoff)?;
+    ui::log_leftovers(&leftovers);
+    if leftovers.is_empty() {
+        return Ok(());
+    }
+
+    with_dry_run_enabled(opts.dry_run, || -> Result<()> {
+        let mut failed = 0usize;
+        for l in &leftovers {
+            if let Err(e) = remove(ctx, l) {
+                tracing::warn!("remove {} failed: {e:#}", l.name);
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            return Err(Failure::Partial.with(format!(
+                "{failed} of {} leftovers could not be removed",
+                leftovers.len()
+            )));
+        }
+        Ok(())
+    })
+}
+
+fn find_leftovers(ctx: &AppCtx, cutoff: u64) -> Result<Vec<Leftover>> {
+    let mut out = Vec::new();
+    let old_enough = |name: &str| pvtools_leftover_ts(name).filter(|ts| *ts <= cutoff);
+
+    if let (Some(z), Some(zfs)) = (&ctx.cfg.backup.sources.zfs, ctx.tools.zfs()) {
+        let mut snaps = Vec::new();
+        for pool in &z.pools {
+            for v in zfs.list_volumes(pool)? {
+                if v.origin.is_some()
+                    && let Some(ts) = old_enough(&v.name)
+                {
+                    out.push(Leftover {
+                        kind: LeftoverKind::ZfsClone,
+                        name: v.name,
+                        created: ts,
+                    });
+                }
+            }
+            for s in zfs.list_snapshots(pool)? {
+                if let Some(ts) = old_enough(&s) {
+                    snaps.push(Leftover {
+                        kind: LeftoverKind::ZfsSnapshot,
+                        name: s,
+                        created: ts,
+                    });
+                }
+            }
+        }
+        // Clones depend on their origin snapshot, so they have to go first.
+        out.extend(snaps);
+    }
+
+    let sources = &ctx.cfg.backup.sources;
+    let vgs: Vec<&String> = sources
+        .lvmthin
+        .iter()
+        .flat_map(|l| &l.vgs)
+        .chain(sources.lvm.iter().flat_map(|l| &l.vgs))
+        .collect();
+    if !vgs.is_empty()
+        && let Some(lvm) = ctx.tools.lvm()
+    {
+        for lv in lvm.list_lvs()? {
+            if !vgs.contains(&&lv.vg_name) {
+                continue;
+            }
+            if let Some(ts) = old_enough(&lv.lv_name) {
+                out.push(Leftover {
+                    kind: LeftoverKind::LvmSnapshot,
+                    name: format!("{}/{}", lv.vg_name, lv.lv_name),
+                    created: ts,
+                });
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+fn remove(ctx: &AppCtx, l: &Leftover) -> Result<()> {
+    match l.kind {
+        LeftoverKind::ZfsClone | LeftoverKind::ZfsSnapshot => {
+            let zfs = ctx.tools.zfs().expect("zfs enabled");
+            zfs.destroy_recursive(&l.name)
+        }
+        LeftoverKind::LvmSnapshot => {
+            let lvm = ctx.tools.lvm().expect("lvm enabled");
+            lvm.lvremove_force(&l.name)
+        }
+    }
+}
@@ -0,0 +1,24 @@
+use anyhow::Result;
+use clap::Args;
+
+use crate::AppCtx;
+
+mod executor;
+
+pub use executor::{CleanupOpts, Leftover, cleanup};
+
+#[derive(Debug, Args)]
+pub struct CleanupArgs {
+    #[arg(long, default_value = "1h")]
+    pub older_than: String,
+
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+impl CleanupArgs {
+    pub fn run(&self, ctx: &AppCtx) -> Result<()> {
+        let opts = executor::CleanupOpts::try_from(self)?;
+        executor::cleanup(ctx, opts)
+    }
+}
@@ -0,0 +1,40 @@
+use std::{io, path::PathBuf};
+
+use anyhow::{Context, Result};
+use clap::{Args, Command};
+use clap_complete::Shell;
+use clap_mangen::Man;
+
+#[derive(Debug, Args)]
+pub struct CompletionsArgs {
+    /// Shell to generate the completion script for
+    pub shell: Shell,
+}
+
+impl CompletionsArgs {
+    pub fn run(&self, mut cmd: Command) -> Result<()> {
+        let name = cmd.get_name().to_string();
+        clap_complete::generate(self.shell, &mut cmd, name, &mut io::stdout());
+        Ok(())
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ManpageArgs {
+    /// Write pvtools.1 and a page per subcommand (pvtools-backup-run.1, ...) into this directory
+    /// instead of printing pvtools.1
+    #[arg(long)]
+    pub dir: Option<PathBuf>,
+}
+
+impl ManpageArgs {
+    pub fn run(&self, cmd: Command) -> Result<()> {
+        match &self.dir {
+            Some(dir) => clap_mangen::generate_to(cmd, dir)
+                .with_context(|| format!("write man pages to {}", dir.display())),
+            None => Man::new(cmd)
+                .render(&mut io::stdout())
+                .context("render man page"),
+        }
+    }
+}
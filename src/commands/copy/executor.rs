@@ -0,0 +1,242 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::{Context, Result, bail};
+use regex::Regex;
+
+use crate::{
+    AppCtx,
+    commands::restore::{
+        RestorePoint, parse_excludes, parse_point, pick_snapshot, select_archives_exact_from,
+    },
+    config::Repo,
+    events::Event,
+    manifest::MANIFEST_ARCHIVE,
+    tooling::{
+        pbs::{BackupItem, BackupOpts, FileClass, PbsFile, PbsSnapshot, snapshot_path},
+        writer::WriteOpts,
+    },
+    ui,
+    utils::{
+        exec_policy::{is_dry_run, with_dry_run_enabled},
+        lock::{LockSet, Resource},
+        signal,
+    },
+};
+
+pub struct CopyOpts {
+    pub from: Option<String>,
+    pub to: String,
+    pub to_ns: Option<String>,
+    pub snapshot: RestorePoint,
+    pub archives: Vec<String>,
+    pub exclude: Vec<Regex>,
+    pub staging_dir: PathBuf,
+    pub dry_run: bool,
+}
+
+impl TryFrom<&super::CopyArgs> for CopyOpts {
+    type Error = anyhow::Error;
+    fn try_from(value: &super::CopyArgs) -> Result<Self> {
+        Ok(Self {
+            from: value.from.clone(),
+            to: value.to.clone(),
+            to_ns: value.to_ns.clone(),
+            snapshot: parse_point(&value.snapshot)?,
+            archives: value.archives.clone(),
+            exclude: parse_excludes(&value.exclude)?,
+            staging_dir: value.staging_dir.clone(),
+            dry_run: value.dry_run,
+        })
+    }
+}
+
+pub fn copy(ctx: &AppCtx, opts: CopyOpts) -> Result<()> {
+    let from = ctx.cfg.resolve_backup_repo(opts.from.as_deref())?;
+    let to = ctx.cfg.pbs.repo_by_alias(&opts.to)?;
+    let _lock = LockSet::try_acquire([
+        Resource::Repo(from.url.clone()),
+        Resource::Repo(to.url.clone()),
+    ])?;
+
+    with_dry_run_enabled(opts.dry_run, || -> Result<()> {
+        ctx.events.emit(Event::RunStarted {
+            command: "copy",
+            backup_id: &ctx.cfg.pbs.backup_id,
+            repo: &from.url,
+            dry_run: opts.dry_run,
+        });
+
+        let res = run_copy(ctx, &opts, from, to);
+        ctx.events.emit(Event::RunFinished {
+            command: "copy",
+            ok: res.is_ok(),
+            error: res.as_ref().err().map(|e| format!("{e:#}")),
+        });
+        res
+    })
+}
+
+fn run_copy(ctx: &AppCtx, opts: &CopyOpts, from: &Repo, to: &Repo) -> Result<()> {
+    let pbs = ctx.tools.pbs();
+    let from_ns = from.ns.as_deref();
+    let to_ns = opts.to_ns.as_deref().or(to.ns.as_deref());
+    if from.url == to.url && from_ns == to_ns {
+        bail!("source and destination are the same repo and namespace");
+    }
+
+    let snaps = pbs.snapshots(from, from_ns)?;
+    let snap = pick_snapshot(&snaps, &ctx.cfg.pbs.backup_id, opts.snapshot.clone())?;
+    let available = copyable_archives(snap);
+    let selected = select_archives_exact_from(
+        &available,
+        &opts.archives,
+        opts.archives.is_empty(),
+        &opts.exclude,
+    )?;
+    if selected.is_empty() {
+        bail!("nothing to copy");
+    }
+
+    let src_path = snapshot_path(&snap.backup_id, snap.backup_time)?;
+    ui::log_pbs_info(&from.url, from_ns, &snap.backup_id, Some(snap.backup_time));
+    tracing::info!(
+        "copying {} archive(s) to {to} (namespace {})",
+        selected.len(),
+        to_ns.unwrap_or("<root>")
+    );
+
+    // An .img archive needs a sized file on upload, so images are staged rather than piped.
+    let need = staged_bytes(snap, &selected);
+    let free = ctx.tools.fs().free_bytes(&opts.staging_dir)?;
+    if need > free {
+        bail!(
+            "staging dir {} has {free} bytes free, the {} archive(s) need {need}",
+            opts.staging_dir.display(),
+            selected.len()
+        );
+    }
+    let staging = tempfile::Builder::new()
+        .prefix("pvtools-copy-")
+        .tempdir_in(&opts.staging_dir)
+        .with_context(|| format!("create staging dir in {}", opts.staging_dir.display()))?;
+    let write_opts = WriteOpts {
+        sparse: true,
+        direct: false,
+        fsync: false,
+        ..WriteOpts::default()
+    };
+
+    let mut staged: Vec<(String, PathBuf)> = Vec::with_capacity(selected.len() + 1);
+    for a in &selected {
+        signal::check()?;
+        let path = staging.path().join(a);
+        ctx.tools
+            .fs()
+            .create_sparse_file(&path, archive_size(snap, a))?;
+
+        let src = pbs.restore_cmd(from, from_ns, &src_path, a);
+        ctx.tools
+            .writer()
+            .write(src, &path, &write_opts)
+            .with_context(|| format!("stage {a} from {from}"))?;
+        staged.push((a.clone(), path));
+    }
+
+    let blob = format!("{MANIFEST_ARCHIVE}.blob");
+    if snap.files.iter().any(|f| f.filename == blob) {
+        let raw = pbs.fetch_blob(from, from_ns, &src_path, MANIFEST_ARCHIVE)?;
+        let path = staging.path().join(MANIFEST_ARCHIVE);
+        if !is_dry_run() {
+            fs::write(&path, raw).with_context(|| format!("write {}", path.display()))?;
+        }
+        staged.push((MANIFEST_ARCHIVE.to_string(), path));
+    }
+
+    if let Some(ns) = to_ns {
+        pbs.ns_ensure(to, ns)?;
+    }
+    let items: Vec<BackupItem> = staged
+        .iter()
+        .map(|(archive, path)| BackupItem {
+            archive,
+            device: path,
+        })
+        .collect();
+    pbs.backup(
+        to,
+        to_ns,
+        &snap.backup_id,
+        &items,
+        BackupOpts {
+            backup_time: Some(snap.backup_time),
+            ..BackupOpts::default()
+        },
+    )
+    .with_context(|| format!("upload to {to}"))?;
+
+    tracing::info!("done");
+    Ok(())
+}
+
+fn archive_size(snap: &PbsSnapshot, archive: &str) -> u64 {
+    snap.files
+        .iter()
+        .find(|f| archive_name(f) == Some(archive))
+        .map(|f| f.size)
+        .unwrap_or(0)
+}
+
+/// Room the staged images of `archives` take at most.
+fn staged_bytes(snap: &PbsSnapshot, archives: &[String]) -> u64 {
+    archives.iter().map(|a| archive_size(snap, a)).sum()
+}
+
+fn archive_name(f: &PbsFile) -> Option<&str> {
+    (f.class() == FileClass::Archive)
+        .then(|| f.filename.strip_suffix(".fidx").unwrap_or(&f.filename))
+}
+
+fn copyable_archives(snap: &PbsSnapshot) -> Vec<String> {
+    snap.files
+        .iter()
+        .filter_map(archive_name)
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str) -> PbsFile {
+        PbsFile {
+            filename: name.to_string(),
+            size: 1024,
+            crypt_mode: None,
+        }
+    }
+
+    #[test]
+    fn copyable_archives_keeps_only_pvtools_images() {
+        let snap = PbsSnapshot {
+            backup_id: "host-backup".to_string(),
+            backup_time: 1,
+            files: vec![
+                file("zfs_vm-1-disk-0_raw_abcd1234.img.fidx"),
+                file("lvmthin_vm-2-disk-0_raw_ef567890.img"),
+                file("pvtools-manifest.conf.blob"),
+                file("client.log.blob"),
+                file("index.json.blob"),
+            ],
+        };
+
+        assert_eq!(
+            copyable_archives(&snap),
+            vec![
+                "zfs_vm-1-disk-0_raw_abcd1234.img".to_string(),
+                "lvmthin_vm-2-disk-0_raw_ef567890.img".to_string(),
+            ]
+        );
+        assert_eq!(staged_bytes(&snap, &copyable_archives(&snap)), 2048);
+    }
+}
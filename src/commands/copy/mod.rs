@@ -0,0 +1,48 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::Args;
+
+use crate::AppCtx;
+
+mod executor;
+
+pub use executor::{CopyOpts, copy};
+
+#[derive(Debug, Args)]
+pub struct CopyArgs {
+    /// Source repo alias (defaults to [backup.target].repo)
+    #[arg(long)]
+    pub from: Option<String>,
+
+    /// Destination repo alias
+    #[arg(long)]
+    pub to: String,
+
+    /// Destination namespace (defaults to the destination repo's ns)
+    #[arg(long)]
+    pub to_ns: Option<String>,
+
+    #[arg(long, default_value = "latest")]
+    pub snapshot: String,
+
+    #[arg(long = "archive")]
+    pub archives: Vec<String>,
+
+    #[arg(long)]
+    pub exclude: Vec<String>,
+
+    /// Directory for the staged images; needs room for the selected archives
+    #[arg(long, default_value = "/var/tmp")]
+    pub staging_dir: PathBuf,
+
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+impl CopyArgs {
+    pub fn run(&self, ctx: &AppCtx) -> Result<()> {
+        let opts = executor::CopyOpts::try_from(self)?;
+        executor::copy(ctx, opts)
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{Result, bail};
+use serde::Serialize;
+
+use crate::{
+    AppCtx,
+    commands::restore::{RestorePoint, parse_point, pick_snapshot},
+    tooling::pbs::{FileClass, PBS_INDEX, PbsSnapshot, parse_index_checksums, snapshot_path},
+    ui,
+};
+
+pub struct DiffOpts {
+    pub source: Option<String>,
+    pub from: RestorePoint,
+    pub to: RestorePoint,
+    pub json: bool,
+}
+
+impl TryFrom<&super::DiffArgs> for DiffOpts {
+    type Error = anyhow::Error;
+    fn try_from(value: &super::DiffArgs) -> Result<Self> {
+        let (from, to) = match value.snapshots.as_slice() {
+            [] => (RestorePoint::BeforeLatest(1), RestorePoint::Latest),
+            [from, to] => (parse_point(from)?, parse_point(to)?),
+            _ => bail!("pass --snapshot twice (older, then newer) or not at all"),
+        };
+        Ok(Self {
+            source: value.source.clone(),
+            from,
+            to,
+            json: value.json,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    Added,
+    Removed,
+    Changed,
+    Unchanged,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ArchiveDiff {
+    pub archive: String,
+    pub change: Change,
+    pub old_size: Option<u64>,
+    pub new_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_csum: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_csum: Option<String>,
+}
+
+pub fn diff(ctx: &AppCtx, opts: DiffOpts) -> Result<()> {
+    let repo = ctx.cfg.resolve_backup_repo(opts.source.as_deref())?;
+    let ns_opt = repo.ns.as_deref();
+    let snaps = ctx.tools.pbs().snapshots(repo, ns_opt)?;
+    let backup_id = &ctx.cfg.pbs.backup_id;
+    let old = pick_snapshot(&snaps, backup_id, opts.from)?;
+    let new = pick_snapshot(&snaps, backup_id, opts.to)?;
+    if old.backup_time == new.backup_time {
+        bail!("both --snapshot values select the same snapshot");
+    }
+
+    let checksums = |snap: &PbsSnapshot| -> BTreeMap<String, String> {
+        let fetched = snapshot_path(&snap.backup_id, snap.backup_time).and_then(|path| {
+            let raw = ctx.tools.pbs().fetch_blob(repo, ns_opt, &path, PBS_INDEX)?;
+            parse_index_checksums(&raw)
+        });
+        fetched.unwrap_or_else(|e| {
+            tracing::warn!(
+                "no checksums for {}, comparing sizes only: {e:#}",
+                snap.backup_time
+            );
+            BTreeMap::new()
+        })
+    };
+    let diffs = diff_snapshots(old, &checksums(old), new, &checksums(new));
+
+    if opts.json {
+        println!("{}", serde_json::to_string_pretty(&diffs)?);
+        return Ok(());
+    }
+    ui::log_pbs_info(&repo.url, ns_opt, backup_id, Some(old.backup_time));
+    ui::log_pbs_info(&repo.url, ns_opt, backup_id, Some(new.backup_time));
+    ui::log_archive_diff(&diffs);
+    Ok(())
+}
+
+fn diff_snapshots(
+    old: &PbsSnapshot,
+    old_sums: &BTreeMap<String, String>,
+    new: &PbsSnapshot,
+    new_sums: &BTreeMap<String, String>,
+) -> Vec<ArchiveDiff> {
+    let archives = |s: &PbsSnapshot| -> BTreeMap<String, u64> {
+        s.files
+            .iter()
+            .filter(|f| f.class() == FileClass::Archive)
+            .map(|f| (f.filename.clone(), f.size))
+            .collect()
+    };
+    let (old_files, new_files) = (archives(old), archives(new));
+    let names: BTreeSet<&String> = old_files.keys().chain(new_files.keys()).collect();
+
+    names
+        .into_iter()
+        .map(|name| {
+            let (old_size, new_size) = (old_files.get(name).copied(), new_files.get(name).copied());
+            let (old_csum, new_csum) = (old_sums.get(name).cloned(), new_sums.get(name).cloned());
+            let change = match (old_size, new_size) {
+                (None, _) => Change::Added,
+                (_, None) => Change::Removed,
+                _ if old_size != new_size => Change::Changed,
+                _ => match (&old_csum, &new_csum) {
+                    (Some(a), Some(b)) if a != b => Change::Changed,
+                    _ => Change::Unchanged,
+                },
+            };
+            ArchiveDiff {
+                archive: name.clone(),
+                change,
+                old_size,
+                new_size,
+                old_csum,
+                new_csum,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tooling::pbs::PbsFile;
+
+    fn snap(t: u64, files: &[(&str, u64)]) -> PbsSnapshot {
+        PbsSnapshot {
+            backup_id: "id".to_string(),
+            backup_time: t,
+            files: files
+                .iter()
+                .map(|(name, size)| PbsFile {
+                    filename: name.to_string(),
+                    size: *size,
+                    crypt_mode: None,
+                })
+                .collect(),
+        }
+    }
+
+    fn sums(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+        entries
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn reports_added_removed_and_changed_archives() {
+        let a = "zfs_vm-1-disk-0_raw_11111111.img.fidx";
+        let b = "zfs_vm-2-disk-0_raw_22222222.img.fidx";
+        let c = "lvmthin_vm-3-disk-0_raw_33333333.img.fidx";
+        let d = "lvm_vm-4-disk-0_raw_44444444.img.fidx";
+        let old = snap(1, &[(a, 10), (b, 10), (c, 10), ("index.json.blob", 1)]);
+        let new = snap(2, &[(a, 10), (b, 10), (d, 5), ("index.json.blob", 2)]);
+        let old_sums = sums(&[(a, "aa"), (b, "bb")]);
+        let new_sums = sums(&[(a, "aa"), (b, "b2")]);
+
+        let out: Vec<(String, Change)> = diff_snapshots(&old, &old_sums, &new, &new_sums)
+            .into_iter()
+            .map(|d| (d.archive, d.change))
+            .collect();
+        assert_eq!(
+            out,
+            vec![
+                (d.to_string(), Change::Added),
+                (c.to_string(), Change::Removed),
+                (a.to_string(), Change::Unchanged),
+                (b.to_string(), Change::Changed),
+            ]
+        );
+    }
+
+    #[test]
+    fn size_change_counts_without_checksums() {
+        let a = "zfs_vm-1-disk-0_raw_11111111.img.fidx";
+        let diffs = diff_snapshots(
+            &snap(1, &[(a, 10)]),
+            &BTreeMap::new(),
+            &snap(2, &[(a, 20)]),
+            &BTreeMap::new(),
+        );
+        assert_eq!(diffs[0].change, Change::Changed);
+    }
+}
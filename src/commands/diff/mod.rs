@@ -0,0 +1,30 @@
+use anyhow::Result;
+use clap::Args;
+
+use crate::AppCtx;
+
+mod executor;
+
+pub use executor::{ArchiveDiff, Change, DiffOpts, diff};
+
+#[derive(Debug, Args)]
+pub struct DiffArgs {
+    /// Repo alias (defaults to [backup.target].repo)
+    #[arg(long)]
+    pub source: Option<String>,
+
+    /// Older and newer snapshot, in this order (default: latest-1 and latest)
+    #[arg(long = "snapshot")]
+    pub snapshots: Vec<String>,
+
+    /// Print every archive as JSON instead of a table of changes
+    #[arg(long)]
+    pub json: bool,
+}
+
+impl DiffArgs {
+    pub fn run(&self, ctx: &AppCtx) -> Result<()> {
+        let opts = executor::DiffOpts::try_from(self)?;
+        executor::diff(ctx, opts)
+    }
+}
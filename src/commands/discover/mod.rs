@@ -0,0 +1,13 @@
+use anyhow::Result;
+use clap::Args;
+
+use crate::{AppCtx, commands::backup};
+
+#[derive(Debug, Args)]
+pub struct DiscoverArgs {}
+
+impl DiscoverArgs {
+    pub fn run(&self, ctx: &AppCtx) -> Result<()> {
+        backup::discover(ctx)
+    }
+}
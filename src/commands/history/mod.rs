@@ -0,0 +1,28 @@
+use anyhow::Result;
+use clap::Args;
+
+use crate::{config::Config, history::History, ui};
+
+#[derive(Debug, Args)]
+pub struct HistoryArgs {
+    /// Show at most this many of the most recent runs
+    #[arg(long, default_value_t = 20)]
+    pub limit: usize,
+    /// Print the runs as JSON instead of a table
+    #[arg(long)]
+    pub json: bool,
+}
+
+impl HistoryArgs {
+    /// Reads the local history file only, so it needs neither PBS nor the storage tools.
+    pub fn run(&self, cfg: &Config) -> Result<()> {
+        let runs = History::new(&cfg.events.history, cfg.events.history_keep).load()?;
+        let recent = &runs[runs.len().saturating_sub(self.limit)..];
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(recent)?);
+            return Ok(());
+        }
+        ui::log_history(recent);
+        Ok(())
+    }
+}
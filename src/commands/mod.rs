@@ -1,2 +1,26 @@
+use clap::Args;
+
 pub mod backup;
+pub mod catalog;
+pub mod cleanup;
+pub mod completions;
+pub mod copy;
+pub mod diff;
+pub mod discover;
+pub mod history;
 pub mod restore;
+pub mod selftest;
+
+// `--ns` of the subcommands that talk to PBS. No doc comment: clap would take it as their about.
+#[derive(Args, Debug, Clone, Default)]
+pub struct NsArg {
+    /// PBS namespace for this run, over the repo's `ns` and `[pbs].ns`; "" is the root
+    #[arg(long = "ns", value_name = "NS")]
+    pub value: Option<String>,
+}
+
+impl NsArg {
+    pub fn get(&self) -> Option<&str> {
+        self.value.as_deref()
+    }
+}
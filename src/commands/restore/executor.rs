@@ -1,17 +1,42 @@
-use std::collections::{BTreeSet, HashSet};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
 
 use anyhow::{Context, Result, bail};
+use glob::Pattern;
+use regex::Regex;
 use tracing;
 
-use super::providers::ProviderRegistry;
+use super::{
+    k8s,
+    matcher::RestoreMatcher,
+    placement::TargetPlacement,
+    plan::RestorePlan,
+    providers::{Provider, ProviderRegistry, restore_stamp},
+};
 use crate::{
     AppCtx,
-    tooling::{dd::DdOpts, pbs::PbsSnapshot},
+    config::{Config, DEFAULT_ASSUMED_RATE, Repo, RestoreTarget, Ssh, WriteOverride},
+    events::Event,
+    manifest::{BackupManifest, CarriedArchive, ClaimRecord, MANIFEST_ARCHIVE},
+    tooling::{
+        Toolbox,
+        pbs::{FileClass, PbsFile, PbsSnapshot, snapshot_path},
+        writer::{WriteOpts, parse_block_size},
+    },
     ui,
     utils::{
-        exec_policy::with_dry_run_enabled,
-        lock::LockGuard,
-        time::{fmt_utc, parse_rfc3339_to_unix},
+        exec_policy::{self, with_dry_run_enabled},
+        failure::Failure,
+        lock::{LockSet, Resource},
+        naming::{parse_archive_name, prerestore_suffix, pve_vmid},
+        signal,
+        ssh::SshRunner,
+        time::{current_epoch, fmt_utc, parse_duration, parse_rfc3339_to_unix},
     },
     volume::{Volume, VolumeSliceExt},
 };
@@ -19,29 +44,78 @@ use crate::{
 #[derive(Debug, Clone)]
 pub enum RestorePoint {
     Latest,
+    /// `latest-N`: the N-th snapshot before the latest one.
+    BeforeLatest(usize),
     At(u64),
+    /// `host/<backup-id>/<RFC3339>` as PBS shows it: exactly that snapshot, in that group.
+    Path {
+        backup_id: String,
+        time: u64,
+    },
 }
 
 pub struct ListSnapshotsOpts {
     pub source: Option<String>,
+    pub backup_id: Option<String>,
+    pub all_groups: bool,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub limit: Option<usize>,
+    pub json: bool,
 }
 
-impl From<&super::ListSnapshotsArgs> for ListSnapshotsOpts {
-    fn from(value: &super::ListSnapshotsArgs) -> Self {
-        Self {
+impl TryFrom<&super::ListSnapshotsArgs> for ListSnapshotsOpts {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &super::ListSnapshotsArgs) -> Result<Self> {
+        let time = |s: &Option<String>, flag: &str| {
+            s.as_deref()
+                .map(|s| parse_time(s).with_context(|| format!("invalid --{flag}")))
+                .transpose()
+        };
+        Ok(Self {
             source: value.source.clone(),
-        }
+            backup_id: value.backup_id.as_ref().map(|id| id.trim().to_string()),
+            all_groups: value.all_groups,
+            since: time(&value.since, "since")?,
+            until: time(&value.until, "until")?,
+            limit: value.limit,
+            json: value.json,
+        })
     }
 }
 
 pub struct ListArchivesOpts {
     pub source: Option<String>,
     pub snapshot: RestorePoint,
+    pub backup_id: Option<String>,
+    pub exclude: Vec<Regex>,
+    pub show_foreign: bool,
 }
 
 impl TryFrom<&super::ListArchivesArgs> for ListArchivesOpts {
     type Error = anyhow::Error;
     fn try_from(value: &super::ListArchivesArgs) -> Result<Self> {
+        let snapshot = parse_point(&value.snapshot)?;
+        let exclude = parse_excludes(&value.exclude)?;
+        Ok(Self {
+            source: value.source.clone(),
+            snapshot,
+            backup_id: value.backup_id.as_ref().map(|id| id.trim().to_string()),
+            exclude,
+            show_foreign: value.show_foreign,
+        })
+    }
+}
+
+pub struct ManifestOpts {
+    pub source: Option<String>,
+    pub snapshot: RestorePoint,
+}
+
+impl TryFrom<&super::ManifestArgs> for ManifestOpts {
+    type Error = anyhow::Error;
+    fn try_from(value: &super::ManifestArgs) -> Result<Self> {
         let snapshot = parse_point(&value.snapshot)?;
         Ok(Self {
             source: value.source.clone(),
@@ -53,46 +127,154 @@ impl TryFrom<&super::ListArchivesArgs> for ListArchivesOpts {
 pub struct RunOpts {
     pub source: Option<String>,
     pub snapshot: RestorePoint,
+    pub backup_id: Option<String>,
     pub archives: Vec<String>,
+    /// `(namespace, name)` of claims from `--pvc`.
+    pub pvcs: Vec<(String, String)>,
+    pub exclude: Vec<Regex>,
     pub all: bool,
+    pub plan: Option<RestorePlan>,
     pub dry_run: bool,
+    pub emit_script: Option<PathBuf>,
+    pub fail_fast: bool,
+    pub strict_routing: bool,
+    pub attach_to_vm: bool,
+    pub k8s_manifests: Option<PathBuf>,
+    pub k8s_apply: bool,
+    pub safety_snapshot: bool,
+    /// Rewrite targets already stamped as restored from the same archive and snapshot.
+    pub force: bool,
+    /// Block size from `--bs`.
+    pub block_size: Option<usize>,
+    pub yes: bool,
 }
 
 impl TryFrom<&super::RestoreRunArgs> for RunOpts {
     type Error = anyhow::Error;
     fn try_from(value: &super::RestoreRunArgs) -> Result<Self> {
         let snapshot = parse_point(&value.snapshot)?;
+        let exclude = parse_excludes(&value.exclude)?;
         Ok(Self {
             source: value.source.clone(),
             snapshot,
+            backup_id: value.backup_id.as_ref().map(|id| id.trim().to_string()),
             archives: value.archives.clone(),
+            pvcs: value
+                .pvcs
+                .iter()
+                .map(|p| parse_pvc(p))
+                .collect::<Result<_>>()?,
+            exclude,
             all: value.all,
+            plan: value.plan.as_deref().map(RestorePlan::load).transpose()?,
             dry_run: value.dry_run,
+            emit_script: value.emit_script.clone(),
+            fail_fast: value.fail_fast,
+            strict_routing: value.strict_routing,
+            attach_to_vm: value.attach_to_vm,
+            k8s_manifests: value.k8s_manifests.clone(),
+            k8s_apply: value.k8s_apply,
+            safety_snapshot: value.safety_snapshot,
+            force: value.force,
+            block_size: value
+                .bs
+                .as_deref()
+                .map(parse_block_size)
+                .transpose()
+                .context("--bs")?,
+            yes: value.yes,
+        })
+    }
+}
+
+pub struct VerifyOpts {
+    pub source: Option<String>,
+    pub snapshot: RestorePoint,
+    pub archives: Vec<String>,
+    pub exclude: Vec<Regex>,
+    pub all: bool,
+    pub device: Option<PathBuf>,
+}
+
+impl TryFrom<&super::VerifyArgs> for VerifyOpts {
+    type Error = anyhow::Error;
+    fn try_from(value: &super::VerifyArgs) -> Result<Self> {
+        Ok(Self {
+            source: value.source.clone(),
+            snapshot: parse_point(&value.snapshot)?,
+            archives: value.archives.clone(),
+            exclude: parse_excludes(&value.exclude)?,
+            all: value.all,
+            device: value.device.clone(),
         })
     }
 }
 
+pub struct ArchiveResult {
+    pub archive: String,
+    pub device: String,
+    pub secs: u64,
+    pub error: Option<String>,
+}
+
+/// What a restore would transfer and touch, for the `--dry-run` impact summary.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RestoreImpact {
+    pub archives: usize,
+    /// Summed archive sizes as the snapshot lists them.
+    pub bytes: u64,
+    /// Archives the snapshot lists no size for.
+    pub unknown: usize,
+    pub created: usize,
+    pub overwritten: usize,
+    /// Bytes per second the duration is estimated at.
+    pub rate: u64,
+}
+
+impl RestoreImpact {
+    pub fn secs(&self) -> u64 {
+        self.bytes.div_ceil(self.rate.max(1))
+    }
+}
+
+fn restore_impact(plan: &[(&Volume, bool)], snap: &PbsSnapshot, rate: u64) -> RestoreImpact {
+    let sizes: Vec<u64> = plan
+        .iter()
+        .map(|(v, _)| {
+            snap.files
+                .iter()
+                .find(|f| f.filename == v.archive)
+                .map_or(0, |f| f.size)
+        })
+        .collect();
+    let overwritten = plan.iter().filter(|(_, o)| *o).count();
+    RestoreImpact {
+        archives: plan.len(),
+        bytes: sizes.iter().sum(),
+        unknown: sizes.iter().filter(|&&s| s == 0).count(),
+        created: plan.len() - overwritten,
+        overwritten,
+        rate,
+    }
+}
+
 pub fn list_snapshots(ctx: &AppCtx, opts: ListSnapshotsOpts) -> Result<()> {
     let repo = ctx.cfg.resolve_backup_repo(opts.source.as_deref())?;
-    let ns_opt = ctx.cfg.pbs.ns.as_deref();
+    let ns_opt = repo.ns.as_deref();
     let snaps = ctx.tools.pbs().snapshots(repo, ns_opt)?;
 
-    ui::log_pbs_info(
-        repo,
-        ctx.cfg.pbs.ns.as_deref(),
-        &ctx.cfg.pbs.backup_id,
-        None,
-    );
+    let all_groups = opts.all_groups;
+    let group = (!all_groups).then(|| opts.backup_id.as_deref().unwrap_or(&ctx.cfg.pbs.backup_id));
+    ui::log_pbs_info(&repo.url, ns_opt, group.unwrap_or("*"), None);
 
-    let mut filtered: Vec<&PbsSnapshot> = snaps
-        .iter()
-        .filter(|s| s.backup_id == ctx.cfg.pbs.backup_id)
-        .collect();
-    filtered.sort_by_key(|s| s.backup_time);
+    let selected = select_snapshots(&snaps, group, &opts);
+    if opts.json {
+        println!("{}", serde_json::to_string_pretty(&selected)?);
+        return Ok(());
+    }
 
-    let rows: Vec<Vec<String>> = filtered
+    let rows: Vec<Vec<String>> = selected
         .into_iter()
-        .rev()
         .map(|s| {
             let when = fmt_utc(s.backup_time).unwrap_or_else(|_| s.backup_time.to_string());
 
@@ -110,18 +292,51 @@ pub fn list_snapshots(ctx: &AppCtx, opts: ListSnapshotsOpts) -> Result<()> {
                 files_joined
             };
 
-            vec![when, files]
+            if all_groups {
+                vec![s.backup_id.clone(), when, files]
+            } else {
+                vec![when, files]
+            }
         })
         .collect();
 
-    ui::log_snapshots(rows);
+    ui::log_snapshots(rows, all_groups);
 
     Ok(())
 }
 
+/// Snapshots of `group` (or every group) within the time window, grouped by backup ID and
+/// newest first, keeping at most `limit` per group.
+fn select_snapshots<'a>(
+    snaps: &'a [PbsSnapshot],
+    group: Option<&str>,
+    opts: &ListSnapshotsOpts,
+) -> Vec<&'a PbsSnapshot> {
+    let mut filtered: Vec<&PbsSnapshot> = snaps
+        .iter()
+        .filter(|s| group.is_none_or(|id| s.backup_id == id))
+        .filter(|s| opts.since.is_none_or(|t| s.backup_time >= t))
+        .filter(|s| opts.until.is_none_or(|t| s.backup_time <= t))
+        .collect();
+    filtered.sort_by(|a, b| {
+        a.backup_id
+            .cmp(&b.backup_id)
+            .then(b.backup_time.cmp(&a.backup_time))
+    });
+    if let Some(limit) = opts.limit {
+        let mut seen: BTreeMap<&str, usize> = BTreeMap::new();
+        filtered.retain(|s| {
+            let n = seen.entry(s.backup_id.as_str()).or_default();
+            *n += 1;
+            *n <= limit
+        });
+    }
+    filtered
+}
+
 pub fn list_archives(ctx: &AppCtx, opts: ListArchivesOpts) -> Result<()> {
     let repo = ctx.cfg.resolve_backup_repo(opts.source.as_deref())?;
-    let ns_opt = ctx.cfg.pbs.ns.as_deref();
+    let ns_opt = repo.ns.as_deref();
     let point = &opts.snapshot;
     let snaps = ctx.tools.pbs().snapshots(repo, ns_opt)?;
 
@@ -129,143 +344,868 @@ pub fn list_archives(ctx: &AppCtx, opts: ListArchivesOpts) -> Result<()> {
         bail!("no snapshots found in repo {repo}");
     }
 
-    let snap = pick_snapshot(&snaps, &ctx.cfg.pbs.backup_id, point.clone())?;
+    let backup_id = opts.backup_id.as_deref().unwrap_or(&ctx.cfg.pbs.backup_id);
+    let snap = pick_snapshot(&snaps, backup_id, point.clone())?;
     let registry = ProviderRegistry::new(ctx, Some(snap));
     let providers = registry.build();
-    let rows: Vec<String> = providers
+    let routed: BTreeSet<String> = providers
         .iter()
         .flat_map(|p| p.list_archives(snap))
-        .collect::<BTreeSet<_>>()
-        .into_iter()
+        .collect();
+    let rows: Vec<(&PbsFile, String, &str)> = snap
+        .files
+        .iter()
+        .filter(|f| f.class() == FileClass::Archive && !is_excluded(&f.filename, &opts.exclude))
+        .map(|f| {
+            let provider = parse_archive_name(&f.filename)
+                .map(|(p, _, _)| p)
+                .unwrap_or_default();
+            let target = registry
+                .matcher()
+                .pick_target_name(&provider, f)
+                .filter(|_| routed.contains(&f.filename))
+                .unwrap_or("<unrouted>");
+            (f, provider, target)
+        })
         .collect();
 
-    ui::log_pbs_info(repo, ns_opt, &snap.backup_id, Some(snap.backup_time));
-    ui::log_pbs_archives(rows);
+    ui::log_pbs_info(&repo.url, ns_opt, &snap.backup_id, Some(snap.backup_time));
+    ui::log_pbs_archives(&rows);
+    ui::log_carried(&snap.backup_id, &carried_archives(ctx, repo, ns_opt, snap));
+
+    let foreign: Vec<&str> = snap
+        .files
+        .iter()
+        .filter(|f| f.class() == FileClass::Foreign)
+        .map(|f| f.filename.as_str())
+        .collect();
+    if opts.show_foreign {
+        ui::log_foreign_files(&foreign);
+    } else if !foreign.is_empty() {
+        tracing::info!(
+            "{} foreign file(s) not shown; use --show-foreign to list them",
+            foreign.len()
+        );
+    }
 
     Ok(())
 }
 
-pub fn restore_run(ctx: &AppCtx, opts: RunOpts) -> Result<()> {
-    let _lock = LockGuard::try_acquire("pvtool-restore")?;
+pub(crate) fn fetch_manifest(
+    ctx: &AppCtx,
+    repo: &Repo,
+    ns: Option<&str>,
+    snap: &PbsSnapshot,
+) -> Result<BackupManifest> {
+    let blob = format!("{MANIFEST_ARCHIVE}.blob");
+    if !snap.files.iter().any(|f| f.filename == blob) {
+        bail!("snapshot has no {MANIFEST_ARCHIVE} (created before manifests were recorded?)");
+    }
+
+    let path = snapshot_path(&snap.backup_id, snap.backup_time)?;
+    let raw = ctx
+        .tools
+        .pbs()
+        .fetch_blob(repo, ns, &path, MANIFEST_ARCHIVE)?;
+    BackupManifest::parse(&raw)
+}
 
-    with_dry_run_enabled(opts.dry_run, || -> Result<()> {
-        let repo = ctx.cfg.resolve_backup_repo(opts.source.as_deref())?;
-        let ns_opt = ctx.cfg.pbs.ns.as_deref();
-        let point = &opts.snapshot;
-        let snaps = ctx.tools.pbs().snapshots(repo, ns_opt)?;
-        if snaps.is_empty() {
-            bail!("no snapshots found in repo {repo}");
+/// Archives the snapshot's manifest lists as left out by `--changed-only`; none if the
+/// snapshot has no manifest or it cannot be read.
+fn carried_archives(
+    ctx: &AppCtx,
+    repo: &Repo,
+    ns: Option<&str>,
+    snap: &PbsSnapshot,
+) -> Vec<CarriedArchive> {
+    let blob = format!("{MANIFEST_ARCHIVE}.blob");
+    if !snap.files.iter().any(|f| f.filename == blob) {
+        return Vec::new();
+    }
+    match fetch_manifest(ctx, repo, ns, snap) {
+        Ok(m) => m.carried,
+        Err(e) => {
+            tracing::warn!("manifest not read, archives left out by --changed-only unknown: {e:#}");
+            Vec::new()
         }
-        let snap = pick_snapshot(&snaps, &ctx.cfg.pbs.backup_id, point.clone())?;
+    }
+}
+
+pub fn show_manifest(ctx: &AppCtx, opts: ManifestOpts) -> Result<()> {
+    let repo = ctx.cfg.resolve_backup_repo(opts.source.as_deref())?;
+    let ns_opt = repo.ns.as_deref();
+    let snaps = ctx.tools.pbs().snapshots(repo, ns_opt)?;
+    let snap = pick_snapshot(&snaps, &ctx.cfg.pbs.backup_id, opts.snapshot)?;
+    let manifest = fetch_manifest(ctx, repo, ns_opt, snap)?;
 
-        let registry = ProviderRegistry::new(ctx, Some(snap));
-        let mut providers = registry.build();
-        let mut available: Vec<String> = Vec::new();
+    ui::log_pbs_info(&repo.url, ns_opt, &snap.backup_id, Some(snap.backup_time));
+    ui::log_manifest(&manifest);
 
-        for p in providers.iter_mut() {
-            let mut a = p.list_archives(snap);
-            available.append(&mut a);
+    Ok(())
+}
+
+pub fn explain(cfg: &Config, archive: &str) -> Result<()> {
+    let (provider, ..) = parse_archive_name(archive)?;
+    let matcher = RestoreMatcher::new(cfg)?;
+    let checks = matcher.check_rules(&provider, archive);
+    let route = matcher.route(&provider, archive);
+    ui::log_route(archive, &provider, &checks, &route);
+    Ok(())
+}
+
+pub fn restore_run(ctx: &AppCtx, opts: RunOpts) -> Result<()> {
+    let repo = ctx.cfg.resolve_backup_repo(opts.source.as_deref())?;
+    let mut resources = target_resources(&ctx.cfg);
+    resources.push(Resource::Repo(repo.url.clone()));
+    let _lock = LockSet::try_acquire(resources)?;
+
+    let script = opts.emit_script.as_deref();
+    exec_policy::emitting_script(script, "pvtools restore run", || {
+        with_dry_run_enabled(opts.dry_run, || -> Result<()> {
+            ctx.events.emit(Event::RunStarted {
+                command: "restore",
+                backup_id: opts.backup_id.as_deref().unwrap_or(&ctx.cfg.pbs.backup_id),
+                repo: &repo.url,
+                dry_run: opts.dry_run,
+            });
+
+            let res = run_restore(ctx, &opts, repo);
+            ctx.events.emit(Event::RunFinished {
+                command: "restore",
+                ok: res.is_ok(),
+                error: res.as_ref().err().map(|e| format!("{e:#}")),
+            });
+            res
+        })
+    })
+}
+
+fn run_restore(ctx: &AppCtx, opts: &RunOpts, repo: &Repo) -> Result<()> {
+    let point = &opts.snapshot;
+    let snaps = ctx.tools.pbs().snapshots(repo, repo.ns.as_deref())?;
+    if snaps.is_empty() {
+        bail!("no snapshots found in repo {}", repo.url);
+    }
+    let backup_id = opts.backup_id.as_deref().unwrap_or(&ctx.cfg.pbs.backup_id);
+    let snap = pick_snapshot(&snaps, backup_id, point.clone())?;
+
+    let registry = ProviderRegistry::new(ctx, Some(snap)).with_plan(opts.plan.as_ref());
+    let mut available: Vec<String> = Vec::new();
+    for p in registry.build().iter() {
+        let mut a = p.list_archives(snap);
+        available.append(&mut a);
+    }
+
+    let claims = match &opts.k8s_manifests {
+        Some(_) => {
+            if opts.k8s_apply && ctx.tools.kube().is_none() {
+                bail!("--k8s-apply needs [backup.kubernetes] to reach the cluster");
+            }
+            fetch_manifest(ctx, repo, repo.ns.as_deref(), snap)
+                .context("--k8s-manifests needs the claims recorded in the manifest")?
+                .claims
         }
+        None => Vec::new(),
+    };
 
-        let selected_archives: Vec<String> =
-            select_archives_exact_from(&available, &opts.archives, opts.all)?;
+    let selected_archives: Vec<String> = match &opts.plan {
+        Some(plan) => {
+            plan.validate(&ctx.cfg, snap)?;
+            let rows: Vec<_> = plan
+                .archives
+                .iter()
+                .map(|e| (e.archive.as_str(), e.target.as_str(), e.size))
+                .collect();
+            ui::log_restore_mapping(&rows);
+            plan.archives.iter().map(|e| e.archive.clone()).collect()
+        }
+        None => {
+            let mut wanted = opts.archives.clone();
+            if !opts.pvcs.is_empty() {
+                let manifest = fetch_manifest(ctx, repo, repo.ns.as_deref(), snap)
+                    .context("--pvc needs the claims recorded in the manifest")?;
+                wanted.extend(archives_for_pvcs(&manifest.claims, &opts.pvcs)?);
+            }
+            select_archives_exact_from(&available, &wanted, opts.all, &opts.exclude)?
+        }
+    };
 
-        if selected_archives.is_empty() {
-            bail!("nothing to restore: specify --all or at least one --archive");
+    let unrouted = if opts.all && opts.plan.is_none() {
+        unrouted_archives(snap, &available, &opts.exclude)
+    } else {
+        Vec::new()
+    };
+    if !unrouted.is_empty() {
+        if opts.strict_routing {
+            bail!(
+                "--strict-routing: {} archive(s) match no restore target: {}",
+                unrouted.len(),
+                unrouted.join(", ")
+            );
         }
+        ui::log_unrouted(&unrouted);
+    }
+    let carried = if opts.all && opts.plan.is_none() {
+        carried_archives(ctx, repo, repo.ns.as_deref(), snap)
+    } else {
+        Vec::new()
+    };
+    ui::log_carried(&snap.backup_id, &carried);
 
-        let mut items: Vec<Volume> = Vec::new();
-        for p in providers.iter_mut() {
-            if opts.all {
-                let mut r = p
-                    .collect_restore(None, true)
-                    .with_context(|| format!("collect restore plan from provider {}", p.name()))?;
-                items.append(&mut r);
-            } else {
-                for a in &selected_archives {
-                    let mut r =
-                        p.collect_restore(Some(a.as_str()), opts.all)
-                            .with_context(|| {
-                                format!("collect restore plan from provider {}", p.name())
-                            })?;
-                    items.append(&mut r);
-                }
+    if selected_archives.is_empty() {
+        bail!("nothing to restore: specify --all or at least one --archive or --pvc");
+    }
+
+    // Archives whose target storage lives on another cluster node are restored over ssh there.
+    let remote = remote_archives(ctx, snap, registry.matcher(), &selected_archives)?;
+    if !remote.is_empty() {
+        ui::log_remote_archives(&remote);
+    }
+    let mut groups: BTreeMap<Option<&str>, Vec<&str>> = BTreeMap::new();
+    for a in &selected_archives {
+        let node = remote
+            .iter()
+            .find(|(r, _, _)| *r == a.as_str())
+            .map(|(_, _, n)| n.as_str());
+        groups.entry(node).or_default().push(a.as_str());
+    }
+
+    let safety = opts.safety_snapshot || ctx.cfg.restore.safety_snapshot;
+    let mut run = RestoreProgress {
+        snap,
+        snap_path: snapshot_path(&snap.backup_id, snap.backup_time)?,
+        safety_suffix: safety.then(|| prerestore_suffix(current_epoch())),
+        total: 0,
+        results: Vec::new(),
+        rollbacks: Vec::new(),
+        restored: Vec::new(),
+    };
+
+    for (node, archives) in &groups {
+        let node_tools;
+        let tools = match node {
+            None => &ctx.tools,
+            Some(n) => {
+                node_tools =
+                    Toolbox::over_ssh(&ctx.cfg, Arc::new(SshRunner::new(node_ssh(ctx, n))))
+                        .with_context(|| format!("prepare restore on node {n}"))?;
+                &node_tools
             }
+        };
+        if !restore_on(ctx, tools, opts, repo, archives, &mut run)? {
+            break;
+        }
+    }
+
+    if run.total == 0 {
+        tracing::info!("nothing to restore");
+        return Ok(());
+    }
+
+    ui::log_restore_results(&run.results, run.total);
+    ui::log_rollbacks(&run.rollbacks);
+    if let Some(path) = &opts.k8s_manifests {
+        write_pv_manifests(ctx, &claims, &run.restored, path, opts.k8s_apply)?;
+    }
+    if !unrouted.is_empty() {
+        tracing::warn!(
+            "{} archive(s) of the snapshot were NOT restored: no restore target",
+            unrouted.len()
+        );
+    }
+    if !carried.is_empty() {
+        tracing::warn!(
+            "{} archive(s) left out by --changed-only were NOT restored: restore them from \
+             the snapshots listed above",
+            carried.len()
+        );
+    }
+
+    let failed = run.results.iter().filter(|r| r.error.is_some()).count();
+    let skipped = run.total - run.results.len();
+    ctx.events.emit(Event::RestoreFinished {
+        total: run.total,
+        failed: failed + skipped,
+    });
+    if failed + skipped > 0 {
+        return Err(Failure::Partial.with(format!(
+            "{} of {} archives failed to restore",
+            failed + skipped,
+            run.total
+        )));
+    }
+
+    tracing::info!("done");
+    Ok(())
+}
+
+/// Archives of `snap` that no provider routes to a target and that are not excluded.
+fn unrouted_archives<'a>(
+    snap: &'a PbsSnapshot,
+    available: &[String],
+    exclude: &[Regex],
+) -> Vec<&'a str> {
+    snap.files
+        .iter()
+        .filter(|f| f.class() == FileClass::Archive)
+        .map(|f| f.filename.as_str())
+        .filter(|f| !available.iter().any(|a| a == f) && !is_excluded(f, exclude))
+        .collect()
+}
+
+fn parse_pvc(s: &str) -> Result<(String, String)> {
+    match s.trim().split_once('/') {
+        Some((ns, name)) if !ns.is_empty() && !name.is_empty() && !name.contains('/') => {
+            Ok((ns.to_string(), name.to_string()))
         }
+        _ => bail!("bad --pvc '{s}': expected namespace/name"),
+    }
+}
 
-        if items.is_empty() {
-            tracing::info!("nothing to restore");
-            return Ok(());
+/// Archives the manifest records for each claim; every claim must have at least one.
+fn archives_for_pvcs(claims: &[ClaimRecord], pvcs: &[(String, String)]) -> Result<Vec<String>> {
+    if claims.is_empty() {
+        bail!("snapshot records no PVCs; backups record them when [backup.kubernetes] is set");
+    }
+    let mut out = Vec::new();
+    for (ns, name) in pvcs {
+        let before = out.len();
+        out.extend(
+            claims
+                .iter()
+                .filter(|c| &c.namespace == ns && &c.name == name)
+                .map(|c| c.archive.clone()),
+        );
+        if out.len() == before {
+            bail!("no archive recorded for PVC {ns}/{name}");
         }
+    }
+    Ok(out)
+}
+
+/// Streams archives from PBS without writing them anywhere. The client checks every chunk
+/// against its digest in the fixed index, so a corrupt or missing chunk fails the stream.
+pub fn verify(ctx: &AppCtx, opts: VerifyOpts) -> Result<()> {
+    let repo = ctx.cfg.resolve_backup_repo(opts.source.as_deref())?;
+    let ns_opt = repo.ns.as_deref();
+    let snaps = ctx.tools.pbs().snapshots(repo, ns_opt)?;
+    if snaps.is_empty() {
+        bail!("no snapshots found in repo {repo}");
+    }
+    let snap = pick_snapshot(&snaps, &ctx.cfg.pbs.backup_id, opts.snapshot.clone())?;
 
-        items.ensure_unique_targets()?;
+    let selected = select_archives_exact_from(
+        &verifiable_archives(snap),
+        &opts.archives,
+        opts.all,
+        &opts.exclude,
+    )?;
+    if selected.is_empty() {
+        bail!("nothing to verify: specify --all or at least one --archive");
+    }
+    if opts.device.is_some() && selected.len() != 1 {
+        bail!(
+            "--device compares exactly one archive, {} selected",
+            selected.len()
+        );
+    }
 
-        ui::log_pbs_info(repo, ns_opt, &ctx.cfg.pbs.backup_id, Some(snap.backup_time));
-        ui::log_archives(&items);
+    let snap_path = snapshot_path(&snap.backup_id, snap.backup_time)?;
+    ui::log_pbs_info(&repo.url, ns_opt, &snap.backup_id, Some(snap.backup_time));
 
-        let dd_opts = DdOpts::default();
+    let writer = ctx.tools.writer();
+    let mut results = Vec::with_capacity(selected.len());
+    for a in &selected {
+        let started = Instant::now();
+        let src = ctx.tools.pbs().restore_cmd(repo, ns_opt, &snap_path, a);
+        let (target, res) = match &opts.device {
+            Some(dev) => {
+                let size = snap
+                    .files
+                    .iter()
+                    .find(|f| &f.filename == a)
+                    .map(|f| f.size)
+                    .unwrap_or(0);
+                tracing::info!("verify {a} against {}", dev.display());
+                (dev.display().to_string(), writer.compare(src, dev, size))
+            }
+            None => {
+                tracing::info!("verify {a}");
+                ("/dev/null".to_string(), writer.discard(src))
+            }
+        };
+        let res = res.with_context(|| format!("verify {a}"));
+        if let Err(e) = &res {
+            tracing::error!("{e:#}");
+        }
+        results.push(ArchiveResult {
+            archive: a.clone(),
+            device: target,
+            secs: started.elapsed().as_secs(),
+            error: res.err().map(|e| format!("{e:#}")),
+        });
+        if signal::interrupted().is_some() {
+            break;
+        }
+    }
 
-        for i in &items {
-            let dd_cmd = ctx.tools.dd().to_file_cmd(&i.device, &dd_opts);
-            ctx.tools
+    ui::log_restore_results(&results, selected.len());
+    let ok = results.iter().filter(|r| r.error.is_none()).count();
+    if ok < selected.len() {
+        return Err(Failure::Partial.with(format!(
+            "{} of {} archives failed verification",
+            selected.len() - ok,
+            selected.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Every pvtools archive of the snapshot, whether or not a restore rule routes it anywhere.
+pub(super) fn verifiable_archives(snap: &PbsSnapshot) -> Vec<String> {
+    snap.files
+        .iter()
+        .filter(|f| f.class() == FileClass::Archive)
+        .map(|f| f.filename.clone())
+        .collect()
+}
+
+struct RestoreProgress<'a> {
+    snap: &'a PbsSnapshot,
+    snap_path: String,
+    safety_suffix: Option<String>,
+    total: usize,
+    results: Vec<ArchiveResult>,
+    rollbacks: Vec<(String, String)>,
+    /// Volumes written without error, for what runs after the restore.
+    restored: Vec<Volume>,
+}
+
+/// Restores `archives` with `tools`; returns false once the run should stop.
+fn restore_on(
+    ctx: &AppCtx,
+    tools: &Toolbox,
+    opts: &RunOpts,
+    repo: &Repo,
+    archives: &[&str],
+    run: &mut RestoreProgress<'_>,
+) -> Result<bool> {
+    let ns_opt = repo.ns.as_deref();
+    let registry =
+        ProviderRegistry::with_tools(ctx, tools, Some(run.snap)).with_plan(opts.plan.as_ref());
+    let mut providers = registry.build();
+
+    let mut items: Vec<Volume> = Vec::new();
+    for p in providers.iter_mut() {
+        for a in archives {
+            let mut r = p
+                .collect_restore(Some(a), false)
+                .with_context(|| format!("collect restore plan from provider {}", p.name()))?;
+            items.append(&mut r);
+        }
+    }
+    if items.is_empty() {
+        return Ok(true);
+    }
+
+    items.ensure_unique_targets()?;
+    run.total += items.len();
+
+    ui::log_pbs_info(
+        &repo.url,
+        ns_opt,
+        &run.snap.backup_id,
+        Some(run.snap.backup_time),
+    );
+    let plan: Vec<(&Volume, bool)> = items
+        .iter()
+        .map(|i| (i, providers.iter().any(|p| p.overwrites(i))))
+        .collect();
+    ui::log_restore_plan(&plan);
+    if exec_policy::is_dry_run() {
+        let rate = ctx.cfg.restore.assumed_rate.unwrap_or(DEFAULT_ASSUMED_RATE);
+        ui::log_restore_impact(&restore_impact(&plan, run.snap, rate));
+    }
+    if !opts.yes && !exec_policy::is_dry_run() && !ui::confirm(&confirm_question(&plan))? {
+        bail!("restore cancelled");
+    }
+
+    let mut restored: Vec<&Volume> = Vec::new();
+    let mut keep_going = true;
+    for i in &items {
+        let started = Instant::now();
+        let stamp = restore_stamp(&i.archive, run.snap);
+        let res = needs_write(&providers, i, &stamp, opts.force).and_then(|write| {
+            if !write {
+                tracing::info!(
+                    "{}: already restored from {stamp}, skipped (--force rewrites it)",
+                    i.device.display()
+                );
+                return Ok(());
+            }
+            for p in &providers {
+                p.create_target(i)
+                    .with_context(|| format!("create {}", i.device.display()))?;
+            }
+            if let Some(suffix) = &run.safety_suffix
+                && let Some(cmd) = take_safety_snapshot(&providers, i, suffix)?
+            {
+                run.rollbacks.push((i.device.display().to_string(), cmd));
+            }
+            let fresh_thin = providers.iter().any(|p| p.skips_zeros(i));
+            let write_opts = write_opts_for(
+                ctx.cfg.restore.write.as_ref(),
+                registry.matcher(),
+                &i.archive,
+                fresh_thin,
+            )?;
+            let write_opts = WriteOpts {
+                block_size: opts.block_size.unwrap_or(write_opts.block_size),
+                ..write_opts
+            };
+            let src = tools
                 .pbs()
-                .restore_to(
-                    repo,
-                    ns_opt,
-                    &snap.backup_id,
-                    &i.archive,
-                    ctx.cfg.pbs.keyfile.as_deref(),
-                    dd_cmd,
-                )
-                .with_context(|| format!("restore pipeline for {}", i.archive))?;
-        }
-
-        tracing::info!("done");
-        Ok(())
-    })
+                .restore_cmd(repo, ns_opt, &run.snap_path, &i.archive);
+            match providers
+                .iter()
+                .find_map(|p| p.receive_stream(i, &src, &write_opts))
+            {
+                Some(res) => res,
+                None => tools.writer().write(src, &i.device, &write_opts),
+            }
+            .with_context(|| format!("restore {}", i.archive))?;
+            mark_restored(&providers, i, Some(&stamp))
+        });
+
+        let failed = res.is_err();
+        if let Err(e) = &res {
+            tracing::error!("{e:#}");
+        }
+        let result = ArchiveResult {
+            archive: i.archive.clone(),
+            device: i.device.display().to_string(),
+            secs: started.elapsed().as_secs(),
+            error: res.err().map(|e| format!("{e:#}")),
+        };
+        ctx.events.emit(Event::ArchiveRestored {
+            archive: &result.archive,
+            target: &result.device,
+            ok: result.error.is_none(),
+            error: result.error.as_deref(),
+        });
+        run.results.push(result);
+        if !failed {
+            restored.push(i);
+            run.restored.push(i.clone());
+        }
+
+        if failed && (opts.fail_fast || signal::interrupted().is_some()) {
+            keep_going = false;
+            break;
+        }
+    }
+    if opts.attach_to_vm {
+        rescan_vms(tools, &restored);
+    }
+    Ok(keep_going)
+}
+
+fn write_pv_manifests(
+    ctx: &AppCtx,
+    claims: &[ClaimRecord],
+    restored: &[Volume],
+    path: &Path,
+    apply: bool,
+) -> Result<()> {
+    let (yaml, skipped) = k8s::pv_manifests(claims, restored)?;
+    for (archive, reason) in skipped {
+        tracing::warn!("no PV manifest for {archive}: {reason}");
+    }
+    if yaml.is_empty() {
+        tracing::warn!(
+            "no restored volume has a recorded PVC; not writing {}",
+            path.display()
+        );
+        return Ok(());
+    }
+    fs::write(path, &yaml).with_context(|| format!("write {}", path.display()))?;
+    tracing::info!("wrote PV/PVC manifests to {}", path.display());
+    if apply && let Some(kube) = ctx.tools.kube() {
+        kube.apply(path)?;
+    }
+    Ok(())
 }
 
-fn parse_point(s: &str) -> Result<RestorePoint> {
+/// Lets PVE pick up restored VM disks; a VM that fails to rescan only gets a warning, as the
+/// data is already written.
+fn rescan_vms(tools: &Toolbox, restored: &[&Volume]) {
+    let vmids: BTreeSet<u32> = restored.iter().filter_map(|v| pve_vmid(&v.disk)).collect();
+    for vmid in vmids {
+        tracing::info!("qm rescan VM {vmid}");
+        if let Err(e) = tools.qm().rescan(vmid) {
+            tracing::warn!("VM {vmid}: {e:#}");
+        }
+    }
+}
+
+/// Login for another cluster node: `[restore.ssh]` settings if given, else plain `ssh <node>`
+/// as PVE sets up between cluster members.
+fn node_ssh(ctx: &AppCtx, node: &str) -> Ssh {
+    match &ctx.cfg.restore.ssh {
+        Some(ssh) => ssh.with_host(node),
+        None => Ssh {
+            host: node.to_string(),
+            user: None,
+            identity_file: None,
+            port: None,
+        },
+    }
+}
+
+/// Selected archives whose target storage is only available on another cluster node,
+/// as `(archive, target, node)`.
+fn remote_archives<'a>(
+    ctx: &'a AppCtx,
+    snap: &'a PbsSnapshot,
+    matcher: &'a RestoreMatcher,
+    selected: &'a [String],
+) -> Result<Vec<(&'a str, &'a str, String)>> {
+    let pvesh = ctx.tools.pvesh();
+    let placement =
+        TargetPlacement::resolve(&ctx.cfg, &pvesh.get_storage()?, &pvesh.cluster_storage()?);
+    let mut out = Vec::new();
+    for f in snap.files.iter().filter(|f| selected.contains(&f.filename)) {
+        let Ok((provider, _, _)) = parse_archive_name(&f.filename) else {
+            continue;
+        };
+        if let Some(target) = matcher.pick_target_name(&provider, f)
+            && let Some(node) = placement.remote_node(target)
+        {
+            out.push((f.filename.as_str(), target, node.to_string()));
+        }
+    }
+    Ok(out)
+}
+
+/// Default writer settings with `[restore] write` and then the overrides of the rule that
+/// routed `archive` applied. Zero blocks are skipped for `fresh_thin` targets unless either
+/// sets `sparse` itself.
+fn write_opts_for(
+    defaults: Option<&WriteOverride>,
+    matcher: &RestoreMatcher,
+    archive: &str,
+    fresh_thin: bool,
+) -> Result<WriteOpts> {
+    let rule = parse_archive_name(archive)
+        .ok()
+        .and_then(|(provider, _, _)| matcher.write_override(&provider, archive));
+    let mut opts = WriteOpts {
+        sparse: fresh_thin,
+        ..WriteOpts::default()
+    };
+    for o in defaults.into_iter().chain(rule) {
+        opts = opts.with_override(o)?;
+    }
+    Ok(opts)
+}
+
+/// Pools, VGs and ssh hosts of every restore target; any of them may receive an archive.
+fn target_resources(cfg: &Config) -> Vec<Resource> {
+    cfg.restore
+        .targets
+        .values()
+        .map(|t| match t {
+            RestoreTarget::Zfs { root, .. } => Resource::zfs(root),
+            RestoreTarget::LvmThin { vg, .. } | RestoreTarget::Lvm { vg, .. } => {
+                Resource::Vg(vg.clone())
+            }
+            RestoreTarget::Ssh { ssh, .. } => Resource::Host(ssh.host.clone()),
+        })
+        .collect()
+}
+
+fn take_safety_snapshot(
+    providers: &[Box<dyn Provider + '_>],
+    vol: &Volume,
+    suffix: &str,
+) -> Result<Option<String>> {
+    for p in providers {
+        if let Some(cmd) = p.safety_snapshot(vol, suffix)? {
+            return Ok(Some(cmd));
+        }
+    }
+    Ok(None)
+}
+
+/// Asks to go ahead with `plan`, listing the devices it creates and the ones it overwrites.
+fn confirm_question(plan: &[(&Volume, bool)]) -> String {
+    let mut question = String::new();
+    for (overwrite, what) in [(false, "Create"), (true, "Overwrite")] {
+        let devices: Vec<String> = plan
+            .iter()
+            .filter(|(_, o)| *o == overwrite)
+            .map(|(v, _)| v.device.display().to_string())
+            .collect();
+        if !devices.is_empty() {
+            question.push_str(&format!(
+                "{what} {} device(s): {}\n",
+                devices.len(),
+                devices.join(", ")
+            ));
+        }
+    }
+    question.push_str("Continue?");
+    question
+}
+
+/// Whether `vol` must be written: it is not stamped with `stamp` yet, or `force` is set. A
+/// stale stamp is removed first, so a write cut short never looks complete.
+fn needs_write(
+    providers: &[Box<dyn Provider + '_>],
+    vol: &Volume,
+    stamp: &str,
+    force: bool,
+) -> Result<bool> {
+    let mut prior = None;
+    for p in providers {
+        if let Some(s) = p.restored_from(vol)? {
+            prior = Some(s);
+            break;
+        }
+    }
+    match prior {
+        Some(s) if s == stamp && !force => Ok(false),
+        Some(_) => mark_restored(providers, vol, None).map(|_| true),
+        None => Ok(true),
+    }
+}
+
+fn mark_restored(
+    providers: &[Box<dyn Provider + '_>],
+    vol: &Volume,
+    stamp: Option<&str>,
+) -> Result<()> {
+    for p in providers {
+        p.mark_restored(vol, stamp)
+            .with_context(|| format!("stamp {}", vol.device.display()))?;
+    }
+    Ok(())
+}
+
+pub fn parse_point(s: &str) -> Result<RestorePoint> {
     if s == "latest" {
         return Ok(RestorePoint::Latest);
     }
+    if let Some(n) = s.strip_prefix("latest-") {
+        let n: usize = n
+            .parse()
+            .with_context(|| format!("invalid snapshot '{s}': expected latest-N"))?;
+        return Ok(match n {
+            0 => RestorePoint::Latest,
+            n => RestorePoint::BeforeLatest(n),
+        });
+    }
+    if let Some((kind, rest)) = s.split_once('/') {
+        let (backup_id, time) = rest
+            .split_once('/')
+            .filter(|(id, t)| !id.is_empty() && !t.contains('/'))
+            .with_context(|| format!("invalid snapshot '{s}': expected host/<backup-id>/<time>"))?;
+        if kind != "host" {
+            bail!("invalid snapshot '{s}': pvtools only writes 'host' backups, not '{kind}'");
+        }
+        let time = parse_rfc3339_to_unix(time)
+            .with_context(|| format!("invalid snapshot '{s}': bad time '{time}'"))?;
+        return Ok(RestorePoint::Path {
+            backup_id: backup_id.to_string(),
+            time,
+        });
+    }
+    parse_time(s)
+        .map(RestorePoint::At)
+        .with_context(|| format!("invalid snapshot '{s}'"))
+}
+
+/// A point in time given as `~<age>`, epoch seconds or RFC3339.
+fn parse_time(s: &str) -> Result<u64> {
+    if let Some(age) = s.strip_prefix('~') {
+        let age = parse_duration(age)?;
+        return Ok(current_epoch().saturating_sub(age.as_secs()));
+    }
     if let Ok(ts) = s.parse::<u64>() {
-        return Ok(RestorePoint::At(ts));
+        return Ok(ts);
     }
-    let ts = parse_rfc3339_to_unix(s)?;
-    Ok(RestorePoint::At(ts))
+    parse_rfc3339_to_unix(s)
 }
 
-fn pick_snapshot<'a>(
+pub(crate) fn pick_snapshot<'a>(
     snaps: &'a [PbsSnapshot],
     backup_id: &str,
     point: RestorePoint,
 ) -> Result<&'a PbsSnapshot> {
-    let cand = snaps
-        .iter()
-        .filter(|s| s.backup_id == backup_id)
-        .filter(|s| match point {
-            RestorePoint::Latest => true,
-            RestorePoint::At(ts) => s.backup_time <= ts,
-        })
-        .max_by_key(|s| s.backup_time);
+    // A snapshot path names its own group, whatever --backup-id or the config say.
+    let backup_id = match &point {
+        RestorePoint::Path { backup_id, .. } => backup_id.as_str(),
+        _ => backup_id,
+    };
+    let mut group: Vec<&PbsSnapshot> = snaps.iter().filter(|s| s.backup_id == backup_id).collect();
+    group.sort_by_key(|s| std::cmp::Reverse(s.backup_time));
+    let cand = match point {
+        RestorePoint::Latest => group.first().copied(),
+        RestorePoint::BeforeLatest(n) => group.get(n).copied(),
+        RestorePoint::At(ts) => group.iter().copied().find(|s| s.backup_time <= ts),
+        RestorePoint::Path { time, .. } => group.iter().copied().find(|s| s.backup_time == time),
+    };
+    if group.is_empty() {
+        let others: BTreeSet<&str> = snaps.iter().map(|s| s.backup_id.as_str()).collect();
+        if !others.is_empty() {
+            bail!(
+                "no snapshots found for backup-id '{backup_id}'; groups in this repo: {} (pick one with --backup-id)",
+                others.into_iter().collect::<Vec<_>>().join(", ")
+            );
+        }
+    }
     let msg = match point {
         RestorePoint::Latest => format!("no snapshots found for backup-id '{backup_id}'"),
+        RestorePoint::BeforeLatest(n) => format!(
+            "backup-id '{backup_id}' has {} snapshot(s); latest-{n} needs at least {}",
+            group.len(),
+            n + 1
+        ),
         RestorePoint::At(ts) => {
             format!("no matching snapshot found before given time {ts} for backup-id '{backup_id}'")
         }
+        RestorePoint::Path { time, .. } => {
+            format!("backup-id '{backup_id}' has no snapshot taken at {time}")
+        }
     };
 
     cand.with_context(|| msg)
 }
 
-fn select_archives_exact_from(
+pub(crate) fn parse_excludes(patterns: &[String]) -> Result<Vec<Regex>> {
+    patterns
+        .iter()
+        .map(|p| Regex::new(p).with_context(|| format!("bad --exclude regex '{p}'")))
+        .collect()
+}
+
+#[inline]
+fn is_excluded(archive: &str, exclude: &[Regex]) -> bool {
+    exclude.iter().any(|re| re.is_match(archive))
+}
+
+pub(crate) fn select_archives_exact_from(
     available: &[String],
     requested: &[String],
     all: bool,
+    exclude: &[Regex],
 ) -> Result<Vec<String>> {
     if all {
-        return Ok(available.to_vec());
+        return Ok(available
+            .iter()
+            .filter(|a| !is_excluded(a, exclude))
+            .cloned()
+            .collect());
     }
     if requested.is_empty() {
         return Ok(vec![]);
@@ -277,14 +1217,403 @@ fn select_archives_exact_from(
     let mut seen = HashSet::<&str>::new();
 
     for r in requested {
-        let r_str = r.as_str();
-        if !available_set.contains(r_str) {
-            bail!("archive not available from providers: {r}");
-        }
-        if seen.insert(r_str) {
-            out.push(r.clone());
+        let matched: Vec<&str> = if is_glob(r) {
+            let pat = Pattern::new(r).with_context(|| format!("bad --archive pattern '{r}'"))?;
+            let m: Vec<&str> = available
+                .iter()
+                .map(|a| a.as_str())
+                .filter(|a| pat.matches(a))
+                .collect();
+            if m.is_empty() {
+                bail!("archive pattern matched nothing: {r}");
+            }
+            m
+        } else {
+            if !available_set.contains(r.as_str()) {
+                bail!("archive not available from providers: {r}");
+            }
+            vec![r.as_str()]
+        };
+
+        for a in matched {
+            if is_excluded(a, exclude) {
+                tracing::info!("skip {a}: matches --exclude");
+                continue;
+            }
+            if seen.insert(a) {
+                out.push(a.to_string());
+            }
         }
     }
 
     Ok(out)
 }
+
+#[inline]
+fn is_glob(s: &str) -> bool {
+    s.contains(['*', '?', '['])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::utils::process::CmdSpec;
+
+    fn available() -> Vec<String> {
+        vec![
+            "zfs_vm-9999-pv-a_raw_11111111.img".to_string(),
+            "zfs_vm-9999-pv-b_raw_22222222.img".to_string(),
+            "lvmthin_vm-7777-pv-c_raw_33333333.img".to_string(),
+        ]
+    }
+
+    #[test]
+    fn select_all_honours_excludes() {
+        let exclude = parse_excludes(&["^lvmthin_".to_string(), "pv-b".to_string()]).unwrap();
+        let out = select_archives_exact_from(&available(), &[], true, &exclude).unwrap();
+        assert_eq!(out, vec!["zfs_vm-9999-pv-a_raw_11111111.img".to_string()]);
+    }
+
+    #[test]
+    fn select_exact_rejects_unknown_and_drops_excluded() {
+        let exclude = parse_excludes(&["pv-a".to_string()]).unwrap();
+        let requested = vec![
+            "zfs_vm-9999-pv-a_raw_11111111.img".to_string(),
+            "zfs_vm-9999-pv-b_raw_22222222.img".to_string(),
+        ];
+        let out = select_archives_exact_from(&available(), &requested, false, &exclude).unwrap();
+        assert_eq!(out, vec!["zfs_vm-9999-pv-b_raw_22222222.img".to_string()]);
+
+        let missing = vec!["zfs_nope_raw_00000000.img".to_string()];
+        assert!(select_archives_exact_from(&available(), &missing, false, &[]).is_err());
+    }
+
+    #[test]
+    fn select_expands_globs_in_available_order() {
+        let requested = vec![
+            "zfs_vm-9999-*".to_string(),
+            "zfs_vm-9999-pv-a_raw_11111111.img".to_string(),
+        ];
+        let out = select_archives_exact_from(&available(), &requested, false, &[]).unwrap();
+        assert_eq!(
+            out,
+            vec![
+                "zfs_vm-9999-pv-a_raw_11111111.img".to_string(),
+                "zfs_vm-9999-pv-b_raw_22222222.img".to_string(),
+            ]
+        );
+
+        let none = vec!["btrfs_*".to_string()];
+        assert!(select_archives_exact_from(&available(), &none, false, &[]).is_err());
+    }
+
+    #[test]
+    fn verify_covers_archives_without_restore_rules() {
+        let file = |name: &str| crate::tooling::pbs::PbsFile {
+            filename: name.to_string(),
+            size: 1,
+            crypt_mode: None,
+        };
+        let snap = PbsSnapshot {
+            backup_id: "id".to_string(),
+            backup_time: 0,
+            files: vec![
+                file("index.json.blob"),
+                file("zfs_vm-1-disk-0_raw_abcd1234.img.fidx"),
+                file("btrfs_vm-2-disk-0_raw_abcd1234.img.fidx"),
+                file("lvm_vm-3-disk-0_raw_abcd1234.img.fidx"),
+            ],
+        };
+        assert_eq!(
+            verifiable_archives(&snap),
+            vec![
+                "zfs_vm-1-disk-0_raw_abcd1234.img.fidx".to_string(),
+                "lvm_vm-3-disk-0_raw_abcd1234.img.fidx".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn dry_run_impact_sums_archive_sizes() {
+        let file = |name: &str, size| crate::tooling::pbs::PbsFile {
+            filename: name.to_string(),
+            size,
+            crypt_mode: None,
+        };
+        let snap = PbsSnapshot {
+            backup_id: "id".to_string(),
+            backup_time: 0,
+            files: vec![
+                file("zfs_vm-1-disk-0_raw_abcd1234.img.fidx", 3 << 30),
+                file("zfs_vm-2-disk-0_raw_abcd1234.img.fidx", 1 << 30),
+                file("zfs_vm-3-disk-0_raw_abcd1234.img.fidx", 0),
+            ],
+        };
+        let vol = |archive: &str| Volume {
+            storage: "local-zfs".to_string(),
+            disk: archive.to_string(),
+            archive: archive.to_string(),
+            device: PathBuf::from("/dev/zvol/tank/x"),
+            size_bytes: None,
+            meta: None,
+        };
+        let vols: Vec<Volume> = snap.files.iter().map(|f| vol(&f.filename)).collect();
+        let plan = vec![(&vols[0], true), (&vols[1], false), (&vols[2], false)];
+
+        let impact = restore_impact(&plan, &snap, 100 << 20);
+        assert_eq!(
+            impact,
+            RestoreImpact {
+                archives: 3,
+                bytes: 4 << 30,
+                unknown: 1,
+                created: 2,
+                overwritten: 1,
+                rate: 100 << 20,
+            }
+        );
+        assert_eq!(impact.secs(), 41);
+    }
+
+    #[test]
+    fn confirmation_lists_created_and_overwritten_devices() {
+        let vol = |dev: &str| Volume {
+            storage: "local-zfs".to_string(),
+            disk: dev.to_string(),
+            archive: dev.to_string(),
+            device: PathBuf::from(dev),
+            size_bytes: None,
+            meta: None,
+        };
+        let (a, b, c) = (vol("/dev/a"), vol("/dev/b"), vol("/dev/c"));
+        assert_eq!(
+            confirm_question(&[(&a, false), (&b, true), (&c, false)]),
+            "Create 2 device(s): /dev/a, /dev/c\nOverwrite 1 device(s): /dev/b\nContinue?"
+        );
+        assert_eq!(
+            confirm_question(&[(&b, true)]),
+            "Overwrite 1 device(s): /dev/b\nContinue?"
+        );
+    }
+
+    /// Target stamped `stamp` that records every stamp change.
+    struct Stamped {
+        stamp: Option<String>,
+        marks: Arc<Mutex<Vec<Option<String>>>>,
+    }
+
+    impl Provider for Stamped {
+        fn name(&self) -> &'static str {
+            "stamped"
+        }
+        fn collect_restore(&mut self, _archive: Option<&str>, _all: bool) -> Result<Vec<Volume>> {
+            Ok(Vec::new())
+        }
+        fn list_archives(&self, _snap: &PbsSnapshot) -> Vec<String> {
+            Vec::new()
+        }
+        fn create_target(&self, _vol: &Volume) -> Result<()> {
+            Ok(())
+        }
+        fn safety_snapshot(&self, _vol: &Volume, _suffix: &str) -> Result<Option<String>> {
+            Ok(None)
+        }
+        fn overwrites(&self, _vol: &Volume) -> bool {
+            true
+        }
+        fn skips_zeros(&self, _vol: &Volume) -> bool {
+            false
+        }
+        fn receive_stream(
+            &self,
+            _vol: &Volume,
+            _src: &CmdSpec,
+            _opts: &WriteOpts,
+        ) -> Option<Result<()>> {
+            None
+        }
+        fn restored_from(&self, _vol: &Volume) -> Result<Option<String>> {
+            Ok(self.stamp.clone())
+        }
+        fn mark_restored(&self, _vol: &Volume, stamp: Option<&str>) -> Result<()> {
+            self.marks.lock().unwrap().push(stamp.map(str::to_string));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stamped_targets_are_skipped_unless_forced() {
+        let vol = Volume {
+            storage: "local-zfs".to_string(),
+            disk: "vm-1-disk-0".to_string(),
+            archive: "zfs_vm-1-disk-0_raw_abcd1234.img".to_string(),
+            device: PathBuf::from("/dev/zvol/tank/vm-1-disk-0"),
+            size_bytes: None,
+            meta: None,
+        };
+        let check = |stamp: Option<&str>, force| {
+            let marks = Arc::new(Mutex::new(Vec::new()));
+            let providers: Vec<Box<dyn Provider>> = vec![Box::new(Stamped {
+                stamp: stamp.map(str::to_string),
+                marks: marks.clone(),
+            })];
+            let write = needs_write(&providers, &vol, "a.img@1", force).unwrap();
+            let marks = marks.lock().unwrap().clone();
+            (write, marks)
+        };
+
+        assert_eq!(check(Some("a.img@1"), false), (false, vec![]));
+        assert_eq!(check(Some("a.img@1"), true), (true, vec![None]));
+        assert_eq!(check(Some("a.img@0"), false), (true, vec![None]));
+        assert_eq!(check(None, false), (true, vec![]));
+    }
+
+    #[test]
+    fn unrouted_archives_skip_routed_and_excluded() {
+        let file = |name: &str| crate::tooling::pbs::PbsFile {
+            filename: name.to_string(),
+            size: 1,
+            crypt_mode: None,
+        };
+        let snap = PbsSnapshot {
+            backup_id: "id".to_string(),
+            backup_time: 0,
+            files: vec![
+                file("index.json.blob"),
+                file("zfs_vm-1-disk-0_raw_abcd1234.img.fidx"),
+                file("zfs_vm-2-disk-0_raw_abcd1234.img.fidx"),
+                file("lvm_vm-3-disk-0_raw_abcd1234.img.fidx"),
+            ],
+        };
+        let available = vec!["zfs_vm-1-disk-0_raw_abcd1234.img.fidx".to_string()];
+        let exclude = parse_excludes(&["^lvm_".to_string()]).unwrap();
+        assert_eq!(
+            unrouted_archives(&snap, &available, &exclude),
+            ["zfs_vm-2-disk-0_raw_abcd1234.img.fidx"]
+        );
+    }
+
+    #[test]
+    fn pick_snapshot_by_index_and_age() {
+        let snap = |t: u64| PbsSnapshot {
+            backup_id: "id".to_string(),
+            backup_time: t,
+            files: Vec::new(),
+        };
+        let snaps = vec![snap(100), snap(300), snap(200)];
+        let pick =
+            |s: &str| pick_snapshot(&snaps, "id", parse_point(s).unwrap()).map(|s| s.backup_time);
+
+        assert_eq!(pick("latest").unwrap(), 300);
+        assert_eq!(pick("latest-0").unwrap(), 300);
+        assert_eq!(pick("latest-1").unwrap(), 200);
+        assert_eq!(pick("latest-2").unwrap(), 100);
+        assert!(pick("latest-3").is_err());
+        assert_eq!(pick("250").unwrap(), 200);
+        assert_eq!(pick("~1d").unwrap(), 300);
+        assert!(parse_point("latest-x").is_err());
+        assert!(parse_point("~3w").is_err());
+    }
+
+    #[test]
+    fn pick_snapshot_names_other_groups() {
+        let snaps = vec![PbsSnapshot {
+            backup_id: "oldhost-backup".to_string(),
+            backup_time: 100,
+            files: Vec::new(),
+        }];
+        let err = pick_snapshot(&snaps, "newhost-backup", RestorePoint::Latest).unwrap_err();
+        assert!(err.to_string().contains("oldhost-backup"), "{err}");
+        assert!(pick_snapshot(&snaps, "oldhost-backup", RestorePoint::Latest).is_ok());
+    }
+
+    #[test]
+    fn snapshot_paths_name_group_and_time() {
+        let snaps = vec![
+            PbsSnapshot {
+                backup_id: "pve2-backup".to_string(),
+                backup_time: 1_714_528_800,
+                files: Vec::new(),
+            },
+            PbsSnapshot {
+                backup_id: "pve2-backup".to_string(),
+                backup_time: 1_714_532_400,
+                files: Vec::new(),
+            },
+        ];
+        let point = parse_point("host/pve2-backup/2024-05-01T02:00:00Z").unwrap();
+        let snap = pick_snapshot(&snaps, "pve1-backup", point).unwrap();
+        assert_eq!(snap.backup_time, 1_714_528_800);
+
+        let missing = parse_point("host/pve2-backup/2024-05-01T02:30:00Z").unwrap();
+        assert!(pick_snapshot(&snaps, "pve1-backup", missing).is_err());
+        assert!(parse_point("vm/100/2024-05-01T02:00:00Z").is_err());
+        assert!(parse_point("host/2024-05-01T02:00:00Z").is_err());
+    }
+
+    #[test]
+    fn list_snapshots_filters_by_window_and_limit() {
+        let snap = |id: &str, t: u64| PbsSnapshot {
+            backup_id: id.to_string(),
+            backup_time: t,
+            files: Vec::new(),
+        };
+        let snaps = vec![
+            snap("b", 100),
+            snap("a", 100),
+            snap("a", 300),
+            snap("a", 200),
+            snap("b", 400),
+            snap("a", 400),
+        ];
+        let opts = ListSnapshotsOpts {
+            source: None,
+            backup_id: None,
+            all_groups: true,
+            since: Some(200),
+            until: Some(400),
+            limit: Some(2),
+            json: false,
+        };
+        let picked = |group| {
+            select_snapshots(&snaps, group, &opts)
+                .into_iter()
+                .map(|s| (s.backup_id.as_str(), s.backup_time))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(picked(None), [("a", 400), ("a", 300), ("b", 400)]);
+        assert_eq!(picked(Some("b")), [("b", 400)]);
+    }
+
+    #[test]
+    fn bad_exclude_regex_is_an_error() {
+        assert!(parse_excludes(&["(".to_string()]).is_err());
+    }
+
+    #[test]
+    fn pvcs_resolve_through_manifest_claims() {
+        let claim = |archive: &str, ns: &str, name: &str| ClaimRecord {
+            archive: archive.to_string(),
+            namespace: ns.to_string(),
+            name: name.to_string(),
+            pv: None,
+        };
+        let claims = vec![
+            claim("zfs_vm-9999-pvc-a_raw_11111111.img", "db", "data-pg-0"),
+            claim("zfs_vm-9999-pvc-b_raw_22222222.img", "web", "uploads"),
+        ];
+        let pvc = parse_pvc("web/uploads").unwrap();
+        assert_eq!(
+            archives_for_pvcs(&claims, &[pvc]).unwrap(),
+            vec!["zfs_vm-9999-pvc-b_raw_22222222.img"]
+        );
+        let missing = parse_pvc("web/cache").unwrap();
+        assert!(archives_for_pvcs(&claims, std::slice::from_ref(&missing)).is_err());
+        assert!(archives_for_pvcs(&[], &[missing]).is_err());
+        for bad in ["uploads", "/uploads", "web/", "a/b/c"] {
+            assert!(parse_pvc(bad).is_err(), "{bad}");
+        }
+    }
+}
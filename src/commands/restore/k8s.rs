@@ -0,0 +1,248 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::{
+    manifest::{ClaimRecord, PvRecord},
+    volume::Volume,
+};
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Object<'a, S> {
+    api_version: &'static str,
+    kind: &'static str,
+    metadata: Metadata<'a>,
+    spec: S,
+}
+
+#[derive(Serialize)]
+struct Metadata<'a> {
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    namespace: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PvSpec<'a> {
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    capacity: BTreeMap<&'static str, &'a str>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    access_modes: &'a [String],
+    persistent_volume_reclaim_policy: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    storage_class_name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    volume_mode: Option<&'a str>,
+    claim_ref: ClaimRef<'a>,
+    csi: Csi<'a>,
+}
+
+#[derive(Serialize)]
+struct ClaimRef<'a> {
+    namespace: &'a str,
+    name: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Csi<'a> {
+    driver: &'a str,
+    volume_handle: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fs_type: Option<&'a str>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    volume_attributes: &'a BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PvcSpec<'a> {
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    access_modes: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    storage_class_name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    volume_mode: Option<&'a str>,
+    volume_name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resources: Option<Resources<'a>>,
+}
+
+#[derive(Serialize)]
+struct Resources<'a> {
+    requests: BTreeMap<&'static str, &'a str>,
+}
+
+/// A PV pre-bound to its original claim and that claim, as a YAML stream, for every restored
+/// volume with a claim recorded in the manifest. Volumes whose PV was not recorded fully are
+/// returned as `(archive, reason)`.
+pub(super) fn pv_manifests<'a>(
+    claims: &[ClaimRecord],
+    restored: &'a [Volume],
+) -> Result<(String, Vec<(&'a str, &'static str)>)> {
+    let mut docs = Vec::new();
+    let mut skipped = Vec::new();
+    for v in restored {
+        // PBS lists the archives the backup recorded with a `.fidx` suffix.
+        let archive = v.archive.strip_suffix(".fidx").unwrap_or(&v.archive);
+        let Some(c) = claims.iter().find(|c| c.archive == archive) else {
+            continue;
+        };
+        let Some(pv) = &c.pv else {
+            skipped.push((v.archive.as_str(), "manifest records no PV for the claim"));
+            continue;
+        };
+        let (Some(driver), Some(handle)) = (&pv.details.driver, &pv.volume_handle) else {
+            skipped.push((v.archive.as_str(), "PV was not provisioned by a CSI driver"));
+            continue;
+        };
+        docs.push(pv_yaml(c, pv, driver, restored_handle(handle, v))?);
+        docs.push(pvc_yaml(c, pv)?);
+    }
+    Ok((docs.join("---\n"), skipped))
+}
+
+/// The recorded handle with its last two parts, storage and disk as Proxmox CSI writes them,
+/// pointing at the restored volume.
+fn restored_handle(handle: &str, v: &Volume) -> String {
+    let parts: Vec<&str> = handle.split('/').collect();
+    match parts.len() {
+        0 | 1 => v.disk.clone(),
+        n => format!("{}/{}/{}", parts[..n - 2].join("/"), v.storage, v.disk)
+            .trim_start_matches('/')
+            .to_string(),
+    }
+}
+
+fn pv_yaml(c: &ClaimRecord, pv: &PvRecord, driver: &str, handle: String) -> Result<String> {
+    let d = &pv.details;
+    serde_norway::to_string(&Object {
+        api_version: "v1",
+        kind: "PersistentVolume",
+        metadata: Metadata {
+            name: &pv.name,
+            namespace: None,
+        },
+        spec: PvSpec {
+            capacity: d.capacity.iter().map(|s| ("storage", s.as_str())).collect(),
+            access_modes: &d.access_modes,
+            persistent_volume_reclaim_policy: "Retain",
+            storage_class_name: d.storage_class.as_deref(),
+            volume_mode: d.volume_mode.as_deref(),
+            claim_ref: ClaimRef {
+                namespace: &c.namespace,
+                name: &c.name,
+            },
+            csi: Csi {
+                driver,
+                volume_handle: handle,
+                fs_type: d.fs_type.as_deref(),
+                volume_attributes: &d.volume_attributes,
+            },
+        },
+    })
+    .with_context(|| format!("serialize PV {}", pv.name))
+}
+
+fn pvc_yaml(c: &ClaimRecord, pv: &PvRecord) -> Result<String> {
+    let d = &pv.details;
+    serde_norway::to_string(&Object {
+        api_version: "v1",
+        kind: "PersistentVolumeClaim",
+        metadata: Metadata {
+            name: &c.name,
+            namespace: Some(&c.namespace),
+        },
+        spec: PvcSpec {
+            access_modes: &d.access_modes,
+            storage_class_name: d.storage_class.as_deref(),
+            volume_mode: d.volume_mode.as_deref(),
+            volume_name: &pv.name,
+            resources: d.capacity.as_deref().map(|s| Resources {
+                requests: BTreeMap::from([("storage", s)]),
+            }),
+        },
+    })
+    .with_context(|| format!("serialize PVC {}/{}", c.namespace, c.name))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::manifest::PvDetails;
+
+    #[test]
+    fn pv_and_claim_point_at_the_restored_volume() {
+        let archive = "zfs_vm-9999-pvc-0a1b_noext_11111111.img";
+        let claims = vec![
+            ClaimRecord {
+                archive: archive.to_string(),
+                namespace: "db".to_string(),
+                name: "data-pg-0".to_string(),
+                pv: Some(PvRecord {
+                    name: "pvc-0a1b".to_string(),
+                    volume_handle: Some("pve/pve1/local-zfs/vm-9999-pvc-0a1b".to_string()),
+                    details: PvDetails {
+                        driver: Some("csi.proxmox.sinextra.dev".to_string()),
+                        capacity: Some("10Gi".to_string()),
+                        access_modes: vec!["ReadWriteOnce".to_string()],
+                        storage_class: Some("proxmox-zfs".to_string()),
+                        ..PvDetails::default()
+                    },
+                }),
+            },
+            ClaimRecord {
+                archive: "zfs_vm-9999-pvc-old_noext_22222222.img".to_string(),
+                namespace: "db".to_string(),
+                name: "old".to_string(),
+                pv: None,
+            },
+        ];
+        let vol = |archive: &str, disk: &str| Volume {
+            storage: "tank-restore".to_string(),
+            disk: disk.to_string(),
+            archive: archive.to_string(),
+            device: PathBuf::from(format!("/dev/zvol/tank/restore/{disk}")),
+            size_bytes: None,
+            meta: None,
+        };
+        let restored = vec![
+            vol(&format!("{archive}.fidx"), "vm-9999-pvc-0a1b"),
+            vol("zfs_vm-9999-pvc-old_noext_22222222.img", "vm-9999-pvc-old"),
+            vol("zfs_vm-100-disk-0_noext_33333333.img", "vm-100-disk-0"),
+        ];
+
+        let (yaml, skipped) = pv_manifests(&claims, &restored).unwrap();
+        assert_eq!(
+            skipped,
+            [(
+                restored[1].archive.as_str(),
+                "manifest records no PV for the claim"
+            )]
+        );
+        let docs: Vec<serde_norway::Value> = yaml
+            .split("---\n")
+            .map(|d| serde_norway::from_str(d).unwrap())
+            .collect();
+        assert_eq!(docs.len(), 2);
+        assert_eq!(
+            docs[0]["spec"]["csi"]["volumeHandle"].as_str(),
+            Some("pve/pve1/tank-restore/vm-9999-pvc-0a1b")
+        );
+        assert_eq!(
+            docs[0]["spec"]["claimRef"]["name"].as_str(),
+            Some("data-pg-0")
+        );
+        assert_eq!(docs[1]["kind"].as_str(), Some("PersistentVolumeClaim"));
+        assert_eq!(docs[1]["spec"]["volumeName"].as_str(), Some("pvc-0a1b"));
+        assert_eq!(
+            docs[1]["spec"]["resources"]["requests"]["storage"].as_str(),
+            Some("10Gi")
+        );
+    }
+}
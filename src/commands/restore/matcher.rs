@@ -3,48 +3,224 @@ use std::collections::HashMap;
 use anyhow::Result;
 use regex::Regex;
 
-use crate::{config::Config, tooling::pbs::PbsFile};
+use crate::{
+    config::{Config, WriteOverride},
+    tooling::pbs::PbsFile,
+};
+
+struct Rule {
+    /// Position in `[[restore.rules]]`.
+    index: usize,
+    re: Option<Regex>,
+    target: String,
+    write: Option<WriteOverride>,
+}
+
+/// Where an archive is restored and why.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Route<'a> {
+    /// Pinned by a `--plan` file.
+    Pinned(&'a str),
+    /// Routed by `[[restore.rules]]` entry `index`; `regex` is `None` for a catch-all rule.
+    Rule {
+        index: usize,
+        regex: Option<&'a str>,
+        target: &'a str,
+    },
+    /// No rule matched; `[restore] default_target`.
+    Default(&'a str),
+    /// No rule matched and there is no default target.
+    Unrouted,
+}
+
+impl<'a> Route<'a> {
+    pub fn target(&self) -> Option<&'a str> {
+        match *self {
+            Route::Pinned(t) | Route::Default(t) | Route::Rule { target: t, .. } => Some(t),
+            Route::Unrouted => None,
+        }
+    }
+}
+
+/// One `[[restore.rules]]` entry of the archive's provider, checked against the archive.
+pub struct RuleCheck<'a> {
+    pub index: usize,
+    pub regex: Option<&'a str>,
+    pub target: &'a str,
+    pub matched: bool,
+}
 
 pub struct RestoreMatcher {
-    rules: HashMap<String, Vec<(Option<Regex>, String)>>,
+    rules: HashMap<String, Vec<Rule>>,
     default_target: Option<String>,
+    /// Archive → target pins from a `--plan` file; they win over every rule.
+    pinned: HashMap<String, String>,
 }
 
 impl RestoreMatcher {
     pub fn new(cfg: &Config) -> Result<Self> {
-        let mut rules: HashMap<String, Vec<(Option<Regex>, String)>> = HashMap::new();
-        for r in &cfg.restore.rules {
+        let mut rules: HashMap<String, Vec<Rule>> = HashMap::new();
+        for (index, r) in cfg.restore.rules.iter().enumerate() {
             let prov = r.match_provider.trim().to_string();
-            let tgt = r.target.trim().to_string();
+            let target = r.target.trim().to_string();
             let re = match r.match_archive_regex.as_deref() {
                 Some(p) if !p.is_empty() => Some(Regex::new(p)?),
                 _ => None,
             };
 
-            rules.entry(prov).or_default().push((re, tgt));
+            rules.entry(prov).or_default().push(Rule {
+                index,
+                re,
+                target,
+                write: r.write.clone(),
+            });
         }
 
         Ok(Self {
             rules,
             default_target: cfg.restore.default_target.clone(),
+            pinned: HashMap::new(),
         })
     }
 
+    pub fn pin(&mut self, archive: &str, target: &str) {
+        self.pinned.insert(archive.to_string(), target.to_string());
+    }
+
     pub fn pick_target_name<'a>(&'a self, source_provider: &str, f: &PbsFile) -> Option<&'a str> {
-        if let Some(v) = self.rules.get(source_provider) {
-            for (re, tgt) in v {
-                if re.as_ref().is_some_and(|r| r.is_match(&f.filename)) {
-                    return Some(tgt.as_str());
-                }
-            }
+        self.route(source_provider, &f.filename).target()
+    }
 
-            for (re, tgt) in v {
-                if re.is_none() {
-                    return Some(tgt.as_str());
-                }
-            }
+    pub fn route(&self, source_provider: &str, archive: &str) -> Route<'_> {
+        if let Some(target) = self.pinned.get(archive) {
+            return Route::Pinned(target);
+        }
+        match self.pick_rule(source_provider, archive) {
+            Some(r) => Route::Rule {
+                index: r.index,
+                regex: r.re.as_ref().map(Regex::as_str),
+                target: &r.target,
+            },
+            None => match self.default_target.as_deref() {
+                Some(t) => Route::Default(t),
+                None => Route::Unrouted,
+            },
         }
+    }
+
+    /// Every rule of `source_provider` in config order, with whether it matches `archive`.
+    /// Catch-all rules always match but lose to any matching regex rule.
+    pub fn check_rules(&self, source_provider: &str, archive: &str) -> Vec<RuleCheck<'_>> {
+        self.rules
+            .get(source_provider)
+            .map(|v| {
+                v.iter()
+                    .map(|r| RuleCheck {
+                        index: r.index,
+                        regex: r.re.as_ref().map(Regex::as_str),
+                        target: &r.target,
+                        matched: r.re.as_ref().is_none_or(|re| re.is_match(archive)),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Writer overrides of the rule that routed `archive`; `None` for default-target archives.
+    pub fn write_override(&self, source_provider: &str, archive: &str) -> Option<&WriteOverride> {
+        if self.pinned.contains_key(archive) {
+            return None;
+        }
+        self.pick_rule(source_provider, archive)
+            .and_then(|r| r.write.as_ref())
+    }
+
+    fn pick_rule(&self, source_provider: &str, filename: &str) -> Option<&Rule> {
+        let v = self.rules.get(source_provider)?;
+        v.iter()
+            .find(|r| r.re.as_ref().is_some_and(|re| re.is_match(filename)))
+            .or_else(|| v.iter().find(|r| r.re.is_none()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::config::{Backup, Events, Pbs, Pve, Restore, RestoreRule};
+
+    const ARCHIVE: &str = "zfs_vm-101-disk-0_raw_abcd1234.img.fidx";
+
+    fn rule(regex: Option<&str>, target: &str) -> RestoreRule {
+        RestoreRule {
+            match_provider: "zfs".to_string(),
+            match_archive_regex: regex.map(str::to_string),
+            target: target.to_string(),
+            write: None,
+        }
+    }
+
+    fn matcher(rules: Vec<RestoreRule>, default_target: Option<&str>) -> RestoreMatcher {
+        let cfg = Config {
+            pbs: Pbs {
+                repos: HashMap::new(),
+                keyfile: None,
+                password: None,
+                key_passphrase: None,
+                create_ns: Default::default(),
+                ns: None,
+                backup_id: "id".to_string(),
+            },
+            pve: Pve::default(),
+            events: Events::default(),
+            log: Default::default(),
+            state: Default::default(),
+            backup: Backup::default(),
+            restore: Restore {
+                rules,
+                default_target: default_target.map(str::to_string),
+                ..Restore::default()
+            },
+            nodes: BTreeMap::new(),
+        };
+        RestoreMatcher::new(&cfg).unwrap()
+    }
+
+    #[test]
+    fn routes_by_regex_then_catch_all_then_default() {
+        let m = matcher(
+            vec![
+                rule(None, "bulk"),
+                rule(Some("^zfs_vm-100-"), "fast"),
+                rule(Some("^zfs_vm-101-"), "slow"),
+            ],
+            Some("spare"),
+        );
+        assert_eq!(
+            m.route("zfs", ARCHIVE),
+            Route::Rule {
+                index: 2,
+                regex: Some("^zfs_vm-101-"),
+                target: "slow"
+            }
+        );
+        let matched: Vec<bool> = m
+            .check_rules("zfs", ARCHIVE)
+            .iter()
+            .map(|c| c.matched)
+            .collect();
+        assert_eq!(matched, [true, false, true]);
+        assert_eq!(
+            m.route("zfs", "zfs_vm-7_raw_ffff.img").target(),
+            Some("bulk")
+        );
+        assert_eq!(
+            m.route("lvm", "lvm_vm-7_raw_ffff.img"),
+            Route::Default("spare")
+        );
 
-        self.default_target.as_deref()
+        let m = matcher(vec![rule(Some("^zfs_vm-100-"), "fast")], None);
+        assert_eq!(m.route("zfs", ARCHIVE), Route::Unrouted);
     }
 }
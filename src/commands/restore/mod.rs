@@ -1,11 +1,29 @@
+use std::path::PathBuf;
+
 use anyhow::Result;
 use clap::{Args, Subcommand};
 
-use crate::AppCtx;
+use crate::{AppCtx, commands::NsArg, config::Config};
 
 mod executor;
+mod k8s;
 mod matcher;
-mod providers;
+mod mount;
+mod placement;
+mod plan;
+pub mod providers;
+
+pub use executor::{
+    ArchiveResult, ListArchivesOpts, ListSnapshotsOpts, ManifestOpts, RestoreImpact, RestorePoint,
+    RunOpts, VerifyOpts, explain, list_archives, list_snapshots, parse_point, restore_run,
+    show_manifest, verify,
+};
+pub(crate) use executor::{
+    fetch_manifest, parse_excludes, pick_snapshot, select_archives_exact_from,
+};
+pub use matcher::{Route, RuleCheck};
+pub use mount::{ExtractOpts, MountOpts, extract, mount, unmount};
+pub use plan::RestorePlan;
 
 #[derive(Debug, Args)]
 pub struct RestoreArgs {
@@ -23,17 +41,65 @@ impl RestoreArgs {
 pub enum RestoreCmd {
     ListSnapshots(ListSnapshotsArgs),
     ListArchives(ListArchivesArgs),
+    Manifest(ManifestArgs),
     Run(RestoreRunArgs),
+    Verify(VerifyArgs),
+    /// Show which restore rule routes an archive, and why
+    Explain(ExplainArgs),
+    /// Mount an archive's filesystem read-only to copy single files out of it
+    Mount(MountArgs),
+    /// Unmount an archive mounted with `restore mount --detach`
+    Unmount(UnmountArgs),
+    /// Copy single files or directories out of an archive without restoring it
+    Extract(ExtractArgs),
 }
 
 #[derive(Args, Debug, Clone)]
 pub struct ListSnapshotsArgs {
     #[arg(long)]
     pub source: Option<String>,
+    #[command(flatten)]
+    pub ns: NsArg,
+    /// List this backup group instead of `[pbs].backup_id`
+    #[arg(long, conflicts_with = "all_groups")]
+    pub backup_id: Option<String>,
+    /// List snapshots of every backup group in the repo
+    #[arg(long)]
+    pub all_groups: bool,
+    /// Only snapshots taken at or after this time (epoch, RFC3339 or ~<age>)
+    #[arg(long, value_name = "TIME")]
+    pub since: Option<String>,
+    /// Only snapshots taken at or before this time (epoch, RFC3339 or ~<age>)
+    #[arg(long, value_name = "TIME")]
+    pub until: Option<String>,
+    /// Show at most the N most recent snapshots of each group
+    #[arg(long, value_name = "N")]
+    pub limit: Option<usize>,
+    /// Print the snapshots as JSON instead of a table
+    #[arg(long)]
+    pub json: bool,
 }
 
 #[derive(Args, Debug, Clone)]
 pub struct ListArchivesArgs {
+    #[arg(long)]
+    pub source: Option<String>,
+    #[command(flatten)]
+    pub ns: NsArg,
+    #[arg(long, default_value = "latest")]
+    pub snapshot: String,
+    /// Backup group to list instead of `[pbs].backup_id`
+    #[arg(long)]
+    pub backup_id: Option<String>,
+    #[arg(long)]
+    pub exclude: Vec<String>,
+    /// Also list snapshot files that are neither pvtools archives nor PBS metadata
+    #[arg(long)]
+    pub show_foreign: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ManifestArgs {
     #[arg(long)]
     pub source: Option<String>,
     #[arg(long, default_value = "latest")]
@@ -44,31 +110,172 @@ pub struct ListArchivesArgs {
 pub struct RestoreRunArgs {
     #[arg(long)]
     pub source: Option<String>,
+    #[command(flatten)]
+    pub ns: NsArg,
     #[arg(long, default_value = "latest")]
     pub snapshot: String,
+    /// Restore from this backup group instead of `[pbs].backup_id`, e.g. another node's
+    #[arg(long)]
+    pub backup_id: Option<String>,
     #[arg(long = "archive")]
     pub archives: Vec<String>,
+    /// Restore the archive(s) of a Kubernetes claim, as `namespace/name` (can be repeated)
+    #[arg(long = "pvc", conflicts_with = "plan")]
+    pub pvcs: Vec<String>,
+    #[arg(long)]
+    pub exclude: Vec<String>,
     #[arg(long)]
     pub all: bool,
+    /// With --all, fail if any archive of the snapshot has no restore target
+    #[arg(long, requires = "all")]
+    pub strict_routing: bool,
+    /// YAML (or .toml) file mapping archives to restore targets; replaces --archive/--all
+    #[arg(long, conflicts_with_all = ["archives", "all", "exclude"])]
+    pub plan: Option<PathBuf>,
     #[arg(long)]
     pub dry_run: bool,
+    /// Write the commands the dry run would execute to this file as a shell script
+    #[arg(long, requires = "dry_run")]
+    pub emit_script: Option<PathBuf>,
+    #[arg(long)]
+    pub fail_fast: bool,
+    /// Snapshot existing targets before overwriting them (also `[restore] safety_snapshot`)
+    #[arg(long)]
+    pub safety_snapshot: bool,
+    /// Rewrite targets already stamped as restored from the same archive and snapshot
+    #[arg(long)]
+    pub force: bool,
+    /// Run `qm rescan` for the VMs owning restored `vm-<vmid>-*` disks, so new disks show up
+    /// in their config
+    #[arg(long)]
+    pub attach_to_vm: bool,
+    /// Write a PersistentVolume and claim for every restored volume of a recorded PVC to this
+    /// YAML file
+    #[arg(long, value_name = "FILE")]
+    pub k8s_manifests: Option<PathBuf>,
+    /// Also `kubectl apply` the --k8s-manifests file
+    #[arg(long, requires = "k8s_manifests")]
+    pub k8s_apply: bool,
+    /// Write block size, e.g. 16M; overrides `[restore] write` and the rules
+    #[arg(long)]
+    pub bs: Option<String>,
+    /// Restore without asking for confirmation (required when stdin is not a terminal)
+    #[arg(long, short = 'y')]
+    pub yes: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ExplainArgs {
+    /// Archive name as listed by `list-archives`
+    #[arg(long)]
+    pub archive: String,
+}
+
+impl ExplainArgs {
+    /// Needs only the config, so `main` runs it before connecting anywhere.
+    pub fn run(&self, cfg: &Config) -> Result<()> {
+        executor::explain(cfg, self.archive.trim())
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct VerifyArgs {
+    #[arg(long)]
+    pub source: Option<String>,
+    #[arg(long, default_value = "latest")]
+    pub snapshot: String,
+    #[arg(long = "archive")]
+    pub archives: Vec<String>,
+    #[arg(long)]
+    pub exclude: Vec<String>,
+    #[arg(long)]
+    pub all: bool,
+    /// Compare the archive byte for byte with this device instead of discarding it
+    #[arg(long)]
+    pub device: Option<PathBuf>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct MountArgs {
+    #[arg(long)]
+    pub source: Option<String>,
+    #[arg(long, default_value = "latest")]
+    pub snapshot: String,
+    /// Mount from this backup group instead of `[pbs].backup_id`
+    #[arg(long)]
+    pub backup_id: Option<String>,
+    /// Archive name as listed by `list-archives`
+    #[arg(long)]
+    pub archive: String,
+    /// Leave the archive mounted and exit; undo with `restore unmount`
+    #[arg(long)]
+    pub detach: bool,
+    /// Directory to mount on; created if missing
+    pub mountpoint: PathBuf,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct UnmountArgs {
+    pub mountpoint: PathBuf,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ExtractArgs {
+    #[arg(long)]
+    pub source: Option<String>,
+    #[arg(long, default_value = "latest")]
+    pub snapshot: String,
+    /// Extract from this backup group instead of `[pbs].backup_id`
+    #[arg(long)]
+    pub backup_id: Option<String>,
+    /// Archive name as listed by `list-archives`
+    #[arg(long)]
+    pub archive: String,
+    /// File or directory inside the archive's filesystem (can be repeated)
+    #[arg(long = "path", required = true)]
+    pub paths: Vec<PathBuf>,
+    /// Directory to copy into; created if missing
+    #[arg(long)]
+    pub to: PathBuf,
 }
 
 impl RestoreCmd {
+    /// The `--ns` given to the subcommand, where it takes one.
+    pub fn ns(&self) -> Option<&str> {
+        match self {
+            RestoreCmd::ListSnapshots(args) => args.ns.get(),
+            RestoreCmd::ListArchives(args) => args.ns.get(),
+            RestoreCmd::Run(args) => args.ns.get(),
+            _ => None,
+        }
+    }
+
     pub fn run(&self, ctx: &AppCtx) -> Result<()> {
         match self {
             RestoreCmd::ListSnapshots(args) => {
-                let opts = executor::ListSnapshotsOpts::from(args);
+                let opts = executor::ListSnapshotsOpts::try_from(args)?;
                 executor::list_snapshots(ctx, opts)
             }
             RestoreCmd::ListArchives(args) => {
                 let opts = executor::ListArchivesOpts::try_from(args)?;
                 executor::list_archives(ctx, opts)
             }
+            RestoreCmd::Manifest(args) => {
+                let opts = executor::ManifestOpts::try_from(args)?;
+                executor::show_manifest(ctx, opts)
+            }
             RestoreCmd::Run(args) => {
                 let opts = executor::RunOpts::try_from(args)?;
                 executor::restore_run(ctx, opts)
             }
+            RestoreCmd::Verify(args) => {
+                let opts = executor::VerifyOpts::try_from(args)?;
+                executor::verify(ctx, opts)
+            }
+            RestoreCmd::Explain(args) => args.run(&ctx.cfg),
+            RestoreCmd::Mount(args) => mount::mount(ctx, MountOpts::try_from(args)?),
+            RestoreCmd::Unmount(args) => mount::unmount(ctx, &args.mountpoint),
+            RestoreCmd::Extract(args) => mount::extract(ctx, ExtractOpts::try_from(args)?),
         }
     }
 }
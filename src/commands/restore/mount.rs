@@ -0,0 +1,305 @@
+use std::{
+    path::{Component, Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+
+use super::executor::{
+    RestorePoint, parse_point, pick_snapshot, select_archives_exact_from, verifiable_archives,
+};
+use crate::{
+    AppCtx,
+    tooling::pbs::snapshot_path,
+    utils::{
+        naming::{parse_archive_name, send_stream_leaf},
+        signal,
+    },
+};
+
+pub struct MountOpts {
+    pub source: Option<String>,
+    pub snapshot: RestorePoint,
+    pub backup_id: Option<String>,
+    pub archive: String,
+    pub mountpoint: PathBuf,
+    pub detach: bool,
+}
+
+impl TryFrom<&super::MountArgs> for MountOpts {
+    type Error = anyhow::Error;
+    fn try_from(value: &super::MountArgs) -> Result<Self> {
+        Ok(Self {
+            source: value.source.clone(),
+            snapshot: parse_point(&value.snapshot)?,
+            backup_id: value.backup_id.as_ref().map(|id| id.trim().to_string()),
+            archive: value.archive.trim().to_string(),
+            mountpoint: value.mountpoint.clone(),
+            detach: value.detach,
+        })
+    }
+}
+
+pub struct ExtractOpts {
+    pub source: Option<String>,
+    pub snapshot: RestorePoint,
+    pub backup_id: Option<String>,
+    pub archive: String,
+    pub paths: Vec<PathBuf>,
+    pub to: PathBuf,
+}
+
+impl TryFrom<&super::ExtractArgs> for ExtractOpts {
+    type Error = anyhow::Error;
+    fn try_from(value: &super::ExtractArgs) -> Result<Self> {
+        Ok(Self {
+            source: value.source.clone(),
+            snapshot: parse_point(&value.snapshot)?,
+            backup_id: value.backup_id.as_ref().map(|id| id.trim().to_string()),
+            archive: value.archive.trim().to_string(),
+            paths: value
+                .paths
+                .iter()
+                .map(|p| inner_path(p))
+                .collect::<Result<_>>()?,
+            to: value.to.clone(),
+        })
+    }
+}
+
+/// Maps an archive to a loop device and mounts its filesystem read-only. Unless detached,
+/// stays in the foreground and unmounts on Ctrl-C or SIGTERM.
+pub fn mount(ctx: &AppCtx, opts: MountOpts) -> Result<()> {
+    let dir = &opts.mountpoint;
+    if let Some(dev) = ctx.tools.mount().source_of(dir)? {
+        bail!(
+            "{} is in use: {} is mounted on it",
+            dir.display(),
+            dev.display()
+        );
+    }
+    ctx.tools.fs().ensure_dir(dir)?;
+
+    let (mapped, what) = map_one(
+        ctx,
+        opts.source.as_deref(),
+        opts.snapshot,
+        opts.backup_id.as_deref(),
+        &opts.archive,
+    )?;
+    let mapped = mapped.mount_on(dir)?;
+
+    if opts.detach {
+        std::mem::forget(mapped);
+        tracing::info!(
+            "{what} mounted read-only on {}; detach it with `pvtools restore unmount {}`",
+            dir.display(),
+            dir.display()
+        );
+        return Ok(());
+    }
+    tracing::info!(
+        "{what} mounted read-only on {}; press Ctrl-C to unmount",
+        dir.display()
+    );
+    while signal::interrupted().is_none() {
+        thread::sleep(Duration::from_millis(200));
+    }
+    Ok(())
+}
+
+/// Copies files or directories out of an archive's filesystem into `opts.to`, each under its
+/// own name, through a read-only mount on a scratch directory.
+pub fn extract(ctx: &AppCtx, opts: ExtractOpts) -> Result<()> {
+    if opts.paths.is_empty() {
+        bail!("nothing to extract: pass at least one --path");
+    }
+    let fs = ctx.tools.fs();
+    fs.ensure_dir(&opts.to)?;
+    // Declared before the mapping so it is removed only after the unmount.
+    let scratch = tempfile::Builder::new()
+        .prefix("pvtools-extract-")
+        .tempdir()
+        .context("create scratch mount dir")?;
+
+    let (mapped, what) = map_one(
+        ctx,
+        opts.source.as_deref(),
+        opts.snapshot,
+        opts.backup_id.as_deref(),
+        &opts.archive,
+    )?;
+    let root = scratch.path();
+    let _mapped = mapped.mount_on(root)?;
+    tracing::info!("extracting {} path(s) from {what}", opts.paths.len());
+
+    for p in &opts.paths {
+        signal::check()?;
+        let src = contained(root, p)?;
+        fs.copy_into(&src, &opts.to)
+            .with_context(|| format!("extract /{}", p.display()))?;
+        tracing::info!("extracted /{} to {}", p.display(), opts.to.display());
+    }
+    Ok(())
+}
+
+/// Unmounts an archive mounted with `--detach` and detaches its loop device.
+pub fn unmount(ctx: &AppCtx, dir: &Path) -> Result<()> {
+    let mount = ctx.tools.mount();
+    let dev = mount
+        .source_of(dir)?
+        .with_context(|| format!("nothing is mounted on {}", dir.display()))?;
+    if !dev.to_string_lossy().starts_with("/dev/loop") {
+        bail!(
+            "{} is mounted from {}, not from a mapped archive",
+            dir.display(),
+            dev.display()
+        );
+    }
+    mount.umount(dir)?;
+    ctx.tools.pbs().unmap(&dev)?;
+    tracing::info!("unmounted {} and unmapped {}", dir.display(), dev.display());
+    Ok(())
+}
+
+/// Maps the one archive `archive` selects in the chosen snapshot, and names it for logs.
+fn map_one<'a>(
+    ctx: &'a AppCtx,
+    source: Option<&str>,
+    point: RestorePoint,
+    backup_id: Option<&str>,
+    archive: &str,
+) -> Result<(Mapped<'a>, String)> {
+    let repo = ctx.cfg.resolve_backup_repo(source)?;
+    let ns = repo.ns.as_deref();
+    let snaps = ctx.tools.pbs().snapshots(repo, ns)?;
+    let backup_id = backup_id.unwrap_or(&ctx.cfg.pbs.backup_id);
+    let snap = pick_snapshot(&snaps, backup_id, point)?;
+
+    let requested = [archive.to_string()];
+    let archive =
+        match select_archives_exact_from(&verifiable_archives(snap), &requested, false, &[])?
+            .as_slice()
+        {
+            [a] => a.clone(),
+            many => bail!(
+                "--archive must select exactly one archive, got {}",
+                many.len()
+            ),
+        };
+    let (_, leaf, _) = parse_archive_name(&archive)?;
+    if send_stream_leaf(&leaf).is_some() {
+        bail!("{archive} is a zfs send stream, not a disk image; restore it to inspect it");
+    }
+
+    let snap_path = snapshot_path(&snap.backup_id, snap.backup_time)?;
+    let device = ctx.tools.pbs().map(repo, ns, &snap_path, &archive)?;
+    let mapped = Mapped {
+        ctx,
+        device,
+        mountpoint: None,
+    };
+    Ok((mapped, format!("{archive} of {snap_path}")))
+}
+
+/// `path` relative to the root of the archive's filesystem; `..` is refused.
+fn inner_path(path: &Path) -> Result<PathBuf> {
+    let mut out = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::RootDir | Component::CurDir => {}
+            Component::Normal(p) => out.push(p),
+            _ => bail!("--path {}: must not contain '..'", path.display()),
+        }
+    }
+    if out.as_os_str().is_empty() {
+        bail!("--path {}: names the filesystem root", path.display());
+    }
+    Ok(out)
+}
+
+/// `root/inner`, refusing paths whose directories resolve outside `root` through symlinks in
+/// the image. The last component itself is copied as it is, symlink or not.
+fn contained(root: &Path, inner: &Path) -> Result<PathBuf> {
+    let src = root.join(inner);
+    let parent = src.parent().unwrap_or(root);
+    let resolved = parent
+        .canonicalize()
+        .with_context(|| format!("/{} not found in the archive", inner.display()))?;
+    if !resolved.starts_with(root.canonicalize()?) {
+        bail!(
+            "/{} leads outside the archive's filesystem",
+            inner.display()
+        );
+    }
+    if src.symlink_metadata().is_err() {
+        bail!("/{} not found in the archive", inner.display());
+    }
+    Ok(src)
+}
+
+/// A mapped archive, and where it is mounted; undone on drop.
+struct Mapped<'a> {
+    ctx: &'a AppCtx,
+    device: PathBuf,
+    mountpoint: Option<PathBuf>,
+}
+
+impl Mapped<'_> {
+    fn mount_on(mut self, dir: &Path) -> Result<Self> {
+        self.ctx.tools.mount().mount_ro(&self.device, dir)?;
+        self.mountpoint = Some(dir.to_path_buf());
+        Ok(self)
+    }
+}
+
+impl Drop for Mapped<'_> {
+    fn drop(&mut self) {
+        signal::shielded(|| {
+            if let Some(dir) = &self.mountpoint {
+                match self.ctx.tools.mount().umount(dir) {
+                    Ok(()) => tracing::info!("unmounted {}", dir.display()),
+                    Err(e) => {
+                        tracing::warn!("[cleanup] {e:#}");
+                        return;
+                    }
+                }
+            }
+            if let Err(e) = self.ctx.tools.pbs().unmap(&self.device) {
+                tracing::warn!("[cleanup] {e:#}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn extract_paths_stay_inside_the_image() {
+        assert_eq!(
+            inner_path(Path::new("/etc/./app.conf")).unwrap(),
+            PathBuf::from("etc/app.conf")
+        );
+        assert!(inner_path(Path::new("/etc/../../root")).is_err());
+        assert!(inner_path(Path::new("/")).is_err());
+
+        let root = TempDir::new().unwrap();
+        let r = root.path();
+        std::fs::create_dir(r.join("etc")).unwrap();
+        std::fs::write(r.join("etc/app.conf"), "x").unwrap();
+        symlink("/etc", r.join("hostetc")).unwrap();
+        symlink("app.conf", r.join("etc/link")).unwrap();
+
+        assert!(contained(r, Path::new("etc/app.conf")).is_ok());
+        assert!(contained(r, Path::new("etc/link")).is_ok());
+        assert!(contained(r, Path::new("hostetc/passwd")).is_err());
+        assert!(contained(r, Path::new("etc/missing")).is_err());
+    }
+}
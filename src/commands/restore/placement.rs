@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    config::{Config, RestoreTarget},
+    tooling::pvesh::{ClusterStorage, Storage},
+};
+
+/// Which PVE nodes can write each restore target, from `/storage` and `/cluster/resources`.
+pub struct TargetPlacement {
+    local: Option<String>,
+    nodes: BTreeMap<String, Vec<String>>,
+}
+
+impl TargetPlacement {
+    pub fn resolve(cfg: &Config, storages: &[Storage], cluster: &[ClusterStorage]) -> Self {
+        let mut nodes = BTreeMap::new();
+        for (tname, tgt) in &cfg.restore.targets {
+            let Some(id) = storage_id(tgt, storages) else {
+                continue;
+            };
+            let mut hosts: Vec<String> = cluster
+                .iter()
+                .filter(|c| c.storage == id && c.is_available())
+                .map(|c| c.node.clone())
+                .collect();
+            hosts.sort_unstable();
+            nodes.insert(tname.clone(), hosts);
+        }
+        Self {
+            local: cfg.pve.node.clone(),
+            nodes,
+        }
+    }
+
+    /// Node that has to run the restore into `target`; `None` if this host can, or if unknown.
+    pub fn remote_node(&self, target: &str) -> Option<&str> {
+        let local = self.local.as_deref()?;
+        let hosts = self.nodes.get(target)?;
+        if hosts.is_empty() || hosts.iter().any(|h| h == local) {
+            return None;
+        }
+        hosts.first().map(|h| h.as_str())
+    }
+}
+
+fn storage_id<'a>(target: &RestoreTarget, storages: &'a [Storage]) -> Option<&'a str> {
+    storages.iter().find_map(|s| match (target, s) {
+        (RestoreTarget::Zfs { root, .. }, Storage::ZfsPool { id, pool, .. }) if pool == root => {
+            Some(id.as_str())
+        }
+        (RestoreTarget::LvmThin { vg, .. }, Storage::LvmThin { id, vgname, .. })
+        | (RestoreTarget::Lvm { vg, .. }, Storage::Lvm { id, vgname, .. })
+            if vgname == vg =>
+        {
+            Some(id.as_str())
+        }
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::config::{Backup, Events, Pbs, Pve, Restore};
+
+    fn row(node: &str, storage: &str, status: &str) -> ClusterStorage {
+        ClusterStorage {
+            node: node.to_string(),
+            storage: storage.to_string(),
+            status: status.to_string(),
+            shared: false,
+        }
+    }
+
+    #[test]
+    fn remote_node_follows_storage_availability() {
+        let mut targets = BTreeMap::new();
+        targets.insert(
+            "z".to_string(),
+            RestoreTarget::Zfs {
+                root: "tank".to_string(),
+                volblocksize: None,
+                name_template: None,
+            },
+        );
+        targets.insert(
+            "l".to_string(),
+            RestoreTarget::Lvm {
+                vg: "data".to_string(),
+                lvcreate_args: Vec::new(),
+                name_template: None,
+            },
+        );
+        let cfg = Config {
+            pbs: Pbs {
+                repos: HashMap::new(),
+                keyfile: None,
+                password: None,
+                key_passphrase: None,
+                create_ns: Default::default(),
+                ns: None,
+                backup_id: "test".to_string(),
+            },
+            pve: Pve {
+                node: Some("pve1".to_string()),
+                ..Pve::default()
+            },
+            events: Events::default(),
+            log: Default::default(),
+            state: Default::default(),
+            backup: Backup::default(),
+            restore: Restore {
+                targets,
+                ..Restore::default()
+            },
+            nodes: BTreeMap::new(),
+        };
+        let storages = vec![
+            Storage::ZfsPool {
+                id: "local-zfs".to_string(),
+                pool: "tank".to_string(),
+                content: vec![],
+            },
+            Storage::Lvm {
+                id: "data-lvm".to_string(),
+                vgname: "data".to_string(),
+                content: vec![],
+            },
+        ];
+        let cluster = vec![
+            row("pve1", "local-zfs", "available"),
+            row("pve2", "local-zfs", "available"),
+            row("pve1", "data-lvm", "unknown"),
+            row("pve3", "data-lvm", "available"),
+        ];
+
+        let p = TargetPlacement::resolve(&cfg, &storages, &cluster);
+        assert_eq!(p.remote_node("z"), None);
+        assert_eq!(p.remote_node("l"), Some("pve3"));
+        assert_eq!(p.remote_node("missing"), None);
+
+        let standalone = TargetPlacement::resolve(&cfg, &storages, &[]);
+        assert_eq!(standalone.remote_node("l"), None);
+    }
+}
@@ -0,0 +1,182 @@
+use std::{collections::BTreeSet, fs, path::Path};
+
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+
+use crate::{
+    config::Config,
+    tooling::pbs::{FileClass, PbsSnapshot},
+};
+
+/// A reviewable archive → restore target mapping for `restore run --plan`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RestorePlan {
+    pub archives: Vec<PlanEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlanEntry {
+    pub archive: String,
+    /// Name of a `[restore.targets.<name>]` section.
+    pub target: String,
+    /// Archive size in bytes the plan was written against.
+    #[serde(default)]
+    pub size: Option<u64>,
+}
+
+impl RestorePlan {
+    /// Reads a YAML plan, or TOML if the file name ends in `.toml`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let raw =
+            fs::read_to_string(path).with_context(|| format!("read plan {}", path.display()))?;
+        let plan: Self = if path.extension().is_some_and(|e| e == "toml") {
+            toml::from_str(&raw).with_context(|| format!("parse plan {}", path.display()))?
+        } else {
+            serde_norway::from_str(&raw)
+                .with_context(|| format!("parse plan {}", path.display()))?
+        };
+        if plan.archives.is_empty() {
+            bail!("plan {} lists no archives", path.display());
+        }
+        Ok(plan)
+    }
+
+    /// Checks every entry against the config and the snapshot before anything is written.
+    pub fn validate(&self, cfg: &Config, snap: &PbsSnapshot) -> Result<()> {
+        let mut seen = BTreeSet::new();
+        for e in &self.archives {
+            if !seen.insert(e.archive.as_str()) {
+                bail!("plan lists archive {} more than once", e.archive);
+            }
+            if !cfg.restore.targets.contains_key(&e.target) {
+                bail!(
+                    "plan maps {} to unknown restore target '{}'",
+                    e.archive,
+                    e.target
+                );
+            }
+            let file = snap
+                .files
+                .iter()
+                .find(|f| f.filename == e.archive && f.class() == FileClass::Archive)
+                .with_context(|| {
+                    format!(
+                        "plan archive {} is not in snapshot {}",
+                        e.archive, snap.backup_time
+                    )
+                })?;
+            if let Some(size) = e.size
+                && size != file.size
+            {
+                bail!(
+                    "plan expects {} to be {size} bytes, snapshot has {}",
+                    e.archive,
+                    file.size
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{
+        config::{Backup, Events, Pbs, Pve, Restore, RestoreTarget},
+        tooling::pbs::PbsFile,
+    };
+
+    const ARCHIVE: &str = "zfs_vm-100-disk-0_raw_abcd1234.img.fidx";
+
+    fn cfg() -> Config {
+        let mut targets = BTreeMap::new();
+        targets.insert(
+            "tank".to_string(),
+            RestoreTarget::Zfs {
+                root: "tank".to_string(),
+                volblocksize: None,
+                name_template: None,
+            },
+        );
+        Config {
+            pbs: Pbs {
+                repos: HashMap::new(),
+                keyfile: None,
+                password: None,
+                key_passphrase: None,
+                create_ns: Default::default(),
+                ns: None,
+                backup_id: "id".to_string(),
+            },
+            pve: Pve::default(),
+            events: Events::default(),
+            log: Default::default(),
+            state: Default::default(),
+            backup: Backup::default(),
+            restore: Restore {
+                targets,
+                ..Restore::default()
+            },
+            nodes: BTreeMap::new(),
+        }
+    }
+
+    fn snap() -> PbsSnapshot {
+        PbsSnapshot {
+            backup_id: "id".to_string(),
+            backup_time: 1,
+            files: vec![PbsFile {
+                filename: ARCHIVE.to_string(),
+                size: 1024,
+                crypt_mode: None,
+            }],
+        }
+    }
+
+    #[test]
+    fn loads_yaml_and_toml_plans() {
+        let tmp = TempDir::new().unwrap();
+        let yaml = tmp.path().join("plan.yaml");
+        fs::write(
+            &yaml,
+            format!("archives:\n  - archive: {ARCHIVE}\n    target: tank\n    size: 1024\n"),
+        )
+        .unwrap();
+        let plan = RestorePlan::load(&yaml).unwrap();
+        assert_eq!(plan.archives[0].target, "tank");
+        assert_eq!(plan.archives[0].size, Some(1024));
+        plan.validate(&cfg(), &snap()).unwrap();
+
+        let toml = tmp.path().join("plan.toml");
+        fs::write(
+            &toml,
+            format!("[[archives]]\narchive = \"{ARCHIVE}\"\ntarget = \"tank\"\n"),
+        )
+        .unwrap();
+        assert!(RestorePlan::load(&toml).unwrap().archives[0].size.is_none());
+    }
+
+    #[test]
+    fn rejects_plans_that_do_not_fit() {
+        let entry = |archive: &str, target: &str, size: Option<u64>| PlanEntry {
+            archive: archive.to_string(),
+            target: target.to_string(),
+            size,
+        };
+        for archives in [
+            vec![entry(ARCHIVE, "nope", None)],
+            vec![entry("zfs_vm-1_raw_ffffffff.img.fidx", "tank", None)],
+            vec![entry(ARCHIVE, "tank", Some(1))],
+            vec![entry(ARCHIVE, "tank", None), entry(ARCHIVE, "tank", None)],
+        ] {
+            assert!(RestorePlan { archives }.validate(&cfg(), &snap()).is_err());
+        }
+    }
+}
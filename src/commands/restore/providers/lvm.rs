@@ -0,0 +1,540 @@
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::{Context, Result, anyhow, bail};
+
+use crate::{
+    commands::restore::{
+        matcher::RestoreMatcher,
+        providers::{NameTemplate, Provider, lv_restored_from, mark_lv_restored, volume_name},
+    },
+    tooling::{
+        LvmPort, PveshPort,
+        pbs::{FileClass, PbsFile, PbsSnapshot},
+        pvesh::Storage,
+        writer::WriteOpts,
+    },
+    utils::{
+        naming::{parse_archive_name, send_stream_leaf},
+        process::CmdSpec,
+    },
+    volume::Volume,
+};
+
+struct LinearTarget {
+    vg: String,
+    lv: String,
+    existed: bool,
+    /// Size of the LV to create.
+    size_bytes: u64,
+}
+
+pub struct LvmRestore<'a> {
+    vg: String,
+    target_name: String,
+    snapshot: Option<&'a PbsSnapshot>,
+    lvm: Arc<dyn LvmPort>,
+    pvesh: Arc<dyn PveshPort>,
+    matcher: Arc<RestoreMatcher>,
+    lvcreate_args: Vec<String>,
+    name_template: Option<String>,
+}
+
+impl<'a> LvmRestore<'a> {
+    pub fn new(
+        snapshot: Option<&'a PbsSnapshot>,
+        lvm: Arc<dyn LvmPort>,
+        pvesh: Arc<dyn PveshPort>,
+        matcher: Arc<RestoreMatcher>,
+        vg: String,
+        target_name: String,
+    ) -> Self {
+        assert!(!vg.trim().is_empty(), "[lvm target] empty vg");
+        assert!(
+            !target_name.trim().is_empty(),
+            "[lvm target] empty target_name"
+        );
+        Self {
+            vg,
+            target_name,
+            snapshot,
+            lvm,
+            pvesh,
+            matcher,
+            lvcreate_args: Vec::new(),
+            name_template: None,
+        }
+    }
+
+    pub fn with_lvcreate_args(mut self, args: Vec<String>) -> Self {
+        self.lvcreate_args = args;
+        self
+    }
+
+    #[inline]
+    fn routes_to_me(&self, f: &PbsFile) -> bool {
+        if f.class() != FileClass::Archive {
+            return false;
+        }
+        // `zfs send` streams can only be received into ZFS.
+        if let Ok((provider, leaf, _id)) = parse_archive_name(&f.filename)
+            && send_stream_leaf(&leaf).is_none()
+            && let Some(tname) = self.matcher.pick_target_name(&provider, f)
+        {
+            return tname == self.target_name;
+        }
+        false
+    }
+
+    fn resolve_lv_target(&self, archive: &str) -> Result<(PathBuf, String, LinearTarget)> {
+        let (_provider, leaf, _id) = parse_archive_name(archive)?;
+        let leaf = volume_name(self.name_template.as_deref(), self.snapshot, archive, &leaf)?;
+
+        let exists = self.lvm.lv_name(&self.vg, &leaf).is_ok();
+
+        let size_bytes = if exists {
+            0
+        } else {
+            let snap = self
+                .snapshot
+                .ok_or_else(|| anyhow!("no snapshot context to size '{archive}'"))?;
+            let file = snap
+                .files
+                .iter()
+                .find(|f| f.filename == archive)
+                .ok_or_else(|| anyhow!("archive {archive} not found in snapshot"))?;
+            file.size
+        };
+
+        let lv_path = format!("/dev/{}/{}", self.vg, leaf);
+        let meta = LinearTarget {
+            vg: self.vg.clone(),
+            lv: leaf.clone(),
+            existed: exists,
+            size_bytes,
+        };
+
+        Ok((PathBuf::from(lv_path), leaf, meta))
+    }
+}
+
+impl NameTemplate for LvmRestore<'_> {
+    fn name_template_mut(&mut self) -> &mut Option<String> {
+        &mut self.name_template
+    }
+}
+
+impl<'a> Provider for LvmRestore<'a> {
+    fn name(&self) -> &'static str {
+        "lvm"
+    }
+
+    fn collect_restore(&mut self, archive: Option<&str>, all: bool) -> Result<Vec<Volume>> {
+        let mut out = Vec::new();
+        let storages = self.pvesh.get_storage()?;
+        let storage_id = find_storage(&storages, &self.vg)?;
+        match (archive, all, self.snapshot) {
+            (Some(a), _, Some(snap)) => {
+                if let Some(file) = snap.files.iter().find(|f| f.filename == a)
+                    && self.routes_to_me(file)
+                {
+                    let (target, leaf, meta) = self.resolve_lv_target(a)?;
+                    out.push(Volume {
+                        storage: storage_id.to_string(),
+                        disk: leaf,
+                        archive: a.to_string(),
+                        device: target,
+                        size_bytes: None,
+                        meta: Some(Arc::new(meta)),
+                    });
+                }
+            }
+            (None, true, Some(snap)) => {
+                for f in &snap.files {
+                    if self.routes_to_me(f) {
+                        let (target, leaf, meta) = self.resolve_lv_target(&f.filename)?;
+                        out.push(Volume {
+                            storage: storage_id.to_string(),
+                            disk: leaf,
+                            archive: f.filename.clone(),
+                            device: target,
+                            size_bytes: None,
+                            meta: Some(Arc::new(meta)),
+                        });
+                    }
+                }
+            }
+            (Some(a), _, None) => bail!("no snapshot context for archive {a}"),
+            (None, true, None) => bail!("no snapshot context provided for restore-all"),
+            (None, false, _) => {}
+        }
+
+        Ok(out)
+    }
+
+    fn list_archives(&self, snap: &PbsSnapshot) -> Vec<String> {
+        snap.files
+            .iter()
+            .filter(|f| self.routes_to_me(f))
+            .map(|f| f.filename.clone())
+            .collect()
+    }
+
+    fn create_target(&self, vol: &Volume) -> Result<()> {
+        match vol.meta::<LinearTarget>().filter(|t| !t.existed) {
+            Some(t) => self
+                .lvm
+                .lvcreate_linear(&t.vg, &t.lv, t.size_bytes, &self.lvcreate_args),
+            None => Ok(()),
+        }
+    }
+
+    fn safety_snapshot(&self, vol: &Volume, suffix: &str) -> Result<Option<String>> {
+        let Some(t) = vol.meta::<LinearTarget>().filter(|t| t.existed) else {
+            return Ok(None);
+        };
+        // A full restore may rewrite every block, so the COW area must match the origin.
+        let snap = format!("{}-{suffix}", t.lv);
+        let snap_fq = self
+            .lvm
+            .lvcreate_cow_snapshot(&t.vg, &t.lv, &snap, "100%ORIGIN")
+            .with_context(|| format!("safety snapshot of {}/{}", t.vg, t.lv))?;
+        Ok(Some(format!("lvconvert --merge {snap_fq}")))
+    }
+
+    fn overwrites(&self, vol: &Volume) -> bool {
+        vol.meta::<LinearTarget>().is_some_and(|t| t.existed)
+    }
+
+    /// A new linear LV maps whatever its extents held before, so zeros must be written.
+    fn skips_zeros(&self, _vol: &Volume) -> bool {
+        false
+    }
+
+    fn receive_stream(
+        &self,
+        _vol: &Volume,
+        _src: &CmdSpec,
+        _opts: &WriteOpts,
+    ) -> Option<Result<()>> {
+        None
+    }
+
+    fn restored_from(&self, vol: &Volume) -> Result<Option<String>> {
+        match vol.meta::<LinearTarget>().filter(|t| t.existed) {
+            Some(t) => lv_restored_from(self.lvm.as_ref(), &format!("{}/{}", t.vg, t.lv)),
+            None => Ok(None),
+        }
+    }
+
+    fn mark_restored(&self, vol: &Volume, stamp: Option<&str>) -> Result<()> {
+        match vol.meta::<LinearTarget>() {
+            Some(t) => mark_lv_restored(self.lvm.as_ref(), &format!("{}/{}", t.vg, t.lv), stamp),
+            None => Ok(()),
+        }
+    }
+}
+
+#[inline]
+fn find_storage<'a>(storages: &'a [Storage], vg_name: &str) -> Result<&'a str> {
+    storages
+        .iter()
+        .find_map(|s| match *s {
+            Storage::Lvm {
+                ref id,
+                vgname: ref storage_name,
+                ..
+            } if storage_name.as_str() == vg_name => Some(id.as_str()),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("LVM storage with vgname='{vg_name}' not found"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::Mutex};
+
+    use super::*;
+    use crate::{
+        commands::restore::providers::restore_stamp,
+        config::{Backup, Config, Events, Pbs, Pve, Restore, RestoreRule, RestoreTarget},
+    };
+
+    struct MockPvesh;
+    impl PveshPort for MockPvesh {
+        fn get_storage(&self) -> Result<Vec<Storage>> {
+            Ok(vec![Storage::Lvm {
+                id: "plain".to_string(),
+                vgname: "data".to_string(),
+                content: vec!["images".to_string()],
+            }])
+        }
+        fn cluster_storage(&self) -> Result<Vec<crate::tooling::pvesh::ClusterStorage>> {
+            Ok(Vec::new())
+        }
+    }
+
+    #[derive(Default)]
+    struct MockLvm {
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl LvmPort for MockLvm {
+        fn list_lvs(&self) -> Result<Vec<crate::tooling::lvm::LvInfo>> {
+            Ok(vec![])
+        }
+        fn lvcreate_snapshot(&self, _vg: &str, _lv: &str, _snap: &str) -> Result<String> {
+            unreachable!("classic LVs need a sized snapshot")
+        }
+        fn lvcreate_cow_snapshot(
+            &self,
+            vg: &str,
+            _lv: &str,
+            snap: &str,
+            size: &str,
+        ) -> Result<String> {
+            self.calls.lock().unwrap().push(format!("snapshot {size}"));
+            Ok(format!("{vg}/{snap}"))
+        }
+        fn lvchange_activate(&self, _lv_fq: &str) -> Result<()> {
+            Ok(())
+        }
+        fn lvremove_force(&self, _lv_fq: &str) -> Result<()> {
+            Ok(())
+        }
+        fn lv_name(&self, _vg: &str, leaf: &str) -> Result<String> {
+            if leaf == "vm-1-disk-0" {
+                Ok(leaf.to_string())
+            } else {
+                bail!("LV not found")
+            }
+        }
+        fn lv_uuid_short8(&self, _vg: &str, _lv: &str) -> Result<String> {
+            Ok("abcd1234".to_string())
+        }
+        fn lv_tags(&self, _lv_fq: &str) -> Result<Vec<String>> {
+            Ok(vec![
+                "keep".to_string(),
+                "pvtools_restored_from=lvm_vm-1-disk-0_noext_abcd1234.img.fidx:1234567890"
+                    .to_string(),
+            ])
+        }
+        fn lvchange_tags(&self, lv_fq: &str, add: &[String], del: &[String]) -> Result<()> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("tags {lv_fq} +{add:?} -{del:?}"));
+            Ok(())
+        }
+        fn lvcreate_thin(
+            &self,
+            _vg: &str,
+            _thinpool: &str,
+            _name: &str,
+            _size_bytes: u64,
+            _extra_args: &[String],
+        ) -> Result<()> {
+            unreachable!("classic targets never create thin LVs")
+        }
+        fn lvcreate_linear(
+            &self,
+            vg: &str,
+            name: &str,
+            size_bytes: u64,
+            _extra_args: &[String],
+        ) -> Result<()> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("create {vg}/{name} {size_bytes}"));
+            Ok(())
+        }
+        fn vg_report(&self, _vg: &str) -> Result<String> {
+            Ok(String::new())
+        }
+        fn thinpool_usage(&self, _vg: &str) -> Result<String> {
+            Ok(String::new())
+        }
+        fn thin_pools(&self, _vg: &str) -> Result<Vec<crate::tooling::lvm::ThinPoolUsage>> {
+            Ok(Vec::new())
+        }
+        fn cow_snapshot_usage(&self, _vg: &str) -> Result<String> {
+            Ok(String::new())
+        }
+    }
+
+    fn test_config() -> Config {
+        let mut targets = BTreeMap::new();
+        targets.insert(
+            "plain".to_string(),
+            RestoreTarget::Lvm {
+                vg: "data".to_string(),
+                lvcreate_args: Vec::new(),
+                name_template: None,
+            },
+        );
+
+        Config {
+            pbs: Pbs {
+                repos: std::collections::HashMap::new(),
+                keyfile: None,
+                password: None,
+                key_passphrase: None,
+                create_ns: Default::default(),
+                ns: None,
+                backup_id: "test".to_string(),
+            },
+            pve: Pve::default(),
+            events: Events::default(),
+            log: Default::default(),
+            state: Default::default(),
+            backup: Backup::default(),
+            restore: Restore {
+                targets,
+                rules: vec![RestoreRule {
+                    match_provider: "lvm".to_string(),
+                    match_archive_regex: None,
+                    target: "plain".to_string(),
+                    write: None,
+                }],
+                ..Restore::default()
+            },
+            nodes: BTreeMap::new(),
+        }
+    }
+
+    fn test_snapshot() -> PbsSnapshot {
+        PbsSnapshot {
+            backup_id: "test".to_string(),
+            backup_time: 1234567890,
+            files: vec![
+                PbsFile {
+                    filename: "lvm_vm-1-disk-0_noext_abcd1234.img.fidx".to_string(),
+                    size: 8 * 1024 * 1024,
+                    crypt_mode: None,
+                },
+                PbsFile {
+                    filename: "lvm_vm-2-disk-0_noext_ef567890.img.fidx".to_string(),
+                    size: 4 * 1024 * 1024,
+                    crypt_mode: None,
+                },
+                PbsFile {
+                    filename: "lvmthin_vm-3_raw_abcd1234.img.fidx".to_string(),
+                    size: 4 * 1024 * 1024,
+                    crypt_mode: None,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn missing_linear_lvs_are_created_once_confirmed() {
+        let snap = test_snapshot();
+        let lvm = Arc::new(MockLvm::default());
+        let cfg = test_config();
+        let matcher = Arc::new(RestoreMatcher::new(&cfg).unwrap());
+        let mut restore = LvmRestore::new(
+            Some(&snap),
+            lvm.clone(),
+            Arc::new(MockPvesh),
+            matcher,
+            "data".to_string(),
+            "plain".to_string(),
+        );
+
+        let items = restore.collect_restore(None, true).unwrap();
+        let devices: Vec<PathBuf> = items.iter().map(|v| v.device.clone()).collect();
+        assert_eq!(
+            devices,
+            vec![
+                PathBuf::from("/dev/data/vm-1-disk-0"),
+                PathBuf::from("/dev/data/vm-2-disk-0"),
+            ]
+        );
+        assert_eq!(items[0].storage, "plain");
+        assert!(lvm.calls.lock().unwrap().is_empty());
+        for v in &items {
+            restore.create_target(v).unwrap();
+        }
+        assert_eq!(
+            *lvm.calls.lock().unwrap(),
+            vec![format!("create data/vm-2-disk-0 {}", 4 * 1024 * 1024)]
+        );
+
+        let rollback = restore
+            .safety_snapshot(&items[0], "pvtools-prerestore-7")
+            .unwrap();
+        assert_eq!(
+            rollback.as_deref(),
+            Some("lvconvert --merge data/vm-1-disk-0-pvtools-prerestore-7")
+        );
+        assert!(restore.safety_snapshot(&items[1], "x").unwrap().is_none());
+        assert_eq!(lvm.calls.lock().unwrap()[1], "snapshot 100%ORIGIN");
+    }
+
+    #[test]
+    fn restore_stamps_are_kept_in_lv_tags() {
+        let snap = test_snapshot();
+        let lvm = Arc::new(MockLvm::default());
+        let cfg = test_config();
+        let matcher = Arc::new(RestoreMatcher::new(&cfg).unwrap());
+        let mut restore = LvmRestore::new(
+            Some(&snap),
+            lvm.clone(),
+            Arc::new(MockPvesh),
+            matcher,
+            "data".to_string(),
+            "plain".to_string(),
+        );
+
+        let items = restore.collect_restore(None, true).unwrap();
+        let stamp = restore_stamp(&items[0].archive, &snap);
+        assert_eq!(
+            restore.restored_from(&items[0]).unwrap().as_deref(),
+            Some(stamp.as_str())
+        );
+        assert!(restore.restored_from(&items[1]).unwrap().is_none());
+
+        restore.mark_restored(&items[1], Some("a.img@7")).unwrap();
+        restore.mark_restored(&items[0], None).unwrap();
+        let calls = lvm.calls.lock().unwrap();
+        assert_eq!(
+            calls[..],
+            [
+                r#"tags data/vm-2-disk-0 +["pvtools_restored_from=a.img:7"] -["pvtools_restored_from=lvm_vm-1-disk-0_noext_abcd1234.img.fidx:1234567890"]"#,
+                r#"tags data/vm-1-disk-0 +[] -["pvtools_restored_from=lvm_vm-1-disk-0_noext_abcd1234.img.fidx:1234567890"]"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn name_template_names_restored_lvs() {
+        let snap = test_snapshot();
+        let lvm = Arc::new(MockLvm::default());
+        let cfg = test_config();
+        let matcher = Arc::new(RestoreMatcher::new(&cfg).unwrap());
+        let mut restore = LvmRestore::new(
+            Some(&snap),
+            lvm.clone(),
+            Arc::new(MockPvesh),
+            matcher,
+            "data".to_string(),
+            "plain".to_string(),
+        )
+        .with_name_template(Some("restored-{leaf}-{date}".to_string()));
+
+        let items = restore.collect_restore(None, true).unwrap();
+        let disks: Vec<&str> = items.iter().map(|v| v.disk.as_str()).collect();
+        assert_eq!(
+            disks,
+            [
+                "restored-vm-1-disk-0-20090213",
+                "restored-vm-2-disk-0-20090213"
+            ]
+        );
+        assert_eq!(
+            items[0].device,
+            PathBuf::from("/dev/data/restored-vm-1-disk-0-20090213")
+        );
+        assert!(!restore.overwrites(&items[0]));
+    }
+}
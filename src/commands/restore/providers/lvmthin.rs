@@ -1,18 +1,33 @@
 use std::{path::PathBuf, sync::Arc};
 
-use anyhow::{Result, anyhow, bail};
+use anyhow::{Context, Result, anyhow, bail};
 
 use crate::{
-    commands::restore::{matcher::RestoreMatcher, providers::Provider},
+    commands::restore::{
+        matcher::RestoreMatcher,
+        providers::{NameTemplate, Provider, lv_restored_from, mark_lv_restored, volume_name},
+    },
     tooling::{
         LvmPort, PveshPort,
-        pbs::{PbsFile, PbsSnapshot},
+        pbs::{FileClass, PbsFile, PbsSnapshot},
         pvesh::Storage,
+        writer::WriteOpts,
+    },
+    utils::{
+        naming::{parse_archive_name, send_stream_leaf},
+        process::CmdSpec,
     },
-    utils::naming::parse_archive_name,
     volume::Volume,
 };
 
+struct LvTarget {
+    vg: String,
+    lv: String,
+    existed: bool,
+    /// Size of the LV to create.
+    size_bytes: u64,
+}
+
 pub struct LvmthinRestore<'a> {
     vg: String,
     thinpool: String,
@@ -21,6 +36,8 @@ pub struct LvmthinRestore<'a> {
     lvm: Arc<dyn LvmPort>,
     pvesh: Arc<dyn PveshPort>,
     matcher: Arc<RestoreMatcher>,
+    lvcreate_args: Vec<String>,
+    name_template: Option<String>,
 }
 
 impl<'a> LvmthinRestore<'a> {
@@ -50,12 +67,24 @@ impl<'a> LvmthinRestore<'a> {
             lvm,
             pvesh,
             matcher,
+            lvcreate_args: Vec::new(),
+            name_template: None,
         }
     }
 
+    pub fn with_lvcreate_args(mut self, args: Vec<String>) -> Self {
+        self.lvcreate_args = args;
+        self
+    }
+
     #[inline]
     fn routes_to_me(&self, f: &PbsFile) -> bool {
-        if let Ok((provider, _leaf, _id)) = parse_archive_name(&f.filename)
+        if f.class() != FileClass::Archive {
+            return false;
+        }
+        // `zfs send` streams can only be received into ZFS.
+        if let Ok((provider, leaf, _id)) = parse_archive_name(&f.filename)
+            && send_stream_leaf(&leaf).is_none()
             && let Some(tname) = self.matcher.pick_target_name(&provider, f)
         {
             return tname == self.target_name;
@@ -63,12 +92,15 @@ impl<'a> LvmthinRestore<'a> {
         false
     }
 
-    fn resolve_lv_target(&self, archive: &str) -> Result<(PathBuf, String)> {
+    fn resolve_lv_target(&self, archive: &str) -> Result<(PathBuf, String, LvTarget)> {
         let (_provider, leaf, _id) = parse_archive_name(archive)?;
+        let leaf = volume_name(self.name_template.as_deref(), self.snapshot, archive, &leaf)?;
 
         let exists = self.lvm.lv_name(&self.vg, &leaf).is_ok();
 
-        if !exists {
+        let size_bytes = if exists {
+            0
+        } else {
             let snap = self
                 .snapshot
                 .ok_or_else(|| anyhow!("no snapshot context to size '{archive}'"))?;
@@ -77,17 +109,24 @@ impl<'a> LvmthinRestore<'a> {
                 .iter()
                 .find(|f| f.filename == archive)
                 .ok_or_else(|| anyhow!("archive {archive} not found in snapshot"))?;
-            let size_bytes = file.size;
-
-            self.lvm
-                .lvcreate_thin(&self.vg, &self.thinpool, &leaf, size_bytes)?;
-            let lv_fq = format!("{}/{}", self.vg, leaf);
-            self.lvm.lvchange_activate(&lv_fq)?;
-        }
+            file.size
+        };
 
         let lv_path = format!("/dev/{}/{}", self.vg, leaf);
+        let meta = LvTarget {
+            vg: self.vg.clone(),
+            lv: leaf.clone(),
+            existed: exists,
+            size_bytes,
+        };
+
+        Ok((PathBuf::from(lv_path), leaf, meta))
+    }
+}
 
-        Ok((PathBuf::from(lv_path), leaf))
+impl NameTemplate for LvmthinRestore<'_> {
+    fn name_template_mut(&mut self) -> &mut Option<String> {
+        &mut self.name_template
     }
 }
 
@@ -105,26 +144,28 @@ impl<'a> Provider for LvmthinRestore<'a> {
                 if let Some(file) = snap.files.iter().find(|f| f.filename == a)
                     && self.routes_to_me(file)
                 {
-                    let (target, leaf) = self.resolve_lv_target(a)?;
+                    let (target, leaf, meta) = self.resolve_lv_target(a)?;
                     out.push(Volume {
                         storage: storage_id.to_string(),
                         disk: leaf,
                         archive: a.to_string(),
                         device: target,
-                        meta: None,
+                        size_bytes: None,
+                        meta: Some(Arc::new(meta)),
                     });
                 }
             }
             (None, true, Some(snap)) => {
                 for f in &snap.files {
                     if self.routes_to_me(f) {
-                        let (target, leaf) = self.resolve_lv_target(&f.filename)?;
+                        let (target, leaf, meta) = self.resolve_lv_target(&f.filename)?;
                         out.push(Volume {
                             storage: storage_id.to_string(),
                             disk: leaf,
                             archive: f.filename.clone(),
                             device: target,
-                            meta: None,
+                            size_bytes: None,
+                            meta: Some(Arc::new(meta)),
                         });
                     }
                 }
@@ -144,6 +185,63 @@ impl<'a> Provider for LvmthinRestore<'a> {
             .map(|f| f.filename.clone())
             .collect()
     }
+
+    fn create_target(&self, vol: &Volume) -> Result<()> {
+        let Some(t) = vol.meta::<LvTarget>().filter(|t| !t.existed) else {
+            return Ok(());
+        };
+        self.lvm.lvcreate_thin(
+            &t.vg,
+            &self.thinpool,
+            &t.lv,
+            t.size_bytes,
+            &self.lvcreate_args,
+        )?;
+        self.lvm.lvchange_activate(&format!("{}/{}", t.vg, t.lv))
+    }
+
+    fn safety_snapshot(&self, vol: &Volume, suffix: &str) -> Result<Option<String>> {
+        let Some(t) = vol.meta::<LvTarget>().filter(|t| t.existed) else {
+            return Ok(None);
+        };
+        let snap = format!("{}-{suffix}", t.lv);
+        let snap_fq = self
+            .lvm
+            .lvcreate_snapshot(&t.vg, &t.lv, &snap)
+            .with_context(|| format!("safety snapshot of {}/{}", t.vg, t.lv))?;
+        Ok(Some(format!("lvconvert --merge {snap_fq}")))
+    }
+
+    fn overwrites(&self, vol: &Volume) -> bool {
+        vol.meta::<LvTarget>().is_some_and(|t| t.existed)
+    }
+
+    fn skips_zeros(&self, vol: &Volume) -> bool {
+        vol.meta::<LvTarget>().is_some_and(|t| !t.existed)
+    }
+
+    fn receive_stream(
+        &self,
+        _vol: &Volume,
+        _src: &CmdSpec,
+        _opts: &WriteOpts,
+    ) -> Option<Result<()>> {
+        None
+    }
+
+    fn restored_from(&self, vol: &Volume) -> Result<Option<String>> {
+        match vol.meta::<LvTarget>().filter(|t| t.existed) {
+            Some(t) => lv_restored_from(self.lvm.as_ref(), &format!("{}/{}", t.vg, t.lv)),
+            None => Ok(None),
+        }
+    }
+
+    fn mark_restored(&self, vol: &Volume, stamp: Option<&str>) -> Result<()> {
+        match vol.meta::<LvTarget>() {
+            Some(t) => mark_lv_restored(self.lvm.as_ref(), &format!("{}/{}", t.vg, t.lv), stamp),
+            None => Ok(()),
+        }
+    }
 }
 
 #[inline]
@@ -170,7 +268,7 @@ mod tests {
     use super::*;
     use crate::{
         commands::restore::matcher::RestoreMatcher,
-        config::{Backup, Config, Pbs, Restore, RestoreTarget},
+        config::{Backup, Config, Events, Pbs, Pve, Restore, RestoreTarget},
         tooling::{LvmPort, PveshPort, pbs::PbsFile, pvesh::Storage},
     };
 
@@ -184,6 +282,9 @@ mod tests {
                 content: vec!["".to_string()],
             }])
         }
+        fn cluster_storage(&self) -> Result<Vec<crate::tooling::pvesh::ClusterStorage>> {
+            Ok(Vec::new())
+        }
     }
 
     struct MockLvm;
@@ -207,15 +308,52 @@ mod tests {
         fn lv_uuid_short8(&self, _vg: &str, _lv: &str) -> Result<String> {
             Ok("abcd1234".to_string())
         }
+        fn lv_tags(&self, _lv_fq: &str) -> Result<Vec<String>> {
+            Ok(Vec::new())
+        }
+        fn lvchange_tags(&self, _lv_fq: &str, _add: &[String], _del: &[String]) -> Result<()> {
+            Ok(())
+        }
         fn lvcreate_thin(
             &self,
             _vg: &str,
             _thinpool: &str,
             _name: &str,
             _size_bytes: u64,
+            _extra_args: &[String],
         ) -> Result<()> {
             Ok(())
         }
+        fn vg_report(&self, _vg: &str) -> Result<String> {
+            Ok(String::new())
+        }
+        fn thinpool_usage(&self, _vg: &str) -> Result<String> {
+            Ok(String::new())
+        }
+        fn lvcreate_cow_snapshot(
+            &self,
+            _vg: &str,
+            _lv: &str,
+            snap: &str,
+            _size: &str,
+        ) -> Result<String> {
+            Ok(snap.to_string())
+        }
+        fn lvcreate_linear(
+            &self,
+            _vg: &str,
+            _name: &str,
+            _size_bytes: u64,
+            _extra_args: &[String],
+        ) -> Result<()> {
+            Ok(())
+        }
+        fn thin_pools(&self, _vg: &str) -> Result<Vec<crate::tooling::lvm::ThinPoolUsage>> {
+            Ok(Vec::new())
+        }
+        fn cow_snapshot_usage(&self, _vg: &str) -> Result<String> {
+            Ok(String::new())
+        }
     }
 
     fn test_config() -> Config {
@@ -225,6 +363,8 @@ mod tests {
             RestoreTarget::LvmThin {
                 vg: "pve".to_string(),
                 thinpool: "data".to_string(),
+                lvcreate_args: Vec::new(),
+                name_template: None,
             },
         );
 
@@ -233,9 +373,15 @@ mod tests {
                 repos: std::collections::HashMap::new(),
                 keyfile: None,
                 password: None,
+                key_passphrase: None,
+                create_ns: Default::default(),
                 ns: None,
                 backup_id: "test".to_string(),
             },
+            pve: Pve::default(),
+            events: Events::default(),
+            log: Default::default(),
+            state: Default::default(),
             backup: Backup::default(),
             restore: Restore {
                 targets,
@@ -243,9 +389,12 @@ mod tests {
                     match_provider: "lvmthin".to_string(),
                     match_archive_regex: None,
                     target: "lvm-pve".to_string(),
+                    write: None,
                 }],
                 default_target: None,
+                ..Restore::default()
             },
+            nodes: BTreeMap::new(),
         }
     }
 
@@ -257,10 +406,12 @@ mod tests {
                 PbsFile {
                     filename: "lvmthin_vm-123_raw_abcd1234.img".to_string(),
                     size: 4 * 1024 * 1024,
+                    crypt_mode: None,
                 },
                 PbsFile {
                     filename: "zfs_vm-456_raw_efgh5678.img".to_string(),
                     size: 4 * 1024 * 1024,
+                    crypt_mode: None,
                 },
             ],
         }
@@ -283,7 +434,7 @@ mod tests {
             "lvm-pve".to_string(),
         );
 
-        let (target, _) = restore
+        let (target, _, _) = restore
             .resolve_lv_target("lvmthin_vm-123_raw_abcd1234.img")
             .unwrap();
         assert_eq!(target, PathBuf::from("/dev/pve/vm-123.raw"));
@@ -312,6 +463,7 @@ mod tests {
         assert_eq!(items.len(), 1);
         assert_eq!(items[0].archive, "lvmthin_vm-123_raw_abcd1234.img");
         assert_eq!(items[0].device, PathBuf::from("/dev/pve/vm-123.raw"));
+        assert!(restore.skips_zeros(&items[0]));
     }
 
     #[test]
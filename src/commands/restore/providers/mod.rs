@@ -1,67 +1,232 @@
+pub mod lvm;
 pub mod lvmthin;
+pub mod ssh;
 pub mod zfs;
 
 use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 
 use crate::{
-    AppCtx, commands::restore::matcher::RestoreMatcher, config::RestoreTarget,
-    tooling::pbs::PbsSnapshot, volume::Volume,
+    AppCtx,
+    commands::restore::{matcher::RestoreMatcher, plan::RestorePlan},
+    config::RestoreTarget,
+    tooling::{LvmPort, Toolbox, pbs::PbsSnapshot, writer::WriteOpts},
+    utils::{
+        naming::{parse_archive_name, render_name_template},
+        process::CmdSpec,
+    },
+    volume::Volume,
 };
 
+/// Name of the volume `archive` (whose leaf is `leaf`) is restored to: the leaf, or the
+/// target's `name_template` filled in.
+fn volume_name(
+    template: Option<&str>,
+    snapshot: Option<&PbsSnapshot>,
+    archive: &str,
+    leaf: &str,
+) -> Result<String> {
+    let Some(template) = template else {
+        return Ok(leaf.to_string());
+    };
+    let (provider, _, id) = parse_archive_name(archive)?;
+    let snap = snapshot.ok_or_else(|| anyhow!("no snapshot context to name '{archive}'"))?;
+    render_name_template(template, &provider, leaf, &id, snap.backup_time)
+}
+
+/// Providers whose volume names a target's `name_template` can set.
+pub trait NameTemplate: Sized {
+    fn name_template_mut(&mut self) -> &mut Option<String>;
+
+    fn with_name_template(mut self, template: Option<String>) -> Self {
+        *self.name_template_mut() = template;
+        self
+    }
+}
+
+/// ZFS user property recording which archive of which snapshot a zvol holds.
+const RESTORED_FROM_PROP: &str = "pvtools:restored_from";
+/// LVM tags cannot hold `@`, so LVs carry `pvtools_restored_from=<archive>:<time>`.
+const RESTORED_FROM_TAG: &str = "pvtools_restored_from=";
+
+/// What `vol` is stamped with once `archive` of `snap` has been fully written to it.
+pub fn restore_stamp(archive: &str, snap: &PbsSnapshot) -> String {
+    format!("{archive}@{}", snap.backup_time)
+}
+
+fn lv_restored_from(lvm: &dyn LvmPort, lv_fq: &str) -> Result<Option<String>> {
+    Ok(lvm.lv_tags(lv_fq)?.iter().find_map(|t| {
+        t.strip_prefix(RESTORED_FROM_TAG)
+            .map(|s| s.replacen(':', "@", 1))
+    }))
+}
+
+fn mark_lv_restored(lvm: &dyn LvmPort, lv_fq: &str, stamp: Option<&str>) -> Result<()> {
+    let del: Vec<String> = lvm
+        .lv_tags(lv_fq)?
+        .into_iter()
+        .filter(|t| t.starts_with(RESTORED_FROM_TAG))
+        .collect();
+    let add: Vec<String> = stamp
+        .map(|s| format!("{RESTORED_FROM_TAG}{}", s.replacen('@', ":", 1)))
+        .into_iter()
+        .collect();
+    if del.is_empty() && add.is_empty() {
+        return Ok(());
+    }
+    lvm.lvchange_tags(lv_fq, &add, &del)
+}
+
 pub trait Provider {
     fn name(&self) -> &'static str;
     fn collect_restore(&mut self, archive: Option<&str>, all: bool) -> Result<Vec<Volume>>;
     fn list_archives(&self, snap: &PbsSnapshot) -> Vec<String>;
+    /// Creates `vol` where `collect_restore` found it missing; runs once the restore is confirmed.
+    /// A no-op for volumes of other providers and for existing targets.
+    fn create_target(&self, vol: &Volume) -> Result<()>;
+    /// Snapshots a pre-existing target before it is overwritten; returns the rollback command.
+    fn safety_snapshot(&self, vol: &Volume, suffix: &str) -> Result<Option<String>>;
+    /// Whether `vol` is one of this provider's targets and existed before the restore.
+    fn overwrites(&self, vol: &Volume) -> bool;
+    /// Whether all-zero blocks may be skipped when writing `vol`: it was created by this
+    /// restore and reads back zeros wherever nothing is written, as thin LVs and zvols do.
+    fn skips_zeros(&self, vol: &Volume) -> bool;
+    /// Restores `vol` from the stream `src` writes, when it is not a device the writer fills.
+    fn receive_stream(&self, vol: &Volume, src: &CmdSpec, opts: &WriteOpts) -> Option<Result<()>>;
+    /// Stamp a pre-existing `vol` carries from an earlier restore that ran to completion.
+    fn restored_from(&self, vol: &Volume) -> Result<Option<String>>;
+    /// Stamps `vol` as holding `stamp`, or removes the stamp with `None`; a no-op for volumes
+    /// of other providers and targets that cannot carry one.
+    fn mark_restored(&self, vol: &Volume, stamp: Option<&str>) -> Result<()>;
 }
 
 pub struct ProviderRegistry<'a> {
     ctx: &'a AppCtx,
+    tools: &'a Toolbox,
     snapshot: Option<&'a PbsSnapshot>,
     matcher: Arc<RestoreMatcher>,
 }
 
 impl<'a> ProviderRegistry<'a> {
     pub fn new(ctx: &'a AppCtx, snapshot: Option<&'a PbsSnapshot>) -> Self {
+        Self::with_tools(ctx, &ctx.tools, snapshot)
+    }
+
+    /// Registry whose providers act through `tools`, e.g. on another cluster node.
+    pub fn with_tools(
+        ctx: &'a AppCtx,
+        tools: &'a Toolbox,
+        snapshot: Option<&'a PbsSnapshot>,
+    ) -> Self {
         let matcher = Arc::new(RestoreMatcher::new(&ctx.cfg).expect("restore matcher"));
         Self {
             ctx,
+            tools,
             snapshot,
             matcher,
         }
     }
 
+    /// Routes the archives of `plan` to its targets, ahead of `[restore.rules]`.
+    pub fn with_plan(mut self, plan: Option<&RestorePlan>) -> Self {
+        if let Some(plan) = plan {
+            let matcher = Arc::get_mut(&mut self.matcher).expect("matcher not shared yet");
+            for e in &plan.archives {
+                matcher.pin(&e.archive, &e.target);
+            }
+        }
+        self
+    }
+
+    pub fn matcher(&self) -> &RestoreMatcher {
+        &self.matcher
+    }
+
     pub fn build(&self) -> Vec<Box<dyn Provider + 'a>> {
         let mut out: Vec<Box<dyn Provider + 'a>> = Vec::new();
         for (tname, tgt) in &self.ctx.cfg.restore.targets {
             match tgt {
-                RestoreTarget::Zfs { root } => {
-                    let zfs_port = self.ctx.tools.zfs().expect("zfs enabled");
-                    let pvesh = self.ctx.tools.pvesh();
-                    let fs = self.ctx.tools.fs();
-                    out.push(Box::new(zfs::ZfsRestore::new(
-                        self.snapshot,
-                        zfs_port,
-                        pvesh,
-                        fs,
-                        self.matcher.clone(),
-                        root.clone(),
-                        tname.clone(),
-                    )));
+                RestoreTarget::Zfs {
+                    root,
+                    volblocksize,
+                    name_template,
+                } => {
+                    let zfs_port = self.tools.zfs().expect("zfs enabled");
+                    let pvesh = self.tools.pvesh();
+                    let fs = self.tools.fs();
+                    out.push(Box::new(
+                        zfs::ZfsRestore::new(
+                            self.snapshot,
+                            zfs_port,
+                            pvesh,
+                            fs,
+                            self.matcher.clone(),
+                            root.clone(),
+                            tname.clone(),
+                        )
+                        .with_volblocksize(volblocksize.clone())
+                        .with_name_template(name_template.clone()),
+                    ));
+                }
+                RestoreTarget::LvmThin {
+                    vg,
+                    thinpool,
+                    lvcreate_args,
+                    name_template,
+                } => {
+                    let lvm_port = self.tools.lvm().expect("lvm enabled");
+                    let pvesh = self.tools.pvesh();
+                    out.push(Box::new(
+                        lvmthin::LvmthinRestore::new(
+                            self.snapshot,
+                            lvm_port,
+                            pvesh,
+                            self.matcher.clone(),
+                            vg.clone(),
+                            thinpool.clone(),
+                            tname.clone(),
+                        )
+                        .with_lvcreate_args(lvcreate_args.clone())
+                        .with_name_template(name_template.clone()),
+                    ));
+                }
+                RestoreTarget::Lvm {
+                    vg,
+                    lvcreate_args,
+                    name_template,
+                } => {
+                    let lvm_port = self.tools.lvm().expect("lvm enabled");
+                    let pvesh = self.tools.pvesh();
+                    out.push(Box::new(
+                        lvm::LvmRestore::new(
+                            self.snapshot,
+                            lvm_port,
+                            pvesh,
+                            self.matcher.clone(),
+                            vg.clone(),
+                            tname.clone(),
+                        )
+                        .with_lvcreate_args(lvcreate_args.clone())
+                        .with_name_template(name_template.clone()),
+                    ));
                 }
-                RestoreTarget::LvmThin { vg, thinpool } => {
-                    let lvm_port = self.ctx.tools.lvm().expect("lvm enabled");
-                    let pvesh = self.ctx.tools.pvesh();
-                    out.push(Box::new(lvmthin::LvmthinRestore::new(
-                        self.snapshot,
-                        lvm_port,
-                        pvesh,
-                        self.matcher.clone(),
-                        vg.clone(),
-                        thinpool.clone(),
-                        tname.clone(),
-                    )));
+                RestoreTarget::Ssh {
+                    ssh,
+                    device_root,
+                    name_template,
+                } => {
+                    out.push(Box::new(
+                        ssh::SshRestore::new(
+                            self.snapshot,
+                            self.ctx.runner.clone(),
+                            self.matcher.clone(),
+                            ssh.clone(),
+                            device_root.clone(),
+                            tname.clone(),
+                        )
+                        .with_name_template(name_template.clone()),
+                    ));
                 }
             }
         }
@@ -0,0 +1,296 @@
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::{Context, Result, bail};
+
+use crate::{
+    commands::restore::{
+        matcher::RestoreMatcher,
+        providers::{NameTemplate, Provider, volume_name},
+    },
+    config::Ssh,
+    tooling::{
+        dd,
+        pbs::{FileClass, PbsFile, PbsSnapshot},
+        writer::WriteOpts,
+    },
+    utils::{
+        naming::{parse_archive_name, send_stream_leaf},
+        process::{CmdSpec, Pipeline, Runner, StdioSpec},
+        ssh::SshRunner,
+    },
+    volume::Volume,
+};
+
+/// Marks volumes on a remote host, which must already exist there.
+struct RemoteDevice;
+
+/// Writes archives to `<device_root>/<disk>` on another host by piping the local
+/// proxmox-backup-client stream into `dd` over ssh; nothing but `dd` is needed there.
+pub struct SshRestore<'a> {
+    target_name: String,
+    device_root: PathBuf,
+    snapshot: Option<&'a PbsSnapshot>,
+    runner: Arc<dyn Runner + Send + Sync>,
+    ssh: SshRunner,
+    matcher: Arc<RestoreMatcher>,
+    name_template: Option<String>,
+}
+
+impl<'a> SshRestore<'a> {
+    pub fn new(
+        snapshot: Option<&'a PbsSnapshot>,
+        runner: Arc<dyn Runner + Send + Sync>,
+        matcher: Arc<RestoreMatcher>,
+        ssh: Ssh,
+        device_root: PathBuf,
+        target_name: String,
+    ) -> Self {
+        assert!(
+            !target_name.trim().is_empty(),
+            "[ssh target] empty target_name"
+        );
+        Self {
+            target_name,
+            device_root,
+            snapshot,
+            runner,
+            ssh: SshRunner::new(ssh),
+            matcher,
+            name_template: None,
+        }
+    }
+
+    #[inline]
+    fn routes_to_me(&self, f: &PbsFile) -> bool {
+        if f.class() != FileClass::Archive {
+            return false;
+        }
+        // `zfs send` streams can only be received into ZFS.
+        if let Ok((provider, leaf, _id)) = parse_archive_name(&f.filename)
+            && send_stream_leaf(&leaf).is_none()
+            && let Some(tname) = self.matcher.pick_target_name(&provider, f)
+        {
+            return tname == self.target_name;
+        }
+        false
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        let test = CmdSpec::new("test").args(["-e", path]);
+        let cmd = self
+            .ssh
+            .command(&test)
+            .stdin(StdioSpec::Null)
+            .stderr(StdioSpec::Null);
+        self.runner.run_capture(&Pipeline::new().cmd(cmd)).is_ok()
+    }
+
+    fn volume(&self, archive: &str) -> Result<Volume> {
+        let (_provider, leaf, _id) = parse_archive_name(archive)?;
+        let leaf = volume_name(self.name_template.as_deref(), self.snapshot, archive, &leaf)?;
+        let device = self.device_root.join(&leaf);
+        // `dd` onto a missing device node would leave a regular file on devtmpfs.
+        if !self.exists(&device.display().to_string()) {
+            bail!(
+                "{} does not exist on {}; create it there before restoring {archive}",
+                device.display(),
+                self.ssh.host()
+            );
+        }
+        Ok(Volume {
+            storage: self.target_name.clone(),
+            disk: leaf,
+            archive: archive.to_string(),
+            device,
+            size_bytes: None,
+            meta: Some(Arc::new(RemoteDevice)),
+        })
+    }
+}
+
+impl NameTemplate for SshRestore<'_> {
+    fn name_template_mut(&mut self) -> &mut Option<String> {
+        &mut self.name_template
+    }
+}
+
+impl<'a> Provider for SshRestore<'a> {
+    fn name(&self) -> &'static str {
+        "ssh"
+    }
+
+    fn collect_restore(&mut self, archive: Option<&str>, all: bool) -> Result<Vec<Volume>> {
+        let mut out = Vec::new();
+        match (archive, all, self.snapshot) {
+            (Some(a), _, Some(snap)) => {
+                if let Some(file) = snap.files.iter().find(|f| f.filename == a)
+                    && self.routes_to_me(file)
+                {
+                    out.push(self.volume(a)?);
+                }
+            }
+            (None, true, Some(snap)) => {
+                for f in snap.files.iter().filter(|f| self.routes_to_me(f)) {
+                    out.push(self.volume(&f.filename)?);
+                }
+            }
+            (Some(a), _, None) => bail!("no snapshot context for archive {a}"),
+            (None, true, None) => bail!("no snapshot context provided for restore-all"),
+            (None, false, _) => {}
+        }
+        Ok(out)
+    }
+
+    fn list_archives(&self, snap: &PbsSnapshot) -> Vec<String> {
+        snap.files
+            .iter()
+            .filter(|f| self.routes_to_me(f))
+            .map(|f| f.filename.clone())
+            .collect()
+    }
+
+    fn create_target(&self, _vol: &Volume) -> Result<()> {
+        Ok(())
+    }
+
+    fn safety_snapshot(&self, _vol: &Volume, _suffix: &str) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    fn overwrites(&self, vol: &Volume) -> bool {
+        vol.meta::<RemoteDevice>().is_some()
+    }
+
+    /// Nothing is known about what the remote device reads back where nothing is written.
+    fn skips_zeros(&self, _vol: &Volume) -> bool {
+        false
+    }
+
+    fn receive_stream(&self, vol: &Volume, src: &CmdSpec, opts: &WriteOpts) -> Option<Result<()>> {
+        vol.meta::<RemoteDevice>()?;
+        let dd = self.ssh.command(&dd::to_file_cmd(&vol.device, opts));
+        Some(
+            self.runner
+                .run(&Pipeline::new().cmd(src.clone()).cmd(dd))
+                .with_context(|| format!("write {} on {}", vol.device.display(), self.ssh.host())),
+        )
+    }
+
+    /// Plain remote devices have nowhere to keep a stamp, so they are always rewritten.
+    fn restored_from(&self, _vol: &Volume) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    fn mark_restored(&self, _vol: &Volume, _stamp: Option<&str>) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        path::Path,
+    };
+
+    use super::*;
+    use crate::{
+        config::{Backup, Config, Events, Pbs, Pve, Restore, RestoreRule, RestoreTarget},
+        tooling::pbs::PbsFile,
+        utils::process::ScriptedRunner,
+    };
+
+    #[test]
+    fn streams_into_dd_over_ssh() {
+        let ssh = Ssh {
+            host: "dr1".to_string(),
+            user: Some("root".to_string()),
+            identity_file: None,
+            port: None,
+        };
+        let device_root = PathBuf::from("/dev/zvol/tank");
+        let cfg = Config {
+            restore: Restore {
+                targets: BTreeMap::from([(
+                    "far".to_string(),
+                    RestoreTarget::Ssh {
+                        ssh: ssh.clone(),
+                        device_root: device_root.clone(),
+                        name_template: None,
+                    },
+                )]),
+                rules: vec![RestoreRule {
+                    match_provider: "zfs".to_string(),
+                    match_archive_regex: None,
+                    target: "far".to_string(),
+                    write: None,
+                }],
+                ..Restore::default()
+            },
+            pbs: Pbs {
+                repos: HashMap::new(),
+                keyfile: None,
+                password: None,
+                key_passphrase: None,
+                create_ns: Default::default(),
+                ns: None,
+                backup_id: "test".to_string(),
+            },
+            pve: Pve::default(),
+            events: Events::default(),
+            log: Default::default(),
+            state: Default::default(),
+            backup: Backup::default(),
+            nodes: BTreeMap::new(),
+        };
+        let snap = PbsSnapshot {
+            backup_id: "test".to_string(),
+            backup_time: 1234567890,
+            files: [
+                "zfs_vm-1-disk-0_noext_abcd1234.img.fidx",
+                "zfs_vm-2-disk-0_noext_ef567890.img.fidx",
+            ]
+            .into_iter()
+            .map(|f| PbsFile {
+                filename: f.to_string(),
+                size: 1 << 20,
+                crypt_mode: None,
+            })
+            .collect(),
+        };
+        let runner = Arc::new(ScriptedRunner::new().fail("vm-2-disk-0", "test failed"));
+        let mut restore = SshRestore::new(
+            Some(&snap),
+            runner.clone(),
+            Arc::new(RestoreMatcher::new(&cfg).unwrap()),
+            ssh,
+            device_root,
+            "far".to_string(),
+        );
+
+        let e = restore.collect_restore(None, true).unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("/dev/zvol/tank/vm-2-disk-0 does not exist on dr1"),
+            "{e}"
+        );
+        let items = restore
+            .collect_restore(Some("zfs_vm-1-disk-0_noext_abcd1234.img.fidx"), false)
+            .unwrap();
+        assert_eq!(items[0].device, Path::new("/dev/zvol/tank/vm-1-disk-0"));
+        assert_eq!(items[0].storage, "far");
+        assert!(restore.overwrites(&items[0]));
+
+        let src = CmdSpec::new("proxmox-backup-client").args(["restore", "snap", "a", "-"]);
+        restore
+            .receive_stream(&items[0], &src, &WriteOpts::default())
+            .unwrap()
+            .unwrap();
+        let calls = runner.calls();
+        assert_eq!(
+            calls.last().unwrap(),
+            "proxmox-backup-client restore snap a - | ssh -T -o BatchMode=yes root@dr1 \
+             'dd of=/dev/zvol/tank/vm-1-disk-0 bs=4194304 conv=notrunc,fsync oflag=direct status=progress'"
+        );
+    }
+}
@@ -6,16 +6,34 @@ use std::{
 use anyhow::{Context, Result, anyhow, bail};
 
 use crate::{
-    commands::restore::{matcher::RestoreMatcher, providers::Provider},
+    commands::restore::{
+        matcher::RestoreMatcher,
+        providers::{NameTemplate, Provider, RESTORED_FROM_PROP, volume_name},
+    },
     tooling::{
         FsPort, PveshPort, ZfsPort,
-        pbs::{PbsFile, PbsSnapshot},
+        pbs::{FileClass, PbsFile, PbsSnapshot},
         pvesh::Storage,
+        writer::WriteOpts,
+    },
+    utils::{
+        naming::{parse_archive_name, send_stream_leaf},
+        process::CmdSpec,
     },
-    utils::naming::parse_archive_name,
     volume::Volume,
 };
 
+struct ZfsTarget {
+    dataset: String,
+    existed: bool,
+    /// Restored with `zfs receive` from a `zfs send` stream archive.
+    stream: bool,
+    /// The device is the zvol itself rather than a file in a mounted dataset.
+    zvol: bool,
+    /// Size of the zvol or sparse file to create.
+    size_bytes: u64,
+}
+
 pub struct ZfsRestore<'a> {
     dest_root: String,
     target_name: String,
@@ -24,6 +42,8 @@ pub struct ZfsRestore<'a> {
     pvesh: Arc<dyn PveshPort>,
     fs: Arc<dyn FsPort>,
     matcher: Arc<RestoreMatcher>,
+    volblocksize: Option<String>,
+    name_template: Option<String>,
 }
 
 impl<'a> ZfsRestore<'a> {
@@ -50,10 +70,21 @@ impl<'a> ZfsRestore<'a> {
             pvesh,
             fs,
             matcher,
+            volblocksize: None,
+            name_template: None,
         }
     }
+
+    pub fn with_volblocksize(mut self, volblocksize: Option<String>) -> Self {
+        self.volblocksize = volblocksize;
+        self
+    }
+
     #[inline]
     fn routes_to_me(&self, f: &PbsFile) -> bool {
+        if f.class() != FileClass::Archive {
+            return false;
+        }
         if let Ok((provider, _leaf, _id)) = parse_archive_name(&f.filename)
             && let Some(tname) = self.matcher.pick_target_name(&provider, f)
         {
@@ -62,10 +93,25 @@ impl<'a> ZfsRestore<'a> {
         false
     }
 
-    fn resolve_dataset_target(&self, archive: &str) -> Result<(PathBuf, String)> {
+    fn resolve_dataset_target(&self, archive: &str) -> Result<(PathBuf, String, ZfsTarget)> {
         let (_provider, leaf, _id) = parse_archive_name(archive)?;
+        if let Some(leaf) = send_stream_leaf(&leaf) {
+            let leaf = volume_name(self.name_template.as_deref(), self.snapshot, archive, leaf)?;
+            let dataset = format!("{}/{}", self.dest_root, leaf);
+            if self.zfs.assert_dataset_exists(&dataset).is_ok() {
+                bail!("{dataset} exists; zfs send archives are only received into new datasets");
+            }
+            let target = ZfsTarget {
+                dataset: dataset.clone(),
+                existed: false,
+                stream: true,
+                zvol: false,
+                size_bytes: 0,
+            };
+            return Ok((PathBuf::from(dataset), leaf, target));
+        }
 
-        let (size_bytes, file_name_for_err) = {
+        let size_bytes = {
             let snap = self
                 .snapshot
                 .ok_or_else(|| anyhow!("no snapshot context to size '{archive}'"))?;
@@ -74,43 +120,39 @@ impl<'a> ZfsRestore<'a> {
                 .iter()
                 .find(|f| f.filename == archive)
                 .ok_or_else(|| anyhow!("archive {archive} not found in snapshot"))?;
-
-            (file.size, file.filename.clone())
+            file.size
         };
+        let leaf = volume_name(self.name_template.as_deref(), self.snapshot, archive, &leaf)?;
         let dataset = format!("{}/{}", self.dest_root, leaf);
 
-        let mp = match self.zfs.dataset_mountpoint(&dataset) {
-            Ok(mp) => mp,
-            Err(_) => {
-                self.zfs
-                    .create_zvol(&dataset, size_bytes)
-                    .with_context(|| format!("zfs create -V {size_bytes} {dataset}"))?;
-                None
-            }
+        let (mp, existed) = match self.zfs.dataset_mountpoint(&dataset) {
+            Ok(mp) => (mp, true),
+            Err(_) => (None, false),
         };
 
+        let zvol = mp.is_none();
         let target = match mp {
             None => Path::new("/dev/zvol").join(&dataset),
-            Some(path) => {
-                let target = Path::new(&path).join(&leaf);
-                self.fs
-                    .ensure_parent_dir(&target)
-                    .with_context(|| format!("create dir for {}", target.display()))?;
-                self.fs
-                    .create_sparse_file(&target, size_bytes)
-                    .with_context(|| {
-                        format!(
-                            "create sparse file {} ({} bytes) for {}",
-                            target.display(),
-                            size_bytes,
-                            file_name_for_err
-                        )
-                    })?;
-                target
-            }
+            Some(path) => Path::new(&path).join(&leaf),
         };
 
-        Ok((target, leaf))
+        Ok((
+            target,
+            leaf,
+            ZfsTarget {
+                dataset,
+                existed,
+                stream: false,
+                zvol,
+                size_bytes,
+            },
+        ))
+    }
+}
+
+impl NameTemplate for ZfsRestore<'_> {
+    fn name_template_mut(&mut self) -> &mut Option<String> {
+        &mut self.name_template
     }
 }
 
@@ -129,26 +171,28 @@ impl<'a> Provider for ZfsRestore<'a> {
                 if let Some(file) = _snap.files.iter().find(|f| f.filename == a)
                     && self.routes_to_me(file)
                 {
-                    let (target, leaf) = self.resolve_dataset_target(a)?;
+                    let (target, leaf, meta) = self.resolve_dataset_target(a)?;
                     out.push(Volume {
                         storage: storage_id.to_string(),
                         disk: leaf,
                         archive: a.to_string(),
                         device: target,
-                        meta: None,
+                        size_bytes: None,
+                        meta: Some(Arc::new(meta)),
                     });
                 }
             }
             (None, true, Some(snap)) => {
                 for f in &snap.files {
                     if self.routes_to_me(f) {
-                        let (target, leaf) = self.resolve_dataset_target(&f.filename)?;
+                        let (target, leaf, meta) = self.resolve_dataset_target(&f.filename)?;
                         out.push(Volume {
                             storage: storage_id.to_string(),
                             disk: leaf,
                             archive: f.filename.clone(),
                             device: target,
-                            meta: None,
+                            size_bytes: None,
+                            meta: Some(Arc::new(meta)),
                         });
                     }
                 }
@@ -168,34 +212,117 @@ impl<'a> Provider for ZfsRestore<'a> {
             .map(|f| f.filename.clone())
             .collect()
     }
+
+    fn create_target(&self, vol: &Volume) -> Result<()> {
+        let Some(t) = vol.meta::<ZfsTarget>() else {
+            return Ok(());
+        };
+        if t.stream {
+            return self.zfs.ensure_dataset(&self.dest_root);
+        }
+        let size_bytes = t.size_bytes;
+        if !t.zvol {
+            let target = &vol.device;
+            self.fs
+                .ensure_parent_dir(target)
+                .with_context(|| format!("create dir for {}", target.display()))?;
+            return self
+                .fs
+                .create_sparse_file(target, size_bytes)
+                .with_context(|| {
+                    format!(
+                        "create sparse file {} ({} bytes) for {}",
+                        target.display(),
+                        size_bytes,
+                        vol.archive
+                    )
+                });
+        }
+        if t.existed {
+            return Ok(());
+        }
+        self.zfs.ensure_dataset(&self.dest_root)?;
+        self.zfs
+            .create_zvol(&t.dataset, size_bytes, self.volblocksize.as_deref())
+            .with_context(|| format!("zfs create -V {size_bytes} {}", t.dataset))
+    }
+
+    fn safety_snapshot(&self, vol: &Volume, suffix: &str) -> Result<Option<String>> {
+        let Some(t) = vol.meta::<ZfsTarget>().filter(|t| t.existed) else {
+            return Ok(None);
+        };
+        let snap = format!("{}@{suffix}", t.dataset);
+        self.zfs
+            .snapshot(&snap)
+            .with_context(|| format!("safety snapshot of {}", t.dataset))?;
+        Ok(Some(format!("zfs rollback -r {snap}")))
+    }
+
+    fn overwrites(&self, vol: &Volume) -> bool {
+        vol.meta::<ZfsTarget>().is_some_and(|t| t.existed)
+    }
+
+    fn skips_zeros(&self, vol: &Volume) -> bool {
+        vol.meta::<ZfsTarget>().is_some_and(|t| !t.existed)
+    }
+
+    fn receive_stream(&self, vol: &Volume, src: &CmdSpec, _opts: &WriteOpts) -> Option<Result<()>> {
+        let t = vol.meta::<ZfsTarget>().filter(|t| t.stream)?;
+        Some(self.zfs.receive(src.clone(), &t.dataset))
+    }
+
+    fn restored_from(&self, vol: &Volume) -> Result<Option<String>> {
+        match vol.meta::<ZfsTarget>().filter(|t| t.existed && t.zvol) {
+            Some(t) => self.zfs.user_property(&t.dataset, RESTORED_FROM_PROP),
+            None => Ok(None),
+        }
+    }
+
+    fn mark_restored(&self, vol: &Volume, stamp: Option<&str>) -> Result<()> {
+        match vol.meta::<ZfsTarget>().filter(|t| t.zvol) {
+            Some(t) => self
+                .zfs
+                .set_user_property(&t.dataset, RESTORED_FROM_PROP, stamp),
+            None => Ok(()),
+        }
+    }
 }
 
-#[inline]
-fn find_storage<'a>(storages: &'a [Storage], pool: &str) -> Result<&'a str> {
+/// The storage whose pool is `root` or its closest ancestor.
+fn find_storage<'a>(storages: &'a [Storage], root: &str) -> Result<&'a str> {
     storages
         .iter()
-        .find_map(|s| match *s {
-            Storage::ZfsPool {
-                ref id,
-                pool: ref zpool_name,
-                ..
-            } if zpool_name.as_str() == pool => Some(id.as_str()),
+        .filter_map(|s| match s {
+            Storage::ZfsPool { id, pool, .. }
+                if root == pool
+                    || root
+                        .strip_prefix(pool.as_str())
+                        .is_some_and(|r| r.starts_with('/')) =>
+            {
+                Some((pool.len(), id.as_str()))
+            }
             _ => None,
         })
-        .ok_or_else(|| anyhow!("Zfs storage with pool='{pool}' not found"))
+        .max_by_key(|(len, _)| *len)
+        .map(|(_, id)| id)
+        .ok_or_else(|| anyhow!("Zfs storage with pool='{root}' not found"))
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{collections::BTreeMap, sync::Arc};
+    use std::{
+        collections::BTreeMap,
+        sync::{Arc, Mutex},
+    };
 
     use anyhow::{Ok, Result};
 
     use super::*;
     use crate::{
         commands::restore::matcher::RestoreMatcher,
-        config::{Backup, Config, Pbs, Restore, RestoreTarget},
+        config::{Backup, Config, Events, Pbs, Pve, Restore, RestoreTarget},
         tooling::{FsPort, PveshPort, ZfsPort, pbs::PbsFile, pvesh::Storage},
+        utils::process::Priority,
     };
 
     struct MockPvesh;
@@ -207,20 +334,33 @@ mod tests {
                 content: vec!["".to_string()],
             }])
         }
+        fn cluster_storage(&self) -> Result<Vec<crate::tooling::pvesh::ClusterStorage>> {
+            Ok(Vec::new())
+        }
     }
 
     struct MockZfs {
         exists: bool,
         mountpoint: Option<String>,
+        created: Mutex<Vec<String>>,
     }
 
     impl ZfsPort for MockZfs {
         fn list_volumes(&self, _pool: &str) -> Result<Vec<crate::tooling::zfs::ZfsVolume>> {
             Ok(vec![])
         }
+        fn list_filesystems(&self, _pool: &str) -> Result<Vec<crate::tooling::zfs::ZfsVolume>> {
+            Ok(vec![])
+        }
+        fn list_snapshots(&self, _pool: &str) -> Result<Vec<String>> {
+            Ok(vec![])
+        }
         fn guid_map(&self, _pool: &str) -> Result<std::collections::HashMap<String, String>> {
             Ok(std::collections::HashMap::new())
         }
+        fn visible_snapdevs(&self, _pool: &str) -> Result<std::collections::HashSet<String>> {
+            Ok(std::collections::HashSet::new())
+        }
         fn snapshot(&self, _name: &str) -> Result<()> {
             Ok(())
         }
@@ -230,6 +370,12 @@ mod tests {
         fn destroy_recursive(&self, _name: &str) -> Result<()> {
             Ok(())
         }
+        fn rename(&self, _from: &str, _to: &str) -> Result<()> {
+            Ok(())
+        }
+        fn written_since(&self, _dataset: &str, _snap: &str) -> Result<Option<u64>> {
+            Ok(None)
+        }
         fn assert_dataset_exists(&self, _dataset: &str) -> Result<()> {
             if self.exists {
                 Ok(())
@@ -238,9 +384,60 @@ mod tests {
             }
         }
         fn dataset_mountpoint(&self, _dataset: &str) -> Result<Option<String>> {
-            Ok(self.mountpoint.clone())
+            if self.exists {
+                Ok(self.mountpoint.clone())
+            } else {
+                bail!("dataset not found")
+            }
+        }
+        fn user_property(&self, _dataset: &str, _prop: &str) -> Result<Option<String>> {
+            Ok(self
+                .exists
+                .then(|| "zfs_vm-123_raw_abcd1234.img@1234567890".to_string()))
         }
-        fn create_zvol(&self, _dataset: &str, _size_bytes: u64) -> Result<()> {
+        fn set_user_property(&self, dataset: &str, prop: &str, value: Option<&str>) -> Result<()> {
+            self.created
+                .lock()
+                .unwrap()
+                .push(format!("set {dataset} {prop}={}", value.unwrap_or("-")));
+            Ok(())
+        }
+        fn create_zvol(
+            &self,
+            dataset: &str,
+            _size_bytes: u64,
+            volblocksize: Option<&str>,
+        ) -> Result<()> {
+            let bs = volblocksize.map_or_else(String::new, |bs| format!(" volblocksize={bs}"));
+            self.created
+                .lock()
+                .unwrap()
+                .push(format!("zvol {dataset}{bs}"));
+            Ok(())
+        }
+        fn ensure_dataset(&self, dataset: &str) -> Result<()> {
+            self.created.lock().unwrap().push(format!("fs {dataset}"));
+            Ok(())
+        }
+        fn pool_status(&self, _pool: &str) -> Result<String> {
+            Ok(String::new())
+        }
+        fn pool_usage(&self, _pool: &str) -> Result<String> {
+            Ok(String::new())
+        }
+        fn send_size(&self, _snap: &str) -> Result<u64> {
+            Ok(0)
+        }
+        fn send_to_file(
+            &self,
+            _snap: &str,
+            _path: &std::path::Path,
+            _priority: Priority,
+            _timeout: Option<std::time::Duration>,
+        ) -> Result<()> {
+            Ok(())
+        }
+        fn receive(&self, _src: CmdSpec, _dataset: &str) -> Result<()> {
             Ok(())
         }
     }
@@ -257,6 +454,18 @@ mod tests {
         fn create_sparse_file(&self, _path: &std::path::Path, _size_bytes: u64) -> Result<()> {
             Ok(())
         }
+        fn write_file(&self, _path: &std::path::Path, _contents: &str) -> Result<()> {
+            Ok(())
+        }
+        fn remove_file(&self, _path: &std::path::Path) -> Result<()> {
+            Ok(())
+        }
+        fn copy_into(&self, _src: &std::path::Path, _dest: &std::path::Path) -> Result<()> {
+            Ok(())
+        }
+        fn free_bytes(&self, _dir: &std::path::Path) -> Result<u64> {
+            Ok(0)
+        }
     }
 
     fn test_config() -> Config {
@@ -265,6 +474,8 @@ mod tests {
             "zfs-tank".to_string(),
             RestoreTarget::Zfs {
                 root: "tank".to_string(),
+                volblocksize: None,
+                name_template: None,
             },
         );
 
@@ -273,9 +484,15 @@ mod tests {
                 repos: std::collections::HashMap::new(),
                 keyfile: None,
                 password: None,
+                key_passphrase: None,
+                create_ns: Default::default(),
                 ns: None,
                 backup_id: "test".to_string(),
             },
+            pve: Pve::default(),
+            events: Events::default(),
+            log: Default::default(),
+            state: Default::default(),
             backup: Backup::default(),
             restore: Restore {
                 targets,
@@ -283,9 +500,12 @@ mod tests {
                     match_provider: "zfs".to_string(),
                     match_archive_regex: None,
                     target: "zfs-tank".to_string(),
+                    write: None,
                 }],
                 default_target: None,
+                ..Restore::default()
             },
+            nodes: BTreeMap::new(),
         }
     }
 
@@ -297,10 +517,12 @@ mod tests {
                 PbsFile {
                     filename: "zfs_vm-123_raw_abcd1234.img".to_string(),
                     size: 4 * 1024 * 1024,
+                    crypt_mode: None,
                 },
                 PbsFile {
                     filename: "lvmthin_vm-456_raw_efgh5678.img".to_string(),
                     size: 4 * 1024 * 1024,
+                    crypt_mode: None,
                 },
             ],
         }
@@ -312,6 +534,7 @@ mod tests {
         let zfs = Arc::new(MockZfs {
             exists: true,
             mountpoint: None,
+            created: Default::default(),
         });
         let pvesh = Arc::new(MockPvesh);
         let fs = Arc::new(MockFs);
@@ -327,7 +550,7 @@ mod tests {
             "zfs-tank".to_string(),
         );
 
-        let (target, _) = restore
+        let (target, _, _) = restore
             .resolve_dataset_target("zfs_vm-123_raw_abcd1234.img")
             .unwrap();
         assert_eq!(target, PathBuf::from("/dev/zvol/tank/vm-123.raw"));
@@ -339,6 +562,7 @@ mod tests {
         let zfs = Arc::new(MockZfs {
             exists: true,
             mountpoint: Some("/mnt/tank".to_string()),
+            created: Default::default(),
         });
         let pvesh = Arc::new(MockPvesh);
         let fs = Arc::new(MockFs);
@@ -354,18 +578,105 @@ mod tests {
             "zfs-tank".to_string(),
         );
 
-        let (target, _) = restore
+        let (target, _, _) = restore
             .resolve_dataset_target("zfs_vm-123_raw_abcd1234.img")
             .unwrap();
         assert_eq!(target, PathBuf::from("/mnt/tank/vm-123.raw"));
     }
 
+    #[test]
+    fn stream_archives_are_received_into_new_datasets() {
+        let snap = test_snapshot();
+        let cfg = test_config();
+        let restore = |exists: bool| {
+            ZfsRestore::new(
+                Some(&snap),
+                Arc::new(MockZfs {
+                    exists,
+                    mountpoint: None,
+                    created: Default::default(),
+                }),
+                Arc::new(MockPvesh),
+                Arc::new(MockFs),
+                Arc::new(RestoreMatcher::new(&cfg).unwrap()),
+                "tank".to_string(),
+                "zfs-tank".to_string(),
+            )
+        };
+
+        let (target, leaf, meta) = restore(false)
+            .resolve_dataset_target("zfs_subvol-100-disk-0_zsend_abcd1234.img")
+            .unwrap();
+        assert_eq!(target, PathBuf::from("tank/subvol-100-disk-0"));
+        assert_eq!(leaf, "subvol-100-disk-0");
+        assert!(meta.stream && !meta.existed);
+        assert!(
+            restore(true)
+                .resolve_dataset_target("zfs_subvol-100-disk-0_zsend_abcd1234.img")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn safety_snapshot_of_existing_zvol() {
+        let snap = test_snapshot();
+        let zfs = Arc::new(MockZfs {
+            exists: true,
+            mountpoint: None,
+            created: Default::default(),
+        });
+        let cfg = test_config();
+        let matcher = Arc::new(RestoreMatcher::new(&cfg).unwrap());
+        let mut restore = ZfsRestore::new(
+            Some(&snap),
+            zfs.clone(),
+            Arc::new(MockPvesh),
+            Arc::new(MockFs),
+            matcher,
+            "tank".to_string(),
+            "zfs-tank".to_string(),
+        );
+
+        let items = restore
+            .collect_restore(Some("zfs_vm-123_raw_abcd1234.img"), false)
+            .unwrap();
+        let cmd = restore
+            .safety_snapshot(&items[0], "pvtools-prerestore-42")
+            .unwrap();
+        assert_eq!(
+            cmd.as_deref(),
+            Some("zfs rollback -r tank/vm-123.raw@pvtools-prerestore-42")
+        );
+
+        let foreign = Volume {
+            meta: None,
+            ..items[0].clone()
+        };
+        assert!(restore.safety_snapshot(&foreign, "x").unwrap().is_none());
+        assert!(restore.overwrites(&items[0]));
+        assert!(!restore.overwrites(&foreign));
+        assert!(!restore.skips_zeros(&items[0]));
+
+        assert_eq!(
+            restore.restored_from(&items[0]).unwrap().as_deref(),
+            Some("zfs_vm-123_raw_abcd1234.img@1234567890")
+        );
+        assert!(restore.restored_from(&foreign).unwrap().is_none());
+        restore.mark_restored(&items[0], None).unwrap();
+        restore.mark_restored(&foreign, Some("x@1")).unwrap();
+        assert_eq!(
+            *zfs.created.lock().unwrap(),
+            ["set tank/vm-123.raw pvtools:restored_from=-"]
+        );
+    }
+
     #[test]
     fn collect_restore_single_archive() {
         let snap = test_snapshot();
         let zfs = Arc::new(MockZfs {
             exists: true,
             mountpoint: None,
+            created: Default::default(),
         });
         let pvesh = Arc::new(MockPvesh);
         let fs = Arc::new(MockFs);
@@ -395,6 +706,7 @@ mod tests {
         let zfs = Arc::new(MockZfs {
             exists: true,
             mountpoint: None,
+            created: Default::default(),
         });
         let pvesh = Arc::new(MockPvesh);
         let fs = Arc::new(MockFs);
@@ -421,6 +733,7 @@ mod tests {
         let zfs = Arc::new(MockZfs {
             exists: true,
             mountpoint: None,
+            created: Default::default(),
         });
         let pvesh = Arc::new(MockPvesh);
         let fs = Arc::new(MockFs);
@@ -446,6 +759,7 @@ mod tests {
         let zfs = Arc::new(MockZfs {
             exists: false,
             mountpoint: None,
+            created: Default::default(),
         });
         let pvesh = Arc::new(MockPvesh);
         let fs = Arc::new(MockFs);
@@ -467,11 +781,77 @@ mod tests {
         );
     }
 
+    #[test]
+    fn missing_root_ancestors_are_created() {
+        let snap = test_snapshot();
+        let zfs = Arc::new(MockZfs {
+            exists: false,
+            mountpoint: None,
+            created: Default::default(),
+        });
+        let cfg = test_config();
+        let mut restore = ZfsRestore::new(
+            Some(&snap),
+            zfs.clone(),
+            Arc::new(MockPvesh),
+            Arc::new(MockFs),
+            Arc::new(RestoreMatcher::new(&cfg).unwrap()),
+            "tank/k8s/restored".to_string(),
+            "zfs-tank".to_string(),
+        );
+
+        let vols = restore
+            .collect_restore(Some("zfs_vm-123_raw_abcd1234.img"), false)
+            .unwrap();
+        assert_eq!(vols[0].storage, "local-zfs");
+        assert_eq!(
+            vols[0].device,
+            PathBuf::from("/dev/zvol/tank/k8s/restored/vm-123.raw")
+        );
+        assert!(zfs.created.lock().unwrap().is_empty());
+        restore.create_target(&vols[0]).unwrap();
+        assert_eq!(
+            *zfs.created.lock().unwrap(),
+            ["fs tank/k8s/restored", "zvol tank/k8s/restored/vm-123.raw"]
+        );
+    }
+
+    #[test]
+    fn zvols_are_created_with_target_volblocksize() {
+        let snap = test_snapshot();
+        let zfs = Arc::new(MockZfs {
+            exists: false,
+            mountpoint: None,
+            created: Default::default(),
+        });
+        let cfg = test_config();
+        let mut restore = ZfsRestore::new(
+            Some(&snap),
+            zfs.clone(),
+            Arc::new(MockPvesh),
+            Arc::new(MockFs),
+            Arc::new(RestoreMatcher::new(&cfg).unwrap()),
+            "tank".to_string(),
+            "zfs-tank".to_string(),
+        )
+        .with_volblocksize(Some("16k".to_string()));
+
+        let vols = restore
+            .collect_restore(Some("zfs_vm-123_raw_abcd1234.img"), false)
+            .unwrap();
+        restore.create_target(&vols[0]).unwrap();
+        assert_eq!(
+            zfs.created.lock().unwrap().last().unwrap(),
+            "zvol tank/vm-123.raw volblocksize=16k"
+        );
+    }
+
     #[test]
     fn collect_restore_all_requires_snapshot() {
         let zfs = Arc::new(MockZfs {
             exists: true,
             mountpoint: None,
+            created: Default::default(),
         });
         let pvesh = Arc::new(MockPvesh);
         let fs = Arc::new(MockFs);
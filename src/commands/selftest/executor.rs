@@ -0,0 +1,433 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result, bail};
+use tempfile::TempDir;
+
+use crate::{
+    AppCtx,
+    commands::{backup, restore},
+    config::{
+        BackupSources, Config, DEFAULT_MAX_POOL_USAGE, DEFAULT_SEND_STAGING_DIR, LvmThin, Repo,
+        Restore, RestoreTarget, Zfs,
+    },
+    events::EventSink,
+    tooling::{PbsPort, Toolbox, pbs::snapshot_path},
+    utils::{
+        bins::ensure_bins,
+        process::{CmdSpec, Pipeline, ProcessRunner, Runner, StdioSpec},
+        signal,
+        time::current_epoch,
+    },
+};
+
+/// Sparse file behind the loop device.
+const IMAGE_SIZE: u64 = 256 << 20;
+const THINPOOL_SIZE: &str = "160M";
+const DISK_SIZE: u64 = 32 << 20;
+const THINPOOL: &str = "data";
+/// The volume backed up; renamed to [`SOURCE`] before the restore recreates it.
+const DISK: &str = "selftest-disk";
+const SOURCE: &str = "selftest-src";
+const BACKUP_ID: &str = "pvtools-selftest";
+const TARGET: &str = "selftest";
+
+pub struct SelftestOpts {
+    pub allow_destructive: bool,
+    pub lvm: bool,
+    pub target: Option<String>,
+}
+
+/// Backs up a volume of random data on a scratch pool or VG, restores it next to the
+/// original and compares both byte for byte.
+pub fn selftest(cfg: &Config, debug: bool, opts: SelftestOpts) -> Result<()> {
+    if !opts.allow_destructive {
+        bail!(
+            "selftest creates and destroys a pool or VG on a loop device; run it on a test box \
+             with --allow-destructive"
+        );
+    }
+    let mut bins = vec!["losetup", "dd", "cmp"];
+    bins.extend(if opts.lvm {
+        ["vgcreate", "vgremove", "lvcreate", "lvrename"].as_slice()
+    } else {
+        ["zpool", "zfs"].as_slice()
+    });
+    ensure_bins(bins)?;
+
+    let name = format!("pvtools-selftest-{}", current_epoch());
+    let runner = Arc::new(ProcessRunner::new());
+    let dir = TempDir::new().context("create scratch dir")?;
+    let image = dir.path().join("disk.img");
+    File::create(&image)
+        .and_then(|f| f.set_len(IMAGE_SIZE))
+        .with_context(|| format!("create {}", image.display()))?;
+
+    let loop_dev = capture(
+        runner.as_ref(),
+        CmdSpec::new("losetup").args(["--find", "--show", &image.display().to_string()]),
+    )?;
+    tracing::info!("selftest: {name} on {loop_dev}");
+    let mut scratch = Scratch {
+        runner: runner.clone(),
+        lvm: opts.lvm,
+        name: name.clone(),
+        loop_dev,
+        created: false,
+        pbs: None,
+        snapshot: None,
+        _dir: dir,
+    };
+    scratch.create()?;
+
+    let scfg = selftest_config(cfg, &name, opts.lvm);
+    let tools = Toolbox::new(&scfg, runner.clone())?;
+    let ctx = AppCtx {
+        debug,
+        cfg: scfg,
+        runner,
+        tools,
+        events: Arc::new(EventSink::disabled()),
+    };
+
+    let disk = scratch.device(DISK);
+    create_disk(&ctx, &name, opts.lvm)?;
+    ctx.tools.block().wait_for_block(&disk)?;
+    run(
+        &ctx,
+        CmdSpec::new("dd").args([
+            "if=/dev/urandom".to_string(),
+            format!("of={}", disk.display()),
+            "bs=1M".to_string(),
+            format!("count={}", DISK_SIZE >> 20),
+            "conv=fsync".to_string(),
+            "status=none".to_string(),
+        ]),
+    )?;
+
+    tracing::info!("selftest: backing up {DISK}");
+    backup::backup(
+        &ctx,
+        backup::RunOpts {
+            target: opts.target.clone(),
+            dry_run: false,
+            emit_script: None,
+            ignore_blackout: true,
+            changed_only: false,
+            only: Vec::new(),
+            include_active: true,
+            force: true,
+        },
+    )
+    .context("selftest backup")?;
+    scratch.snapshot = latest_snapshot(&ctx, opts.target.as_deref())?;
+    scratch.pbs = Some(ctx.tools.pbs());
+
+    rename_disk(&ctx, &name, opts.lvm)?;
+    tracing::info!("selftest: restoring {DISK}");
+    restore::restore_run(
+        &ctx,
+        restore::RunOpts {
+            source: opts.target.clone(),
+            snapshot: restore::parse_point("latest")?,
+            backup_id: None,
+            archives: Vec::new(),
+            pvcs: Vec::new(),
+            exclude: Vec::new(),
+            all: true,
+            plan: None,
+            dry_run: false,
+            emit_script: None,
+            fail_fast: true,
+            strict_routing: true,
+            attach_to_vm: false,
+            k8s_manifests: None,
+            k8s_apply: false,
+            safety_snapshot: false,
+            force: true,
+            block_size: None,
+            yes: true,
+        },
+    )
+    .context("selftest restore")?;
+
+    let source = scratch.device(SOURCE);
+    ctx.tools.block().wait_for_block(&source)?;
+    ctx.tools.block().wait_for_block(&disk)?;
+    run(
+        &ctx,
+        CmdSpec::new("cmp").args([
+            "-n".to_string(),
+            DISK_SIZE.to_string(),
+            source.display().to_string(),
+            disk.display().to_string(),
+        ]),
+    )
+    .context("restored volume differs from the original")?;
+    tracing::info!("selftest passed: {DISK_SIZE} bytes backed up and restored intact");
+    Ok(())
+}
+
+/// The user's config with the scratch pool or VG as the only source and restore target, and
+/// a backup group of its own.
+fn selftest_config(cfg: &Config, name: &str, lvm: bool) -> Config {
+    let mut cfg = cfg.clone();
+    cfg.pbs.backup_id = BACKUP_ID.to_string();
+    cfg.pve.enabled = false;
+    cfg.nodes.clear();
+
+    let b = &mut cfg.backup;
+    b.sources = BackupSources::default();
+    let target = if lvm {
+        b.sources.lvmthin = Some(LvmThin {
+            vgs: vec![name.to_string()],
+            storage_map: BTreeMap::new(),
+            max_pool_usage: DEFAULT_MAX_POOL_USAGE,
+            pool_usage_action: Default::default(),
+        });
+        RestoreTarget::LvmThin {
+            vg: name.to_string(),
+            thinpool: THINPOOL.to_string(),
+            lvcreate_args: Vec::new(),
+            name_template: None,
+        }
+    } else {
+        b.sources.zfs = Some(Zfs {
+            pools: vec![name.to_string()],
+            storage_map: BTreeMap::new(),
+            send_pools: Vec::new(),
+            staging_dir: PathBuf::from(DEFAULT_SEND_STAGING_DIR),
+            snapshot_devices: false,
+            use_existing_snapshot: None,
+        });
+        RestoreTarget::Zfs {
+            root: name.to_string(),
+            volblocksize: None,
+            name_template: None,
+        }
+    };
+    b.pv_prefixes = vec![DISK.to_string()];
+    b.pv_exclude_re = None;
+    b.pv_exclude_re_src = None;
+    b.snapshot_max_age = None;
+    b.blackout.clear();
+    b.groups.clear();
+    b.ssh = None;
+    b.kubernetes = None;
+
+    cfg.restore = Restore {
+        targets: BTreeMap::from([(TARGET.to_string(), target)]),
+        default_target: Some(TARGET.to_string()),
+        ..Restore::default()
+    };
+    cfg
+}
+
+fn create_disk(ctx: &AppCtx, name: &str, lvm: bool) -> Result<()> {
+    if lvm {
+        let lvm = ctx.tools.lvm().context("lvm tools not available")?;
+        lvm.lvcreate_thin(name, THINPOOL, DISK, DISK_SIZE, &[])
+    } else {
+        let zfs = ctx.tools.zfs().context("zfs tools not available")?;
+        zfs.create_zvol(&format!("{name}/{DISK}"), DISK_SIZE, None)
+    }
+}
+
+fn rename_disk(ctx: &AppCtx, name: &str, lvm: bool) -> Result<()> {
+    if lvm {
+        run(ctx, CmdSpec::new("lvrename").args([name, DISK, SOURCE]))
+    } else {
+        let zfs = ctx.tools.zfs().context("zfs tools not available")?;
+        zfs.rename(&format!("{name}/{DISK}"), &format!("{name}/{SOURCE}"))
+    }
+}
+
+/// The snapshot the selftest backup just wrote, so it can be forgotten afterwards.
+fn latest_snapshot(ctx: &AppCtx, target: Option<&str>) -> Result<Option<(Repo, String)>> {
+    let repo = ctx.cfg.resolve_backup_repo(target)?;
+    let snaps = ctx.tools.pbs().snapshots(repo, repo.ns.as_deref())?;
+    snaps
+        .iter()
+        .filter(|s| s.backup_id == BACKUP_ID)
+        .map(|s| s.backup_time)
+        .max()
+        .map(|t| Ok((repo.clone(), snapshot_path(BACKUP_ID, t)?)))
+        .transpose()
+}
+
+fn run(ctx: &AppCtx, cmd: CmdSpec) -> Result<()> {
+    let what = cmd.render();
+    ctx.runner
+        .run(&Pipeline::new().cmd(cmd))
+        .with_context(|| format!("run {what}"))
+}
+
+fn capture(runner: &dyn Runner, cmd: CmdSpec) -> Result<String> {
+    let what = cmd.render();
+    runner
+        .run_capture(&Pipeline::new().cmd(cmd.stderr(StdioSpec::Pipe)))
+        .map(|s| s.trim().to_string())
+        .with_context(|| format!("run {what}"))
+}
+
+/// The loop device and the pool or VG on it, torn down on drop together with the PBS
+/// snapshot of the run.
+struct Scratch {
+    runner: Arc<ProcessRunner>,
+    lvm: bool,
+    name: String,
+    loop_dev: String,
+    created: bool,
+    pbs: Option<Arc<dyn PbsPort>>,
+    /// Repo and path of the selftest backup.
+    snapshot: Option<(Repo, String)>,
+    _dir: TempDir,
+}
+
+impl Scratch {
+    fn create(&mut self) -> Result<()> {
+        let (name, dev) = (self.name.as_str(), self.loop_dev.as_str());
+        if self.lvm {
+            capture(
+                self.runner.as_ref(),
+                CmdSpec::new("vgcreate").args([name, dev]),
+            )?;
+            self.created = true;
+            capture(
+                self.runner.as_ref(),
+                CmdSpec::new("lvcreate").args([
+                    "--type",
+                    "thin-pool",
+                    "-L",
+                    THINPOOL_SIZE,
+                    "-n",
+                    THINPOOL,
+                    name,
+                ]),
+            )?;
+        } else {
+            capture(
+                self.runner.as_ref(),
+                CmdSpec::new("zpool").args(["create", "-m", "none", name, dev]),
+            )?;
+            self.created = true;
+        }
+        Ok(())
+    }
+
+    fn device(&self, leaf: &str) -> PathBuf {
+        if self.lvm {
+            Path::new("/dev").join(&self.name).join(leaf)
+        } else {
+            Path::new("/dev/zvol").join(&self.name).join(leaf)
+        }
+    }
+
+    fn teardown(&mut self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if let (Some(pbs), Some((repo, snap))) = (&self.pbs, self.snapshot.take())
+            && let Err(e) = pbs.forget(&repo, repo.ns.as_deref(), &snap)
+        {
+            errors.push(format!("{e:#}"));
+        }
+        let mut steps = Vec::new();
+        if self.created {
+            steps.push(if self.lvm {
+                CmdSpec::new("vgremove").args(["-f", &self.name])
+            } else {
+                CmdSpec::new("zpool").args(["destroy", "-f", &self.name])
+            });
+        }
+        steps.push(CmdSpec::new("losetup").args(["-d", &self.loop_dev]));
+        errors.extend(
+            steps
+                .into_iter()
+                .filter_map(|cmd| capture(self.runner.as_ref(), cmd).err())
+                .map(|e| format!("{e:#}")),
+        );
+        errors
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        signal::shielded(|| {
+            for e in self.teardown() {
+                tracing::warn!("[cleanup] {e}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::config::{Backup, Events, Pbs, Pve};
+
+    #[test]
+    fn config_points_only_at_the_scratch_pool() {
+        let cfg = Config {
+            pbs: Pbs {
+                repos: HashMap::new(),
+                keyfile: None,
+                password: None,
+                key_passphrase: None,
+                create_ns: Default::default(),
+                ns: None,
+                backup_id: "prod".to_string(),
+            },
+            pve: Pve::default(),
+            events: Events::default(),
+            log: Default::default(),
+            state: Default::default(),
+            backup: Backup {
+                sources: BackupSources {
+                    zfs: Some(Zfs {
+                        pools: vec!["tank".to_string()],
+                        storage_map: BTreeMap::new(),
+                        send_pools: Vec::new(),
+                        staging_dir: PathBuf::from("/var/tmp"),
+                        snapshot_devices: false,
+                        use_existing_snapshot: None,
+                    }),
+                    ..BackupSources::default()
+                },
+                pv_prefixes: vec!["vm-".to_string()],
+                ..Backup::default()
+            },
+            restore: Restore::default(),
+            nodes: BTreeMap::new(),
+        };
+
+        let zfs = selftest_config(&cfg, "pvtools-selftest-1", false);
+        assert_eq!(zfs.pbs.backup_id, BACKUP_ID);
+        assert!(!zfs.pve.enabled);
+        assert_eq!(
+            zfs.backup.sources.zfs.as_ref().unwrap().pools,
+            ["pvtools-selftest-1"]
+        );
+        assert_eq!(zfs.backup.pv_prefixes, [DISK]);
+        assert!(matches!(
+            &zfs.restore.targets[TARGET],
+            RestoreTarget::Zfs { root, .. } if root == "pvtools-selftest-1"
+        ));
+        assert_eq!(zfs.restore.default_target.as_deref(), Some(TARGET));
+
+        let lvm = selftest_config(&cfg, "pvtools-selftest-1", true);
+        assert!(lvm.backup.sources.zfs.is_none());
+        assert_eq!(
+            lvm.backup.sources.lvmthin.as_ref().unwrap().vgs,
+            ["pvtools-selftest-1"]
+        );
+        assert!(matches!(
+            &lvm.restore.targets[TARGET],
+            RestoreTarget::LvmThin { thinpool, .. } if thinpool == THINPOOL
+        ));
+    }
+}
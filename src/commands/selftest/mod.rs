@@ -0,0 +1,36 @@
+use anyhow::Result;
+use clap::Args;
+
+use crate::config::Config;
+
+mod executor;
+
+#[derive(Debug, Args)]
+pub struct SelftestArgs {
+    /// Required: creates and destroys a loop device with a scratch pool or VG on this host
+    #[arg(long)]
+    pub allow_destructive: bool,
+
+    /// Exercise an LVM-thin VG instead of a ZFS pool
+    #[arg(long)]
+    pub lvm: bool,
+
+    /// PBS repo alias to back up to; defaults like `backup run --target`
+    #[arg(long)]
+    pub target: Option<String>,
+}
+
+impl SelftestArgs {
+    /// Builds its own context around the scratch pool, so `main` runs it before the toolbox.
+    pub fn run(&self, cfg: &Config, debug: bool) -> Result<()> {
+        executor::selftest(
+            cfg,
+            debug,
+            executor::SelftestOpts {
+                allow_destructive: self.allow_destructive,
+                lvm: self.lvm,
+                target: self.target.clone(),
+            },
+        )
+    }
+}
@@ -1,29 +1,175 @@
 use std::{
     collections::{BTreeMap, BTreeSet, HashMap},
-    fmt,
+    fmt, fs,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
 use anyhow::{Context, Result, anyhow, bail};
 use config as cfg;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    state::{DEFAULT_STATE_DIR, StateStore},
+    tooling::writer::{parse_block_size, parse_size},
+    utils::{
+        blackout::Blackout,
+        naming::{self, KNOWN_PROVIDERS, NameScheme},
+        process::{IoClass, Priority},
+        time::parse_duration,
+    },
+};
+
+mod origin;
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub pbs: Pbs,
+    pub pve: Pve,
+    pub events: Events,
+    pub log: Log,
+    pub state: State,
     pub backup: Backup,
     pub restore: Restore,
+    pub nodes: BTreeMap<String, Node>,
+}
+
+/// A cluster node backed up over ssh, with its own sources and PBS backup group.
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub ssh: Ssh,
+    pub backup_id: String,
+    pub sources: BackupSources,
+}
+
+const HISTORY_FILE: &str = "history.jsonl";
+const DEFAULT_HISTORY_KEEP: usize = 100;
+
+#[derive(Debug, Clone)]
+pub struct Events {
+    pub socket: Option<PathBuf>,
+    /// Run summaries shown by `pvtools history`.
+    pub history: PathBuf,
+    /// Number of runs kept in `history`; 0 keeps none.
+    pub history_keep: usize,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self {
+            socket: None,
+            history: Path::new(DEFAULT_STATE_DIR).join(HISTORY_FILE),
+            history_keep: DEFAULT_HISTORY_KEEP,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct State {
+    /// Caches, recorded certificate fingerprints and, unless `[events] history` says
+    /// otherwise, the run history.
+    pub dir: PathBuf,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from(DEFAULT_STATE_DIR),
+        }
+    }
+}
+
+impl State {
+    pub fn store(&self) -> StateStore {
+        StateStore::new(&self.dir)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Log {
+    /// `RUST_LOG`-style directives such as `pvtools::tooling::pbs=debug`.
+    pub filters: Option<String>,
+}
+
+impl Log {
+    /// Directives the run logs with: the default level, then `filters`, then `env` (RUST_LOG).
+    /// For the same target a later directive wins, so RUST_LOG overrides the config.
+    pub fn directives(&self, debug: bool, env: Option<&str>) -> String {
+        let default = if debug { "debug" } else { "info" };
+        [Some(default), self.filters.as_deref(), env]
+            .into_iter()
+            .flatten()
+            .filter(|d| !d.trim().is_empty())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+const DEFAULT_PVESH_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_STORAGE_CACHE_TTL: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone)]
+pub struct Pve {
+    /// `false` on hosts without Proxmox VE: pvesh is never called and storage IDs come
+    /// from `storage_map`.
+    pub enabled: bool,
+    pub timeout: Duration,
+    /// How long `pvesh get /storage` output is reused from disk; zero disables the cache.
+    pub cache_ttl: Duration,
+    pub storage_map: BTreeMap<String, String>,
+    /// PVE node name of this host; `None` treats every restore target as local.
+    pub node: Option<String>,
+}
+
+impl Default for Pve {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            timeout: Duration::from_secs(DEFAULT_PVESH_TIMEOUT_SECS),
+            cache_ttl: DEFAULT_STORAGE_CACHE_TTL,
+            storage_map: BTreeMap::new(),
+            node: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Pbs {
-    pub repos: HashMap<String, String>,
+    pub repos: HashMap<String, Repo>,
     pub keyfile: Option<PathBuf>,
     pub password: Option<String>,
+    /// Passphrase of an encrypted `keyfile`.
+    pub key_passphrase: Option<String>,
     pub ns: Option<String>,
+    pub create_ns: NsCreate,
     pub backup_id: String,
 }
+
+/// Whether runs may create a missing PBS namespace.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NsCreate {
+    /// Create it before the first upload.
+    #[default]
+    Auto,
+    /// Fail the backup or copy that would upload into it.
+    Never,
+    /// Like `Never`, and also fail restores and listings instead of showing no snapshots.
+    RequireExisting,
+}
+
+impl fmt::Display for NsCreate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            NsCreate::Auto => "auto",
+            NsCreate::Never => "never",
+            NsCreate::RequireExisting => "require-existing",
+        })
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct Backup {
     pub target: BackupTarget,
@@ -31,6 +177,128 @@ pub struct Backup {
     pub pv_prefixes: Vec<String>,
     pub pv_exclude_re: Option<Regex>,
     pub pv_exclude_re_src: Option<String>,
+    pub snapshot_max_age: Option<Duration>,
+    pub snapshot_age_action: SnapshotAgeAction,
+    /// Limit for uploading one volume, counted from the end of the previous one, and for
+    /// staging one `zfs send` stream.
+    pub volume_timeout: Option<Duration>,
+    /// Wall-clock limit for a whole backup run.
+    pub run_timeout: Option<Duration>,
+    /// How long a snapshot's device node may take to appear; 5s when unset.
+    pub device_timeout: Option<Duration>,
+    /// After a device node failed to appear, reload the udev rules and wait once more.
+    pub device_udev_reload: bool,
+    /// `backup run` does nothing while the group's latest snapshot is younger than this.
+    pub min_interval: Option<Duration>,
+    /// `--changed-only` uploads an unchanged volume again once this many later snapshots
+    /// left it out; [`DEFAULT_CHANGED_ONLY_MAX_RUNS`] if unset.
+    pub changed_only_max_runs: Option<u32>,
+    /// ... or once the snapshot holding it is this old; [`DEFAULT_CHANGED_ONLY_MAX_AGE`] if unset.
+    pub changed_only_max_age: Option<Duration>,
+    pub blackout: Vec<Blackout>,
+    pub blackout_action: BlackoutAction,
+    /// Volumes open on the host or attached to a Kubernetes node.
+    pub active_volumes: ActiveVolumes,
+    /// Format of the archive names new backups get.
+    pub archive_names: NameScheme,
+    /// Take every volume's snapshot before any clone, activation or staging starts.
+    pub snapshot_barrier: bool,
+    /// Sequence volumes are prepared and uploaded in.
+    pub order: BackupOrder,
+    /// `io_priority`/`cpu_nice` the zfs send and upload processes run at.
+    pub priority: Priority,
+    pub ssh: Option<Ssh>,
+    /// Cluster whose PVC names are recorded with each backup.
+    pub kubernetes: Option<Kubernetes>,
+    pub groups: Vec<VolumeGroup>,
+}
+
+/// `[[backup.groups]]`: volumes snapshotted back to back between a `pre` and a `post` hook.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct VolumeGroup {
+    pub name: String,
+    /// Regexes on the disk name or the `<namespace>/<name>` of its PVC, in snapshot order.
+    pub volumes: Vec<String>,
+    /// Commands (argv) run on the host that takes the snapshots.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub pre: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub post: Vec<String>,
+}
+
+/// kubectl settings for `[backup.kubernetes]`; kubectl always runs on this host.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Kubernetes {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kubeconfig: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+}
+
+/// Host that runs zfs/lvm/pvesh/PBS commands when pvtools itself runs elsewhere.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Ssh {
+    pub host: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identity_file: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+}
+
+impl Ssh {
+    /// Same login settings, pointed at another host.
+    pub fn with_host(&self, host: &str) -> Self {
+        Self {
+            host: host.to_string(),
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotAgeAction {
+    #[default]
+    Warn,
+    Abort,
+}
+
+/// What `backup run` does with volumes a process or Kubernetes node is using.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ActiveVolumes {
+    /// Not checked.
+    #[default]
+    Ignore,
+    Warn,
+    /// Skipped unless `backup run --include-active`.
+    Skip,
+}
+
+/// `[backup] order`: the sequence `backup run` prepares and uploads volumes in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackupOrder {
+    /// As discovered: sources in config order, volumes as each source lists them.
+    #[default]
+    Config,
+    /// By disk name, then archive name.
+    Alphabetical,
+    /// Smallest first; volumes of unknown size go last.
+    SizeAsc,
+    /// Largest first; volumes of unknown size go last.
+    SizeDesc,
+}
+
+/// What `backup run` does when it starts inside a blackout window.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlackoutAction {
+    #[default]
+    Refuse,
+    Wait,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -42,39 +310,178 @@ pub struct BackupTarget {
 pub struct BackupSources {
     pub zfs: Option<Zfs>,
     pub lvmthin: Option<LvmThin>,
+    pub lvm: Option<Lvm>,
+    /// `[backup.sources.external.<name>]` providers, by name.
+    pub external: BTreeMap<String, External>,
+}
+
+impl BackupSources {
+    pub fn is_empty(&self) -> bool {
+        self.zfs.is_none()
+            && self.lvmthin.is_none()
+            && self.lvm.is_none()
+            && self.external.is_empty()
+    }
+}
+
+/// A backup source implemented by an executable speaking the external provider protocol.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct External {
+    pub command: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    /// Limit for each call; [`DEFAULT_EXTERNAL_TIMEOUT`] if unset.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_secs"
+    )]
+    pub timeout: Option<Duration>,
+}
+
+fn serialize_secs<S: serde::Serializer>(d: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
+    match d {
+        Some(d) => s.serialize_str(&format!("{}s", d.as_secs())),
+        None => s.serialize_none(),
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Zfs {
     pub pools: Vec<String>,
+    /// Pool → PVE storage ID, used instead of what pvesh reports.
+    pub storage_map: BTreeMap<String, String>,
+    /// Pools backed up as `zfs send` streams, filesystems included, instead of raw devices.
+    pub send_pools: Vec<String>,
+    /// Where `zfs send` streams are staged for the upload.
+    pub staging_dir: PathBuf,
+    /// Read zvols with `snapdev=visible` from their snapshot's device node instead of a clone.
+    pub snapshot_devices: bool,
+    /// Back up from the newest existing snapshot whose name matches this glob instead of
+    /// taking one.
+    pub use_existing_snapshot: Option<String>,
 }
 
+pub(crate) const DEFAULT_SEND_STAGING_DIR: &str = "/var/tmp";
+
+/// 100 MiB/s.
+pub const DEFAULT_ASSUMED_RATE: u64 = 100 << 20;
+
+pub const DEFAULT_CHANGED_ONLY_MAX_RUNS: u32 = 6;
+/// 7 days.
+pub const DEFAULT_CHANGED_ONLY_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// 10 minutes.
+pub const DEFAULT_EXTERNAL_TIMEOUT: Duration = Duration::from_secs(600);
+
 #[derive(Debug, Clone)]
 pub struct LvmThin {
     pub vgs: Vec<String>,
+    /// VG → PVE storage ID, used instead of what pvesh reports.
+    pub storage_map: BTreeMap<String, String>,
+    /// Data/metadata usage (percent) of a thin pool above which no snapshots are taken in it.
+    pub max_pool_usage: u8,
+    pub pool_usage_action: PoolUsageAction,
+}
+
+pub(crate) const DEFAULT_MAX_POOL_USAGE: u8 = 90;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PoolUsageAction {
+    Warn,
+    #[default]
+    Abort,
+}
+
+/// Classic (linear/striped) LVs, snapshotted with a fixed copy-on-write size.
+#[derive(Debug, Clone)]
+pub struct Lvm {
+    pub vgs: Vec<String>,
+    /// VG → PVE storage ID, used instead of what pvesh reports.
+    pub storage_map: BTreeMap<String, String>,
+    pub snapshot_size: String,
 }
 
+const DEFAULT_LVM_SNAPSHOT_SIZE: &str = "5G";
+
 #[derive(Debug, Clone, Default)]
 pub struct Restore {
     pub targets: BTreeMap<String, RestoreTarget>,
     pub rules: Vec<RestoreRule>,
     pub default_target: Option<String>,
+    pub safety_snapshot: bool,
+    /// Bytes per second `restore run --dry-run` estimates durations at;
+    /// [`DEFAULT_ASSUMED_RATE`] if unset.
+    pub assumed_rate: Option<u64>,
+    /// Writer settings for every archive; a rule's `write` overrides them field by field.
+    pub write: Option<WriteOverride>,
+    pub ssh: Option<Ssh>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum RestoreTarget {
-    Zfs { root: String },
-    LvmThin { vg: String, thinpool: String },
+    Zfs {
+        root: String,
+        /// `volblocksize` of zvols created on this target; the pool default if unset.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        volblocksize: Option<String>,
+        /// Names volumes restored here instead of the archive's leaf; see
+        /// [`naming::render_name_template`].
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name_template: Option<String>,
+    },
+    LvmThin {
+        vg: String,
+        thinpool: String,
+        /// Extra `lvcreate` arguments for LVs created on this target.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        lvcreate_args: Vec<String>,
+        /// Names volumes restored here instead of the archive's leaf; see
+        /// [`naming::render_name_template`].
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name_template: Option<String>,
+    },
+    Lvm {
+        vg: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        lvcreate_args: Vec<String>,
+        /// Names volumes restored here instead of the archive's leaf; see
+        /// [`naming::render_name_template`].
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name_template: Option<String>,
+    },
+    /// Devices or image files named after the disk under `device_root` on a host that need not
+    /// run pvtools; the archive is streamed from here into `dd` over ssh.
+    Ssh {
+        #[serde(flatten)]
+        ssh: Ssh,
+        device_root: PathBuf,
+        /// Names volumes restored here instead of the archive's leaf; see
+        /// [`naming::render_name_template`].
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name_template: Option<String>,
+    },
 }
 
 impl fmt::Display for RestoreTarget {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            RestoreTarget::Zfs { root } => write!(f, "zfs(root={})", root),
-            RestoreTarget::LvmThin { vg, thinpool } => {
+            RestoreTarget::Zfs { root, .. } => write!(f, "zfs(root={})", root),
+            RestoreTarget::LvmThin { vg, thinpool, .. } => {
                 write!(f, "lvmthin(vg={}, thinpool={})", vg, thinpool)
             }
+            RestoreTarget::Lvm { vg, .. } => write!(f, "lvm(vg={})", vg),
+            RestoreTarget::Ssh {
+                ssh, device_root, ..
+            } => {
+                write!(
+                    f,
+                    "ssh(host={}, device_root={})",
+                    ssh.host,
+                    device_root.display()
+                )
+            }
         }
     }
 }
@@ -86,11 +493,56 @@ pub struct RestoreRule {
     #[serde(rename = "match.archive_regex")]
     pub match_archive_regex: Option<String>,
     pub target: String,
+    #[serde(default, alias = "dd", skip_serializing_if = "Option::is_none")]
+    pub write: Option<WriteOverride>,
+}
+
+/// Writer settings for archives routed by a rule; unset fields keep the defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WriteOverride {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bs: Option<String>,
+    #[serde(
+        default,
+        alias = "oflag_direct",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub direct: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sparse: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fsync: Option<bool>,
+}
+
+/// A PBS repository alias from `[pbs.repos]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Repo {
+    pub url: String,
+    /// `--ns`, else the repo's own namespace, else `[pbs].ns`.
+    pub ns: Option<String>,
+    /// SHA-256 fingerprint the server certificate must match.
+    pub fingerprint: Option<String>,
+    /// The server certificate is signed by a CA in the system trust store.
+    pub trusted_ca: bool,
+    /// The repo's own `keyfile`, else `[pbs].keyfile`.
+    pub keyfile: Option<PathBuf>,
+    /// The repo's own `password_cmd`/`password_file`, else the `[pbs]` password.
+    pub password: Option<String>,
+    /// The repo's own `key_passphrase_file`, else `[pbs].key_passphrase_file` when the repo uses
+    /// the `[pbs].keyfile`.
+    pub key_passphrase: Option<String>,
+}
+
+impl fmt::Display for Repo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.url)
+    }
 }
 
 impl Pbs {
-    pub fn repo_by_alias<'a>(&'a self, alias: &str) -> Result<&'a str> {
-        self.repos.get(alias).map(|s| s.as_str()).ok_or_else(|| {
+    pub fn repo_by_alias<'a>(&'a self, alias: &str) -> Result<&'a Repo> {
+        self.repos.get(alias).ok_or_else(|| {
             anyhow!(
                 "unknown repo alias '{}'; known: {}",
                 alias,
@@ -100,31 +552,43 @@ impl Pbs {
     }
 
     #[inline]
-    fn join_aliases(repos: &HashMap<String, String>) -> String {
+    fn join_aliases(repos: &HashMap<String, Repo>) -> String {
         let mut keys: Vec<&str> = repos.keys().map(|s| s.as_str()).collect();
         keys.sort_unstable();
         keys.join("|")
     }
 }
 
+/// Why [`Backup::pv_allows`] turns a volume name down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PvDenial {
+    /// None of `pv_prefixes` matches.
+    Prefix,
+    ExcludedByRegex,
+}
+
 impl Backup {
     pub fn pv_allows(&self, name: &str) -> bool {
-        let pref_ok = if self.pv_prefixes.is_empty() {
-            true
-        } else {
-            self.pv_prefixes.iter().any(|p| name.starts_with(p))
-        };
-        let not_excluded = self
+        self.pv_denial(name).is_none()
+    }
+
+    pub fn pv_denial(&self, name: &str) -> Option<PvDenial> {
+        if !self.pv_prefixes.is_empty() && !self.pv_prefixes.iter().any(|p| name.starts_with(p)) {
+            return Some(PvDenial::Prefix);
+        }
+        if self
             .pv_exclude_re
             .as_ref()
-            .map(|re| !re.is_match(name))
-            .unwrap_or(true);
-        pref_ok && not_excluded
+            .is_some_and(|re| re.is_match(name))
+        {
+            return Some(PvDenial::ExcludedByRegex);
+        }
+        None
     }
 }
 
 impl Config {
-    pub fn resolve_backup_repo<'a>(&'a self, sel: Option<&str>) -> Result<&'a str> {
+    pub fn resolve_backup_repo<'a>(&'a self, sel: Option<&str>) -> Result<&'a Repo> {
         if let Some(alias) = sel {
             return self.pbs.repo_by_alias(alias);
         }
@@ -136,7 +600,7 @@ impl Config {
             Pbs::join_aliases(&self.pbs.repos)
         );
     }
-    pub fn resolve_source_repo<'a>(&'a self, sel: Option<&str>) -> Result<&'a str> {
+    pub fn resolve_source_repo<'a>(&'a self, sel: Option<&str>) -> Result<&'a Repo> {
         if let Some(alias) = sel {
             return self.pbs.repo_by_alias(alias);
         }
@@ -146,33 +610,85 @@ impl Config {
         );
     }
 
+    /// Uses namespace `ns` with every repo for this run, over the repos' own and `[pbs].ns`;
+    /// an empty one is the root namespace.
+    pub fn override_ns(&mut self, ns: &str) {
+        let ns = Some(ns.trim().trim_matches('/'))
+            .filter(|s| !s.is_empty())
+            .map(str::to_string);
+        for repo in self.pbs.repos.values_mut() {
+            repo.ns = ns.clone();
+        }
+        self.pbs.ns = ns;
+    }
+
     pub fn known_repo_aliases(&self) -> String {
         Pbs::join_aliases(&self.pbs.repos)
     }
     pub fn load(path: &Path) -> Result<Self> {
-        let base_dir = path
+        Self::load_all(&[path.to_path_buf()])
+    }
+
+    /// Loads `paths` in order, each file overriding the keys of the ones before it; the files a
+    /// file lists in `include` are loaded just before it. Relative paths inside any of them
+    /// resolve from the directory of the first file.
+    pub fn load_all(paths: &[PathBuf]) -> Result<Self> {
+        Self::load_files(paths, false)
+    }
+
+    /// [`Self::load_all`], failing on keys no setting reads as if `[meta] strict = true` was set.
+    pub fn load_all_strict(paths: &[PathBuf]) -> Result<Self> {
+        Self::load_files(paths, true)
+    }
+
+    fn load_files(paths: &[PathBuf], strict: bool) -> Result<Self> {
+        let first = paths.first().context("no config file given")?;
+        let base_dir = first
             .parent()
             .filter(|p| !p.as_os_str().is_empty())
             .unwrap_or_else(|| Path::new("."));
 
-        let raw: RawConfig = cfg::Config::builder()
-            .add_source(cfg::File::from(path))
-            .build()
-            .with_context(|| format!("load {}", path.display()))?
-            .try_deserialize()
-            .with_context(|| format!("deserialize {}", path.display()))?;
+        let files = config_files(paths)?;
+        let mut builder = cfg::Config::builder();
+        for f in &files {
+            builder = builder.add_source(cfg::File::from(f.as_path()));
+        }
+        let shown = files
+            .iter()
+            .map(|f| f.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let built = builder.build().with_context(|| format!("load {shown}"))?;
+        let (raw, ignored) =
+            origin::deserialize_raw(built).with_context(|| format!("deserialize {shown}"))?;
+        let strict = strict || raw.meta.as_ref().and_then(|m| m.strict).unwrap_or(false);
+        if strict && !ignored.is_empty() {
+            bail!(
+                "unknown config keys in {shown} (strict mode): {}",
+                ignored.join(", ")
+            );
+        }
+        for key in ignored {
+            tracing::warn!("config key {key} is not a known setting and is ignored");
+        }
 
         let n = config_helpers::Normalizer { base_dir };
-        let repos = Self::build_repos(raw.pbs.repos)?;
+        let ns = n.trim_opt(raw.pbs.ns);
         let keyfile = n.trim_opt(raw.pbs.keyfile).map(|s| n.resolve(&s));
-        let password = match n.trim_opt(raw.pbs.password_file).map(|s| n.resolve(&s)) {
-            Some(p) => Some(
-                n.read_secret(&p)
-                    .with_context(|| format!("read PBS token from {}", p.display()))?,
-            ),
-            None => None,
+        let password = pbs_password(&n, raw.pbs.password_cmd, raw.pbs.password_file, |k| {
+            std::env::var(k).ok()
+        })?;
+        let key_passphrase = key_passphrase(&n, raw.pbs.key_passphrase_file)?;
+        let global = Repo {
+            url: String::new(),
+            ns: ns.clone(),
+            fingerprint: None,
+            trusted_ca: false,
+            keyfile: keyfile.clone(),
+            password: password.clone(),
+            key_passphrase: key_passphrase.clone(),
         };
-        let ns = n.trim_opt(raw.pbs.ns);
+        let repos = Self::build_repos(&n, raw.pbs.repos, &global)?;
         let backup_id = n
             .trim_opt(raw.pbs.backup_id)
             .unwrap_or_else(|| format!("{}-backup", n.hostname()));
@@ -180,10 +696,64 @@ impl Config {
             repos,
             keyfile,
             password,
+            key_passphrase,
             ns,
+            create_ns: raw.pbs.create_ns.unwrap_or_default(),
             backup_id,
         };
 
+        let raw_pve = raw.pve.unwrap_or_default();
+        let timeout_secs = raw_pve.timeout_secs.unwrap_or(DEFAULT_PVESH_TIMEOUT_SECS);
+        if timeout_secs == 0 {
+            bail!("pve.timeout_secs must be > 0");
+        }
+        let mut storage_map = BTreeMap::new();
+        for (name, id) in raw_pve.storage_map.unwrap_or_default() {
+            let name = name.trim().to_string();
+            let id = n
+                .trim_opt(Some(id))
+                .ok_or_else(|| anyhow!("[pve.storage_map] empty storage id for '{name}'"))?;
+            if name.is_empty() {
+                bail!("[pve.storage_map] empty pool/vg name");
+            }
+            storage_map.insert(name, id);
+        }
+        let cache_ttl = match n.trim_opt(raw_pve.cache_ttl) {
+            Some(s) => parse_duration(&s).with_context(|| format!("bad pve.cache_ttl: {s}"))?,
+            None => DEFAULT_STORAGE_CACHE_TTL,
+        };
+        let pve_node = n.trim_opt(raw_pve.node);
+        let mut pve = Pve {
+            enabled: raw_pve.enabled.unwrap_or(true),
+            timeout: Duration::from_secs(timeout_secs),
+            cache_ttl,
+            storage_map,
+            node: None,
+        };
+
+        let state = State {
+            dir: n
+                .trim_opt(raw.state.unwrap_or_default().dir)
+                .map(|s| n.resolve(&s))
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_STATE_DIR)),
+        };
+
+        let raw_events = raw.events.unwrap_or_default();
+        let events = Events {
+            socket: n.trim_opt(raw_events.socket).map(|s| n.resolve(&s)),
+            history: n
+                .trim_opt(raw_events.history)
+                .map(|s| n.resolve(&s))
+                .unwrap_or_else(|| state.dir.join(HISTORY_FILE)),
+            history_keep: raw_events.history_keep.unwrap_or(DEFAULT_HISTORY_KEEP),
+        };
+
+        let filters = n.trim_opt(raw.log.unwrap_or_default().filters);
+        if let Some(f) = &filters {
+            EnvFilter::try_new(f).with_context(|| format!("bad log.filters: {f}"))?;
+        }
+        let log = Log { filters };
+
         let pv_prefixes = raw
             .backup
             .pv_prefixes
@@ -197,23 +767,48 @@ impl Config {
             Some(s) => Some(Regex::new(s).with_context(|| format!("bad pbs.pv_exclude_re: {s}"))?),
             None => None,
         };
-        let mut sources = BackupSources::default();
-        if let Some(bs) = raw.backup.sources {
-            if let Some(z) = bs.zfs {
-                let pools = n.dedup(z.pools);
-                if pools.is_empty() {
-                    bail!("backup.sources.zfs.pools must not be empty");
-                }
-                sources.zfs = Some(Zfs { pools });
-            }
-            if let Some(l) = bs.lvmthin {
-                let vgs = n.dedup(l.vgs);
-                if vgs.is_empty() {
-                    bail!("backup.sources.lvmthin.vgs must not be empty");
-                }
-                sources.lvmthin = Some(LvmThin { vgs });
+        let positive_duration = |raw: Option<String>, key: &str| -> Result<Option<Duration>> {
+            let Some(s) = n.trim_opt(raw) else {
+                return Ok(None);
+            };
+            let d = parse_duration(&s).with_context(|| format!("bad backup.{key}: {s}"))?;
+            if d.is_zero() {
+                bail!("backup.{key} must be > 0");
             }
-        }
+            Ok(Some(d))
+        };
+        let snapshot_max_age = positive_duration(raw.backup.snapshot_max_age, "snapshot_max_age")?;
+        let volume_timeout = positive_duration(raw.backup.volume_timeout, "volume_timeout")?;
+        let run_timeout = positive_duration(raw.backup.run_timeout, "run_timeout")?;
+        let device_timeout = positive_duration(raw.backup.device_timeout, "device_timeout")?;
+        let min_interval = positive_duration(raw.backup.min_interval, "min_interval")?;
+        let changed_only_max_age =
+            positive_duration(raw.backup.changed_only_max_age, "changed_only_max_age")?;
+        let blackout = raw
+            .backup
+            .blackout
+            .unwrap_or_default()
+            .iter()
+            .map(|s| {
+                s.parse::<Blackout>()
+                    .with_context(|| format!("bad backup.blackout: {s}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let io = match n.trim_opt(raw.backup.io_priority) {
+            Some(s) => Some(
+                s.parse::<IoClass>()
+                    .with_context(|| format!("bad backup.io_priority: {s}"))?,
+            ),
+            None => None,
+        };
+        let nice = match raw.backup.cpu_nice {
+            Some(v) if !(-20..=19).contains(&v) => bail!("backup.cpu_nice must be -20..=19"),
+            v => v.map(|v| v as i8),
+        };
+        let sources = match raw.backup.sources {
+            Some(bs) => normalize_sources(&n, bs, "backup.sources")?,
+            None => BackupSources::default(),
+        };
         let backup = Backup {
             target: BackupTarget {
                 repo: raw.backup.target.and_then(|t| n.trim_opt(t.repo)),
@@ -222,7 +817,71 @@ impl Config {
             pv_prefixes,
             pv_exclude_re,
             pv_exclude_re_src,
+            snapshot_max_age,
+            snapshot_age_action: raw.backup.snapshot_age_action.unwrap_or_default(),
+            volume_timeout,
+            run_timeout,
+            device_timeout,
+            device_udev_reload: raw.backup.device_udev_reload.unwrap_or(false),
+            min_interval,
+            changed_only_max_runs: raw.backup.changed_only_max_runs,
+            changed_only_max_age,
+            blackout,
+            blackout_action: raw.backup.blackout_action.unwrap_or_default(),
+            active_volumes: raw.backup.active_volumes.unwrap_or_default(),
+            archive_names: raw.backup.archive_names.unwrap_or_default(),
+            snapshot_barrier: raw.backup.snapshot_barrier.unwrap_or(false),
+            order: raw.backup.order.unwrap_or_default(),
+            priority: Priority { io, nice },
+            ssh: normalize_ssh(&n, raw.backup.ssh, "backup.ssh")?,
+            kubernetes: raw.backup.kubernetes.map(|k| Kubernetes {
+                kubeconfig: n.trim_opt(k.kubeconfig).map(|p| n.resolve(&p)),
+                context: n.trim_opt(k.context),
+            }),
+            groups: normalize_groups(raw.backup.groups.unwrap_or_default())?,
         };
+
+        let mut nodes = BTreeMap::new();
+        let mut node_ids = BTreeSet::new();
+        for (name_raw, rn) in raw.nodes.unwrap_or_default() {
+            let name = name_raw.trim().to_string();
+            if !Self::valid_name(&name) {
+                bail!("invalid node name '{name}'; allowed: [A-Za-z0-9_-], len 1..32");
+            }
+            let section = format!("nodes.{name}");
+            let ssh = RawSsh {
+                host: rn.host.or_else(|| Some(name.clone())),
+                user: rn.user,
+                identity_file: rn.identity_file,
+                port: rn.port,
+            };
+            let ssh = normalize_ssh(&n, Some(ssh), &section)?.expect("ssh section given");
+            let sources = normalize_sources(
+                &n,
+                rn.sources.unwrap_or_default(),
+                &format!("{section}.sources"),
+            )?;
+            if sources.is_empty() {
+                bail!("[{section}] needs at least one source");
+            }
+            let backup_id = n
+                .trim_opt(rn.backup_id)
+                .unwrap_or_else(|| format!("{name}-backup"));
+            if !node_ids.insert(backup_id.clone()) {
+                bail!("[{section}] backup_id '{backup_id}' is used by another node");
+            }
+            nodes.insert(
+                name,
+                Node {
+                    ssh,
+                    backup_id,
+                    sources,
+                },
+            );
+        }
+        if !nodes.is_empty() && (!backup.sources.is_empty() || backup.ssh.is_some()) {
+            bail!("[nodes.*] replaces [backup.sources] and [backup.ssh]; use one or the other");
+        }
         let mut targets: BTreeMap<String, RestoreTarget> = BTreeMap::new();
         if let Some(rt) = raw.restore.targets {
             for (name_raw, t) in rt {
@@ -236,21 +895,90 @@ impl Config {
                         name
                     );
                 }
+                let template = |t: Option<String>| -> Result<Option<String>> {
+                    let Some(t) = n.trim_opt(t) else {
+                        return Ok(None);
+                    };
+                    naming::render_name_template(&t, "zfs", "vm-1-disk-0", "ab12", 0)
+                        .with_context(|| format!("[restore.targets.{name}] bad name_template"))?;
+                    Ok(Some(t))
+                };
                 let normalized = match t {
-                    RawRestoreTarget::Zfs { root } => {
+                    RawRestoreTarget::Zfs {
+                        root,
+                        volblocksize,
+                        name_template,
+                    } => {
                         let root = n.trim_opt(root).ok_or_else(|| {
                             anyhow!("[restore.targets.{name}] root must not be empty")
                         })?;
-                        RestoreTarget::Zfs { root }
+                        let volblocksize = n
+                            .trim_opt(volblocksize)
+                            .map(|v| {
+                                normalize_volblocksize(&v).with_context(|| {
+                                    format!("[restore.targets.{name}] bad volblocksize '{v}'")
+                                })
+                            })
+                            .transpose()?;
+                        RestoreTarget::Zfs {
+                            root,
+                            volblocksize,
+                            name_template: template(name_template)?,
+                        }
                     }
-                    RawRestoreTarget::LvmThin { vg, thinpool } => {
+                    RawRestoreTarget::LvmThin {
+                        vg,
+                        thinpool,
+                        lvcreate_args,
+                        name_template,
+                    } => {
                         let vg = n.trim_opt(vg).ok_or_else(|| {
                             anyhow!("[restore.targets.{name}] vg must not be empty")
                         })?;
                         let thinpool = n.trim_opt(thinpool).ok_or_else(|| {
                             anyhow!("[restore.targets.{name}] thinpool must not be empty")
                         })?;
-                        RestoreTarget::LvmThin { vg, thinpool }
+                        RestoreTarget::LvmThin {
+                            vg,
+                            thinpool,
+                            lvcreate_args: lvcreate_args.unwrap_or_default(),
+                            name_template: template(name_template)?,
+                        }
+                    }
+                    RawRestoreTarget::Lvm {
+                        vg,
+                        lvcreate_args,
+                        name_template,
+                    } => {
+                        let vg = n.trim_opt(vg).ok_or_else(|| {
+                            anyhow!("[restore.targets.{name}] vg must not be empty")
+                        })?;
+                        RestoreTarget::Lvm {
+                            vg,
+                            lvcreate_args: lvcreate_args.unwrap_or_default(),
+                            name_template: template(name_template)?,
+                        }
+                    }
+                    RawRestoreTarget::Ssh {
+                        ssh,
+                        device_root,
+                        name_template,
+                    } => {
+                        let section = format!("restore.targets.{name}");
+                        let ssh =
+                            normalize_ssh(&n, Some(ssh), &section)?.expect("ssh section given");
+                        let device_root = n
+                            .trim_opt(device_root)
+                            .map(PathBuf::from)
+                            .filter(|p| p.is_absolute())
+                            .ok_or_else(|| {
+                                anyhow!("[{section}] device_root must be an absolute path")
+                            })?;
+                        RestoreTarget::Ssh {
+                            ssh,
+                            device_root,
+                            name_template: template(name_template)?,
+                        }
                     }
                 };
                 if targets.insert(name.clone(), normalized).is_some() {
@@ -266,7 +994,7 @@ impl Config {
                 if provider.is_empty() {
                     bail!("[restore.rules] match.provider must not be empty");
                 }
-                if !matches!(provider.as_str(), "zfs" | "lvmthin") {
+                if !KNOWN_PROVIDERS.contains(&provider.as_str()) {
                     bail!("[restore.rules] unknown provider '{}'", provider);
                 }
                 let target = r.target.trim().to_string();
@@ -295,6 +1023,11 @@ impl Config {
                     None => None,
                 };
 
+                let write = r
+                    .write
+                    .map(|w| normalize_write(w, "restore.rules"))
+                    .transpose()?;
+
                 if !seen.insert((provider.clone(), target.clone())) {
                     bail!(
                         "[restore.rules] duplicate rule for provider='{}' target='{}'",
@@ -307,6 +1040,7 @@ impl Config {
                     match_provider: provider,
                     match_archive_regex,
                     target,
+                    write,
                 });
             }
         }
@@ -314,22 +1048,87 @@ impl Config {
             targets,
             rules,
             default_target: n.trim_opt(raw.restore.default_target),
+            safety_snapshot: raw.restore.safety_snapshot.unwrap_or(false),
+            assumed_rate: n
+                .trim_opt(raw.restore.assumed_rate)
+                .map(|s| parse_rate(&s).with_context(|| format!("bad restore.assumed_rate: {s}")))
+                .transpose()?,
+            write: raw
+                .restore
+                .write
+                .map(|w| normalize_write(w, "restore"))
+                .transpose()?,
+            ssh: normalize_ssh(&n, raw.restore.ssh, "restore.ssh")?,
         };
+        // Restores run where [restore.ssh] points, so that host is the local node.
+        pve.node = pve_node.or_else(|| match &restore.ssh {
+            Some(ssh) => node_name(&ssh.host),
+            None => node_name(&n.hostname()),
+        });
         Ok(Self {
             pbs,
+            pve,
+            events,
+            log,
+            state,
             backup,
             restore,
+            nodes,
         })
     }
 
-    fn build_repos(raw_repos: HashMap<String, String>) -> Result<HashMap<String, String>> {
+    /// `defaults` holds the `[pbs]` settings a repo falls back to.
+    fn build_repos(
+        n: &config_helpers::Normalizer<'_>,
+        raw_repos: BTreeMap<String, RawRepo>,
+        defaults: &Repo,
+    ) -> Result<HashMap<String, Repo>> {
         if raw_repos.is_empty() {
             bail!("define at least one repository under [pbs.repos]");
         }
 
-        let mut repos: HashMap<String, String> = HashMap::with_capacity(raw_repos.len());
+        let mut repos: HashMap<String, Repo> = HashMap::with_capacity(raw_repos.len());
 
-        for (raw_name, raw_url) in raw_repos {
+        for (raw_name, raw_repo) in raw_repos {
+            let mut repo = match raw_repo {
+                RawRepo::Url(url) => Repo {
+                    url,
+                    ..defaults.clone()
+                },
+                RawRepo::Table(RawRepoTable {
+                    url,
+                    ns,
+                    fingerprint,
+                    trusted_ca,
+                    keyfile,
+                    password_file,
+                    password_cmd,
+                    key_passphrase_file,
+                }) => {
+                    let own_key = n.trim_opt(keyfile).map(|s| n.resolve(&s));
+                    let own_pass = key_passphrase(n, key_passphrase_file)?;
+                    let key_passphrase = match (&own_key, own_pass) {
+                        (_, Some(pass)) => Some(pass),
+                        (Some(_), None) => None,
+                        (None, None) => defaults.key_passphrase.clone(),
+                    };
+                    let password = if password_cmd.is_some() || password_file.is_some() {
+                        pbs_password(n, password_cmd, password_file, |_| None)
+                            .with_context(|| format!("repo '{}'", raw_name.trim()))?
+                    } else {
+                        defaults.password.clone()
+                    };
+                    Repo {
+                        url,
+                        ns: n.trim_opt(ns).or_else(|| defaults.ns.clone()),
+                        fingerprint: n.trim_opt(fingerprint),
+                        trusted_ca: trusted_ca.unwrap_or(false),
+                        keyfile: own_key.or_else(|| defaults.keyfile.clone()),
+                        password,
+                        key_passphrase,
+                    }
+                }
+            };
             let name = raw_name.trim().to_string();
             if name.is_empty() {
                 bail!("empty repo name in [pbs.repos]");
@@ -337,11 +1136,19 @@ impl Config {
             if !Self::valid_name(&name) {
                 bail!("bad repo name '{}': use [A-Za-z0-9_-], length 1..32", name);
             }
-            let url = raw_url.trim().to_string();
-            if url.is_empty() {
+            repo.url = repo.url.trim().to_string();
+            if repo.url.is_empty() {
                 bail!("empty URL for repo '{}'", name);
             }
-            if repos.insert(name.clone(), url).is_some() {
+            if let Some(fp) = &repo.fingerprint
+                && !valid_fingerprint(fp)
+            {
+                bail!(
+                    "bad fingerprint for repo '{}': expected 32 colon-separated hex bytes",
+                    name
+                );
+            }
+            if repos.insert(name.clone(), repo).is_some() {
                 bail!("duplicate repo entry '{}'", name);
             }
         }
@@ -357,21 +1164,82 @@ impl Config {
                 .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
     }
 
+    /// [`Self::to_redacted_toml`], each value followed by a comment naming the config file
+    /// that set it, the environment variable it came from, or `default`.
+    pub fn to_annotated_toml(&self, paths: &[PathBuf]) -> Result<String> {
+        let origins = origin::key_origins(&config_files(paths)?)?;
+        Ok(origin::annotate(&self.to_redacted_toml()?, &origins, |k| {
+            std::env::var(k).ok()
+        }))
+    }
+
     pub fn to_redacted_toml(&self) -> Result<String> {
         #[derive(Serialize)]
         struct PbsOut<'a> {
-            repos: BTreeMap<&'a str, &'a str>,
+            repos: BTreeMap<&'a str, RepoOut<'a>>,
             keyfile: Option<String>,
             password: &'static str,
+            key_passphrase: &'static str,
             ns: Option<&'a str>,
+            create_ns: NsCreate,
             backup_id: &'a str,
         }
+        #[derive(Serialize)]
+        #[serde(untagged)]
+        enum RepoOut<'a> {
+            Url(&'a str),
+            Table {
+                url: &'a str,
+                #[serde(skip_serializing_if = "Option::is_none")]
+                ns: Option<&'a str>,
+                #[serde(skip_serializing_if = "Option::is_none")]
+                fingerprint: Option<&'a str>,
+                #[serde(skip_serializing_if = "std::ops::Not::not")]
+                trusted_ca: bool,
+                #[serde(skip_serializing_if = "Option::is_none")]
+                keyfile: Option<String>,
+                #[serde(skip_serializing_if = "Option::is_none")]
+                password: Option<&'static str>,
+                #[serde(skip_serializing_if = "Option::is_none")]
+                key_passphrase: Option<&'static str>,
+            },
+        }
+        #[derive(Serialize)]
+        struct PveOut<'a> {
+            enabled: bool,
+            timeout_secs: u64,
+            cache_ttl: String,
+            #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+            storage_map: &'a BTreeMap<String, String>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            node: Option<&'a str>,
+        }
+        #[derive(Serialize)]
+        struct EventsOut {
+            #[serde(skip_serializing_if = "Option::is_none")]
+            socket: Option<String>,
+            history: String,
+            history_keep: usize,
+        }
+        #[derive(Serialize)]
+        struct StateOut {
+            dir: String,
+        }
+        #[derive(Serialize)]
+        struct LogOut<'a> {
+            #[serde(skip_serializing_if = "Option::is_none")]
+            filters: Option<&'a str>,
+        }
         #[derive(Serialize, Default)]
         struct BackupSourcesOut<'a> {
             #[serde(skip_serializing_if = "Option::is_none")]
             zfs: Option<ZfsOut<'a>>,
             #[serde(skip_serializing_if = "Option::is_none")]
             lvmthin: Option<LvmThinOut<'a>>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            lvm: Option<LvmOut<'a>>,
+            #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+            external: BTreeMap<&'a str, &'a External>,
         }
         #[derive(Serialize)]
         struct BackupOut<'a> {
@@ -380,6 +1248,39 @@ impl Config {
             sources: BackupSourcesOut<'a>,
             pv_prefixes: &'a [String],
             pv_exclude_re: Option<&'a str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            snapshot_max_age: Option<String>,
+            snapshot_age_action: SnapshotAgeAction,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            volume_timeout: Option<String>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            run_timeout: Option<String>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            device_timeout: Option<String>,
+            device_udev_reload: bool,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            min_interval: Option<String>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            changed_only_max_runs: Option<u32>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            changed_only_max_age: Option<String>,
+            #[serde(skip_serializing_if = "Vec::is_empty")]
+            blackout: Vec<String>,
+            blackout_action: BlackoutAction,
+            active_volumes: ActiveVolumes,
+            archive_names: NameScheme,
+            snapshot_barrier: bool,
+            order: BackupOrder,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            io_priority: Option<String>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            cpu_nice: Option<i8>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            ssh: Option<&'a Ssh>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            kubernetes: Option<&'a Kubernetes>,
+            #[serde(skip_serializing_if = "<[_]>::is_empty")]
+            groups: &'a [VolumeGroup],
         }
         #[derive(Serialize)]
         struct BackupTargetOut<'a> {
@@ -389,52 +1290,140 @@ impl Config {
         #[derive(Serialize)]
         struct ZfsOut<'a> {
             pools: &'a [String],
+            #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+            storage_map: &'a BTreeMap<String, String>,
+            #[serde(skip_serializing_if = "<[String]>::is_empty")]
+            send_pools: &'a [String],
+            #[serde(skip_serializing_if = "Option::is_none")]
+            staging_dir: Option<&'a Path>,
+            #[serde(skip_serializing_if = "std::ops::Not::not")]
+            snapshot_devices: bool,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            use_existing_snapshot: Option<&'a str>,
         }
         #[derive(Serialize)]
         struct LvmThinOut<'a> {
             vgs: &'a [String],
+            #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+            storage_map: &'a BTreeMap<String, String>,
+            max_pool_usage: u8,
+            pool_usage_action: PoolUsageAction,
+        }
+        #[derive(Serialize)]
+        struct LvmOut<'a> {
+            vgs: &'a [String],
+            #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+            storage_map: &'a BTreeMap<String, String>,
+            snapshot_size: &'a str,
         }
         #[derive(Serialize)]
         struct RestoreOut<'a> {
+            safety_snapshot: bool,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            assumed_rate: Option<String>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            write: Option<&'a WriteOverride>,
             #[serde(skip_serializing_if = "BTreeMap::is_empty")]
             targets: BTreeMap<&'a str, &'a RestoreTarget>,
             #[serde(skip_serializing_if = "is_empty_slice")]
             rules: &'a [RestoreRule],
             #[serde(skip_serializing_if = "Option::is_none")]
             default_target: Option<&'a str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            ssh: Option<&'a Ssh>,
+        }
+        #[derive(Serialize)]
+        struct NodeOut<'a> {
+            #[serde(flatten)]
+            ssh: &'a Ssh,
+            backup_id: &'a str,
+            sources: BackupSourcesOut<'a>,
         }
         #[derive(Serialize)]
         struct Out<'a> {
             pbs: PbsOut<'a>,
+            pve: PveOut<'a>,
+            events: EventsOut,
+            log: LogOut<'a>,
+            state: StateOut,
             backup: BackupOut<'a>,
             restore: RestoreOut<'a>,
+            #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+            nodes: BTreeMap<&'a str, NodeOut<'a>>,
         }
         fn is_empty_sources(s: &BackupSourcesOut<'_>) -> bool {
-            s.zfs.is_none() && s.lvmthin.is_none()
+            s.zfs.is_none() && s.lvmthin.is_none() && s.lvm.is_none() && s.external.is_empty()
+        }
+        fn sources_out(s: &BackupSources) -> BackupSourcesOut<'_> {
+            BackupSourcesOut {
+                zfs: s.zfs.as_ref().map(|z| ZfsOut {
+                    pools: &z.pools,
+                    storage_map: &z.storage_map,
+                    send_pools: &z.send_pools,
+                    staging_dir: (!z.send_pools.is_empty()).then_some(z.staging_dir.as_path()),
+                    snapshot_devices: z.snapshot_devices,
+                    use_existing_snapshot: z.use_existing_snapshot.as_deref(),
+                }),
+                lvmthin: s.lvmthin.as_ref().map(|l| LvmThinOut {
+                    vgs: &l.vgs,
+                    storage_map: &l.storage_map,
+                    max_pool_usage: l.max_pool_usage,
+                    pool_usage_action: l.pool_usage_action,
+                }),
+                lvm: s.lvm.as_ref().map(|l| LvmOut {
+                    vgs: &l.vgs,
+                    storage_map: &l.storage_map,
+                    snapshot_size: &l.snapshot_size,
+                }),
+                external: s.external.iter().map(|(k, v)| (k.as_str(), v)).collect(),
+            }
         }
 
-        let repos_sorted: BTreeMap<&str, &str> = self
+        let repos_sorted: BTreeMap<&str, RepoOut<'_>> = self
             .pbs
             .repos
             .iter()
-            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .map(|(k, r)| {
+                let ns = r.ns.as_deref().filter(|_| r.ns != self.pbs.ns);
+                let keyfile = r
+                    .keyfile
+                    .as_ref()
+                    .filter(|_| r.keyfile != self.pbs.keyfile)
+                    .map(|p| p.display().to_string());
+                let redacted = |own: &Option<String>, global: &Option<String>| {
+                    (own != global).then(|| {
+                        if own.is_some() {
+                            "<redacted>"
+                        } else {
+                            "<none>"
+                        }
+                    })
+                };
+                let password = redacted(&r.password, &self.pbs.password);
+                let key_passphrase = redacted(&r.key_passphrase, &self.pbs.key_passphrase);
+                let out = if ns.is_none()
+                    && r.fingerprint.is_none()
+                    && !r.trusted_ca
+                    && keyfile.is_none()
+                    && password.is_none()
+                    && key_passphrase.is_none()
+                {
+                    RepoOut::Url(&r.url)
+                } else {
+                    RepoOut::Table {
+                        url: &r.url,
+                        ns,
+                        fingerprint: r.fingerprint.as_deref(),
+                        trusted_ca: r.trusted_ca,
+                        keyfile,
+                        password,
+                        key_passphrase,
+                    }
+                };
+                (k.as_str(), out)
+            })
             .collect();
 
-        let sources_out = BackupSourcesOut {
-            zfs: self
-                .backup
-                .sources
-                .zfs
-                .as_ref()
-                .map(|z| ZfsOut { pools: &z.pools }),
-            lvmthin: self
-                .backup
-                .sources
-                .lvmthin
-                .as_ref()
-                .map(|l| LvmThinOut { vgs: &l.vgs }),
-        };
-
         let restore_targets_sorted: BTreeMap<&str, &RestoreTarget> = self
             .restore
             .targets
@@ -451,22 +1440,99 @@ impl Config {
                 } else {
                     "<none>"
                 },
+                key_passphrase: if self.pbs.key_passphrase.is_some() {
+                    "<redacted>"
+                } else {
+                    "<none>"
+                },
                 ns: self.pbs.ns.as_deref(),
+                create_ns: self.pbs.create_ns,
                 backup_id: &self.pbs.backup_id,
             },
+            pve: PveOut {
+                enabled: self.pve.enabled,
+                timeout_secs: self.pve.timeout.as_secs(),
+                cache_ttl: format!("{}s", self.pve.cache_ttl.as_secs()),
+                storage_map: &self.pve.storage_map,
+                node: self.pve.node.as_deref(),
+            },
+            events: EventsOut {
+                socket: self.events.socket.as_ref().map(|p| p.display().to_string()),
+                history: self.events.history.display().to_string(),
+                history_keep: self.events.history_keep,
+            },
+            log: LogOut {
+                filters: self.log.filters.as_deref(),
+            },
+            state: StateOut {
+                dir: self.state.dir.display().to_string(),
+            },
             backup: BackupOut {
                 target: BackupTargetOut {
                     repo: self.backup.target.repo.as_deref(),
                 },
-                sources: sources_out,
+                sources: sources_out(&self.backup.sources),
                 pv_prefixes: &self.backup.pv_prefixes,
                 pv_exclude_re: self.backup.pv_exclude_re_src.as_deref(),
+                snapshot_max_age: self
+                    .backup
+                    .snapshot_max_age
+                    .map(|d| format!("{}s", d.as_secs())),
+                snapshot_age_action: self.backup.snapshot_age_action,
+                volume_timeout: self
+                    .backup
+                    .volume_timeout
+                    .map(|d| format!("{}s", d.as_secs())),
+                run_timeout: self.backup.run_timeout.map(|d| format!("{}s", d.as_secs())),
+                device_timeout: self
+                    .backup
+                    .device_timeout
+                    .map(|d| format!("{}s", d.as_secs())),
+                device_udev_reload: self.backup.device_udev_reload,
+                min_interval: self
+                    .backup
+                    .min_interval
+                    .map(|d| format!("{}s", d.as_secs())),
+                changed_only_max_runs: self.backup.changed_only_max_runs,
+                changed_only_max_age: self
+                    .backup
+                    .changed_only_max_age
+                    .map(|d| format!("{}s", d.as_secs())),
+                blackout: self.backup.blackout.iter().map(|w| w.to_string()).collect(),
+                blackout_action: self.backup.blackout_action,
+                active_volumes: self.backup.active_volumes,
+                archive_names: self.backup.archive_names,
+                snapshot_barrier: self.backup.snapshot_barrier,
+                order: self.backup.order,
+                io_priority: self.backup.priority.io.map(|c| c.to_string()),
+                cpu_nice: self.backup.priority.nice,
+                ssh: self.backup.ssh.as_ref(),
+                kubernetes: self.backup.kubernetes.as_ref(),
+                groups: &self.backup.groups,
             },
             restore: RestoreOut {
+                safety_snapshot: self.restore.safety_snapshot,
+                assumed_rate: self.restore.assumed_rate.map(|r| format!("{r}/s")),
+                write: self.restore.write.as_ref(),
                 targets: restore_targets_sorted,
                 rules: &self.restore.rules,
                 default_target: self.restore.default_target.as_deref(),
+                ssh: self.restore.ssh.as_ref(),
             },
+            nodes: self
+                .nodes
+                .iter()
+                .map(|(name, node)| {
+                    (
+                        name.as_str(),
+                        NodeOut {
+                            ssh: &node.ssh,
+                            backup_id: &node.backup_id,
+                            sources: sources_out(&node.sources),
+                        },
+                    )
+                })
+                .collect(),
         };
         Ok(toml::to_string_pretty(&out)?)
     }
@@ -476,23 +1542,86 @@ impl Config {
 struct RawConfig {
     pbs: RawPbs,
 
+    #[serde(default)]
+    pve: Option<RawPve>,
+
+    #[serde(default)]
+    events: Option<RawEvents>,
+
+    #[serde(default)]
+    log: Option<RawLog>,
+
+    #[serde(default)]
+    state: Option<RawState>,
+
     #[serde(default)]
     backup: RawBackup,
 
     #[serde(default)]
     restore: RawRestore,
+
+    #[serde(default)]
+    nodes: Option<BTreeMap<String, RawNode>>,
+
+    #[serde(default)]
+    meta: Option<RawMeta>,
 }
 
 #[derive(Debug, Deserialize)]
-struct RawPbs {
+struct RawMeta {
+    strict: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawNode {
+    host: Option<String>,
+    user: Option<String>,
+    identity_file: Option<String>,
+    port: Option<u16>,
+    backup_id: Option<String>,
+    #[serde(default)]
+    sources: Option<RawBackupSources>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawPbs {
     #[serde(default)]
-    repos: HashMap<String, String>,
+    repos: BTreeMap<String, RawRepo>,
     keyfile: Option<String>,
     password_file: Option<String>,
+    password_cmd: Option<String>,
+    key_passphrase_file: Option<String>,
     ns: Option<String>,
+    create_ns: Option<NsCreate>,
     backup_id: Option<String>,
 }
 
+#[derive(Debug, Deserialize, Default)]
+struct RawPve {
+    enabled: Option<bool>,
+    timeout_secs: Option<u64>,
+    cache_ttl: Option<String>,
+    storage_map: Option<BTreeMap<String, String>>,
+    node: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct RawEvents {
+    socket: Option<String>,
+    history: Option<String>,
+    history_keep: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct RawLog {
+    filters: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct RawState {
+    dir: Option<String>,
+}
+
 #[derive(Debug, Deserialize, Default)]
 struct RawBackup {
     #[serde(default)]
@@ -501,6 +1630,54 @@ struct RawBackup {
     sources: Option<RawBackupSources>,
     pv_prefixes: Option<Vec<String>>,
     pv_exclude_re: Option<String>,
+    snapshot_max_age: Option<String>,
+    snapshot_age_action: Option<SnapshotAgeAction>,
+    volume_timeout: Option<String>,
+    run_timeout: Option<String>,
+    device_timeout: Option<String>,
+    device_udev_reload: Option<bool>,
+    min_interval: Option<String>,
+    changed_only_max_runs: Option<u32>,
+    changed_only_max_age: Option<String>,
+    blackout: Option<Vec<String>>,
+    blackout_action: Option<BlackoutAction>,
+    active_volumes: Option<ActiveVolumes>,
+    #[serde(default)]
+    archive_names: Option<NameScheme>,
+    snapshot_barrier: Option<bool>,
+    order: Option<BackupOrder>,
+    io_priority: Option<String>,
+    cpu_nice: Option<i32>,
+    #[serde(default)]
+    ssh: Option<RawSsh>,
+    #[serde(default)]
+    kubernetes: Option<RawKubernetes>,
+    groups: Option<Vec<RawVolumeGroup>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawVolumeGroup {
+    name: String,
+    volumes: Vec<String>,
+    #[serde(default)]
+    pre: Vec<String>,
+    #[serde(default)]
+    post: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawKubernetes {
+    kubeconfig: Option<String>,
+    context: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawSsh {
+    host: Option<String>,
+    user: Option<String>,
+    identity_file: Option<String>,
+    port: Option<u16>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -514,15 +1691,95 @@ struct RawBackupSources {
     zfs: Option<RawZfs>,
     #[serde(default)]
     lvmthin: Option<RawLvmThin>,
+    #[serde(default)]
+    lvm: Option<RawLvm>,
+    #[serde(default)]
+    external: Option<BTreeMap<String, RawExternal>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawExternal {
+    command: String,
+    args: Option<Vec<String>>,
+    timeout: Option<String>,
 }
 #[derive(Debug, Deserialize)]
 struct RawZfs {
     pools: Vec<String>,
+    storage_map: Option<BTreeMap<String, String>>,
+    send_pools: Option<Vec<String>>,
+    staging_dir: Option<PathBuf>,
+    snapshot_devices: Option<bool>,
+    use_existing_snapshot: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 struct RawLvmThin {
     vgs: Vec<String>,
+    storage_map: Option<BTreeMap<String, String>>,
+    max_pool_usage: Option<u8>,
+    pool_usage_action: Option<PoolUsageAction>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawLvm {
+    vgs: Vec<String>,
+    storage_map: Option<BTreeMap<String, String>>,
+    snapshot_size: Option<String>,
+}
+
+/// `alias = "url"` or `alias = { url = "...", ns = "...", fingerprint = "..." }`.
+#[derive(Debug)]
+enum RawRepo {
+    Url(String),
+    Table(RawRepoTable),
+}
+
+#[derive(Debug, Deserialize)]
+struct RawRepoTable {
+    url: String,
+    ns: Option<String>,
+    fingerprint: Option<String>,
+    trusted_ca: Option<bool>,
+    keyfile: Option<String>,
+    password_file: Option<String>,
+    password_cmd: Option<String>,
+    key_passphrase_file: Option<String>,
+}
+
+// By hand rather than `untagged`, so the table's unknown keys reach `origin::deserialize_raw`.
+impl<'de> Deserialize<'de> for RawRepo {
+    fn deserialize<D: serde::Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        struct RepoVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for RepoVisitor {
+            type Value = RawRepo;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a repository URL or a table with `url`")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<RawRepo, E> {
+                Ok(RawRepo::Url(v.to_string()))
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(self, map: A) -> Result<RawRepo, A::Error> {
+                RawRepoTable::deserialize(serde::de::value::MapAccessDeserializer::new(map))
+                    .map(RawRepo::Table)
+            }
+        }
+
+        de.deserialize_any(RepoVisitor)
+    }
+}
+
+/// `AA:BB:...`, the SHA-256 form proxmox-backup-client prints and accepts.
+fn valid_fingerprint(fp: &str) -> bool {
+    let bytes: Vec<&str> = fp.split(':').collect();
+    bytes.len() == 32
+        && bytes
+            .iter()
+            .all(|b| b.len() == 2 && b.bytes().all(|c| c.is_ascii_hexdigit()))
 }
 
 #[derive(Debug, Deserialize, Default)]
@@ -533,22 +1790,443 @@ struct RawRestore {
     rules: Option<Vec<RestoreRule>>,
     #[serde(default)]
     default_target: Option<String>,
+    #[serde(default)]
+    safety_snapshot: Option<bool>,
+    #[serde(default)]
+    assumed_rate: Option<String>,
+    #[serde(default, alias = "dd")]
+    write: Option<WriteOverride>,
+    #[serde(default)]
+    ssh: Option<RawSsh>,
 }
 
 #[derive(Debug, Deserialize)]
-#[serde(rename_all = "lowercase")]
-#[serde(tag = "type")]
+#[serde(try_from = "RawTargetTable")]
 enum RawRestoreTarget {
-    #[serde(rename = "zfs")]
-    Zfs { root: Option<String> },
-
-    #[serde(rename = "lvmthin")]
+    Zfs {
+        root: Option<String>,
+        volblocksize: Option<String>,
+        name_template: Option<String>,
+    },
     LvmThin {
         vg: Option<String>,
         thinpool: Option<String>,
+        lvcreate_args: Option<Vec<String>>,
+        name_template: Option<String>,
+    },
+    Lvm {
+        vg: Option<String>,
+        lvcreate_args: Option<Vec<String>>,
+        name_template: Option<String>,
     },
+    Ssh {
+        ssh: RawSsh,
+        device_root: Option<String>,
+        name_template: Option<String>,
+    },
+}
+
+/// Every key of any target type, read as one struct: an internally tagged enum buffers the
+/// table, which would hide its unknown keys from `origin::deserialize_raw`.
+#[derive(Debug, Deserialize)]
+struct RawTargetTable {
+    #[serde(rename = "type")]
+    kind: String,
+    root: Option<String>,
+    volblocksize: Option<String>,
+    vg: Option<String>,
+    thinpool: Option<String>,
+    lvcreate_args: Option<Vec<String>>,
+    host: Option<String>,
+    user: Option<String>,
+    identity_file: Option<String>,
+    port: Option<u16>,
+    device_root: Option<String>,
+    name_template: Option<String>,
+}
+
+impl TryFrom<RawTargetTable> for RawRestoreTarget {
+    type Error = String;
+
+    fn try_from(t: RawTargetTable) -> Result<Self, String> {
+        let set = [
+            ("root", t.root.is_some()),
+            ("volblocksize", t.volblocksize.is_some()),
+            ("vg", t.vg.is_some()),
+            ("thinpool", t.thinpool.is_some()),
+            ("lvcreate_args", t.lvcreate_args.is_some()),
+            ("host", t.host.is_some()),
+            ("user", t.user.is_some()),
+            ("identity_file", t.identity_file.is_some()),
+            ("port", t.port.is_some()),
+            ("device_root", t.device_root.is_some()),
+        ];
+        let own: &[&str] = match t.kind.as_str() {
+            "zfs" => &["root", "volblocksize"],
+            "lvmthin" => &["vg", "thinpool", "lvcreate_args"],
+            "lvm" => &["vg", "lvcreate_args"],
+            "ssh" => &["host", "user", "identity_file", "port", "device_root"],
+            other => {
+                return Err(format!(
+                    "unknown target type '{other}', expected zfs, lvmthin, lvm or ssh"
+                ));
+            }
+        };
+        if let Some((key, _)) = set.iter().find(|(k, s)| *s && !own.contains(k)) {
+            return Err(format!(
+                "`{key}` does not apply to type = \"{}\" targets",
+                t.kind
+            ));
+        }
+        Ok(match t.kind.as_str() {
+            "zfs" => RawRestoreTarget::Zfs {
+                root: t.root,
+                volblocksize: t.volblocksize,
+                name_template: t.name_template,
+            },
+            "lvmthin" => RawRestoreTarget::LvmThin {
+                vg: t.vg,
+                thinpool: t.thinpool,
+                lvcreate_args: t.lvcreate_args,
+                name_template: t.name_template,
+            },
+            "lvm" => RawRestoreTarget::Lvm {
+                vg: t.vg,
+                lvcreate_args: t.lvcreate_args,
+                name_template: t.name_template,
+            },
+            _ => RawRestoreTarget::Ssh {
+                ssh: RawSsh {
+                    host: t.host,
+                    user: t.user,
+                    identity_file: t.identity_file,
+                    port: t.port,
+                },
+                device_root: t.device_root,
+                name_template: t.name_template,
+            },
+        })
+    }
+}
+
+/// Short host name as PVE uses it for node names; IP addresses give no node name.
+fn node_name(host: &str) -> Option<String> {
+    if host.parse::<std::net::IpAddr>().is_ok() {
+        return None;
+    }
+    host.split('.').next().map(|s| s.to_string())
+}
+
+fn normalize_sources(
+    n: &config_helpers::Normalizer<'_>,
+    bs: RawBackupSources,
+    section: &str,
+) -> Result<BackupSources> {
+    let mut sources = BackupSources::default();
+    if let Some(z) = bs.zfs {
+        let pools = n.dedup(z.pools);
+        if pools.is_empty() {
+            bail!("{section}.zfs.pools must not be empty");
+        }
+        let storage_map = source_storage_map(n, z.storage_map, &pools, &format!("{section}.zfs"))?;
+        let send_pools = n.dedup(z.send_pools.unwrap_or_default());
+        if let Some(p) = send_pools.iter().find(|p| !pools.contains(p)) {
+            bail!("{section}.zfs.send_pools: '{p}' is not in pools");
+        }
+        let staging_dir = z
+            .staging_dir
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_SEND_STAGING_DIR));
+        if !staging_dir.is_absolute() {
+            bail!("{section}.zfs.staging_dir must be an absolute path");
+        }
+        let use_existing_snapshot = n.trim_opt(z.use_existing_snapshot);
+        if let Some(p) = &use_existing_snapshot {
+            if p.contains(['@', '/']) {
+                bail!(
+                    "{section}.zfs.use_existing_snapshot: '{p}' must be a snapshot name, without '@' or '/'"
+                );
+            }
+            glob::Pattern::new(p)
+                .with_context(|| format!("bad {section}.zfs.use_existing_snapshot: {p}"))?;
+        }
+        sources.zfs = Some(Zfs {
+            pools,
+            storage_map,
+            send_pools,
+            staging_dir,
+            snapshot_devices: z.snapshot_devices.unwrap_or(false),
+            use_existing_snapshot,
+        });
+    }
+    if let Some(l) = bs.lvmthin {
+        let vgs = n.dedup(l.vgs);
+        if vgs.is_empty() {
+            bail!("{section}.lvmthin.vgs must not be empty");
+        }
+        let max_pool_usage = l.max_pool_usage.unwrap_or(DEFAULT_MAX_POOL_USAGE);
+        if !(1..=100).contains(&max_pool_usage) {
+            bail!("{section}.lvmthin.max_pool_usage must be within 1..=100");
+        }
+        let storage_map =
+            source_storage_map(n, l.storage_map, &vgs, &format!("{section}.lvmthin"))?;
+        sources.lvmthin = Some(LvmThin {
+            vgs,
+            storage_map,
+            max_pool_usage,
+            pool_usage_action: l.pool_usage_action.unwrap_or_default(),
+        });
+    }
+    if let Some(l) = bs.lvm {
+        let vgs = n.dedup(l.vgs);
+        if vgs.is_empty() {
+            bail!("{section}.lvm.vgs must not be empty");
+        }
+        let snapshot_size = n
+            .trim_opt(l.snapshot_size)
+            .unwrap_or_else(|| DEFAULT_LVM_SNAPSHOT_SIZE.to_string());
+        if !valid_lvm_size(&snapshot_size) {
+            bail!("bad {section}.lvm.snapshot_size '{snapshot_size}' (e.g. 512M, 5G)");
+        }
+        let storage_map = source_storage_map(n, l.storage_map, &vgs, &format!("{section}.lvm"))?;
+        sources.lvm = Some(Lvm {
+            vgs,
+            storage_map,
+            snapshot_size,
+        });
+    }
+    for (name, e) in bs.external.unwrap_or_default() {
+        let name = name.trim().to_string();
+        // The name becomes the archive prefix, which archive names split on '_'.
+        let valid = (1..=32).contains(&name.len())
+            && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-');
+        if !valid {
+            bail!("bad {section}.external name '{name}': use [A-Za-z0-9-], length 1..32");
+        }
+        if KNOWN_PROVIDERS.contains(&name.as_str()) {
+            bail!("{section}.external.{name}: '{name}' is a built-in provider name");
+        }
+        let command = n
+            .trim_opt(Some(e.command))
+            .ok_or_else(|| anyhow!("{section}.external.{name}.command must not be empty"))?;
+        let timeout = n
+            .trim_opt(e.timeout)
+            .map(|s| {
+                let d = parse_duration(&s)
+                    .with_context(|| format!("bad {section}.external.{name}.timeout: {s}"))?;
+                if d.is_zero() {
+                    bail!("{section}.external.{name}.timeout must be > 0");
+                }
+                Ok(d)
+            })
+            .transpose()?;
+        sources.external.insert(
+            name,
+            External {
+                command,
+                args: e.args.unwrap_or_default(),
+                timeout,
+            },
+        );
+    }
+    Ok(sources)
+}
+
+/// Keys must be pools/VGs listed in the same section.
+fn source_storage_map(
+    n: &config_helpers::Normalizer<'_>,
+    raw: Option<BTreeMap<String, String>>,
+    names: &[String],
+    section: &str,
+) -> Result<BTreeMap<String, String>> {
+    let mut out = BTreeMap::new();
+    for (name, id) in raw.unwrap_or_default() {
+        let name = name.trim().to_string();
+        if !names.contains(&name) {
+            bail!("[{section}].storage_map: '{name}' is not listed in this section");
+        }
+        let id = n
+            .trim_opt(Some(id))
+            .ok_or_else(|| anyhow!("[{section}].storage_map: empty storage id for '{name}'"))?;
+        out.insert(name, id);
+    }
+    Ok(out)
+}
+
+/// Appends `path` to `out`, preceded by the files of its `include` list (relative to `path`).
+/// Only TOML files can include others.
+/// `paths` with the files each one includes, in load order.
+fn config_files(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for p in paths {
+        expand_includes(p, &mut Vec::new(), &mut files)?;
+    }
+    Ok(files)
+}
+
+fn expand_includes(path: &Path, stack: &mut Vec<PathBuf>, out: &mut Vec<PathBuf>) -> Result<()> {
+    #[derive(Deserialize)]
+    struct Includes {
+        #[serde(default)]
+        include: Vec<String>,
+    }
+
+    if stack.iter().any(|p| p == path) {
+        bail!("config include cycle at {}", path.display());
+    }
+    if path.extension().is_none_or(|e| e != "toml") {
+        out.push(path.to_path_buf());
+        return Ok(());
+    }
+    let raw = fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+    let inc: Includes =
+        toml::from_str(&raw).with_context(|| format!("parse {}", path.display()))?;
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    stack.push(path.to_path_buf());
+    for i in inc.include {
+        expand_includes(&dir.join(i.trim()), stack, out)
+            .with_context(|| format!("included from {}", path.display()))?;
+    }
+    stack.pop();
+    out.push(path.to_path_buf());
+    Ok(())
+}
+
+/// systemd credential (`LoadCredential=`) read when nothing else sets the PBS password.
+const PASSWORD_CREDENTIAL: &str = "pbs-password";
+
+/// PBS password; the first source set wins: `PBS_PASSWORD`, `password_cmd`, `password_file`,
+/// then the `pbs-password` systemd credential.
+fn pbs_password(
+    n: &config_helpers::Normalizer<'_>,
+    cmd: Option<String>,
+    file: Option<String>,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<Option<String>> {
+    if let Some(pw) = env("PBS_PASSWORD").filter(|s| !s.is_empty()) {
+        return Ok(Some(pw));
+    }
+    if let Some(cmd) = n.trim_opt(cmd) {
+        return n
+            .secret_from_cmd(&cmd)
+            .with_context(|| format!("get PBS token from password_cmd '{cmd}'"))
+            .map(Some);
+    }
+    if let Some(p) = n.trim_opt(file).map(|s| n.resolve(&s)) {
+        return n
+            .read_secret(&p)
+            .with_context(|| format!("read PBS token from {}", p.display()))
+            .map(Some);
+    }
+    let Some(dir) = env("CREDENTIALS_DIRECTORY").filter(|s| !s.is_empty()) else {
+        return Ok(None);
+    };
+    let p = Path::new(&dir).join(PASSWORD_CREDENTIAL);
+    if !p.exists() {
+        return Ok(None);
+    }
+    n.read_secret(&p)
+        .with_context(|| format!("read PBS token from credential {}", p.display()))
+        .map(Some)
+}
+
+/// A transfer rate such as `200M`, `1G/s` or `52428800`: bytes per second, binary units.
+fn parse_rate(s: &str) -> Result<u64> {
+    let s = s.trim();
+    parse_size(s.strip_suffix("/s").unwrap_or(s))
+}
+
+fn key_passphrase(
+    n: &config_helpers::Normalizer<'_>,
+    file: Option<String>,
+) -> Result<Option<String>> {
+    let Some(p) = n.trim_opt(file).map(|s| n.resolve(&s)) else {
+        return Ok(None);
+    };
+    n.read_secret(&p)
+        .with_context(|| format!("read key passphrase from {}", p.display()))
+        .map(Some)
+}
+
+fn normalize_groups(raw: Vec<RawVolumeGroup>) -> Result<Vec<VolumeGroup>> {
+    let mut seen = BTreeSet::new();
+    let mut out = Vec::new();
+    for g in raw {
+        let name = g.name.trim().to_string();
+        if name.is_empty() {
+            bail!("[[backup.groups]] name must not be empty");
+        }
+        if !seen.insert(name.clone()) {
+            bail!("duplicate backup group '{name}'");
+        }
+        if g.volumes.is_empty() {
+            bail!("backup group '{name}': volumes must not be empty");
+        }
+        for re in &g.volumes {
+            Regex::new(re)
+                .with_context(|| format!("backup group '{name}': bad volume regex '{re}'"))?;
+        }
+        for (hook, argv) in [("pre", &g.pre), ("post", &g.post)] {
+            if argv.first().is_some_and(|c| c.trim().is_empty()) {
+                bail!("backup group '{name}': {hook} command must not be empty");
+            }
+        }
+        out.push(VolumeGroup {
+            name,
+            volumes: g.volumes,
+            pre: g.pre,
+            post: g.post,
+        });
+    }
+    Ok(out)
+}
+
+fn normalize_ssh(
+    n: &config_helpers::Normalizer<'_>,
+    raw: Option<RawSsh>,
+    section: &str,
+) -> Result<Option<Ssh>> {
+    let Some(raw) = raw else {
+        return Ok(None);
+    };
+    let host = n
+        .trim_opt(raw.host)
+        .ok_or_else(|| anyhow!("[{section}] host must not be empty"))?;
+    if raw.port == Some(0) {
+        bail!("[{section}] port must be > 0");
+    }
+    Ok(Some(Ssh {
+        host,
+        user: n.trim_opt(raw.user),
+        identity_file: n.trim_opt(raw.identity_file).map(|p| n.resolve(&p)),
+        port: raw.port,
+    }))
+}
+
+fn normalize_write(mut w: WriteOverride, section: &str) -> Result<WriteOverride> {
+    if let Some(bs) = &w.bs {
+        let bs = bs.trim();
+        parse_block_size(bs).with_context(|| format!("[{section}] bad write.bs '{bs}'"))?;
+        w.bs = Some(bs.to_string());
+    }
+    Ok(w)
+}
+
+/// A zvol block size: a power of two from 512 bytes to 16M, e.g. `16k`.
+fn normalize_volblocksize(s: &str) -> Result<String> {
+    let bytes = parse_block_size(s)?;
+    if !bytes.is_power_of_two() || !(512..=16 << 20).contains(&bytes) {
+        bail!("expected a power of two from 512 to 16M");
+    }
+    Ok(s.to_string())
 }
 
+/// Size as accepted by `lvcreate -L`: a number with an optional unit suffix.
+fn valid_lvm_size(s: &str) -> bool {
+    let num = s.trim_end_matches(|c: char| "bBsSkKmMgGtTpPeE".contains(c));
+    s.len() - num.len() <= 1 && !num.is_empty() && num.parse::<f64>().is_ok_and(|v| v > 0.0)
+}
+
+/// Block size as accepted by `dd bs=`: a positive integer with an optional K/M/G suffix.
 fn is_empty_slice<T>(s: &&[T]) -> bool {
     s.is_empty()
 }
@@ -558,10 +2236,10 @@ mod config_helpers {
         collections::HashSet,
         fs,
         path::{Path, PathBuf},
-        process::Command,
+        process::{Command, Stdio},
     };
 
-    use anyhow::Result;
+    use anyhow::{Result, bail};
 
     pub(super) struct Normalizer<'a> {
         pub base_dir: &'a Path,
@@ -591,6 +2269,27 @@ mod config_helpers {
             Ok(s)
         }
 
+        /// Stdout of `sh -c cmd`, run in the config's directory, without trailing newlines.
+        pub fn secret_from_cmd(&self, cmd: &str) -> Result<String> {
+            let out = Command::new("sh")
+                .args(["-c", cmd])
+                .current_dir(self.base_dir)
+                .stdin(Stdio::null())
+                .stderr(Stdio::inherit())
+                .output()?;
+            if !out.status.success() {
+                bail!("exited with {}", out.status);
+            }
+            let mut s = String::from_utf8(out.stdout)?;
+            while s.ends_with('\n') || s.ends_with('\r') {
+                s.pop();
+            }
+            if s.is_empty() {
+                bail!("printed nothing");
+            }
+            Ok(s)
+        }
+
         pub fn hostname(&self) -> String {
             Command::new("hostname")
                 .output()
@@ -665,17 +2364,143 @@ target = "z"
         );
 
         let cfg = Config::load(&cfg_path).unwrap();
-        assert_eq!(cfg.resolve_backup_repo(None).unwrap(), "url-b");
+        assert_eq!(cfg.resolve_backup_repo(None).unwrap().url, "url-b");
         assert_eq!(cfg.backup.sources.zfs.as_ref().unwrap().pools, vec!["tank"]);
         assert!(cfg.restore.targets.contains_key("z"));
         assert_eq!(cfg.pbs.password.as_deref(), Some("sekret"));
+        assert!(cfg.pbs.key_passphrase.is_none());
     }
 
     #[test]
-    fn print_config_redacts_and_sorts() {
+    fn overlays_and_includes() {
         let tmp = TempDir::new().unwrap();
         let dir = tmp.path();
-        write(&dir.join("token"), "sekret");
+        write(
+            &dir.join("shared.toml"),
+            r#"
+[pbs]
+backup_id = "shared"
+[pbs.repos]
+nas = "url-nas"
+[backup.target]
+repo = "nas"
+"#,
+        );
+        write(
+            &dir.join("node.toml"),
+            r#"
+include = ["shared.toml"]
+[pbs]
+backup_id = "node"
+[backup.sources.zfs]
+pools = ["tank"]
+"#,
+        );
+        write(&dir.join("prod.toml"), "[pbs]\nbackup_id = \"prod\"\n");
+
+        let cfg = Config::load(&dir.join("node.toml")).unwrap();
+        assert_eq!(cfg.pbs.backup_id, "node");
+        assert_eq!(cfg.resolve_backup_repo(None).unwrap().url, "url-nas");
+        assert_eq!(cfg.backup.sources.zfs.as_ref().unwrap().pools, vec!["tank"]);
+
+        let cfg = Config::load_all(&[dir.join("node.toml"), dir.join("prod.toml")]).unwrap();
+        assert_eq!(cfg.pbs.backup_id, "prod");
+
+        write(&dir.join("shared.toml"), "include = [\"node.toml\"]\n");
+        let err = Config::load(&dir.join("node.toml")).unwrap_err();
+        assert!(format!("{err:#}").contains("include cycle"));
+    }
+
+    #[test]
+    fn strict_mode_rejects_unknown_keys() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("config.toml");
+        let body = "[pbs]\nbackup_id = \"b\"\n[pbs.repos]\nnas = \"url\"\n\
+                    [backup]\npv_prefixs = [\"vm-\"]\n[restor]\ndefault_target = \"z\"\n";
+        write(&path, body);
+
+        assert!(Config::load(&path).is_ok());
+        let err = Config::load_all_strict(std::slice::from_ref(&path)).unwrap_err();
+        let msg = format!("{err:#}");
+        assert!(msg.contains("backup.pv_prefixs, restor"), "{msg}");
+
+        write(&path, &format!("[meta]\nstrict = true\n{body}"));
+        assert!(Config::load(&path).is_err());
+    }
+
+    #[test]
+    fn log_filters_layer_under_rust_log() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("config.toml");
+        let body = "[pbs]\nbackup_id = \"b\"\n[pbs.repos]\nnas = \"url\"\n[log]\n";
+        write(
+            &path,
+            &format!("{body}filters = \"pvtools::tooling::pbs=debug\"\n"),
+        );
+        let log = Config::load(&path).unwrap().log;
+        assert_eq!(
+            log.directives(false, Some("warn")),
+            "info,pvtools::tooling::pbs=debug,warn"
+        );
+        assert_eq!(
+            log.directives(true, None),
+            "debug,pvtools::tooling::pbs=debug"
+        );
+
+        write(&path, &format!("{body}filters = \"pvtools=loud\"\n"));
+        let err = Config::load(&path).unwrap_err();
+        assert!(format!("{err:#}").contains("bad log.filters"), "{err:#}");
+    }
+
+    #[test]
+    fn pbs_password_sources_in_order() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        write(&dir.join("token"), "from-file\n");
+        write(&dir.join(PASSWORD_CREDENTIAL), "from-credential");
+        let n = config_helpers::Normalizer { base_dir: dir };
+        let creds = dir.display().to_string();
+        let env = |pw: Option<&str>| {
+            let creds = creds.clone();
+            let pw = pw.map(str::to_string);
+            move |k: &str| match k {
+                "PBS_PASSWORD" => pw.clone(),
+                "CREDENTIALS_DIRECTORY" => Some(creds.clone()),
+                _ => None,
+            }
+        };
+        let get = |cmd: Option<&str>, file: Option<&str>, pw: Option<&str>| {
+            pbs_password(&n, cmd.map(Into::into), file.map(Into::into), env(pw))
+        };
+
+        let cmd = Some("cat token | tr f F");
+        assert_eq!(
+            get(cmd, Some("token"), Some("from-env"))
+                .unwrap()
+                .as_deref(),
+            Some("from-env")
+        );
+        assert_eq!(
+            get(cmd, Some("token"), None).unwrap().as_deref(),
+            Some("From-File")
+        );
+        assert_eq!(
+            get(None, Some("token"), None).unwrap().as_deref(),
+            Some("from-file")
+        );
+        assert_eq!(
+            get(None, None, None).unwrap().as_deref(),
+            Some("from-credential")
+        );
+        assert!(get(Some("exit 3"), None, None).is_err());
+        assert!(get(Some("true"), None, None).is_err());
+    }
+
+    #[test]
+    fn reads_key_passphrase_file() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        write(&dir.join("enc.pass"), "open sesame\n");
 
         let cfg_path = dir.join("config.toml");
         write(
@@ -683,37 +2508,816 @@ target = "z"
             r#"
 [pbs]
 backup_id = "id"
-password_file = "token"
+keyfile = "enc.key"
+key_passphrase_file = "enc.pass"
 [pbs.repos]
-b = "url-b"
 a = "url-a"
 
-[backup]
-
 [backup.target]
 repo = "a"
+"#,
+        );
 
-[backup.sources.lvmthin]
-vgs = ["pve"]
+        let cfg = Config::load(&cfg_path).unwrap();
+        assert_eq!(cfg.pbs.key_passphrase.as_deref(), Some("open sesame"));
+        assert!(
+            cfg.to_redacted_toml()
+                .unwrap()
+                .contains(r#"key_passphrase = "<redacted>""#)
+        );
+    }
 
-[restore.targets.l]
-type = "lvmthin"
-vg = "pve"
-thinpool = "data"
+    #[test]
+    fn per_repo_credentials_fall_back_to_pbs() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        write(&dir.join("token"), "global-token\n");
+        write(&dir.join("offsite.token"), "offsite-token\n");
+        write(&dir.join("enc.pass"), "open sesame\n");
 
-[[restore.rules]]
-"match.provider" = "lvmthin"
-target = "l"
+        let cfg_path = dir.join("config.toml");
+        write(
+            &cfg_path,
+            r#"
+[pbs]
+backup_id = "id"
+keyfile = "enc.key"
+key_passphrase_file = "enc.pass"
+password_file = "token"
+[pbs.repos]
+local = "url-local"
+offsite = { url = "url-offsite", keyfile = "offsite.key", password_file = "offsite.token" }
 "#,
         );
 
         let cfg = Config::load(&cfg_path).unwrap();
+        let local = cfg.pbs.repo_by_alias("local").unwrap();
+        assert_eq!(local.keyfile, Some(dir.join("enc.key")));
+        assert_eq!(local.password.as_deref(), Some("global-token"));
+        assert_eq!(local.key_passphrase.as_deref(), Some("open sesame"));
+        let offsite = cfg.pbs.repo_by_alias("offsite").unwrap();
+        assert_eq!(offsite.keyfile, Some(dir.join("offsite.key")));
+        assert_eq!(offsite.password.as_deref(), Some("offsite-token"));
+        assert_eq!(offsite.key_passphrase, None);
+
+        // With PBS_PASSWORD exported the [pbs] password is that token; the repo's own file still wins.
+        let from_env = Repo {
+            password: Some("env-token".to_string()),
+            ..cfg.pbs.repo_by_alias("local").unwrap().clone()
+        };
+        let raw: BTreeMap<String, RawRepo> = toml::from_str(
+            r#"
+local = "url-local"
+offsite = { url = "url-offsite", password_file = "offsite.token" }
+"#,
+        )
+        .unwrap();
+        let n = config_helpers::Normalizer { base_dir: dir };
+        let repos = Config::build_repos(&n, raw, &from_env).unwrap();
+        assert_eq!(repos["local"].password.as_deref(), Some("env-token"));
+        assert_eq!(repos["offsite"].password.as_deref(), Some("offsite-token"));
+
         let printed = cfg.to_redacted_toml().unwrap();
-        assert!(printed.contains(r#"password = "<redacted>""#));
+        assert!(printed.contains("local = \"url-local\""), "{printed}");
+        assert!(printed.contains(r#"password = "<redacted>""#), "{printed}");
+        assert!(!printed.contains("offsite-token"), "{printed}");
+    }
+
+    #[test]
+    fn print_config_redacts_and_sorts() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        write(&dir.join("token"), "sekret");
+
+        let cfg_path = dir.join("config.toml");
+        write(
+            &cfg_path,
+            r#"
+[pbs]
+backup_id = "id"
+password_file = "token"
+[pbs.repos]
+b = "url-b"
+a = "url-a"
+
+[backup]
+
+[backup.target]
+repo = "a"
+
+[backup.sources.lvmthin]
+vgs = ["pve"]
+
+[restore.targets.l]
+type = "lvmthin"
+vg = "pve"
+thinpool = "data"
+
+[[restore.rules]]
+"match.provider" = "lvmthin"
+target = "l"
+"#,
+        );
+
+        let cfg = Config::load(&cfg_path).unwrap();
+        let printed = cfg.to_redacted_toml().unwrap();
+        assert!(printed.contains(r#"password = "<redacted>""#));
         assert!(
             printed.find("\na = \"url-a\"").unwrap() < printed.find("\nb = \"url-b\"").unwrap()
         );
         assert!(printed.contains("[backup.target]"));
         assert!(printed.contains("[restore.targets.l]"));
     }
+
+    #[test]
+    fn load_ssh_restore_target() {
+        let tmp = TempDir::new().unwrap();
+        let cfg_path = tmp.path().join("config.toml");
+        let base = r#"
+[pbs]
+backup_id = "id"
+[pbs.repos]
+a = "url-a"
+
+[restore.targets.far]
+type = "ssh"
+host = "dr1"
+user = "root"
+port = 2222
+"#;
+        write(
+            &cfg_path,
+            &format!("{base}device_root = \"/dev/zvol/tank\"\n"),
+        );
+        let cfg = Config::load(&cfg_path).unwrap();
+        assert!(matches!(
+            cfg.restore.targets.get("far"),
+            Some(RestoreTarget::Ssh { ssh, device_root, .. })
+                if ssh.host == "dr1" && ssh.port == Some(2222) && device_root == Path::new("/dev/zvol/tank")
+        ));
+
+        write(&cfg_path, &format!("{base}device_root = \"images\"\n"));
+        let err = Config::load(&cfg_path).unwrap_err();
+        assert!(format!("{err:#}").contains("device_root must be an absolute path"));
+
+        write(
+            &cfg_path,
+            &format!("{base}device_root = \"/dev\"\nthinpool = \"data\"\n"),
+        );
+        let err = Config::load(&cfg_path).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("`thinpool` does not apply to type = \"ssh\" targets"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn load_classic_lvm_source_and_target() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        let cfg_path = dir.join("config.toml");
+        write(
+            &cfg_path,
+            r#"
+[pbs]
+backup_id = "id"
+[pbs.repos]
+a = "url-a"
+
+[backup.sources.lvm]
+vgs = ["data"]
+
+[restore.targets.plain]
+type = "lvm"
+vg = "data"
+
+[[restore.rules]]
+"match.provider" = "lvm"
+target = "plain"
+"#,
+        );
+
+        let cfg = Config::load(&cfg_path).unwrap();
+        let lvm = cfg.backup.sources.lvm.as_ref().unwrap();
+        assert_eq!(lvm.vgs, vec!["data"]);
+        assert_eq!(lvm.snapshot_size, DEFAULT_LVM_SNAPSHOT_SIZE);
+        assert!(matches!(
+            cfg.restore.targets.get("plain"),
+            Some(RestoreTarget::Lvm { vg, .. }) if vg == "data"
+        ));
+    }
+
+    #[test]
+    fn load_restore_assumed_rate() {
+        let tmp = TempDir::new().unwrap();
+        let cfg_path = tmp.path().join("config.toml");
+        write(
+            &cfg_path,
+            "[pbs.repos]\na = \"url-a\"\n[restore]\nassumed_rate = \"250M/s\"\n",
+        );
+        let cfg = Config::load(&cfg_path).unwrap();
+        assert_eq!(cfg.restore.assumed_rate, Some(250 << 20));
+        assert_eq!(parse_rate("1G").unwrap(), 1 << 30);
+        assert_eq!(parse_rate("52428800").unwrap(), 50 << 20);
+
+        write(
+            &cfg_path,
+            "[pbs.repos]\na = \"url-a\"\n[restore]\nassumed_rate = \"fast\"\n",
+        );
+        assert!(Config::load(&cfg_path).is_err());
+    }
+
+    #[test]
+    fn restore_target_creation_settings() {
+        let tmp = TempDir::new().unwrap();
+        let cfg_path = tmp.path().join("config.toml");
+        let load = |zfs_extra: &str| {
+            write(
+                &cfg_path,
+                &format!(
+                    r#"
+[pbs]
+backup_id = "id"
+[pbs.repos]
+a = "url-a"
+
+[restore.targets.db]
+type = "zfs"
+root = "tank/db"
+{zfs_extra}
+
+[restore.targets.thin]
+type = "lvmthin"
+vg = "pve"
+thinpool = "data"
+lvcreate_args = ["--readahead", "auto"]
+"#
+                ),
+            );
+            Config::load(&cfg_path)
+        };
+
+        let cfg = load(r#"volblocksize = "16k""#).unwrap();
+        assert!(matches!(
+            cfg.restore.targets.get("db"),
+            Some(RestoreTarget::Zfs { volblocksize: Some(bs), .. }) if bs == "16k"
+        ));
+        assert!(matches!(
+            cfg.restore.targets.get("thin"),
+            Some(RestoreTarget::LvmThin { lvcreate_args, .. }) if lvcreate_args == &["--readahead", "auto"]
+        ));
+        for bad in ["12k", "256", "32M", "x"] {
+            assert!(
+                load(&format!(r#"volblocksize = "{bad}""#)).is_err(),
+                "{bad}"
+            );
+        }
+
+        let cfg = load(r#"name_template = "restored-{leaf}-{date}""#).unwrap();
+        assert!(matches!(
+            cfg.restore.targets.get("db"),
+            Some(RestoreTarget::Zfs { name_template: Some(t), .. }) if t == "restored-{leaf}-{date}"
+        ));
+        let err = load(r#"name_template = "{leaf}-{host}""#).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("unknown variable {host}"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn lvm_snapshot_size_format() {
+        for ok in ["5G", "512M", "1.5g", "100"] {
+            assert!(valid_lvm_size(ok), "{ok}");
+        }
+        for bad in ["", "G", "5GB", "-1G", "0", "10%ORIGIN"] {
+            assert!(!valid_lvm_size(bad), "{bad}");
+        }
+    }
+
+    #[test]
+    fn load_rule_dd_overrides() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        let cfg_path = dir.join("config.toml");
+        let body = |bs: &str| {
+            format!(
+                r#"
+[pbs]
+backup_id = "id"
+[pbs.repos]
+a = "url-a"
+
+[restore.targets.z]
+type = "zfs"
+root = "tank"
+
+[[restore.rules]]
+"match.provider" = "zfs"
+"match.archive_regex" = 'media'
+target = "z"
+dd = {{ bs = "{bs}", direct = false }}
+
+[[restore.rules]]
+"match.provider" = "zfs"
+target = "z2"
+"#
+            )
+        };
+
+        write(&cfg_path, &body("16M"));
+        let cfg = Config::load(&cfg_path).unwrap();
+        assert_eq!(
+            cfg.restore.rules[0].write,
+            Some(WriteOverride {
+                bs: Some("16M".to_string()),
+                direct: Some(false),
+                sparse: None,
+                fsync: None,
+            })
+        );
+        assert_eq!(cfg.restore.rules[1].write, None);
+
+        write(&cfg_path, &body("16MB"));
+        assert!(Config::load(&cfg_path).is_err());
+    }
+
+    #[test]
+    fn load_restore_write_defaults() {
+        let tmp = TempDir::new().unwrap();
+        let cfg_path = tmp.path().join("config.toml");
+        write(
+            &cfg_path,
+            r#"
+[pbs]
+backup_id = "id"
+[pbs.repos]
+a = "url-a"
+
+[restore]
+dd = { bs = " 16M ", oflag_direct = false, fsync = true }
+"#,
+        );
+        let cfg = Config::load(&cfg_path).unwrap();
+        assert_eq!(
+            cfg.restore.write,
+            Some(WriteOverride {
+                bs: Some("16M".to_string()),
+                direct: Some(false),
+                sparse: None,
+                fsync: Some(true),
+            })
+        );
+    }
+
+    #[test]
+    fn load_ssh_sections() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        let cfg_path = dir.join("config.toml");
+        write(
+            &cfg_path,
+            r#"
+[pbs]
+backup_id = "id"
+[pbs.repos]
+a = "url-a"
+
+[backup.ssh]
+host = "10.0.0.5"
+identity_file = "id_ed25519"
+port = 2222
+
+[restore.ssh]
+host = "pve2.example.org"
+user = "root"
+"#,
+        );
+
+        let cfg = Config::load(&cfg_path).unwrap();
+        let b = cfg.backup.ssh.as_ref().unwrap();
+        assert_eq!(b.host, "10.0.0.5");
+        assert_eq!(
+            b.identity_file.as_deref(),
+            Some(dir.join("id_ed25519").as_path())
+        );
+        assert_eq!(b.port, Some(2222));
+        assert_eq!(
+            cfg.restore.ssh.as_ref().unwrap().user.as_deref(),
+            Some("root")
+        );
+        assert_eq!(cfg.pve.node.as_deref(), Some("pve2"));
+
+        write(&cfg_path, "[pbs]\n[backup.ssh]\nhost = \" \"\n");
+        assert!(Config::load(&cfg_path).is_err());
+    }
+
+    #[test]
+    fn load_lvmthin_pool_usage_limits() {
+        let tmp = TempDir::new().unwrap();
+        let cfg_path = tmp.path().join("config.toml");
+        write(
+            &cfg_path,
+            "[pbs.repos]\na = \"url-a\"\n[backup.sources.lvmthin]\nvgs = [\"pve\"]\n",
+        );
+        let cfg = Config::load(&cfg_path).unwrap();
+        let l = cfg.backup.sources.lvmthin.as_ref().unwrap();
+        assert_eq!(l.max_pool_usage, DEFAULT_MAX_POOL_USAGE);
+        assert_eq!(l.pool_usage_action, PoolUsageAction::Abort);
+
+        write(
+            &cfg_path,
+            "[pbs.repos]\na = \"url-a\"\n[backup.sources.lvmthin]\nvgs = [\"pve\"]\nmax_pool_usage = 80\npool_usage_action = \"warn\"\n",
+        );
+        let cfg = Config::load(&cfg_path).unwrap();
+        let l = cfg.backup.sources.lvmthin.as_ref().unwrap();
+        assert_eq!(l.max_pool_usage, 80);
+        assert_eq!(l.pool_usage_action, PoolUsageAction::Warn);
+
+        write(
+            &cfg_path,
+            "[pbs.repos]\na = \"url-a\"\n[backup.sources.lvmthin]\nvgs = [\"pve\"]\nmax_pool_usage = 0\n",
+        );
+        assert!(Config::load(&cfg_path).is_err());
+    }
+
+    #[test]
+    fn load_backup_timeouts() {
+        let tmp = TempDir::new().unwrap();
+        let cfg_path = tmp.path().join("config.toml");
+        write(
+            &cfg_path,
+            "[pbs.repos]\na = \"url-a\"\n[backup]\nvolume_timeout = \"30m\"\nrun_timeout = \"6h\"\nmin_interval = \"20h\"\nchanged_only_max_runs = 3\nchanged_only_max_age = \"2d\"\n",
+        );
+        let cfg = Config::load(&cfg_path).unwrap();
+        assert_eq!(
+            cfg.backup.volume_timeout,
+            Some(Duration::from_secs(30 * 60))
+        );
+        assert_eq!(cfg.backup.run_timeout, Some(Duration::from_secs(6 * 3600)));
+        assert_eq!(cfg.backup.snapshot_max_age, None);
+        assert_eq!(
+            cfg.backup.min_interval,
+            Some(Duration::from_secs(20 * 3600))
+        );
+        assert_eq!(cfg.backup.changed_only_max_runs, Some(3));
+        assert_eq!(
+            cfg.backup.changed_only_max_age,
+            Some(Duration::from_secs(2 * 86400))
+        );
+
+        write(
+            &cfg_path,
+            "[pbs.repos]\na = \"url-a\"\n[backup]\nrun_timeout = \"0s\"\n",
+        );
+        assert!(Config::load(&cfg_path).is_err());
+    }
+
+    #[test]
+    fn load_source_storage_maps() {
+        let tmp = TempDir::new().unwrap();
+        let cfg_path = tmp.path().join("config.toml");
+        write(
+            &cfg_path,
+            r#"
+[pbs.repos]
+a = "url-a"
+[backup.sources.zfs]
+pools = ["tank", "scratch"]
+storage_map = { scratch = "scratch-zfs" }
+[backup.sources.lvmthin]
+vgs = ["pve"]
+storage_map = { pve = "local-lvm" }
+"#,
+        );
+        let cfg = Config::load(&cfg_path).unwrap();
+        let s = &cfg.backup.sources;
+        assert_eq!(
+            s.zfs.as_ref().unwrap().storage_map["scratch"],
+            "scratch-zfs"
+        );
+        assert_eq!(s.lvmthin.as_ref().unwrap().storage_map["pve"], "local-lvm");
+
+        write(
+            &cfg_path,
+            "[pbs.repos]\na = \"url-a\"\n[backup.sources.zfs]\npools = [\"tank\"]\nstorage_map = { other = \"x\" }\n",
+        );
+        assert!(Config::load(&cfg_path).is_err());
+    }
+
+    #[test]
+    fn load_pve_offline_mode_and_cache_ttl() {
+        let tmp = TempDir::new().unwrap();
+        let cfg_path = tmp.path().join("config.toml");
+        write(&cfg_path, "[pbs.repos]\na = \"url-a\"\n");
+        let cfg = Config::load(&cfg_path).unwrap();
+        assert!(cfg.pve.enabled);
+        assert_eq!(cfg.pve.cache_ttl, Duration::from_secs(300));
+
+        write(
+            &cfg_path,
+            "[pbs.repos]\na = \"url-a\"\n[pve]\nenabled = false\ncache_ttl = \"0s\"\n",
+        );
+        let cfg = Config::load(&cfg_path).unwrap();
+        assert!(!cfg.pve.enabled);
+        assert!(cfg.pve.cache_ttl.is_zero());
+
+        write(
+            &cfg_path,
+            "[pbs.repos]\na = \"url-a\"\n[pve]\ncache_ttl = \"soon\"\n",
+        );
+        assert!(Config::load(&cfg_path).is_err());
+    }
+
+    #[test]
+    fn history_defaults_into_the_state_dir() {
+        let tmp = TempDir::new().unwrap();
+        let cfg_path = tmp.path().join("config.toml");
+        write(&cfg_path, "[pbs.repos]\na = \"url-a\"\n");
+        let cfg = Config::load(&cfg_path).unwrap();
+        assert_eq!(cfg.state.dir, Path::new("/var/lib/pvtools"));
+        assert_eq!(
+            cfg.events.history,
+            Path::new("/var/lib/pvtools/history.jsonl")
+        );
+
+        write(
+            &cfg_path,
+            "[pbs.repos]\na = \"url-a\"\n[state]\ndir = \"/srv/pvtools\"\n",
+        );
+        let cfg = Config::load(&cfg_path).unwrap();
+        assert_eq!(cfg.events.history, Path::new("/srv/pvtools/history.jsonl"));
+
+        write(
+            &cfg_path,
+            "[pbs.repos]\na = \"url-a\"\n[state]\ndir = \"/srv/pvtools\"\n\
+             [events]\nhistory = \"/var/log/pvtools.jsonl\"\n",
+        );
+        let cfg = Config::load(&cfg_path).unwrap();
+        assert_eq!(cfg.events.history, Path::new("/var/log/pvtools.jsonl"));
+    }
+
+    #[test]
+    fn load_backup_blackout_windows() {
+        let tmp = TempDir::new().unwrap();
+        let cfg_path = tmp.path().join("config.toml");
+        write(
+            &cfg_path,
+            "[pbs.repos]\na = \"url-a\"\n[backup]\nblackout = [\"Mon..Fri 08:00-18:00\", \"23:00-01:00\"]\nblackout_action = \"wait\"\n",
+        );
+        let cfg = Config::load(&cfg_path).unwrap();
+        assert_eq!(cfg.backup.blackout.len(), 2);
+        assert!(cfg.backup.blackout[0].contains(2, 9 * 60));
+        assert!(cfg.backup.blackout[1].contains(6, 30));
+        assert_eq!(cfg.backup.blackout_action, BlackoutAction::Wait);
+        assert_eq!(cfg.backup.active_volumes, ActiveVolumes::Ignore);
+
+        write(
+            &cfg_path,
+            "[pbs.repos]\na = \"url-a\"\n[backup]\nblackout = [\"weekdays 08:00-18:00\"]\n",
+        );
+        assert!(Config::load(&cfg_path).is_err());
+    }
+
+    #[test]
+    fn load_backup_order() {
+        let tmp = TempDir::new().unwrap();
+        let cfg_path = tmp.path().join("config.toml");
+        write(&cfg_path, "[pbs.repos]\na = \"url-a\"\n");
+        assert_eq!(
+            Config::load(&cfg_path).unwrap().backup.order,
+            BackupOrder::Config
+        );
+
+        write(
+            &cfg_path,
+            "[pbs.repos]\na = \"url-a\"\n[backup]\norder = \"size-desc\"\n",
+        );
+        let cfg = Config::load(&cfg_path).unwrap();
+        assert_eq!(cfg.backup.order, BackupOrder::SizeDesc);
+        assert!(
+            cfg.to_redacted_toml()
+                .unwrap()
+                .contains("order = \"size-desc\"")
+        );
+
+        write(
+            &cfg_path,
+            "[pbs.repos]\na = \"url-a\"\n[backup]\norder = \"biggest\"\n",
+        );
+        assert!(Config::load(&cfg_path).is_err());
+    }
+
+    #[test]
+    fn load_backup_priority() {
+        let tmp = TempDir::new().unwrap();
+        let cfg_path = tmp.path().join("config.toml");
+        write(
+            &cfg_path,
+            "[pbs.repos]\na = \"url-a\"\n[backup]\nio_priority = \"idle\"\ncpu_nice = 10\n",
+        );
+        let cfg = Config::load(&cfg_path).unwrap();
+        assert_eq!(
+            cfg.backup.priority,
+            Priority {
+                io: Some(IoClass::Idle),
+                nice: Some(10),
+            }
+        );
+        assert!(
+            cfg.to_redacted_toml()
+                .unwrap()
+                .contains("io_priority = \"idle\"\ncpu_nice = 10")
+        );
+
+        for bad in ["io_priority = \"realtime\"", "cpu_nice = 20"] {
+            write(
+                &cfg_path,
+                &format!("[pbs.repos]\na = \"url-a\"\n[backup]\n{bad}\n"),
+            );
+            assert!(Config::load(&cfg_path).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn load_per_repo_namespaces() {
+        let tmp = TempDir::new().unwrap();
+        let cfg_path = tmp.path().join("config.toml");
+        write(
+            &cfg_path,
+            r#"
+[pbs]
+ns = "shared"
+[pbs.repos]
+nas = "url-a"
+prod = { url = "url-b", ns = " k8s/prod " }
+"#,
+        );
+
+        let cfg = Config::load(&cfg_path).unwrap();
+        let nas = cfg.pbs.repo_by_alias("nas").unwrap();
+        assert_eq!(
+            (nas.url.as_str(), nas.ns.as_deref()),
+            ("url-a", Some("shared"))
+        );
+        let prod = cfg.pbs.repo_by_alias("prod").unwrap();
+        assert_eq!(
+            (prod.url.as_str(), prod.ns.as_deref()),
+            ("url-b", Some("k8s/prod"))
+        );
+
+        let printed = cfg.to_redacted_toml().unwrap();
+        assert!(printed.contains(r#"nas = "url-a""#), "{printed}");
+        assert!(printed.contains(r#"ns = "k8s/prod""#), "{printed}");
+
+        let mut cfg = cfg;
+        cfg.override_ns("/k8s/staging/");
+        assert_eq!(
+            cfg.pbs.repo_by_alias("prod").unwrap().ns.as_deref(),
+            Some("k8s/staging")
+        );
+        cfg.override_ns("");
+        assert_eq!(cfg.pbs.repo_by_alias("nas").unwrap().ns, None);
+        assert_eq!(cfg.pbs.ns, None);
+    }
+
+    #[test]
+    fn load_repo_fingerprints() {
+        let tmp = TempDir::new().unwrap();
+        let cfg_path = tmp.path().join("config.toml");
+        let fp = ["ab"; 32].join(":");
+        write(
+            &cfg_path,
+            &format!(
+                r#"
+[pbs.repos]
+nas = {{ url = "url-a", fingerprint = "{fp}" }}
+cloud = {{ url = "url-b", trusted_ca = true }}
+"#
+            ),
+        );
+
+        let cfg = Config::load(&cfg_path).unwrap();
+        let nas = cfg.pbs.repo_by_alias("nas").unwrap();
+        assert_eq!(nas.fingerprint.as_deref(), Some(fp.as_str()));
+        assert!(cfg.pbs.repo_by_alias("cloud").unwrap().trusted_ca);
+        let printed = cfg.to_redacted_toml().unwrap();
+        assert!(
+            printed.contains(&format!(r#"fingerprint = "{fp}""#)),
+            "{printed}"
+        );
+        assert!(printed.contains("trusted_ca = true"), "{printed}");
+
+        write(
+            &cfg_path,
+            "[pbs.repos]\nnas = { url = \"url-a\", fingerprint = \"ab:cd\" }\n",
+        );
+        let err = Config::load(&cfg_path).unwrap_err();
+        assert!(format!("{err:#}").contains("bad fingerprint"), "{err:#}");
+    }
+
+    #[test]
+    fn load_zfs_send_pools() {
+        let tmp = TempDir::new().unwrap();
+        let cfg_path = tmp.path().join("config.toml");
+        let base = "[pbs.repos]\na = \"url-a\"\n[backup.sources.zfs]\npools = [\"tank\", \"rpool/data\"]\n";
+        write(&cfg_path, &format!("{base}send_pools = [\"rpool/data\"]\n"));
+        let cfg = Config::load(&cfg_path).unwrap();
+        let z = cfg.backup.sources.zfs.as_ref().unwrap();
+        assert_eq!(z.send_pools, ["rpool/data"]);
+        assert_eq!(z.staging_dir, Path::new("/var/tmp"));
+
+        for bad in [
+            "send_pools = [\"backup\"]\n",
+            "send_pools = [\"tank\"]\nstaging_dir = \"tmp\"\n",
+            "use_existing_snapshot = \"autosnap_[hourly\"\n",
+        ] {
+            write(&cfg_path, &format!("{base}{bad}"));
+            assert!(Config::load(&cfg_path).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn load_external_sources() {
+        let tmp = TempDir::new().unwrap();
+        let cfg_path = tmp.path().join("config.toml");
+        let base = "[pbs.repos]\na = \"url-a\"\n";
+        write(
+            &cfg_path,
+            &format!(
+                "{base}[backup.sources.external.iscsi]\ncommand = \" /usr/local/bin/iscsi-provider \"\nargs = [\"--portal\", \"10.0.0.9\"]\ntimeout = \"2m\"\n"
+            ),
+        );
+        let cfg = Config::load(&cfg_path).unwrap();
+        let ext = &cfg.backup.sources.external["iscsi"];
+        assert_eq!(ext.command, "/usr/local/bin/iscsi-provider");
+        assert_eq!(ext.args, ["--portal", "10.0.0.9"]);
+        assert_eq!(ext.timeout, Some(Duration::from_secs(120)));
+        assert!(!cfg.backup.sources.is_empty());
+        let printed = cfg.to_redacted_toml().unwrap();
+        assert!(
+            printed.contains("[backup.sources.external.iscsi]"),
+            "{printed}"
+        );
+        assert!(printed.contains("timeout = \"120s\""), "{printed}");
+
+        for bad in [
+            "[backup.sources.external.zfs]\ncommand = \"x\"\n",
+            "[backup.sources.external.my_san]\ncommand = \"x\"\n",
+            "[backup.sources.external.san]\ncommand = \" \"\n",
+            "[backup.sources.external.san]\ncommand = \"x\"\ntimeout = \"0s\"\n",
+        ] {
+            write(&cfg_path, &format!("{base}{bad}"));
+            assert!(Config::load(&cfg_path).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn load_nodes_sections() {
+        let tmp = TempDir::new().unwrap();
+        let cfg_path = tmp.path().join("config.toml");
+        let base = r#"
+[pbs]
+backup_id = "id"
+[pbs.repos]
+a = "url-a"
+
+[nodes.pve1]
+[nodes.pve1.sources.zfs]
+pools = ["tank"]
+
+[nodes.pve2]
+host = "10.0.0.2"
+user = "root"
+backup_id = "pve2-vms"
+[nodes.pve2.sources.lvmthin]
+vgs = ["pve"]
+"#;
+        write(&cfg_path, base);
+
+        let cfg = Config::load(&cfg_path).unwrap();
+        let pve1 = &cfg.nodes["pve1"];
+        assert_eq!(pve1.ssh.host, "pve1");
+        assert_eq!(pve1.backup_id, "pve1-backup");
+        assert_eq!(pve1.sources.zfs.as_ref().unwrap().pools, vec!["tank"]);
+        let pve2 = &cfg.nodes["pve2"];
+        assert_eq!(pve2.ssh.host, "10.0.0.2");
+        assert_eq!(pve2.ssh.user.as_deref(), Some("root"));
+        assert_eq!(pve2.backup_id, "pve2-vms");
+
+        write(
+            &cfg_path,
+            &base.replace("backup_id = \"pve2-vms\"", "backup_id = \"pve1-backup\""),
+        );
+        assert!(Config::load(&cfg_path).is_err());
+
+        write(
+            &cfg_path,
+            &format!("{base}\n[backup.sources.zfs]\npools = [\"tank\"]\n"),
+        );
+        assert!(Config::load(&cfg_path).is_err());
+
+        write(&cfg_path, "[pbs]\n[nodes.pve1]\nhost = \"pve1\"\n");
+        assert!(Config::load(&cfg_path).is_err());
+    }
 }
@@ -0,0 +1,232 @@
+//! Where config keys come from, and which keys nothing reads.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::Deserializer;
+use toml::Value;
+
+use super::RawConfig;
+
+/// Printed keys whose value is read from config keys of another name.
+const SOURCES: &[(&str, &[&str])] = &[
+    ("pbs.password", &["pbs.password_cmd", "pbs.password_file"]),
+    ("pbs.key_passphrase", &["pbs.key_passphrase_file"]),
+    ("restore.write", &["restore.write", "restore.dd"]),
+];
+
+/// Keys read by something other than [`RawConfig`].
+const HANDLED_ELSEWHERE: &[&str] = &["include"];
+
+/// Deserializes [`RawConfig`] from the merged config and lists the keys it ignores, dotted.
+pub(super) fn deserialize_raw<'de, D: Deserializer<'de>>(
+    de: D,
+) -> Result<(RawConfig, Vec<String>), D::Error> {
+    let mut ignored = Vec::new();
+    let raw = serde_ignored::deserialize(de, |path| ignored.push(dotted(&path)))?;
+    ignored.retain(|k| !HANDLED_ELSEWHERE.contains(&k.as_str()));
+    ignored.sort();
+    Ok((raw, ignored))
+}
+
+fn dotted(path: &serde_ignored::Path<'_>) -> String {
+    use serde_ignored::Path;
+    match path {
+        Path::Root => String::new(),
+        Path::Seq { parent, index } => format!("{}[{index}]", dotted(parent)),
+        Path::Map { parent, key } => match dotted(parent) {
+            p if p.is_empty() => key.clone(),
+            p => format!("{p}.{key}"),
+        },
+        Path::Some { parent }
+        | Path::NewtypeStruct { parent }
+        | Path::NewtypeVariant { parent } => dotted(parent),
+    }
+}
+
+/// The file that sets each key last, by dotted key; arrays count as one key.
+pub(super) fn key_origins(files: &[PathBuf]) -> Result<BTreeMap<String, PathBuf>> {
+    fn walk(t: &toml::Table, prefix: &str, file: &Path, out: &mut BTreeMap<String, PathBuf>) {
+        for (k, v) in t {
+            let key = if prefix.is_empty() {
+                k.clone()
+            } else {
+                format!("{prefix}.{k}")
+            };
+            match v {
+                Value::Table(sub) => walk(sub, &key, file, out),
+                _ => {
+                    out.insert(key, file.to_path_buf());
+                }
+            }
+        }
+    }
+
+    let mut out = BTreeMap::new();
+    for f in files
+        .iter()
+        .filter(|f| f.extension().is_some_and(|e| e == "toml"))
+    {
+        let raw = fs::read_to_string(f).with_context(|| format!("read {}", f.display()))?;
+        let table: toml::Table =
+            toml::from_str(&raw).with_context(|| format!("parse {}", f.display()))?;
+        walk(&table, "", f, &mut out);
+    }
+    Ok(out)
+}
+
+/// Appends to every `key = value` line of `printed` where the value comes from: a file,
+/// an environment variable, or the default.
+pub(super) fn annotate(
+    printed: &str,
+    origins: &BTreeMap<String, PathBuf>,
+    env: impl Fn(&str) -> Option<String>,
+) -> String {
+    let mut table = String::new();
+    let mut out = String::new();
+    for line in printed.lines() {
+        out.push_str(line);
+        if let Some(h) = line.strip_prefix('[') {
+            table = h.trim_matches(|c| c == '[' || c == ']').to_string();
+        } else if !line.starts_with(char::is_whitespace)
+            && let Some((key, _)) = line.split_once(" = ")
+        {
+            let key = key.trim_matches('"');
+            let path = if table.is_empty() {
+                key.to_string()
+            } else {
+                format!("{table}.{key}")
+            };
+            out.push_str("  # ");
+            out.push_str(&origin_of(&path, origins, &env));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn origin_of(
+    path: &str,
+    origins: &BTreeMap<String, PathBuf>,
+    env: impl Fn(&str) -> Option<String>,
+) -> String {
+    if path == "pbs.password" && env("PBS_PASSWORD").is_some_and(|v| !v.is_empty()) {
+        return "env PBS_PASSWORD".to_string();
+    }
+    let own = [path];
+    let sources = SOURCES
+        .iter()
+        .find(|(p, _)| *p == path)
+        .map_or(&own[..], |(_, s)| *s);
+    for key in sources {
+        let below = format!("{key}.");
+        let file = origins.get(*key).or_else(|| {
+            origins
+                .range(below.clone()..)
+                .take_while(|(k, _)| k.starts_with(&below))
+                .map(|(_, f)| f)
+                .last()
+        });
+        if let Some(f) = file {
+            return f.display().to_string();
+        }
+    }
+    // Keys inside arrays of tables: the array as a whole.
+    let mut parent = path;
+    while let Some((p, _)) = parent.rsplit_once('.') {
+        if let Some(f) = origins.get(p) {
+            return f.display().to_string();
+        }
+        parent = p;
+    }
+    "default".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn typos_are_ignored_keys() {
+        let merged: Value = toml::from_str(
+            r#"
+include = ["other.toml"]
+[pbs]
+backup_id = "b"
+[pbs.repos]
+nas = { url = "url-a", fingerprnt = "x" }
+[backup]
+pv_prefixs = ["vm-"]
+[backup.sources.zfs]
+pools = ["tank"]
+[restore.targets.z]
+type = "zfs"
+root = "tank"
+rot = "tank"
+[[restore.rules]]
+"match.provider" = "zfs"
+target = "z"
+taget = "z"
+[colour]
+x = 1
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            deserialize_raw(merged).unwrap().1,
+            vec![
+                "backup.pv_prefixs",
+                "colour",
+                "pbs.repos.nas.fingerprnt",
+                "restore.rules[0].taget",
+                "restore.targets.z.rot",
+            ]
+        );
+    }
+
+    #[test]
+    fn annotates_values_with_the_file_that_set_them() {
+        let tmp = TempDir::new().unwrap();
+        let (base, node) = (tmp.path().join("base.toml"), tmp.path().join("node.toml"));
+        fs::write(
+            &base,
+            "[pbs]\nbackup_id = \"a\"\npassword_file = \"t\"\n[pbs.repos]\nnas = \"u\"\n",
+        )
+        .unwrap();
+        fs::write(
+            &node,
+            "[pbs]\nbackup_id = \"b\"\n[[restore.rules]]\ntarget = \"z\"\n",
+        )
+        .unwrap();
+        let origins = key_origins(&[base.clone(), node.clone()]).unwrap();
+
+        let printed = "[pbs]\npassword = \"<redacted>\"\nbackup_id = \"b\"\nns = \"x\"\n\n\
+                       [pbs.repos]\nnas = \"u\"\n\n[[restore.rules]]\ntarget = \"z\"\n";
+        let out = annotate(printed, &origins, |_| None);
+        let (b, n) = (base.display(), node.display());
+        assert!(
+            out.contains(&format!("password = \"<redacted>\"  # {b}\n")),
+            "{out}"
+        );
+        assert!(
+            out.contains(&format!("backup_id = \"b\"  # {n}\n")),
+            "{out}"
+        );
+        assert!(out.contains("ns = \"x\"  # default\n"), "{out}");
+        assert!(out.contains(&format!("nas = \"u\"  # {b}\n")), "{out}");
+        assert!(out.contains(&format!("target = \"z\"  # {n}\n")), "{out}");
+        assert!(out.contains("\n[pbs.repos]\n"), "{out}");
+
+        let out = annotate(printed, &origins, |_| Some("pw".to_string()));
+        assert!(
+            out.contains("password = \"<redacted>\"  # env PBS_PASSWORD\n"),
+            "{out}"
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use std::{io::Write, path::Path, sync::Mutex, time::Duration};
+
+use serde::Serialize;
+
+use crate::{
+    commands::backup::VolumeOutcome,
+    history::{History, Recorder},
+    tooling::pbs::UploadStats,
+    utils::time::current_epoch,
+};
+
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    RunStarted {
+        command: &'a str,
+        backup_id: &'a str,
+        repo: &'a str,
+        dry_run: bool,
+    },
+    VolumeDiscovered {
+        storage: &'a str,
+        disk: &'a str,
+        archive: &'a str,
+    },
+    VolumeSkipped {
+        archive: &'a str,
+        reason: &'a str,
+    },
+    ArchiveUploaded {
+        archive: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        stats: Option<&'a UploadStats>,
+    },
+    ArchiveRestored {
+        archive: &'a str,
+        target: &'a str,
+        ok: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<&'a str>,
+    },
+    /// Every volume selected for a backup, with how it fared.
+    BackupSummary {
+        volumes: &'a [VolumeOutcome],
+    },
+    RestoreFinished {
+        total: usize,
+        failed: usize,
+    },
+    RunFinished {
+        command: &'a str,
+        ok: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    ts: u64,
+    pid: u32,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+/// Best-effort NDJSON publisher; a missing or broken socket never fails the run.
+/// Also feeds the run history, if one is kept.
+#[derive(Default)]
+pub struct EventSink {
+    #[cfg(unix)]
+    stream: Mutex<Option<std::os::unix::net::UnixStream>>,
+    #[cfg(not(unix))]
+    stream: Mutex<Option<std::fs::File>>,
+    history: Mutex<Option<Recorder>>,
+}
+
+impl EventSink {
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    pub fn with_history(self, history: History) -> Self {
+        Self {
+            history: Mutex::new(Some(Recorder::new(history))),
+            ..self
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn connect(path: &Path) -> Self {
+        use std::os::unix::net::UnixStream;
+
+        match UnixStream::connect(path) {
+            Ok(s) => {
+                let _ = s.set_write_timeout(Some(WRITE_TIMEOUT));
+                Self {
+                    stream: Mutex::new(Some(s)),
+                    ..Self::default()
+                }
+            }
+            Err(e) => {
+                tracing::warn!("events: connect {} failed, disabled: {e}", path.display());
+                Self::disabled()
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn connect(path: &Path) -> Self {
+        tracing::warn!(
+            "events: unix sockets unsupported, ignoring {}",
+            path.display()
+        );
+        Self::disabled()
+    }
+
+    pub fn emit(&self, event: Event<'_>) {
+        if let Ok(mut guard) = self.history.lock()
+            && let Some(rec) = guard.as_mut()
+            && let Err(e) = rec.observe(&event)
+        {
+            tracing::warn!("history: {e:#}");
+        }
+        let Ok(mut guard) = self.stream.lock() else {
+            return;
+        };
+        let Some(stream) = guard.as_mut() else {
+            return;
+        };
+        let env = Envelope {
+            ts: current_epoch(),
+            pid: std::process::id(),
+            event: &event,
+        };
+        let res = serde_json::to_vec(&env)
+            .map_err(std::io::Error::other)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                stream.write_all(&line)
+            });
+        if let Err(e) = res {
+            tracing::warn!("events: write failed, disabled: {e}");
+            *guard = None;
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        os::unix::net::UnixListener,
+    };
+
+    use super::*;
+
+    #[test]
+    fn emits_ndjson_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let sink = EventSink::connect(&path);
+        let (conn, _) = listener.accept().unwrap();
+        let stats = UploadStats {
+            archive: "a.img".to_string(),
+            size: 4096,
+            uploaded: 1024,
+            compressed: 512,
+            secs: 0.5,
+        };
+        sink.emit(Event::ArchiveUploaded {
+            archive: "a.img",
+            stats: Some(&stats),
+        });
+        sink.emit(Event::RestoreFinished {
+            total: 2,
+            failed: 1,
+        });
+        drop(sink);
+
+        let lines: Vec<serde_json::Value> = BufReader::new(conn)
+            .lines()
+            .map(|l| serde_json::from_str(&l.unwrap()).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["event"], "archive_uploaded");
+        assert_eq!(lines[0]["archive"], "a.img");
+        assert_eq!(lines[0]["stats"]["uploaded"], 1024);
+        assert_eq!(lines[1]["failed"], 1);
+        assert!(lines[1]["ts"].as_u64().is_some());
+    }
+
+    #[test]
+    fn missing_socket_disables_sink() {
+        let sink = EventSink::connect(Path::new("/nonexistent/pvtools.sock"));
+        sink.emit(Event::ArchiveUploaded {
+            archive: "a.img",
+            stats: None,
+        });
+    }
+}
@@ -0,0 +1,211 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    events::Event,
+    state::{with_lock, write_atomic},
+    utils::time::current_epoch,
+};
+
+/// Summary of one finished run, one JSON line in the history file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunRecord {
+    pub command: String,
+    pub backup_id: String,
+    pub repo: String,
+    pub started: u64,
+    pub secs: u64,
+    pub ok: bool,
+    /// Archives uploaded or restored.
+    #[serde(default)]
+    pub archives: usize,
+    /// `<archive>: <reason>` for every skipped or failed archive.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failures: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// NDJSON file holding the last `keep` run records, oldest first.
+#[derive(Debug, Clone)]
+pub struct History {
+    path: PathBuf,
+    keep: usize,
+}
+
+impl History {
+    pub fn new(path: &Path, keep: usize) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            keep,
+        }
+    }
+
+    /// Appends `rec` and drops the oldest records beyond `keep`, under an exclusive lock. The
+    /// file is replaced atomically, so readers never see it half written.
+    pub fn append(&self, rec: &RunRecord) -> Result<()> {
+        with_lock(&self.path, || {
+            let raw = match fs::read_to_string(&self.path) {
+                Ok(raw) => raw,
+                Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+                Err(e) => return Err(e).with_context(|| format!("read {}", self.path.display())),
+            };
+            let mut lines: Vec<&str> = raw.lines().filter(|l| !l.trim().is_empty()).collect();
+            let line = serde_json::to_string(rec).context("serialize run record")?;
+            lines.push(&line);
+            let excess = lines.len().saturating_sub(self.keep);
+
+            let mut out = lines[excess..].join("\n");
+            out.push('\n');
+            write_atomic(&self.path, out.as_bytes())
+        })
+    }
+
+    /// Every readable record, oldest first; a missing file is an empty history.
+    pub fn load(&self) -> Result<Vec<RunRecord>> {
+        let raw = match fs::read_to_string(&self.path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("read {}", self.path.display())),
+        };
+        Ok(raw
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .filter_map(|l| match serde_json::from_str(l) {
+                Ok(rec) => Some(rec),
+                Err(e) => {
+                    tracing::warn!("{}: skipping bad record: {e}", self.path.display());
+                    None
+                }
+            })
+            .collect())
+    }
+}
+
+/// Builds a [`RunRecord`] from the events of a run and appends it once the run finishes.
+/// Dry runs are not recorded.
+pub struct Recorder {
+    history: History,
+    run: Option<RunRecord>,
+}
+
+impl Recorder {
+    pub fn new(history: History) -> Self {
+        Self { history, run: None }
+    }
+
+    pub fn observe(&mut self, event: &Event<'_>) -> Result<()> {
+        match event {
+            Event::RunStarted {
+                command,
+                backup_id,
+                repo,
+                dry_run,
+            } => {
+                self.run = (!dry_run).then(|| RunRecord {
+                    command: command.to_string(),
+                    backup_id: backup_id.to_string(),
+                    repo: repo.to_string(),
+                    started: current_epoch(),
+                    secs: 0,
+                    ok: false,
+                    archives: 0,
+                    failures: Vec::new(),
+                    error: None,
+                });
+            }
+            Event::ArchiveUploaded { .. } => {
+                if let Some(run) = &mut self.run {
+                    run.archives += 1;
+                }
+            }
+            Event::VolumeSkipped { archive, reason } => {
+                if let Some(run) = &mut self.run {
+                    run.failures.push(format!("{archive}: {reason}"));
+                }
+            }
+            Event::ArchiveRestored {
+                archive, ok, error, ..
+            } => {
+                if let Some(run) = &mut self.run {
+                    if *ok {
+                        run.archives += 1;
+                    } else {
+                        run.failures
+                            .push(format!("{archive}: {}", error.unwrap_or("failed")));
+                    }
+                }
+            }
+            Event::RunFinished { ok, error, .. } => {
+                if let Some(mut run) = self.run.take() {
+                    run.secs = current_epoch().saturating_sub(run.started);
+                    run.ok = *ok;
+                    run.error = error.clone();
+                    self.history.append(&run)?;
+                }
+            }
+            Event::VolumeDiscovered { .. }
+            | Event::BackupSummary { .. }
+            | Event::RestoreFinished { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn records_runs_and_keeps_the_newest() {
+        let tmp = TempDir::new().unwrap();
+        let history = History::new(&tmp.path().join("state/history.jsonl"), 2);
+        let mut rec = Recorder::new(history.clone());
+
+        for (i, dry_run) in [(0, false), (1, true), (2, false), (3, false)] {
+            rec.observe(&Event::RunStarted {
+                command: "restore",
+                backup_id: "pve1",
+                repo: "nas",
+                dry_run,
+            })
+            .unwrap();
+            rec.observe(&Event::ArchiveRestored {
+                archive: "zfs_a_raw_1.img",
+                target: "/dev/zvol/tank/a",
+                ok: true,
+                error: None,
+            })
+            .unwrap();
+            rec.observe(&Event::ArchiveRestored {
+                archive: "zfs_b_raw_2.img",
+                target: "/dev/zvol/tank/b",
+                ok: false,
+                error: Some("no space"),
+            })
+            .unwrap();
+            rec.observe(&Event::RunFinished {
+                command: "restore",
+                ok: false,
+                error: Some(format!("run {i}")),
+            })
+            .unwrap();
+        }
+
+        let runs = history.load().unwrap();
+        assert_eq!(
+            runs.iter().map(|r| r.error.as_deref()).collect::<Vec<_>>(),
+            [Some("run 2"), Some("run 3")]
+        );
+        assert_eq!(runs[1].archives, 1);
+        assert_eq!(runs[1].failures, ["zfs_b_raw_2.img: no space"]);
+    }
+}
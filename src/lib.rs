@@ -0,0 +1,31 @@
+//! Backup and restore orchestration for Proxmox volumes and Kubernetes PVs on Proxmox Backup
+//! Server. The `pvtools` binary is a thin CLI over this crate: load a [`config::Config`], build
+//! a [`tooling::Toolbox`] around a [`utils::process::Runner`] and hand both to the commands in
+//! [`commands`] through an [`AppCtx`].
+
+use std::sync::Arc;
+
+pub mod commands;
+pub mod config;
+pub mod events;
+pub mod history;
+pub mod manifest;
+pub mod state;
+pub mod tooling;
+mod ui;
+pub mod utils;
+pub mod volume;
+
+use config::Config;
+use events::EventSink;
+use tooling::Toolbox;
+use utils::process::Runner;
+
+/// Everything a command needs: the config, where commands run and how runs are reported.
+pub struct AppCtx {
+    pub debug: bool,
+    pub cfg: Config,
+    pub runner: Arc<dyn Runner>,
+    pub tools: Toolbox,
+    pub events: Arc<EventSink>,
+}
@@ -1,28 +1,23 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{path::PathBuf, process::ExitCode, sync::Arc};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::{CommandFactory, Parser, Subcommand};
+use pvtools::{
+    AppCtx,
+    commands::{self, backup, catalog, cleanup, copy, diff, discover, restore, selftest},
+    config::{Config, Log},
+    events::EventSink,
+    history::History,
+    tooling::{self, Toolbox},
+    utils::{
+        failure::Failure,
+        process::{ProcessRunner, Runner},
+        signal,
+        ssh::SshRunner,
+    },
+};
 use tracing_subscriber::{EnvFilter, fmt};
 
-mod commands;
-mod config;
-mod tooling;
-mod ui;
-mod utils;
-mod volume;
-
-use commands::{backup, restore};
-use config::Config;
-use tooling::Toolbox;
-use utils::process::{ProcessRunner, Runner};
-
-pub struct AppCtx {
-    pub debug: bool,
-    pub cfg: Config,
-    pub runner: Arc<dyn Runner>,
-    pub tools: Toolbox,
-}
-
 #[derive(Parser, Debug)]
 #[command(
     name = "pvtools",
@@ -31,8 +26,9 @@ pub struct AppCtx {
     version = env!("CARGO_PKG_VERSION")
 )]
 struct Cli {
+    /// Config file; repeat to overlay files, later ones overriding earlier ones
     #[arg(long, default_value = "./config.toml", global = true)]
-    config: PathBuf,
+    config: Vec<PathBuf>,
 
     #[arg(long, global = true)]
     debug: bool,
@@ -43,6 +39,19 @@ struct Cli {
     #[arg(long, global = true)]
     print_config: bool,
 
+    /// Fail on config keys no setting reads instead of warning about them
+    #[arg(long, global = true)]
+    strict_config: bool,
+
+    /// With --print-config, follow each value with the file, environment variable or default
+    /// it comes from
+    #[arg(long, global = true, requires = "print_config")]
+    show_origin: bool,
+
+    /// Record the certificate fingerprint of PBS servers seen for the first time without asking
+    #[arg(long, global = true)]
+    trust_new_fingerprint: bool,
+
     #[command(subcommand)]
     command: Option<Cmd>,
 }
@@ -51,39 +60,107 @@ struct Cli {
 enum Cmd {
     Backup(backup::BackupArgs),
     Restore(restore::RestoreArgs),
+    Cleanup(cleanup::CleanupArgs),
+    Copy(copy::CopyArgs),
+    /// Compare the archives of two snapshots
+    Diff(diff::DiffArgs),
+    /// Export a catalog of the archives in a backup group
+    Catalog(catalog::CatalogArgs),
+    /// List every volume the backup sources consider and why each is accepted or rejected
+    Discover(discover::DiscoverArgs),
+    /// Show the summaries of previous runs
+    History(commands::history::HistoryArgs),
+    /// Back up and restore a scratch volume on a loop device to check the whole toolchain
+    Selftest(selftest::SelftestArgs),
+    /// Print a shell completion script
+    Completions(commands::completions::CompletionsArgs),
+    /// Print the man page, or write one per subcommand into a directory
+    Manpage(commands::completions::ManpageArgs),
 }
 
-fn init_tracing(debug: bool) {
-    let default = if debug { "debug" } else { "info" };
-    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default));
-    let _ = fmt()
-        .with_env_filter(filter)
+/// Swaps the log filter once the config's `[log] filters` are known.
+type ReloadLog = Box<dyn Fn(&Log)>;
+
+fn init_tracing(debug: bool) -> ReloadLog {
+    // An unparsable RUST_LOG is left out, as before.
+    let env = std::env::var("RUST_LOG")
+        .ok()
+        .filter(|v| EnvFilter::try_new(v).is_ok());
+    let filter = move |log: &Log| EnvFilter::new(log.directives(debug, env.as_deref()));
+    let builder = fmt()
+        .with_env_filter(filter(&Log::default()))
         .with_level(false)
         .with_target(false)
         .with_file(debug)
         .with_line_number(debug)
         .without_time()
-        .try_init();
+        .with_filter_reloading();
+    let handle = builder.reload_handle();
+    let _ = builder.try_init();
+    Box::new(move |log| {
+        if let Err(e) = handle.reload(filter(log)) {
+            tracing::warn!("log filters not applied: {e}");
+        }
+    })
 }
 
-fn main() -> Result<()> {
+fn main() -> ExitCode {
     let cli = Cli::parse();
-    init_tracing(cli.debug);
+    let reload_log = init_tracing(cli.debug);
+
+    if let Err(e) = signal::install() {
+        tracing::warn!("signal handlers not installed: {e:#}");
+    }
 
+    match run(cli, &reload_log) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            if let Some(sig) = signal::interrupted() {
+                tracing::error!("{}; cleanup done: {e:#}", signal::Interrupted(sig));
+                return ExitCode::from(signal::exit_code(sig));
+            }
+            let failure = Failure::of(&e);
+            if failure == Some(Failure::Partial) {
+                eprintln!("Error: {e}");
+            } else {
+                eprintln!("Error: {e:?}");
+            }
+            failure.map_or(ExitCode::FAILURE, |f| ExitCode::from(f.exit_code()))
+        }
+    }
+}
+
+fn run(cli: Cli, reload_log: &ReloadLog) -> Result<()> {
     if cli.command.is_none() && !cli.check_config && !cli.print_config {
         let mut cmd = Cli::command();
         cmd.print_help()?;
         println!();
         return Ok(());
     }
-    let cfg = Config::load(&cli.config)?;
+    // Generated from the CLI definition alone, so they work without a config.
+    match &cli.command {
+        Some(Cmd::Completions(args)) => return args.run(Cli::command()),
+        Some(Cmd::Manpage(args)) => return args.run(Cli::command()),
+        _ => {}
+    }
+    let loaded = if cli.strict_config {
+        Config::load_all_strict(&cli.config)
+    } else {
+        Config::load_all(&cli.config)
+    };
+    let mut cfg = loaded.context(Failure::Config)?;
+    reload_log(&cfg.log);
 
     if cli.check_config {
         tracing::info!("config OK");
         return Ok(());
     }
     if cli.print_config {
-        println!("{}", cfg.to_redacted_toml()?);
+        if cli.show_origin {
+            print!("{}", cfg.to_annotated_toml(&cli.config)?);
+        } else {
+            println!("{}", cfg.to_redacted_toml()?);
+        }
         return Ok(());
     }
 
@@ -93,19 +170,90 @@ fn main() -> Result<()> {
         println!();
         return Ok(());
     };
+    let ns = match &cmd {
+        Cmd::Backup(args) => args.cmd.ns(),
+        Cmd::Restore(args) => args.cmd.ns(),
+        _ => None,
+    };
+    if let Some(ns) = ns {
+        cfg.override_ns(ns);
+    }
+    if let Cmd::History(args) = &cmd {
+        return args.run(&cfg);
+    }
+    if let Cmd::Restore(restore::RestoreArgs {
+        cmd: restore::RestoreCmd::Explain(args),
+    }) = &cmd
+    {
+        return args.run(&cfg);
+    }
+    tooling::pbs::resolve_key_passphrase(&mut cfg.pbs)?;
+    if let Cmd::Selftest(args) = &cmd {
+        return args.run(&cfg, cli.debug);
+    }
 
-    let runner = Arc::new(ProcessRunner::new());
-    let tools = Toolbox::new(&cfg, runner.clone())?;
+    let ssh = match &cmd {
+        Cmd::Backup(_) | Cmd::Cleanup(_) | Cmd::Discover(_) => cfg.backup.ssh.clone(),
+        Cmd::Restore(_) => cfg.restore.ssh.clone(),
+        Cmd::Copy(_)
+        | Cmd::Diff(_)
+        | Cmd::Catalog(_)
+        | Cmd::History(_)
+        | Cmd::Selftest(_)
+        | Cmd::Completions(_)
+        | Cmd::Manpage(_) => None,
+    };
+    let ssh_runner = ssh.map(|ssh| Arc::new(SshRunner::new(ssh)));
+    let local_runner = Arc::new(ProcessRunner::new());
+    let runner: Arc<dyn Runner> = match &ssh_runner {
+        Some(r) => {
+            tracing::info!("running commands on {}", r.destination());
+            r.clone()
+        }
+        None => local_runner.clone(),
+    };
+    if matches!(
+        cmd,
+        Cmd::Backup(_) | Cmd::Restore(_) | Cmd::Copy(_) | Cmd::Diff(_) | Cmd::Catalog(_)
+    ) {
+        tooling::tls::pin_fingerprints(
+            &mut cfg.pbs,
+            runner.as_ref(),
+            &cfg.state.store(),
+            cli.trust_new_fingerprint,
+        )?;
+    }
+    let tools = match ssh_runner {
+        Some(r) => Toolbox::over_ssh(&cfg, r)?,
+        None => Toolbox::new(&cfg, local_runner)?,
+    };
+
+    let mut events = match &cfg.events.socket {
+        Some(path) => EventSink::connect(path),
+        None => EventSink::disabled(),
+    };
+    if cfg.events.history_keep > 0 {
+        events = events.with_history(History::new(&cfg.events.history, cfg.events.history_keep));
+    }
 
     let ctx = AppCtx {
         debug: cli.debug,
         cfg,
         runner,
         tools,
+        events: Arc::new(events),
     };
 
     match cmd {
         Cmd::Backup(args) => args.run(&ctx),
         Cmd::Restore(args) => args.run(&ctx),
+        Cmd::Cleanup(args) => args.run(&ctx),
+        Cmd::Copy(args) => args.run(&ctx),
+        Cmd::Diff(args) => args.run(&ctx),
+        Cmd::Catalog(args) => args.run(&ctx),
+        Cmd::Discover(args) => args.run(&ctx),
+        Cmd::History(_) | Cmd::Selftest(_) | Cmd::Completions(_) | Cmd::Manpage(_) => {
+            unreachable!("handled before the toolbox is built")
+        }
     }
 }
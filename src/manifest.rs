@@ -0,0 +1,161 @@
+use std::{collections::BTreeMap, io::Write};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+
+use crate::utils::time::current_epoch;
+
+pub const MANIFEST_ARCHIVE: &str = "pvtools-manifest.conf";
+pub const MANIFEST_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupManifest {
+    pub version: u32,
+    pub created: u64,
+    pub backup_id: String,
+    #[serde(default)]
+    pub storage: Vec<StorageStatus>,
+    /// Kubernetes claims bound to the archived volumes at backup time.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub claims: Vec<ClaimRecord>,
+    /// Archives `--changed-only` left out as unchanged; an earlier snapshot holds them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub carried: Vec<CarriedArchive>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CarriedArchive {
+    pub archive: String,
+    /// Backup time of the snapshot of the same group that holds the archive.
+    pub backup_time: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClaimRecord {
+    pub archive: String,
+    pub namespace: String,
+    pub name: String,
+    /// The bound PV; missing in manifests written before PVs were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pv: Option<PvRecord>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PvRecord {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volume_handle: Option<String>,
+    #[serde(flatten)]
+    pub details: PvDetails,
+}
+
+/// The parts of a PV spec needed to re-create it and its claim.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PvDetails {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub driver: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capacity: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub access_modes: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage_class: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volume_mode: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fs_type: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub volume_attributes: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StorageStatus {
+    pub provider: String,
+    pub source: String,
+    pub command: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl StorageStatus {
+    pub fn capture(provider: &str, source: &str, command: &str, res: Result<String>) -> Self {
+        let (output, error) = match res {
+            Ok(out) => (Some(out), None),
+            Err(e) => {
+                tracing::warn!(
+                    "{provider}: '{command}' failed, recording error in manifest: {e:#}"
+                );
+                (None, Some(format!("{e:#}")))
+            }
+        };
+        Self {
+            provider: provider.to_string(),
+            source: source.to_string(),
+            command: command.to_string(),
+            output,
+            error,
+        }
+    }
+}
+
+impl BackupManifest {
+    pub fn new(backup_id: &str, storage: Vec<StorageStatus>) -> Self {
+        Self {
+            version: MANIFEST_VERSION,
+            created: current_epoch(),
+            backup_id: backup_id.to_string(),
+            storage,
+            claims: Vec::new(),
+            carried: Vec::new(),
+        }
+    }
+
+    pub fn write_temp(&self) -> Result<NamedTempFile> {
+        let mut f = tempfile::Builder::new()
+            .prefix("pvtools-manifest-")
+            .suffix(".json")
+            .tempfile()
+            .context("create manifest temp file")?;
+        serde_json::to_writer_pretty(&mut f, self).context("serialize manifest")?;
+        f.flush().context("flush manifest temp file")?;
+        Ok(f)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).context("serialize manifest")
+    }
+
+    pub fn parse(raw: &str) -> Result<Self> {
+        serde_json::from_str(raw).context("parse pvtools manifest json")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use anyhow::anyhow;
+
+    use super::*;
+
+    #[test]
+    fn roundtrip_through_temp_file() {
+        let m = BackupManifest::new(
+            "host-backup",
+            vec![
+                StorageStatus::capture("zfs", "tank", "zpool status -P tank", Ok("ONLINE".into())),
+                StorageStatus::capture("lvmthin", "pve", "vgs", Err(anyhow!("boom"))),
+            ],
+        );
+        let f = m.write_temp().unwrap();
+        let parsed = BackupManifest::parse(&fs::read_to_string(f.path()).unwrap()).unwrap();
+
+        assert_eq!(parsed.version, MANIFEST_VERSION);
+        assert_eq!(parsed.backup_id, "host-backup");
+        assert_eq!(parsed.storage[0].output.as_deref(), Some("ONLINE"));
+        assert_eq!(parsed.storage[1].error.as_deref(), Some("boom"));
+    }
+}
@@ -0,0 +1,217 @@
+//! The directory pvtools keeps state in between runs: caches, recorded certificate
+//! fingerprints and, by default, the run history.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use fs2::FileExt;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+
+pub const DEFAULT_STATE_DIR: &str = "/var/lib/pvtools";
+
+/// A JSON document kept in a [`StateStore`], stored as `{"version": N, "data": ...}`.
+pub trait Versioned: Serialize + DeserializeOwned {
+    const VERSION: u32;
+
+    /// `data` as written at `version`, brought up to [`Versioned::VERSION`]. Files written
+    /// before documents carried a version come in as version 0.
+    fn migrate(version: u32, data: Value) -> Result<Value>;
+}
+
+#[derive(Serialize)]
+struct EnvelopeOut<'a, T> {
+    version: u32,
+    data: &'a T,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EnvelopeIn {
+    version: u32,
+    data: Value,
+}
+
+/// Owns the state directory. Documents are replaced atomically, so a crash leaves the old or
+/// the new content, and read-modify-write cycles hold a lock, so concurrent runs do not lose
+/// each other's changes.
+#[derive(Debug, Clone)]
+pub struct StateStore {
+    dir: PathBuf,
+}
+
+impl StateStore {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    /// The document `name`, or `None` when there is none yet.
+    pub fn load<T: Versioned>(&self, name: &str) -> Result<Option<T>> {
+        let path = self.path(name);
+        let raw = match fs::read(&path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+        };
+        decode(&raw).with_context(|| format!("parse {}", path.display()))
+    }
+
+    pub fn save<T: Versioned>(&self, name: &str, doc: &T) -> Result<()> {
+        let body = serde_json::to_vec_pretty(&EnvelopeOut {
+            version: T::VERSION,
+            data: doc,
+        })?;
+        write_atomic(&self.path(name), &body)
+    }
+
+    /// Loads `name` (the default when there is none), lets `f` change it and saves it, all
+    /// under the document's lock.
+    pub fn update<T, R>(&self, name: &str, f: impl FnOnce(&mut T) -> Result<R>) -> Result<R>
+    where
+        T: Versioned + Default,
+    {
+        with_lock(&self.path(name), || {
+            let mut doc = self.load::<T>(name)?.unwrap_or_default();
+            let out = f(&mut doc)?;
+            self.save(name, &doc)?;
+            Ok(out)
+        })
+    }
+}
+
+fn decode<T: Versioned>(raw: &[u8]) -> Result<Option<T>> {
+    let value: Value = serde_json::from_slice(raw)?;
+    let (version, data) = match serde_json::from_value::<EnvelopeIn>(value.clone()) {
+        Ok(env) => (env.version, env.data),
+        Err(_) => (0, value),
+    };
+    if version > T::VERSION {
+        bail!(
+            "written by a newer pvtools (schema version {version}, this one reads up to {})",
+            T::VERSION
+        );
+    }
+    let data = if version < T::VERSION {
+        T::migrate(version, data)?
+    } else {
+        data
+    };
+    Ok(Some(serde_json::from_value(data)?))
+}
+
+/// Replaces `path` with `bytes` through a synced temp file in the same directory and a rename.
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    let dir = path
+        .parent()
+        .filter(|d| !d.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)
+        .with_context(|| format!("create temp file in {}", dir.display()))?;
+    tmp.write_all(bytes)
+        .and_then(|_| tmp.as_file().sync_all())
+        .with_context(|| format!("write {}", tmp.path().display()))?;
+    tmp.persist(path)
+        .with_context(|| format!("rename to {}", path.display()))?;
+    Ok(())
+}
+
+/// Runs `f` holding an exclusive lock on `<path>.lock`, waiting for other holders. The file
+/// itself cannot carry the lock: [`write_atomic`] replaces it.
+pub fn with_lock<R>(path: &Path, f: impl FnOnce() -> Result<R>) -> Result<R> {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".lock");
+    let lock_path = PathBuf::from(name);
+    if let Some(dir) = lock_path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+    }
+    let lock = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+        .with_context(|| format!("open {}", lock_path.display()))?;
+    lock.lock_exclusive()
+        .with_context(|| format!("flock {}", lock_path.display()))?;
+    let out = f();
+    let _ = FileExt::unlock(&lock);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::Arc, thread};
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// Version 1 was a bare map of names to counts; version 2 wraps it.
+    #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+    struct Counts {
+        counts: BTreeMap<String, u32>,
+    }
+
+    impl Versioned for Counts {
+        const VERSION: u32 = 2;
+
+        fn migrate(version: u32, data: Value) -> Result<Value> {
+            match version {
+                0 | 1 => Ok(serde_json::json!({ "counts": data })),
+                v => bail!("unknown version {v}"),
+            }
+        }
+    }
+
+    #[test]
+    fn migrates_old_documents_and_serializes_updates() {
+        let tmp = TempDir::new().unwrap();
+        let store = StateStore::new(&tmp.path().join("state"));
+        assert_eq!(store.load::<Counts>("counts.json").unwrap(), None);
+
+        fs::create_dir_all(tmp.path().join("state")).unwrap();
+        fs::write(store.path("counts.json"), r#"{"a": 1}"#).unwrap();
+        let old = store.load::<Counts>("counts.json").unwrap().unwrap();
+        assert_eq!(old.counts["a"], 1);
+
+        let store = Arc::new(store);
+        let workers: Vec<_> = (0..8)
+            .map(|_| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    store
+                        .update("counts.json", |c: &mut Counts| {
+                            *c.counts.entry("a".to_string()).or_default() += 1;
+                            Ok(())
+                        })
+                        .unwrap()
+                })
+            })
+            .collect();
+        for w in workers {
+            w.join().unwrap();
+        }
+        let saved: Value =
+            serde_json::from_slice(&fs::read(store.path("counts.json")).unwrap()).unwrap();
+        assert_eq!(saved["version"], 2);
+        assert_eq!(saved["data"]["counts"]["a"], 9);
+
+        fs::write(
+            store.path("counts.json"),
+            r#"{"version": 3, "data": {"counts": {}}}"#,
+        )
+        .unwrap();
+        let err = store.load::<Counts>("counts.json").unwrap_err();
+        assert!(format!("{err:#}").contains("newer pvtools"), "{err:#}");
+    }
+}
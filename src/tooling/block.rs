@@ -1,6 +1,11 @@
 use std::{
+    ffi::CString,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
     path::Path,
-    sync::Arc,
+    sync::{Arc, OnceLock},
     time::{Duration, Instant},
 };
 
@@ -12,7 +17,14 @@ use crate::utils::{
     process::{CmdSpec, Pipeline, Runner, StdioSpec},
 };
 
-pub const REQ_BINS: &[&str] = &["udevadm"];
+/// udevadm is used when present; without it device nodes are waited for directly.
+pub const REQ_BINS: &[&str] = &["test"];
+
+const UDEVADM_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_WAIT: Duration = Duration::from_secs(5);
+/// Longest sleep between checks while waiting on inotify, which may miss a directory that
+/// appears after the watch was set.
+const MAX_WATCH_SLICE: Duration = Duration::from_millis(500);
 
 pub trait BlockPort: Send + Sync {
     fn wait_for_block(&self, dev: &Path) -> Result<()>;
@@ -23,46 +35,137 @@ type DynRunner = dyn Runner + Send + Sync;
 
 pub struct BlockCli {
     runner: Arc<DynRunner>,
+    /// Commands run on another host, so paths cannot be checked or watched from here.
+    remote: bool,
+    timeout: Duration,
+    /// Reload the udev rules and wait once more when a node did not appear.
+    reload: bool,
+    udevadm: OnceLock<bool>,
 }
 
 impl BlockCli {
-    pub fn new(runner: Arc<DynRunner>) -> Self {
-        Self { runner }
+    pub fn new(
+        runner: Arc<DynRunner>,
+        remote: bool,
+        timeout: Option<Duration>,
+        reload: bool,
+    ) -> Self {
+        Self {
+            runner,
+            remote,
+            timeout: timeout.unwrap_or(DEFAULT_WAIT),
+            reload,
+            udevadm: OnceLock::new(),
+        }
     }
 
     #[inline]
     fn udev_trigger_cmd(&self) -> CmdSpec {
         CmdSpec::new("udevadm")
             .args(["trigger", "--subsystem-match=block", "--action=add"])
+            .with_timeout(UDEVADM_TIMEOUT)
             .stdout(StdioSpec::Null)
             .stderr(StdioSpec::Null)
     }
 
+    /// Whether udevadm is installed where commands run; minimal container images lack it.
+    fn has_udevadm(&self) -> bool {
+        *self.udevadm.get_or_init(|| {
+            let cmd = CmdSpec::new("sh")
+                .args(["-c", "command -v udevadm"])
+                .stdout(StdioSpec::Null)
+                .stderr(StdioSpec::Null);
+            let found = self.runner.run(&Pipeline::new().cmd(cmd)).is_ok();
+            if !found {
+                tracing::debug!("[wait] no udevadm, waiting for device nodes directly");
+            }
+            found
+        })
+    }
+
+    /// Checked through the runner when commands run on another host.
+    fn exists(&self, dev: &Path) -> bool {
+        if !self.remote {
+            return dev.exists();
+        }
+        let cmd = CmdSpec::new("test")
+            .args(["-e".to_string(), dev.display().to_string()])
+            .stdout(StdioSpec::Null)
+            .stderr(StdioSpec::Null);
+        self.runner.run(&Pipeline::new().cmd(cmd)).is_ok()
+    }
+
     #[inline]
-    fn udev_settle_cmd(&self) -> CmdSpec {
+    fn udev_reload_cmd(&self) -> CmdSpec {
         CmdSpec::new("udevadm")
-            .arg("settle")
+            .args(["control", "--reload"])
+            .with_timeout(UDEVADM_TIMEOUT)
             .stdout(StdioSpec::Null)
             .stderr(StdioSpec::Null)
     }
-}
 
-impl BlockPort for BlockCli {
-    fn wait_for_block(&self, dev: &Path) -> Result<()> {
-        self.wait_for_block_with(dev, Duration::from_secs(5), Duration::from_millis(100))
-    }
+    /// Logs what may tell why `dev` did not show up: the zvol's volmode or the LV's state, the
+    /// kernel log and the directory the node belongs in. Each check may fail on its own.
+    fn log_diagnostics(&self, dev: &Path) {
+        let path = dev.to_string_lossy();
+        let mut checks: Vec<(String, CmdSpec)> = Vec::new();
+        if let Some(ds) = path.strip_prefix("/dev/zvol/") {
+            checks.push((
+                format!("zfs properties of {ds}"),
+                CmdSpec::new("zfs").args([
+                    "get",
+                    "-H",
+                    "-o",
+                    "property,value",
+                    "volmode,snapdev,readonly",
+                    ds,
+                ]),
+            ));
+        } else if let Some(lv) = lvm_name(&path) {
+            checks.push((
+                format!("state of LV {lv}"),
+                CmdSpec::new("lvs").args(["--noheadings", "-o", "lv_name,lv_attr,lv_active", lv]),
+            ));
+        }
+        checks.push((
+            "kernel log".to_string(),
+            CmdSpec::new("sh").args(["-c", "dmesg | tail -n 20"]),
+        ));
+        if let Some(dir) = dev.parent() {
+            checks.push((
+                format!("contents of {}", dir.display()),
+                CmdSpec::new("ls").args(["-l".to_string(), dir.display().to_string()]),
+            ));
+        }
 
-    fn wait_for_block_with(&self, dev: &Path, timeout: Duration, delay: Duration) -> Result<()> {
-        if exec_policy::is_dry_run() {
-            tracing::info!("[wait] DRY-RUN: skip waiting for {}", dev.display());
-            return Ok(());
+        tracing::warn!(
+            "[wait] {} did not appear; diagnostics follow",
+            dev.display()
+        );
+        for (what, cmd) in checks {
+            let cmd = cmd
+                .stdout(StdioSpec::Pipe)
+                .stderr(StdioSpec::Pipe)
+                .with_timeout(UDEVADM_TIMEOUT);
+            match self.runner.run_capture(&Pipeline::new().cmd(cmd)) {
+                Ok(out) => tracing::warn!("[wait] {what}:\n{}", out.trim_end()),
+                Err(e) => tracing::warn!("[wait] {what}: {e:#}"),
+            }
         }
+    }
 
+    fn wait_once(&self, dev: &Path, timeout: Duration, delay: Duration) -> Result<()> {
         let start = Instant::now();
         let mut warned = false;
+        let udev = self.has_udevadm();
+        let watch = if udev || self.remote {
+            None
+        } else {
+            DirWatch::new()
+        };
 
         while start.elapsed() < timeout {
-            if dev.exists() {
+            if self.exists(dev) {
                 return Ok(());
             }
             if start.elapsed() > Duration::from_secs(1) && !warned {
@@ -70,16 +173,176 @@ impl BlockPort for BlockCli {
                 warned = true;
             }
 
+            if udev {
+                let _ = self
+                    .runner
+                    .run(&Pipeline::new().cmd(self.udev_trigger_cmd()));
+                let _ = self
+                    .runner
+                    .run(&Pipeline::new().cmd(self.udev_settle_cmd()));
+            }
+            match &watch {
+                Some(w) => {
+                    let left = timeout.saturating_sub(start.elapsed());
+                    w.wait(dev, left.min(MAX_WATCH_SLICE));
+                }
+                None => std::thread::sleep(delay),
+            }
+        }
+
+        Err(anyhow!("device node did not appear: {}", dev.display()))
+    }
+
+    #[inline]
+    fn udev_settle_cmd(&self) -> CmdSpec {
+        CmdSpec::new("udevadm")
+            .arg("settle")
+            .with_timeout(UDEVADM_TIMEOUT)
+            .stdout(StdioSpec::Null)
+            .stderr(StdioSpec::Null)
+    }
+}
+
+impl BlockPort for BlockCli {
+    fn wait_for_block(&self, dev: &Path) -> Result<()> {
+        self.wait_for_block_with(dev, self.timeout, Duration::from_millis(100))
+    }
+
+    fn wait_for_block_with(&self, dev: &Path, timeout: Duration, delay: Duration) -> Result<()> {
+        if exec_policy::is_dry_run() {
+            tracing::info!("[wait] DRY-RUN: skip waiting for {}", dev.display());
+            return Ok(());
+        }
+        let Err(e) = self.wait_once(dev, timeout, delay) else {
+            return Ok(());
+        };
+        self.log_diagnostics(dev);
+        if self.reload && self.has_udevadm() {
+            tracing::warn!(
+                "[wait] reloading udev rules and waiting once more for {}",
+                dev.display()
+            );
             let _ = self
                 .runner
-                .run(&Pipeline::new().cmd(self.udev_trigger_cmd()));
-            let _ = self
-                .runner
-                .run(&Pipeline::new().cmd(self.udev_settle_cmd()));
+                .run(&Pipeline::new().cmd(self.udev_reload_cmd()));
+            if self.wait_once(dev, timeout, delay).is_ok() {
+                return Ok(());
+            }
+        }
+        Err(e)
+    }
+}
 
-            std::thread::sleep(delay);
+/// `<vg>/<lv>` of an LVM device path, `/dev/<vg>/<lv>`.
+fn lvm_name(path: &str) -> Option<&str> {
+    let name = path.strip_prefix("/dev/")?;
+    let (vg, lv) = name.split_once('/')?;
+    (!vg.is_empty() && !lv.is_empty() && !lv.contains('/') && vg != "mapper").then_some(name)
+}
+
+/// inotify on the closest existing directory above a device path, so a node created by the
+/// kernel or by whatever manages /dev here is seen at once instead of after a sleep.
+struct DirWatch(OwnedFd);
+
+impl DirWatch {
+    fn new() -> Option<Self> {
+        // SAFETY: inotify_init1 takes no pointers; a non-negative result is a new fd we own.
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+        (fd >= 0).then(|| Self(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+
+    /// Returns when an entry is created in or moved into the closest existing ancestor of
+    /// `path`, or after `max`.
+    fn wait(&self, path: &Path, max: Duration) {
+        let fd = self.0.as_raw_fd();
+        if let Some(dir) = path.ancestors().skip(1).find(|d| d.is_dir())
+            && let Ok(dir) = CString::new(dir.as_os_str().as_bytes())
+        {
+            // SAFETY: dir is a valid NUL-terminated string; watching a directory twice only
+            // updates the existing watch.
+            unsafe {
+                libc::inotify_add_watch(fd, dir.as_ptr(), libc::IN_CREATE | libc::IN_MOVED_TO);
+            }
         }
+        let mut pfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ms = i32::try_from(max.as_millis()).unwrap_or(i32::MAX);
+        // SAFETY: one valid pollfd; reads go into a local buffer of the given length on a
+        // non-blocking fd, so draining stops at EAGAIN.
+        unsafe {
+            libc::poll(&mut pfd, 1, ms);
+            let mut buf = [0u8; 4096];
+            while libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) > 0 {}
+        }
+    }
+}
 
-        Err(anyhow!("device node did not appear: {}", dev.display()))
+#[cfg(test)]
+mod tests {
+    use std::{fs, thread};
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::utils::process::ScriptedRunner;
+
+    #[test]
+    fn waits_for_a_node_without_udevadm() {
+        let runner = Arc::new(ScriptedRunner::new().fail("command -v udevadm", "not found"));
+        let block = BlockCli::new(runner.clone(), false, Some(Duration::from_secs(5)), false);
+        let tmp = TempDir::new().unwrap();
+        let dev = tmp.path().join("zvol/tank/vm-1");
+
+        let created = dev.clone();
+        let maker = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            fs::create_dir_all(created.parent().unwrap()).unwrap();
+            fs::write(&created, "").unwrap();
+        });
+        block.wait_for_block(&dev).unwrap();
+        maker.join().unwrap();
+        assert_eq!(runner.calls(), ["sh -c 'command -v udevadm'"]);
+
+        let missing = tmp.path().join("zvol/tank/vm-2");
+        let err = block
+            .wait_for_block_with(&missing, Duration::from_millis(200), Duration::ZERO)
+            .unwrap_err();
+        assert!(err.to_string().contains("did not appear"), "{err}");
+    }
+
+    #[test]
+    fn timeout_logs_diagnostics_and_retries_after_udev_reload() {
+        let runner = Arc::new(ScriptedRunner::new().fail("test -e", "missing"));
+        let dev = Path::new("/dev/zvol/tank/vm-1-pvtools-1");
+        let diagnostics = [
+            "zfs get -H -o property,value volmode,snapdev,readonly tank/vm-1-pvtools-1",
+            "sh -c 'dmesg | tail -n 20'",
+            "ls -l /dev/zvol/tank",
+        ];
+
+        let block = BlockCli::new(runner.clone(), true, None, false);
+        assert!(
+            block
+                .wait_for_block_with(dev, Duration::ZERO, Duration::ZERO)
+                .is_err()
+        );
+        let mut want = vec!["sh -c 'command -v udevadm'"];
+        want.extend(diagnostics);
+        assert_eq!(runner.calls(), want);
+
+        let runner = Arc::new(ScriptedRunner::new().fail("test -e", "missing"));
+        let block = BlockCli::new(runner.clone(), true, None, true);
+        assert!(
+            block
+                .wait_for_block_with(dev, Duration::ZERO, Duration::ZERO)
+                .is_err()
+        );
+        want.push("udevadm control --reload");
+        assert_eq!(runner.calls(), want);
+        assert_eq!(lvm_name("/dev/pve/vm-1-snap"), Some("pve/vm-1-snap"));
+        assert_eq!(lvm_name("/dev/mapper/pve-data"), None);
     }
 }
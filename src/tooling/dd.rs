@@ -1,55 +1,86 @@
-use std::path::Path;
+use std::{path::Path, sync::Arc};
 
-use crate::utils::process::CmdSpec;
+use anyhow::{Context, Result};
 
-pub const REQ_BINS: &[&str] = &["dd"];
+use super::writer::{WriteOpts, WriterPort};
+use crate::utils::process::{CmdSpec, Pipeline, Runner, StdioSpec};
 
-#[derive(Debug, Clone)]
-pub struct DdOpts {
-    pub bs: Option<&'static str>,
-    pub conv_notrunc: bool,
-    pub oflag_direct: bool,
-    pub status_progress: bool,
+/// Only needed on ssh hosts: locally, archives are written by [`super::writer::NativeWriter`].
+pub const REQ_BINS: &[&str] = &["dd", "cmp"];
+
+/// Pipes archive streams into dd/cmp next to the client, for targets on another host.
+pub struct DdCli {
+    runner: Arc<dyn Runner + Send + Sync>,
 }
 
-impl Default for DdOpts {
-    fn default() -> Self {
-        Self {
-            bs: Some("4M"),
-            conv_notrunc: true,
-            oflag_direct: true,
-            status_progress: true,
-        }
+impl DdCli {
+    pub fn new(runner: Arc<dyn Runner + Send + Sync>) -> Self {
+        Self { runner }
     }
 }
 
-pub trait DdPort: Send + Sync {
-    fn to_file_cmd(&self, target: &Path, opts: &DdOpts) -> CmdSpec;
+pub(crate) fn to_file_cmd(target: &Path, opts: &WriteOpts) -> CmdSpec {
+    let conv: Vec<&str> = [
+        (true, "notrunc"),
+        (opts.sparse, "sparse"),
+        (opts.fsync, "fsync"),
+    ]
+    .into_iter()
+    .filter_map(|(on, c)| on.then_some(c))
+    .collect();
+    let mut cmd = CmdSpec::new("dd")
+        .arg(format!("of={}", target.display()))
+        .arg(format!("bs={}", opts.block_size))
+        .arg(format!("conv={}", conv.join(",")));
+    if opts.direct {
+        cmd = cmd.arg("oflag=direct");
+    }
+    cmd.arg("status=progress")
 }
 
-pub struct DdCli;
+pub(super) fn compare_cmd(device: &Path, len: u64) -> CmdSpec {
+    let mut cmp = CmdSpec::new("cmp");
+    if len > 0 {
+        cmp = cmp.arg("-n").arg(len.to_string());
+    }
+    cmp.arg("-").arg(device.display().to_string())
+}
 
-impl DdCli {
-    pub fn new() -> Self {
-        Self
+impl WriterPort for DdCli {
+    fn write(&self, src: CmdSpec, target: &Path, opts: &WriteOpts) -> Result<()> {
+        self.runner
+            .run(&Pipeline::new().cmd(src).cmd(to_file_cmd(target, opts)))
+            .with_context(|| format!("write {}", target.display()))
+    }
+
+    fn discard(&self, src: CmdSpec) -> Result<()> {
+        let dd = CmdSpec::new("dd")
+            .args(["of=/dev/null", "bs=4M", "status=progress"])
+            .stdout(StdioSpec::Null);
+        self.runner.run(&Pipeline::new().cmd(src).cmd(dd))
+    }
+
+    fn compare(&self, src: CmdSpec, device: &Path, len: u64) -> Result<()> {
+        self.runner
+            .run(&Pipeline::new().cmd(src).cmd(compare_cmd(device, len)))
+            .with_context(|| format!("compare with {}", device.display()))
     }
 }
 
-impl DdPort for DdCli {
-    fn to_file_cmd(&self, target: &Path, opts: &DdOpts) -> CmdSpec {
-        let mut cmd = CmdSpec::new("dd").arg(format!("of={}", target.display()));
-        if let Some(bs) = opts.bs {
-            cmd = cmd.arg(format!("bs={}", bs));
-        }
-        if opts.conv_notrunc {
-            cmd = cmd.arg("conv=notrunc");
-        }
-        if opts.oflag_direct {
-            cmd = cmd.arg("oflag=direct");
-        }
-        if opts.status_progress {
-            cmd = cmd.arg("status=progress");
-        }
-        cmd
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dd_command_mirrors_write_opts() {
+        let opts = WriteOpts {
+            block_size: 16 << 20,
+            sparse: true,
+            ..WriteOpts::default()
+        };
+        assert_eq!(
+            to_file_cmd(Path::new("/dev/zvol/tank/vm-1-disk-0"), &opts).render(),
+            "dd of=/dev/zvol/tank/vm-1-disk-0 bs=16777216 conv=notrunc,sparse,fsync oflag=direct status=progress"
+        );
     }
 }
@@ -1,10 +1,13 @@
-use std::{path::Path, sync::Arc};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, anyhow};
 
 use crate::utils::process::{CmdSpec, Pipeline, Runner, StdioSpec};
 
-pub const REQ_BINS: &[&str] = &["mkdir", "truncate"];
+pub const REQ_BINS: &[&str] = &["mkdir", "truncate", "sh", "rm", "cp", "df"];
 
 type DynRunner = dyn Runner + Send + Sync;
 
@@ -12,6 +15,12 @@ pub trait FsPort: Send + Sync {
     fn ensure_dir(&self, dir: &Path) -> Result<()>;
     fn ensure_parent_dir(&self, path: &Path) -> Result<()>;
     fn create_sparse_file(&self, path: &Path, size_bytes: u64) -> Result<()>;
+    fn write_file(&self, path: &Path, contents: &str) -> Result<()>;
+    fn remove_file(&self, path: &Path) -> Result<()>;
+    /// Copies `src`, recursively and with its attributes, into the directory `dest`.
+    fn copy_into(&self, src: &Path, dest: &Path) -> Result<()>;
+    /// Bytes available to unprivileged writers on the filesystem holding `dir`.
+    fn free_bytes(&self, dir: &Path) -> Result<u64>;
 }
 
 pub struct FsCli {
@@ -67,4 +76,80 @@ impl FsPort for FsCli {
             .run(&Pipeline::new().cmd(cmd))
             .with_context(|| format!("truncate -s {} {}", size_bytes, path.display()))
     }
+
+    fn write_file(&self, path: &Path, contents: &str) -> Result<()> {
+        let cmd = CmdSpec::new("sh")
+            .args(["-c", r#"printf '%s' "$1" > "$2""#, "sh", contents])
+            .arg(path.display().to_string())
+            .stdout(StdioSpec::Null)
+            .stderr(StdioSpec::Inherit);
+        self.runner
+            .run(&Pipeline::new().cmd(cmd))
+            .with_context(|| format!("write {}", path.display()))
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        let cmd = CmdSpec::new("rm")
+            .arg("-f")
+            .arg(path.display().to_string())
+            .stdout(StdioSpec::Null)
+            .stderr(StdioSpec::Inherit);
+        self.runner
+            .run(&Pipeline::new().cmd(cmd))
+            .with_context(|| format!("rm -f {}", path.display()))
+    }
+
+    fn copy_into(&self, src: &Path, dest: &Path) -> Result<()> {
+        let cmd = CmdSpec::new("cp")
+            .args(["-a", "--"])
+            .arg(src.display().to_string())
+            .arg(format!("{}/", dest.display()))
+            .stdout(StdioSpec::Null)
+            .stderr(StdioSpec::Inherit);
+        self.runner
+            .run(&Pipeline::new().cmd(cmd))
+            .with_context(|| format!("cp -a {} {}", src.display(), dest.display()))
+    }
+
+    fn free_bytes(&self, dir: &Path) -> Result<u64> {
+        let cmd = CmdSpec::new("df")
+            .args(["-B1", "--output=avail"])
+            .arg(dir.display().to_string())
+            .stdout(StdioSpec::Pipe)
+            .stderr(StdioSpec::Null);
+        let out = self
+            .runner
+            .run_capture(&Pipeline::new().cmd(cmd))
+            .with_context(|| format!("df {}", dir.display()))?;
+        out.lines()
+            .nth(1)
+            .and_then(|l| l.trim().parse().ok())
+            .ok_or_else(|| anyhow!("unexpected df output for {}: '{out}'", dir.display()))
+    }
+}
+
+/// A file written through an [`FsPort`] (possibly on another host), removed on drop.
+pub struct PortFile {
+    fs: Arc<dyn FsPort>,
+    path: PathBuf,
+}
+
+impl PortFile {
+    pub fn write(fs: Arc<dyn FsPort>, path: PathBuf, contents: &str) -> Result<Self> {
+        fs.write_file(&path, contents)?;
+        Ok(Self { fs, path })
+    }
+
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for PortFile {
+    fn drop(&mut self) {
+        if let Err(e) = self.fs.remove_file(&self.path) {
+            tracing::warn!("{e:#}");
+        }
+    }
 }
@@ -0,0 +1,269 @@
+use std::{collections::BTreeMap, path::Path, sync::Arc};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::{
+    config::Kubernetes,
+    manifest::PvDetails,
+    utils::process::{CmdSpec, Pipeline, Runner, StdioSpec},
+};
+
+pub const REQ_BINS: &[&str] = &["kubectl"];
+
+/// A PersistentVolume and the claim bound to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PvClaim {
+    pub pv: String,
+    /// CSI volume handle; Proxmox CSI ends it with the zvol or LV name.
+    pub volume_handle: Option<String>,
+    pub namespace: String,
+    pub name: String,
+    pub details: PvDetails,
+}
+
+impl PvClaim {
+    /// Whether `disk`, a zvol or LV name, backs this PV.
+    pub fn matches_disk(&self, disk: &str) -> bool {
+        let handle_leaf = self
+            .volume_handle
+            .as_deref()
+            .and_then(|h| h.rsplit('/').next());
+        handle_leaf == Some(disk) || disk.contains(&self.pv)
+    }
+}
+
+pub trait KubePort: Send + Sync {
+    /// Every bound PV in the cluster.
+    fn claims(&self) -> Result<Vec<PvClaim>>;
+    /// Node each attached PV is attached to, by PV name.
+    fn attachments(&self) -> Result<BTreeMap<String, String>>;
+    /// `kubectl apply -f path`.
+    fn apply(&self, path: &Path) -> Result<()>;
+}
+
+pub struct KubectlCli {
+    runner: Arc<dyn Runner + Send + Sync>,
+    cfg: Kubernetes,
+}
+
+impl KubectlCli {
+    pub fn new(runner: Arc<dyn Runner + Send + Sync>, cfg: Kubernetes) -> Self {
+        Self { runner, cfg }
+    }
+}
+
+impl KubectlCli {
+    fn kubectl(&self) -> CmdSpec {
+        let mut cmd = CmdSpec::new("kubectl");
+        if let Some(path) = &self.cfg.kubeconfig {
+            cmd = cmd.arg("--kubeconfig").arg(path.display().to_string());
+        }
+        if let Some(context) = &self.cfg.context {
+            cmd = cmd.args(["--context", context]);
+        }
+        cmd
+    }
+}
+
+impl KubePort for KubectlCli {
+    fn apply(&self, path: &Path) -> Result<()> {
+        let cmd = self
+            .kubectl()
+            .args(["apply", "-f", &path.display().to_string()])
+            .stderr(StdioSpec::Inherit);
+        self.runner
+            .run(&Pipeline::new().cmd(cmd))
+            .with_context(|| format!("kubectl apply -f {}", path.display()))
+    }
+
+    fn claims(&self) -> Result<Vec<PvClaim>> {
+        let cmd = self
+            .kubectl()
+            .args(["get", "pv", "-o", "json"])
+            .stdout(StdioSpec::Pipe)
+            .stderr(StdioSpec::Pipe);
+        let out = self
+            .runner
+            .run_capture(&Pipeline::new().cmd(cmd))
+            .context("run kubectl get pv")?;
+        parse_pvs(&out)
+    }
+
+    fn attachments(&self) -> Result<BTreeMap<String, String>> {
+        let cmd = self
+            .kubectl()
+            .args(["get", "volumeattachments", "-o", "json"])
+            .stdout(StdioSpec::Pipe)
+            .stderr(StdioSpec::Pipe);
+        let out = self
+            .runner
+            .run_capture(&Pipeline::new().cmd(cmd))
+            .context("run kubectl get volumeattachments")?;
+        parse_attachments(&out)
+    }
+}
+
+#[derive(Deserialize)]
+struct PvList {
+    items: Vec<Pv>,
+}
+
+#[derive(Deserialize)]
+struct Pv {
+    metadata: PvMeta,
+    spec: PvSpec,
+}
+
+#[derive(Deserialize)]
+struct PvMeta {
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PvSpec {
+    claim_ref: Option<ClaimRef>,
+    csi: Option<Csi>,
+    #[serde(default)]
+    capacity: BTreeMap<String, String>,
+    #[serde(default)]
+    access_modes: Vec<String>,
+    storage_class_name: Option<String>,
+    volume_mode: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ClaimRef {
+    namespace: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Csi {
+    driver: String,
+    volume_handle: String,
+    fs_type: Option<String>,
+    #[serde(default)]
+    volume_attributes: BTreeMap<String, String>,
+}
+
+fn parse_pvs(raw: &str) -> Result<Vec<PvClaim>> {
+    let list: PvList = serde_json::from_str(raw).context("parse kubectl get pv json")?;
+    Ok(list
+        .items
+        .into_iter()
+        .filter_map(|pv| {
+            let mut spec = pv.spec;
+            let claim = spec.claim_ref?;
+            let details = PvDetails {
+                driver: spec.csi.as_ref().map(|c| c.driver.clone()),
+                capacity: spec.capacity.remove("storage"),
+                access_modes: spec.access_modes,
+                storage_class: spec.storage_class_name,
+                volume_mode: spec.volume_mode,
+                fs_type: spec.csi.as_mut().and_then(|c| c.fs_type.take()),
+                volume_attributes: spec
+                    .csi
+                    .as_mut()
+                    .map(|c| std::mem::take(&mut c.volume_attributes))
+                    .unwrap_or_default(),
+            };
+            Some(PvClaim {
+                pv: pv.metadata.name,
+                volume_handle: spec.csi.map(|c| c.volume_handle),
+                namespace: claim.namespace,
+                name: claim.name,
+                details,
+            })
+        })
+        .collect())
+}
+
+#[derive(Deserialize)]
+struct AttachmentList {
+    items: Vec<Attachment>,
+}
+
+#[derive(Deserialize)]
+struct Attachment {
+    spec: AttachmentSpec,
+    #[serde(default)]
+    status: AttachmentStatus,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AttachmentSpec {
+    node_name: String,
+    source: AttachmentSource,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AttachmentSource {
+    persistent_volume_name: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+struct AttachmentStatus {
+    #[serde(default)]
+    attached: bool,
+}
+
+fn parse_attachments(raw: &str) -> Result<BTreeMap<String, String>> {
+    let list: AttachmentList =
+        serde_json::from_str(raw).context("parse kubectl get volumeattachments json")?;
+    Ok(list
+        .items
+        .into_iter()
+        .filter(|a| a.status.attached)
+        .filter_map(|a| Some((a.spec.source.persistent_volume_name?, a.spec.node_name)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bound_pvs() {
+        let raw = r#"{"items": [
+            {"metadata": {"name": "pvc-0a1b"},
+             "spec": {"claimRef": {"namespace": "db", "name": "data-pg-0"},
+                      "csi": {"driver": "csi.proxmox.sinextra.dev",
+                              "volumeHandle": "pve/pve1/local-zfs/vm-9999-pvc-0a1b"}}},
+            {"metadata": {"name": "pvc-free"}, "spec": {}}
+        ]}"#;
+        let claims = parse_pvs(raw).unwrap();
+        assert_eq!(claims.len(), 1);
+        assert_eq!(
+            (claims[0].namespace.as_str(), claims[0].name.as_str()),
+            ("db", "data-pg-0")
+        );
+        assert!(claims[0].matches_disk("vm-9999-pvc-0a1b"));
+        assert_eq!(
+            claims[0].details.driver.as_deref(),
+            Some("csi.proxmox.sinextra.dev")
+        );
+        assert!(!claims[0].matches_disk("vm-9999-pvc-ffff"));
+    }
+
+    #[test]
+    fn parses_attached_pvs() {
+        let raw = r#"{"items": [
+            {"spec": {"nodeName": "worker-1", "source": {"persistentVolumeName": "pvc-0a1b"}},
+             "status": {"attached": true}},
+            {"spec": {"nodeName": "worker-2", "source": {"persistentVolumeName": "pvc-gone"}},
+             "status": {"attached": false}},
+            {"spec": {"nodeName": "worker-2", "source": {"inlineVolumeSpec": {}}},
+             "status": {"attached": true}}
+        ]}"#;
+        let attached = parse_attachments(raw).unwrap();
+        assert_eq!(
+            attached.into_iter().collect::<Vec<_>>(),
+            [("pvc-0a1b".to_string(), "worker-1".to_string())]
+        );
+    }
+}
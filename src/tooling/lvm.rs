@@ -1,11 +1,13 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use anyhow::{Context, Result};
 use serde::Deserialize;
 
 use crate::utils::process::{CmdSpec, Pipeline, Runner, StdioSpec};
 
-pub const REQ_BINS: &[&str] = &["lvs", "lvcreate", "lvchange", "lvremove"];
+pub const REQ_BINS: &[&str] = &["lvs", "vgs", "lvcreate", "lvchange", "lvremove"];
+
+const LVS_TIMEOUT: Duration = Duration::from_secs(60);
 
 #[derive(Deserialize)]
 struct LvsJson {
@@ -23,22 +25,117 @@ pub struct LvInfo {
     pub vg_name: String,
     #[serde(default)]
     pub segtype: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub origin: Option<String>,
+    /// Some process, e.g. a running VM, holds the LV's device open.
+    #[serde(default, rename = "lv_device_open", deserialize_with = "open_flag")]
+    pub open: bool,
+    #[serde(default, rename = "lv_size", deserialize_with = "byte_size")]
+    pub size: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct PoolsJson {
+    report: Vec<PoolReport>,
+}
+
+#[derive(Deserialize)]
+struct PoolReport {
+    lv: Vec<ThinPoolRow>,
+}
+
+#[derive(Deserialize)]
+struct ThinPoolRow {
+    lv_name: String,
+    #[serde(default)]
+    data_percent: String,
+    #[serde(default)]
+    metadata_percent: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThinPoolUsage {
+    pub name: String,
+    pub data_percent: f64,
+    pub metadata_percent: f64,
+}
+
+fn parse_thin_pools(json: &str) -> Result<Vec<ThinPoolUsage>> {
+    let pct = |s: &str| -> Result<f64> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Ok(0.0);
+        }
+        s.parse().with_context(|| format!("parse percent '{s}'"))
+    };
+    let json: PoolsJson = serde_json::from_str(json).context("parse lvs json")?;
+    json.report
+        .into_iter()
+        .flat_map(|r| r.lv)
+        .map(|row| {
+            Ok(ThinPoolUsage {
+                data_percent: pct(&row.data_percent)?,
+                metadata_percent: pct(&row.metadata_percent)?,
+                name: row.lv_name,
+            })
+        })
+        .collect()
+}
+
+fn empty_as_none<'de, D>(d: D) -> std::result::Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s: Option<String> = Option::deserialize(d)?;
+    Ok(s.filter(|s| !s.is_empty()))
+}
+
+/// lvs reports `lv_device_open` as `open` or an empty string.
+fn open_flag<'de, D>(d: D) -> std::result::Result<bool, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(empty_as_none(d)?.is_some_and(|s| s == "open"))
+}
+
+/// `--units b` sizes such as `4194304B`.
+fn byte_size<'de, D>(d: D) -> std::result::Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(empty_as_none(d)?.and_then(|s| s.trim_end_matches('B').parse().ok()))
 }
 
 pub trait LvmPort: Send + Sync {
     fn list_lvs(&self) -> Result<Vec<LvInfo>>;
     fn lvcreate_snapshot(&self, vg: &str, lv: &str, snap: &str) -> Result<String>;
+    fn lvcreate_cow_snapshot(&self, vg: &str, lv: &str, snap: &str, size: &str) -> Result<String>;
     fn lvchange_activate(&self, lv_fq: &str) -> Result<()>;
     fn lvremove_force(&self, lv_fq: &str) -> Result<()>;
     fn lv_name(&self, vg: &str, lv: &str) -> Result<String>;
     fn lv_uuid_short8(&self, vg: &str, lv: &str) -> Result<String>;
+    fn lv_tags(&self, lv_fq: &str) -> Result<Vec<String>>;
+    /// Removes the tags `del` from `lv_fq` and adds `add`, in one `lvchange`.
+    fn lvchange_tags(&self, lv_fq: &str, add: &[String], del: &[String]) -> Result<()>;
     fn lvcreate_thin(
         &self,
         vg: &str,
         thinpool: &str,
         name: &str,
         size_bytes: u64,
+        extra_args: &[String],
     ) -> anyhow::Result<()>;
+    fn lvcreate_linear(
+        &self,
+        vg: &str,
+        name: &str,
+        size_bytes: u64,
+        extra_args: &[String],
+    ) -> Result<()>;
+    fn vg_report(&self, vg: &str) -> Result<String>;
+    fn thinpool_usage(&self, vg: &str) -> Result<String>;
+    fn thin_pools(&self, vg: &str) -> Result<Vec<ThinPoolUsage>>;
+    fn cow_snapshot_usage(&self, vg: &str) -> Result<String>;
 }
 
 type DynRunner = dyn Runner + Send + Sync;
@@ -54,7 +151,11 @@ impl LvmCli {
 
     #[inline]
     fn lvs(&self) -> CmdSpec {
-        CmdSpec::new("lvs")
+        CmdSpec::new("lvs").with_timeout(LVS_TIMEOUT)
+    }
+    #[inline]
+    fn vgs(&self) -> CmdSpec {
+        CmdSpec::new("vgs").with_timeout(LVS_TIMEOUT)
     }
     #[inline]
     fn lvcreate(&self) -> CmdSpec {
@@ -80,7 +181,7 @@ impl LvmPort for LvmCli {
                 "--units",
                 "b",
                 "-o",
-                "lv_name,vg_name,segtype",
+                "lv_name,vg_name,segtype,origin,lv_device_open,lv_size",
             ])
             .stdout(StdioSpec::Pipe)
             .stderr(StdioSpec::Inherit);
@@ -91,16 +192,7 @@ impl LvmPort for LvmCli {
             .context("run lvs")?;
 
         let json: LvsJson = serde_json::from_str(&out).context("parse lvs json")?;
-        Ok(json
-            .report
-            .into_iter()
-            .flat_map(|r| r.lv)
-            .map(|r| LvInfo {
-                lv_name: r.lv_name,
-                vg_name: r.vg_name,
-                segtype: r.segtype,
-            })
-            .collect())
+        Ok(json.report.into_iter().flat_map(|r| r.lv).collect())
     }
 
     fn lvcreate_snapshot(&self, vg: &str, lv: &str, snap: &str) -> Result<String> {
@@ -118,6 +210,23 @@ impl LvmPort for LvmCli {
         Ok(format!("{vg}/{snap}"))
     }
 
+    fn lvcreate_cow_snapshot(&self, vg: &str, lv: &str, snap: &str, size: &str) -> Result<String> {
+        let src = format!("{vg}/{lv}");
+        // Relative sizes such as 100%ORIGIN are extents, not a byte size.
+        let flag = if size.contains('%') { "-l" } else { "-L" };
+        let cmd = self
+            .lvcreate()
+            .args(["-s", flag, size, "-n", snap, &src])
+            .stderr(StdioSpec::Inherit)
+            .stdout(StdioSpec::Inherit);
+
+        self.runner
+            .run(&Pipeline::new().cmd(cmd))
+            .with_context(|| format!("lvcreate -s {flag} {size} -n {snap} {src}"))?;
+
+        Ok(format!("{vg}/{snap}"))
+    }
+
     fn lvchange_activate(&self, lv_fq: &str) -> Result<()> {
         let cmd = self
             .lvchange()
@@ -158,6 +267,44 @@ impl LvmPort for LvmCli {
         Ok(out)
     }
 
+    fn lv_tags(&self, lv_fq: &str) -> Result<Vec<String>> {
+        let cmd = self
+            .lvs()
+            .args(["--noheadings", "-o", "lv_tags", lv_fq])
+            .stdout(StdioSpec::Pipe)
+            .stderr(StdioSpec::Null);
+
+        let out = self
+            .runner
+            .run_capture(&Pipeline::new().cmd(cmd))
+            .with_context(|| format!("lvs lv_tags for {lv_fq}"))?;
+
+        Ok(out
+            .trim()
+            .split(',')
+            .filter(|t| !t.is_empty())
+            .map(str::to_string)
+            .collect())
+    }
+
+    fn lvchange_tags(&self, lv_fq: &str, add: &[String], del: &[String]) -> Result<()> {
+        let mut cmd = self.lvchange();
+        for tag in del {
+            cmd = cmd.args(["--deltag", tag]);
+        }
+        for tag in add {
+            cmd = cmd.args(["--addtag", tag]);
+        }
+        let cmd = cmd
+            .arg(lv_fq)
+            .stderr(StdioSpec::Inherit)
+            .stdout(StdioSpec::Inherit);
+
+        self.runner
+            .run(&Pipeline::new().cmd(cmd))
+            .with_context(|| format!("lvchange tags of {lv_fq}"))
+    }
+
     fn lv_uuid_short8(&self, vg: &str, lv: &str) -> Result<String> {
         let target = format!("{vg}/{lv}");
         let cmd = self
@@ -199,11 +346,13 @@ impl LvmPort for LvmCli {
         thinpool: &str,
         name: &str,
         size_bytes: u64,
+        extra_args: &[String],
     ) -> anyhow::Result<()> {
         let src = format!("{vg}/{thinpool}");
         let cmd = self
             .lvcreate()
-            .args(["-T", &src, "-n", name, "-V", &format!("{}B", &size_bytes)])
+            .args(["-T", &src, "-n", name, "-V", &format!("{}B", size_bytes)])
+            .args(extra_args)
             .stderr(StdioSpec::Inherit)
             .stdout(StdioSpec::Inherit);
 
@@ -213,4 +362,130 @@ impl LvmPort for LvmCli {
 
         Ok(())
     }
+
+    fn lvcreate_linear(
+        &self,
+        vg: &str,
+        name: &str,
+        size_bytes: u64,
+        extra_args: &[String],
+    ) -> Result<()> {
+        let cmd = self
+            .lvcreate()
+            .args(["-L", &format!("{size_bytes}B"), "-n", name])
+            .args(extra_args)
+            .arg(vg)
+            .stderr(StdioSpec::Inherit)
+            .stdout(StdioSpec::Inherit);
+
+        self.runner
+            .run(&Pipeline::new().cmd(cmd))
+            .with_context(|| format!("lvcreate -L {size_bytes}B -n {name} {vg}"))
+    }
+
+    fn vg_report(&self, vg: &str) -> Result<String> {
+        let cmd = self
+            .vgs()
+            .args(["-o", "all", "--reportformat", "json", "--units", "b", vg])
+            .stdout(StdioSpec::Pipe)
+            .stderr(StdioSpec::Null);
+
+        self.runner
+            .run_capture(&Pipeline::new().cmd(cmd))
+            .with_context(|| format!("vgs -o all {vg}"))
+    }
+
+    fn thinpool_usage(&self, vg: &str) -> Result<String> {
+        let cmd = self
+            .lvs()
+            .args([
+                "--noheadings",
+                "-o",
+                "lv_full_name,lv_size,data_percent,metadata_percent",
+                "-S",
+                "segtype=thin-pool",
+                vg,
+            ])
+            .stdout(StdioSpec::Pipe)
+            .stderr(StdioSpec::Null);
+
+        self.runner
+            .run_capture(&Pipeline::new().cmd(cmd))
+            .map(|s| s.trim().to_string())
+            .with_context(|| format!("lvs thin-pool usage for {vg}"))
+    }
+
+    fn thin_pools(&self, vg: &str) -> Result<Vec<ThinPoolUsage>> {
+        let cmd = self
+            .lvs()
+            .args([
+                "--reportformat",
+                "json",
+                "-o",
+                "lv_name,data_percent,metadata_percent",
+                "-S",
+                "segtype=thin-pool",
+                vg,
+            ])
+            .stdout(StdioSpec::Pipe)
+            .stderr(StdioSpec::Inherit);
+
+        let out = self
+            .runner
+            .run_capture(&Pipeline::new().cmd(cmd))
+            .with_context(|| format!("lvs thin pools of {vg}"))?;
+        parse_thin_pools(&out).with_context(|| format!("lvs thin pools of {vg}"))
+    }
+
+    fn cow_snapshot_usage(&self, vg: &str) -> Result<String> {
+        let cmd = self
+            .lvs()
+            .args([
+                "--noheadings",
+                "-o",
+                "lv_full_name,lv_size,data_percent",
+                "-S",
+                "lv_attr=~^[sS]",
+                vg,
+            ])
+            .stdout(StdioSpec::Pipe)
+            .stderr(StdioSpec::Null);
+
+        self.runner
+            .run_capture(&Pipeline::new().cmd(cmd))
+            .map(|s| s.trim().to_string())
+            .with_context(|| format!("lvs snapshot usage for {vg}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_thin_pool_usage() {
+        let json = r#"{"report":[{"lv":[
+            {"lv_name":"data","data_percent":"91.20","metadata_percent":"4.05"},
+            {"lv_name":"fresh","data_percent":"","metadata_percent":""}
+        ]}]}"#;
+        let pools = parse_thin_pools(json).unwrap();
+        assert_eq!(pools.len(), 2);
+        assert_eq!(pools[0].name, "data");
+        assert_eq!(pools[0].data_percent, 91.2);
+        assert_eq!(pools[0].metadata_percent, 4.05);
+        assert_eq!(pools[1].data_percent, 0.0);
+    }
+
+    #[test]
+    fn parses_open_lvs() {
+        let json = r#"{"report":[{"lv":[
+            {"lv_name":"vm-1-disk-0","vg_name":"pve","segtype":"thin","origin":"","lv_device_open":"open","lv_size":"4194304B"},
+            {"lv_name":"vm-2-disk-0","vg_name":"pve","segtype":"thin","origin":"","lv_device_open":""}
+        ]}]}"#;
+        let lvs: LvsJson = serde_json::from_str(json).unwrap();
+        let open: Vec<bool> = lvs.report[0].lv.iter().map(|lv| lv.open).collect();
+        assert_eq!(open, [true, false]);
+        let sizes: Vec<Option<u64>> = lvs.report[0].lv.iter().map(|lv| lv.size).collect();
+        assert_eq!(sizes, [Some(4194304), None]);
+    }
 }
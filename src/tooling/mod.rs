@@ -3,24 +3,37 @@ use std::{collections::BTreeSet, sync::Arc};
 use anyhow::Result;
 
 use crate::{
-    config::Config,
-    utils::{bins::ensure_bins, process::Runner},
+    config::{Config, RestoreTarget},
+    utils::{
+        bins::{ensure_bins, ensure_remote_bins},
+        process::{ProcessRunner, Runner},
+        ssh::{self, SshRunner},
+    },
 };
 
 pub mod block;
 pub mod dd;
 pub mod fs;
+pub mod kube;
 pub mod lvm;
+pub mod mount;
 pub mod pbs;
 pub mod pvesh;
+pub mod qm;
+pub mod tls;
+pub mod writer;
 pub mod zfs;
 
 pub use block::{BlockCli, BlockPort};
-pub use dd::{DdCli, DdPort};
+pub use dd::DdCli;
 pub use fs::{FsCli, FsPort};
+pub use kube::{KubePort, KubectlCli};
 pub use lvm::{LvmCli, LvmPort};
+pub use mount::{MountCli, MountPort};
 pub use pbs::{PbsCli, PbsPort};
-pub use pvesh::{PveshCli, PveshPort};
+pub use pvesh::{CachedPvesh, ConfigStorage, PveshCli, PveshPort, StorageCache};
+pub use qm::{QmCli, QmPort};
+pub use writer::{NativeWriter, WriterPort};
 pub use zfs::{ZfsCli, ZfsPort};
 
 pub struct Toolbox {
@@ -28,42 +41,99 @@ pub struct Toolbox {
     zfs: Option<Arc<dyn ZfsPort>>,
     lvm: Option<Arc<dyn LvmPort>>,
     block: Arc<dyn BlockPort>,
-    dd: Arc<dyn DdPort>,
+    writer: Arc<dyn WriterPort>,
     pvesh: Arc<dyn PveshPort>,
     fs: Arc<dyn FsPort>,
+    kube: Option<Arc<dyn KubePort>>,
+    qm: Arc<dyn QmPort>,
+    mount: Arc<dyn MountPort>,
+    remote: bool,
 }
 
 impl Toolbox {
     pub fn new(cfg: &Config, runner: Arc<dyn Runner + Send + Sync>) -> Result<Self> {
-        ensure_bins_for_cfg(cfg)?;
+        ensure_bins(required_bins(cfg, false))?;
+        Ok(Self::build(cfg, runner, None))
+    }
 
+    /// Tools that run every command on `ssh.host`.
+    pub fn over_ssh(cfg: &Config, runner: Arc<SshRunner>) -> Result<Self> {
+        ensure_bins(ssh::REQ_BINS)?;
+        if cfg.backup.kubernetes.is_some() {
+            ensure_bins(kube::REQ_BINS)?;
+        }
+        ensure_remote_bins(runner.as_ref(), runner.host(), &required_bins(cfg, true))?;
+        let host = runner.host().to_string();
+        Ok(Self::build(cfg, runner, Some(&host)))
+    }
+
+    /// `host` is the ssh host commands run on, `None` for this one.
+    fn build(cfg: &Config, runner: Arc<dyn Runner + Send + Sync>, host: Option<&str>) -> Self {
+        let remote = host.is_some();
         let pbs_cfg = Arc::new(cfg.pbs.clone());
         let pbs: Arc<dyn PbsPort> = Arc::new(PbsCli::new(runner.clone(), pbs_cfg));
 
-        let zfs: Option<Arc<dyn ZfsPort>> = if cfg.backup.sources.zfs.is_some() {
+        let zfs: Option<Arc<dyn ZfsPort>> = if uses_zfs(cfg) {
             Some(Arc::new(ZfsCli::new(runner.clone())) as Arc<dyn ZfsPort>)
         } else {
             None
         };
-        let lvm: Option<Arc<dyn LvmPort>> = if cfg.backup.sources.lvmthin.is_some() {
+        let lvm: Option<Arc<dyn LvmPort>> = if uses_lvm(cfg) {
             Some(Arc::new(LvmCli::new(runner.clone())) as Arc<dyn LvmPort>)
         } else {
             None
         };
-        let block = Arc::new(BlockCli::new(runner.clone())) as Arc<dyn BlockPort>;
-        let dd = Arc::new(DdCli::new()) as Arc<dyn DdPort>;
-        let pvesh = Arc::new(PveshCli::new(runner.clone())) as Arc<dyn PveshPort>;
+        let block = Arc::new(BlockCli::new(
+            runner.clone(),
+            remote,
+            cfg.backup.device_timeout,
+            cfg.backup.device_udev_reload,
+        )) as Arc<dyn BlockPort>;
+        let writer: Arc<dyn WriterPort> = if remote {
+            Arc::new(DdCli::new(runner.clone()))
+        } else {
+            Arc::new(NativeWriter::new(runner.clone()))
+        };
+        let pvesh: Arc<dyn PveshPort> = if cfg.pve.enabled {
+            let cli = Arc::new(PveshCli::new(runner.clone(), cfg.pve.timeout));
+            let cached = Arc::new(CachedPvesh::new(Arc::new(StorageCache::new(
+                cli,
+                cfg.state.store(),
+                StorageCache::file_for(host),
+                cfg.pve.cache_ttl,
+            ))));
+            Arc::new(ConfigStorage::new(cfg, Some(cached)))
+        } else {
+            Arc::new(ConfigStorage::new(cfg, None))
+        };
         let fs = Arc::new(FsCli::new(runner.clone())) as Arc<dyn FsPort>;
+        let qm = Arc::new(QmCli::new(runner.clone())) as Arc<dyn QmPort>;
+        let mount = Arc::new(MountCli::new(runner.clone())) as Arc<dyn MountPort>;
+        // The kubeconfig lives here, so kubectl never goes over ssh.
+        let kube = cfg.backup.kubernetes.as_ref().map(|k| {
+            Arc::new(KubectlCli::new(Arc::new(ProcessRunner::new()), k.clone()))
+                as Arc<dyn KubePort>
+        });
 
-        Ok(Self {
+        Self {
             pbs,
             zfs,
             lvm,
             block,
-            dd,
+            writer,
             pvesh,
             fs,
-        })
+            kube,
+            qm,
+            mount,
+            remote,
+        }
+    }
+
+    /// Whether commands run on another host, so local temp files are not visible to them.
+    #[inline]
+    pub fn is_remote(&self) -> bool {
+        self.remote
     }
 
     #[inline]
@@ -83,8 +153,8 @@ impl Toolbox {
         self.block.clone()
     }
     #[inline]
-    pub fn dd(&self) -> Arc<dyn DdPort> {
-        self.dd.clone()
+    pub fn writer(&self) -> Arc<dyn WriterPort> {
+        self.writer.clone()
     }
     #[inline]
     pub fn pvesh(&self) -> Arc<dyn PveshPort> {
@@ -94,9 +164,41 @@ impl Toolbox {
     pub fn fs(&self) -> Arc<dyn FsPort> {
         self.fs.clone()
     }
+    #[inline]
+    pub fn kube(&self) -> Option<Arc<dyn KubePort>> {
+        self.kube.clone()
+    }
+    #[inline]
+    pub fn qm(&self) -> Arc<dyn QmPort> {
+        self.qm.clone()
+    }
+    #[inline]
+    pub fn mount(&self) -> Arc<dyn MountPort> {
+        self.mount.clone()
+    }
 }
 
-fn ensure_bins_for_cfg(cfg: &Config) -> Result<()> {
+fn uses_zfs(cfg: &Config) -> bool {
+    cfg.backup.sources.zfs.is_some()
+        || cfg
+            .restore
+            .targets
+            .values()
+            .any(|t| matches!(t, RestoreTarget::Zfs { .. }))
+}
+
+fn uses_lvm(cfg: &Config) -> bool {
+    let s = &cfg.backup.sources;
+    s.lvmthin.is_some()
+        || s.lvm.is_some()
+        || cfg
+            .restore
+            .targets
+            .values()
+            .any(|t| matches!(t, RestoreTarget::LvmThin { .. } | RestoreTarget::Lvm { .. }))
+}
+
+fn required_bins(cfg: &Config, remote: bool) -> Vec<&'static str> {
     let mut all: BTreeSet<&'static str> = BTreeSet::new();
 
     for b in pbs::REQ_BINS {
@@ -105,27 +207,51 @@ fn ensure_bins_for_cfg(cfg: &Config) -> Result<()> {
     for b in block::REQ_BINS {
         all.insert(b);
     }
-    if cfg.backup.sources.zfs.is_some() {
+    if uses_zfs(cfg) {
         for b in zfs::REQ_BINS {
             all.insert(b);
         }
     }
-    if cfg.backup.sources.lvmthin.is_some() {
+    if uses_lvm(cfg) {
         for b in lvm::REQ_BINS {
             all.insert(b);
         }
     }
 
-    for b in dd::REQ_BINS {
-        all.insert(b);
+    if remote {
+        for b in dd::REQ_BINS {
+            all.insert(b);
+        }
+    } else if cfg.backup.kubernetes.is_some() {
+        for b in kube::REQ_BINS {
+            all.insert(b);
+        }
     }
-    for b in pvesh::REQ_BINS {
-        all.insert(b);
+    if cfg.pve.enabled {
+        for b in pvesh::REQ_BINS {
+            all.insert(b);
+        }
     }
     for b in fs::REQ_BINS {
         all.insert(b);
     }
+    if cfg
+        .restore
+        .targets
+        .values()
+        .any(|t| matches!(t, RestoreTarget::Ssh { .. }))
+    {
+        for b in ssh::REQ_BINS {
+            all.insert(b);
+        }
+    }
+    let priority = cfg.backup.priority;
+    if priority.io.is_some() {
+        all.insert("ionice");
+    }
+    if priority.nice.is_some() {
+        all.insert("nice");
+    }
 
-    let list: Vec<&'static str> = all.into_iter().collect();
-    ensure_bins(list)
+    all.into_iter().collect()
 }
@@ -0,0 +1,76 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+
+use crate::utils::process::{CmdSpec, Pipeline, Runner, StdioSpec};
+
+const MOUNT_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub trait MountPort: Send + Sync {
+    /// Mounts the filesystem on `dev` read-only on `dir`, without replaying its journal.
+    fn mount_ro(&self, dev: &Path, dir: &Path) -> Result<()>;
+    fn umount(&self, dir: &Path) -> Result<()>;
+    /// Device mounted on `dir`, if any.
+    fn source_of(&self, dir: &Path) -> Result<Option<PathBuf>>;
+}
+
+type DynRunner = dyn Runner + Send + Sync;
+
+pub struct MountCli {
+    runner: Arc<DynRunner>,
+}
+
+impl MountCli {
+    pub fn new(runner: Arc<DynRunner>) -> Self {
+        Self { runner }
+    }
+}
+
+impl MountPort for MountCli {
+    fn mount_ro(&self, dev: &Path, dir: &Path) -> Result<()> {
+        // `noload` (ext4) and `norecovery` (xfs) keep the journal of a crash-consistent image
+        // from being replayed; plain `ro` covers filesystems that know neither option.
+        let mount = |opts: &str| {
+            let cmd = CmdSpec::new("mount")
+                .args(["-o", opts])
+                .arg(dev.display().to_string())
+                .arg(dir.display().to_string())
+                .with_timeout(MOUNT_TIMEOUT)
+                .stderr(StdioSpec::Pipe);
+            self.runner.run_capture(&Pipeline::new().cmd(cmd))
+        };
+        mount("ro,noload")
+            .or_else(|_| mount("ro,norecovery"))
+            .or_else(|_| mount("ro"))
+            .map(drop)
+            .with_context(|| format!("mount {} on {}", dev.display(), dir.display()))
+    }
+
+    fn umount(&self, dir: &Path) -> Result<()> {
+        let cmd = CmdSpec::new("umount")
+            .arg(dir.display().to_string())
+            .with_timeout(MOUNT_TIMEOUT);
+        self.runner
+            .run(&Pipeline::new().cmd(cmd))
+            .with_context(|| format!("umount {}", dir.display()))
+    }
+
+    fn source_of(&self, dir: &Path) -> Result<Option<PathBuf>> {
+        let cmd = CmdSpec::new("findmnt")
+            .args(["-n", "-o", "SOURCE", "--mountpoint"])
+            .arg(dir.display().to_string())
+            .stderr(StdioSpec::Null);
+        // findmnt exits 1 when nothing is mounted there.
+        Ok(self
+            .runner
+            .run_capture(&Pipeline::new().cmd(cmd))
+            .ok()
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .map(PathBuf::from))
+    }
+}
@@ -1,25 +1,171 @@
-use std::{path::Path, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
-use anyhow::{Context, Result};
-use serde::Deserialize;
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    config::Pbs,
+    config::{NsCreate, Pbs, Repo},
+    manifest::MANIFEST_ARCHIVE,
+    ui,
     utils::{
         exec_policy,
-        process::{CmdSpec, EnvValue, Pipeline, Runner, StdioSpec},
+        failure::Failure,
+        naming::{KNOWN_PROVIDERS, parse_archive_name},
+        process::{self, CmdSpec, EnvValue, Pipeline, Priority, Runner, StdioSpec},
+        signal,
+        time::fmt_utc,
     },
 };
 
 pub const REQ_BINS: &[&str] = &["proxmox-backup-client"];
 
-#[derive(Debug, Deserialize)]
+/// Read by proxmox-backup-client to unlock an encrypted keyfile.
+const ENCRYPTION_PASSWORD_ENV: &str = "PBS_ENCRYPTION_PASSWORD";
+
+/// Fills in the keyfile passphrases `key_passphrase_file` did not, of `[pbs]` and of every
+/// repo: from `PBS_ENCRYPTION_PASSWORD`, else by prompting if the keyfile here is encrypted,
+/// once per keyfile. Passing it explicitly also gets it to ssh hosts, which do not see this
+/// environment.
+pub fn resolve_key_passphrase(pbs: &mut Pbs) -> Result<()> {
+    let mut known = BTreeMap::new();
+    fill_key_passphrase(pbs.keyfile.as_deref(), &mut pbs.key_passphrase, &mut known)?;
+    let mut names: Vec<&String> = pbs.repos.keys().collect();
+    names.sort();
+    let names: Vec<String> = names.into_iter().cloned().collect();
+    for name in names {
+        let repo = pbs.repos.get_mut(&name).expect("name taken from repos");
+        fill_key_passphrase(
+            repo.keyfile.as_deref(),
+            &mut repo.key_passphrase,
+            &mut known,
+        )
+        .with_context(|| format!("repo '{name}'"))?;
+    }
+    Ok(())
+}
+
+fn fill_key_passphrase(
+    keyfile: Option<&Path>,
+    pass: &mut Option<String>,
+    known: &mut BTreeMap<PathBuf, String>,
+) -> Result<()> {
+    let Some(keyfile) = keyfile else {
+        return Ok(());
+    };
+    if let Some(p) = pass {
+        known
+            .entry(keyfile.to_path_buf())
+            .or_insert_with(|| p.clone());
+        return Ok(());
+    }
+    if let Some(p) = known.get(keyfile) {
+        *pass = Some(p.clone());
+        return Ok(());
+    }
+    if let Ok(p) = std::env::var(ENCRYPTION_PASSWORD_ENV) {
+        *pass = Some(p);
+        return Ok(());
+    }
+    // Keyfiles that only exist on an ssh host cannot be checked from here.
+    let Ok(raw) = std::fs::read_to_string(keyfile) else {
+        return Ok(());
+    };
+    if keyfile_is_encrypted(&raw).with_context(|| format!("parse keyfile {}", keyfile.display()))? {
+        let question = format!("Passphrase for {}:", keyfile.display());
+        let p = ui::prompt_secret(&question).with_context(|| {
+            format!(
+                "{} is encrypted; set key_passphrase_file or {ENCRYPTION_PASSWORD_ENV}",
+                keyfile.display()
+            )
+        })?;
+        known.insert(keyfile.to_path_buf(), p.clone());
+        *pass = Some(p);
+    }
+    Ok(())
+}
+
+/// PBS key configs carry their key derivation settings in `kdf`, which is null for plain keys.
+fn keyfile_is_encrypted(raw: &str) -> Result<bool> {
+    let v: serde_json::Value = serde_json::from_str(raw)?;
+    Ok(v.get("kdf").is_some_and(|k| !k.is_null()))
+}
+
+/// proxmox-backup-client reports rejected credentials only on stderr.
+const AUTH_ERRORS: &[&str] = &[
+    "authentication failed",
+    "permission check failed",
+    "401 Unauthorized",
+    "no password input mechanism",
+];
+
+/// Tags a failed PBS query whose captured stderr shows rejected credentials.
+fn classify(e: anyhow::Error) -> anyhow::Error {
+    let msg = format!("{e:#}");
+    if AUTH_ERRORS.iter().any(|m| msg.contains(m)) {
+        e.context(Failure::PbsAuth)
+    } else {
+        e
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct PbsFile {
     pub filename: String,
+    #[serde(default)]
     pub size: u64,
+    /// `none`, `encrypt` or `sign-only`; missing for files listed by older servers.
+    #[serde(
+        rename = "crypt-mode",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub crypt_mode: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileClass {
+    /// A pvtools disk image (`<provider>_<stem>_<ext>_<id>.img`).
+    Archive,
+    /// PBS bookkeeping or the pvtools manifest.
+    Metadata,
+    /// Anything else found in the snapshot, e.g. written by another client.
+    Foreign,
+}
+
+impl FileClass {
+    pub fn label(&self) -> &'static str {
+        match self {
+            FileClass::Archive => "archive",
+            FileClass::Metadata => "metadata",
+            FileClass::Foreign => "foreign",
+        }
+    }
+}
+
+const PBS_METADATA: &[&str] = &["index.json.blob", "client.log.blob"];
+
+impl PbsFile {
+    pub fn class(&self) -> FileClass {
+        let name = self.filename.as_str();
+        if PBS_METADATA.contains(&name) || name.strip_suffix(".blob") == Some(MANIFEST_ARCHIVE) {
+            return FileClass::Metadata;
+        }
+        match parse_archive_name(name) {
+            Ok((provider, ..)) if KNOWN_PROVIDERS.contains(&provider.as_str()) => {
+                FileClass::Archive
+            }
+            _ => FileClass::Foreign,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct PbsSnapshot {
     #[serde(rename = "backup-id")]
     pub backup_id: String,
@@ -28,34 +174,148 @@ pub struct PbsSnapshot {
     pub files: Vec<PbsFile>,
 }
 
+/// Chunk totals the client records in a snapshot's `index.json` when its upload finishes,
+/// across all of the snapshot's archives.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotStats {
+    /// Chunks uploaded, new or not.
+    #[serde(default)]
+    pub count: u64,
+    /// Of those, chunks the datastore already had.
+    #[serde(default)]
+    pub duplicates: u64,
+    /// Bytes of new chunks, before and after compression.
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub compressed_size: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct PbsGroup {
+    #[serde(rename = "backup-type")]
+    backup_type: String,
+    #[serde(rename = "backup-id")]
+    backup_id: String,
+    #[serde(default)]
+    owner: Option<String>,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct BackupItem<'a> {
     pub archive: &'a str,
     pub device: &'a Path,
 }
 
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BackupOpts {
+    /// Keep the original snapshot time (used when copying between repos).
+    pub backup_time: Option<u64>,
+    pub timeout: Option<Duration>,
+    /// Limit for each archive, counted from the end of the previous one.
+    pub archive_timeout: Option<Duration>,
+    pub priority: Priority,
+}
+
+/// What the client reported for one uploaded archive.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UploadStats {
+    pub archive: String,
+    /// Bytes read from the device.
+    pub size: u64,
+    /// Bytes of new chunks sent, before compression.
+    pub uploaded: u64,
+    pub compressed: u64,
+    pub secs: f64,
+}
+
 pub trait PbsPort: Send + Sync {
-    fn snapshots(&self, repo: &str, ns: Option<&str>) -> Result<Vec<PbsSnapshot>>;
-    fn ns_exists(&self, repo: &str, ns: &str) -> Result<bool>;
-    fn ns_ensure(&self, repo: &str, ns: &str) -> Result<()>;
+    fn snapshots(&self, repo: &Repo, ns: Option<&str>) -> Result<Vec<PbsSnapshot>>;
+    fn ns_exists(&self, repo: &Repo, ns: &str) -> Result<bool>;
+    fn ns_ensure(&self, repo: &Repo, ns: &str) -> Result<()>;
+
+    /// Owner of the `host/<backup_id>` group, if the group exists and is visible to the
+    /// configured credentials.
+    fn group_owner(&self, repo: &Repo, ns: Option<&str>, backup_id: &str)
+    -> Result<Option<String>>;
     fn backup(
         &self,
-        repo: &str,
+        repo: &Repo,
         ns: Option<&str>,
         backup_id: &str,
-        keyfile: Option<&Path>,
         items: &[BackupItem<'_>],
-    ) -> Result<()>;
+        opts: BackupOpts,
+    ) -> Result<Vec<UploadStats>>;
+
+    /// Command that writes `archive` of `snapshot` to its stdout.
+    fn restore_cmd(&self, repo: &Repo, ns: Option<&str>, snapshot: &str, archive: &str) -> CmdSpec;
 
-    fn restore_to(
+    fn fetch_blob(
         &self,
-        repo: &str,
+        repo: &Repo,
         ns: Option<&str>,
-        backup_id: &str,
+        snapshot: &str,
         archive: &str,
-        keyfile: Option<&Path>,
-        dd_cmd: crate::utils::process::CmdSpec,
-    ) -> Result<()>;
+    ) -> Result<String>;
+
+    /// Deduplication totals of `snapshot`, if its client recorded them.
+    fn snapshot_stats(
+        &self,
+        repo: &Repo,
+        ns: Option<&str>,
+        snapshot: &str,
+    ) -> Result<Option<SnapshotStats>>;
+
+    /// Removes `snapshot` (`host/<id>/<time>`) from the repo.
+    fn forget(&self, repo: &Repo, ns: Option<&str>, snapshot: &str) -> Result<()>;
+
+    /// Attaches `archive` of `snapshot` read-only to a loop device and returns the device.
+    fn map(&self, repo: &Repo, ns: Option<&str>, snapshot: &str, archive: &str) -> Result<PathBuf>;
+
+    /// Detaches a loop device attached by [`PbsPort::map`].
+    fn unmap(&self, device: &Path) -> Result<()>;
+}
+
+/// PBS's own manifest of a snapshot (`index.json.blob`), fetched like any other blob.
+pub const PBS_INDEX: &str = "index.json";
+
+#[derive(Deserialize)]
+struct PbsIndex {
+    files: Vec<PbsIndexFile>,
+    #[serde(default)]
+    unprotected: PbsUnprotected,
+}
+
+#[derive(Default, Deserialize)]
+struct PbsUnprotected {
+    chunk_upload_stats: Option<SnapshotStats>,
+}
+
+#[derive(Deserialize)]
+struct PbsIndexFile {
+    filename: String,
+    csum: String,
+}
+
+/// File name -> checksum from a snapshot's `index.json`. For an image archive the checksum
+/// covers its fixed index, i.e. the digests of all chunks.
+pub fn parse_index_checksums(raw: &str) -> Result<BTreeMap<String, String>> {
+    let index: PbsIndex = serde_json::from_str(raw).context("parse PBS index.json")?;
+    Ok(index
+        .files
+        .into_iter()
+        .map(|f| (f.filename, f.csum))
+        .collect())
+}
+
+/// The `unprotected.chunk_upload_stats` of a snapshot's `index.json`.
+fn parse_snapshot_stats(raw: &str) -> Result<Option<SnapshotStats>> {
+    let index: PbsIndex = serde_json::from_str(raw).context("parse PBS index.json")?;
+    Ok(index.unprotected.chunk_upload_stats)
+}
+
+pub fn snapshot_path(backup_id: &str, backup_time: u64) -> Result<String> {
+    Ok(format!("host/{backup_id}/{}", fmt_utc(backup_time)?))
 }
 
 type DynRunner = dyn Runner + Send + Sync;
@@ -70,20 +330,43 @@ impl PbsCli {
         Self { runner, pbs }
     }
 
-    fn pbs_client(&self) -> CmdSpec {
+    /// The client with the fingerprint and secrets of `repo`.
+    fn pbs_client(&self, repo: &Repo) -> CmdSpec {
         let mut cmd = CmdSpec::new("proxmox-backup-client");
-        if let Some(ref pw) = self.pbs.password {
-            cmd = cmd.env("PBS_PASSWORD", EnvValue::Secret(pw.clone()));
+        if let Some(fp) = &repo.fingerprint {
+            cmd = cmd.env("PBS_FINGERPRINT", EnvValue::Plain(fp.clone()));
+        }
+        if let Some(pw) = &repo.password {
+            cmd = cmd.env("PBS_PASSWORD", EnvValue::Secret(pw.to_string()));
+        }
+        if let Some(pass) = &repo.key_passphrase {
+            cmd = cmd.env(ENCRYPTION_PASSWORD_ENV, EnvValue::Secret(pass.to_string()));
         }
         cmd
     }
 }
 
 impl PbsPort for PbsCli {
-    fn snapshots(&self, repo: &str, ns: Option<&str>) -> Result<Vec<PbsSnapshot>> {
-        let mut cmd =
-            self.pbs_client()
-                .args(["snapshots", "--repository", repo, "--output-format", "json"]);
+    fn snapshots(&self, repo: &Repo, ns: Option<&str>) -> Result<Vec<PbsSnapshot>> {
+        if let Some(ns) = ns
+            && self.pbs.create_ns == NsCreate::RequireExisting
+            && !self.ns_exists(repo, ns)?
+        {
+            bail!(
+                "namespace '{ns}' does not exist on {repo} ([pbs] create_ns = \"{}\")",
+                self.pbs.create_ns
+            );
+        }
+        let mut cmd = self
+            .pbs_client(repo)
+            .args([
+                "snapshots",
+                "--repository",
+                &repo.url,
+                "--output-format",
+                "json",
+            ])
+            .stderr(StdioSpec::Pipe);
         if let Some(ns) = ns {
             cmd = cmd.args(["--ns", ns]);
         }
@@ -91,6 +374,7 @@ impl PbsPort for PbsCli {
         let out = self
             .runner
             .run_capture(&Pipeline::new().cmd(cmd))
+            .map_err(classify)
             .context("run proxmox-backup-client snapshots")?;
 
         let snaps: Vec<PbsSnapshot> =
@@ -98,31 +382,39 @@ impl PbsPort for PbsCli {
         Ok(snaps)
     }
 
-    fn ns_exists(&self, repo: &str, ns: &str) -> Result<bool> {
+    fn ns_exists(&self, repo: &Repo, ns: &str) -> Result<bool> {
         let cmd = self
-            .pbs_client()
-            .args(["namespace", "list", "--repository", repo])
+            .pbs_client(repo)
+            .args(["namespace", "list", "--repository", &repo.url])
             .stdout(StdioSpec::Pipe)
-            .stderr(StdioSpec::Null);
+            .stderr(StdioSpec::Pipe);
         let out = self
             .runner
             .run_capture(&Pipeline::new().cmd(cmd))
+            .map_err(classify)
             .with_context(|| format!("pbs namespace list on {repo}"))?;
         Ok(out
             .lines()
             .any(|line| line.split_whitespace().any(|tok| tok == ns)))
     }
 
-    fn ns_ensure(&self, repo: &str, ns: &str) -> Result<()> {
+    fn ns_ensure(&self, repo: &Repo, ns: &str) -> Result<()> {
         if self.ns_exists(repo, ns)? {
             tracing::debug!("namespace '{ns}' exists on {repo}");
             return Ok(());
         }
+        if self.pbs.create_ns != NsCreate::Auto {
+            bail!(
+                "namespace '{ns}' does not exist on {repo} and [pbs] create_ns = \"{}\" does not \
+                 allow creating it; create it on the PBS server first",
+                self.pbs.create_ns
+            );
+        }
 
         tracing::info!("namespace '{ns}' not found on {repo}, creating…");
         let cmd = self
-            .pbs_client()
-            .args(["namespace", "create", ns, "--repository", repo])
+            .pbs_client(repo)
+            .args(["namespace", "create", ns, "--repository", &repo.url])
             .stdout(StdioSpec::Inherit)
             .stderr(StdioSpec::Inherit);
         self.runner
@@ -137,23 +429,49 @@ impl PbsPort for PbsCli {
         if self.ns_exists(repo, ns)? {
             Ok(())
         } else {
-            anyhow::bail!("namespace '{ns}' still not visible after create on {repo}")
+            bail!("namespace '{ns}' still not visible after create on {repo}")
         }
     }
 
+    fn group_owner(
+        &self,
+        repo: &Repo,
+        ns: Option<&str>,
+        backup_id: &str,
+    ) -> Result<Option<String>> {
+        let mut cmd = self
+            .pbs_client(repo)
+            .args(["list", "--repository", &repo.url, "--output-format", "json"])
+            .stderr(StdioSpec::Pipe);
+        if let Some(ns) = ns {
+            cmd = cmd.args(["--ns", ns]);
+        }
+        let out = self
+            .runner
+            .run_capture(&Pipeline::new().cmd(cmd))
+            .map_err(classify)
+            .context("run proxmox-backup-client list")?;
+        let groups: Vec<PbsGroup> = serde_json::from_str(&out).context("parse PBS groups json")?;
+        Ok(groups
+            .into_iter()
+            .find(|g| g.backup_type == "host" && g.backup_id == backup_id)
+            .and_then(|g| g.owner))
+    }
+
     fn backup(
         &self,
-        repo: &str,
+        repo: &Repo,
         ns: Option<&str>,
         backup_id: &str,
-        keyfile: Option<&Path>,
         items: &[BackupItem<'_>],
-    ) -> Result<()> {
+        opts: BackupOpts,
+    ) -> Result<Vec<UploadStats>> {
+        // Both streams go through us: newer clients log the per-archive summary to stderr.
         let mut cmd = self
-            .pbs_client()
+            .pbs_client(repo)
             .arg("backup")
-            .stdout(StdioSpec::Inherit)
-            .stderr(StdioSpec::Inherit);
+            .stdout(StdioSpec::Pipe)
+            .stderr(StdioSpec::Stdout);
 
         for it in items {
             let pair = format!("{}:{}", it.archive, it.device.display());
@@ -161,47 +479,459 @@ impl PbsPort for PbsCli {
         }
 
         cmd = cmd.arg("--backup-id").arg(backup_id);
+        if let Some(t) = opts.backup_time {
+            cmd = cmd.arg("--backup-time").arg(t.to_string());
+        }
         if let Some(ns) = ns {
             cmd = cmd.arg("--ns").arg(ns);
         }
-        cmd = cmd.arg("--repository").arg(repo);
+        cmd = cmd.arg("--repository").arg(&repo.url);
 
-        if let Some(kf) = keyfile {
+        if let Some(kf) = &repo.keyfile {
             cmd = cmd.arg("--keyfile").arg(kf.display().to_string());
         }
+        if let Some(t) = opts.timeout {
+            cmd = cmd.with_timeout(t);
+        }
+        if let Some(t) = opts.archive_timeout {
+            cmd = cmd.with_step_timeout(t);
+        }
+        cmd = cmd.priority(opts.priority);
 
+        let mut stats = Vec::new();
         self.runner
-            .run(&Pipeline::new().cmd(cmd))
-            .context("run proxmox-backup-client backup")
+            .run_stream(&Pipeline::new().cmd(cmd), &mut |r| {
+                let mut r = BufReader::new(r);
+                let mut line = Vec::new();
+                while r
+                    .read_until(b'\n', &mut line)
+                    .context("read client output")?
+                    > 0
+                {
+                    let _ = io::stdout().write_all(&line);
+                    if let Some(s) = parse_upload_stats(&String::from_utf8_lossy(&line)) {
+                        stats.push(s);
+                        process::step_done();
+                    }
+                    line.clear();
+                    signal::check()?;
+                }
+                Ok(())
+            })
+            .context("run proxmox-backup-client backup")?;
+        Ok(stats)
     }
 
-    fn restore_to(
+    fn restore_cmd(&self, repo: &Repo, ns: Option<&str>, snapshot: &str, archive: &str) -> CmdSpec {
+        let mut cmd = self
+            .pbs_client(repo)
+            .args(["restore", snapshot, archive, "-"])
+            .stdout(StdioSpec::Pipe)
+            .stderr(StdioSpec::Inherit);
+        if let Some(ns) = ns {
+            cmd = cmd.arg("--ns").arg(ns);
+        }
+        cmd = cmd.arg("--repository").arg(&repo.url);
+        if let Some(kf) = &repo.keyfile {
+            cmd = cmd.arg("--keyfile").arg(kf.display().to_string());
+        }
+        cmd
+    }
+
+    fn fetch_blob(
         &self,
-        repo: &str,
+        repo: &Repo,
         ns: Option<&str>,
-        backup_id: &str,
+        snapshot: &str,
         archive: &str,
-        keyfile: Option<&Path>,
-        dd_cmd: crate::utils::process::CmdSpec,
-    ) -> Result<()> {
-        let mut pbs = self
-            .pbs_client()
-            .arg("restore")
-            .arg(format!("host/{}", backup_id))
-            .arg(archive)
-            .arg("-");
+    ) -> Result<String> {
+        let cmd = self.restore_cmd(repo, ns, snapshot, archive);
+        self.runner
+            .run_capture(&Pipeline::new().cmd(cmd))
+            .with_context(|| format!("fetch {archive} from {snapshot} on repo {repo}"))
+    }
 
+    fn snapshot_stats(
+        &self,
+        repo: &Repo,
+        ns: Option<&str>,
+        snapshot: &str,
+    ) -> Result<Option<SnapshotStats>> {
+        let raw = self.fetch_blob(repo, ns, snapshot, PBS_INDEX)?;
+        parse_snapshot_stats(&raw)
+    }
+
+    fn forget(&self, repo: &Repo, ns: Option<&str>, snapshot: &str) -> Result<()> {
+        let mut cmd =
+            self.pbs_client(repo)
+                .args(["snapshot", "forget", snapshot, "--repository", &repo.url]);
         if let Some(ns) = ns {
-            pbs = pbs.arg("--ns").arg(ns);
+            cmd = cmd.args(["--ns", ns]);
         }
-        pbs = pbs.arg("--repository").arg(repo);
+        self.runner
+            .run(&Pipeline::new().cmd(cmd))
+            .map_err(classify)
+            .with_context(|| format!("forget {snapshot} on repo {repo}"))
+    }
 
-        if let Some(kf) = keyfile {
-            pbs = pbs.arg("--keyfile").arg(kf.display().to_string());
+    fn map(&self, repo: &Repo, ns: Option<&str>, snapshot: &str, archive: &str) -> Result<PathBuf> {
+        if let Some(dev) = self.mapped_on(snapshot, archive)? {
+            bail!(
+                "{archive} of {snapshot} is already mapped on {}",
+                dev.display()
+            );
         }
+        let mut cmd = self.pbs_client(repo).args(["map", snapshot, archive]);
+        if let Some(ns) = ns {
+            cmd = cmd.arg("--ns").arg(ns);
+        }
+        cmd = cmd.arg("--repository").arg(&repo.url);
+        if let Some(kf) = &repo.keyfile {
+            cmd = cmd.arg("--keyfile").arg(kf.display().to_string());
+        }
+        self.runner
+            .run(&Pipeline::new().cmd(cmd))
+            .map_err(classify)
+            .with_context(|| format!("map {archive} of {snapshot}"))?;
+        self.mapped_on(snapshot, archive)?
+            .with_context(|| format!("{archive} of {snapshot} does not show up as mapped"))
+    }
 
+    fn unmap(&self, device: &Path) -> Result<()> {
+        let cmd = CmdSpec::new("proxmox-backup-client")
+            .arg("unmap")
+            .arg(device.display().to_string());
         self.runner
-            .run(&Pipeline::new().cmd(pbs).cmd(dd_cmd))
-            .with_context(|| format!("restore pipeline for {archive} on repo {repo}"))
+            .run(&Pipeline::new().cmd(cmd))
+            .with_context(|| format!("unmap {}", device.display()))
+    }
+}
+
+impl PbsCli {
+    /// Loop device a mapping of `archive` of `snapshot` is attached to, as `unmap` without
+    /// arguments lists them.
+    fn mapped_on(&self, snapshot: &str, archive: &str) -> Result<Option<PathBuf>> {
+        let cmd = CmdSpec::new("proxmox-backup-client")
+            .arg("unmap")
+            .stderr(StdioSpec::Pipe);
+        let out = self
+            .runner
+            .run_capture(&Pipeline::new().cmd(cmd))
+            .context("list mapped archives")?;
+        Ok(find_mapping(&out, snapshot, archive))
+    }
+}
+
+/// Device of the `<device>: <name>` line whose name refers to `archive` of `snapshot`.
+fn find_mapping(listing: &str, snapshot: &str, archive: &str) -> Option<PathBuf> {
+    let archive = archive.strip_suffix(".fidx").unwrap_or(archive);
+    listing.lines().find_map(|line| {
+        let (dev, name) = line.split_once(':')?;
+        let name = name.trim();
+        let name = name.strip_suffix(".fidx").unwrap_or(name);
+        (dev.starts_with("/dev/") && name.contains(snapshot) && name.ends_with(archive))
+            .then(|| PathBuf::from(dev))
+    })
+}
+
+/// Reads the client's `<archive>: had to backup <new> of <size> (compressed <c>) in <t> s` line.
+fn parse_upload_stats(line: &str) -> Option<UploadStats> {
+    let (archive, rest) = line.trim().split_once(": had to backup ")?;
+    let (uploaded, rest) = rest.split_once(" of ")?;
+    let (size, rest) = rest.split_once(" (compressed ")?;
+    let (compressed, rest) = rest.split_once(") in ")?;
+    let secs = rest.split('s').next()?.trim().parse().ok()?;
+    Some(UploadStats {
+        archive: archive.rsplit(' ').next()?.to_string(),
+        size: parse_human_bytes(size)?,
+        uploaded: parse_human_bytes(uploaded)?,
+        compressed: parse_human_bytes(compressed)?,
+        secs,
+    })
+}
+
+/// `1.5 GiB`, `512 B` or `3 MB`, as the client prints sizes.
+fn parse_human_bytes(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let value: f64 = s[..split].parse().ok()?;
+    let unit = match s[split..].trim() {
+        "B" | "" => 1u64,
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        "TiB" => 1 << 40,
+        "PiB" => 1 << 50,
+        "KB" | "kB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        "TB" => 1_000_000_000_000,
+        "PB" => 1_000_000_000_000_000,
+        _ => return None,
+    };
+    Some((value * unit as f64).round() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::process::{ProcessRunner, ScriptedRunner};
+
+    #[test]
+    fn parses_client_upload_summary() {
+        let s = parse_upload_stats(
+            "zfs_vm-100-disk-0_raw_abcd1234.img: had to backup 1.5 GiB of 10 GiB \
+             (compressed 512 MiB) in 12.34 s (average 124.5 MiB/s)\n",
+        )
+        .unwrap();
+        assert_eq!(s.archive, "zfs_vm-100-disk-0_raw_abcd1234.img");
+        assert_eq!((s.uploaded, s.size), (3 << 29, 10 << 30));
+        assert_eq!(s.compressed, 512 << 20);
+        assert!((s.secs - 12.34).abs() < 1e-9);
+
+        let old = parse_upload_stats("a.img: had to backup 0 B of 4 MB (compressed 0 B) in 0.51s");
+        assert_eq!(old.map(|s| s.size), Some(4_000_000));
+        assert!(parse_upload_stats("Upload image '/dev/zd0' to 'pbs' as a.img.fidx").is_none());
+    }
+
+    fn class_of(name: &str) -> FileClass {
+        PbsFile {
+            filename: name.to_string(),
+            size: 0,
+            crypt_mode: None,
+        }
+        .class()
+    }
+
+    fn repo(url: &str) -> Repo {
+        Repo {
+            url: url.to_string(),
+            ns: None,
+            fingerprint: None,
+            trusted_ca: false,
+            keyfile: None,
+            password: None,
+            key_passphrase: None,
+        }
+    }
+
+    #[test]
+    fn passes_repo_fingerprint_to_client() {
+        let pbs = Pbs {
+            repos: Default::default(),
+            keyfile: None,
+            password: Some("global-token".to_string()),
+            key_passphrase: None,
+            create_ns: Default::default(),
+            ns: None,
+            backup_id: "id".to_string(),
+        };
+        let cli = PbsCli::new(Arc::new(ProcessRunner::new()), Arc::new(pbs));
+        let nas = Repo {
+            fingerprint: Some("aa:bb".to_string()),
+            password: Some("nas-token".to_string()),
+            ..repo("pbs@pbs!t@nas:store")
+        };
+        // A second alias on the same URL keeps its own credentials.
+        let tenant = Repo {
+            ns: Some("tenant".to_string()),
+            password: Some("tenant-token".to_string()),
+            ..repo("pbs@pbs!t@nas:store")
+        };
+        assert_eq!(
+            cli.pbs_client(&nas).render(),
+            "PBS_FINGERPRINT=aa:bb PBS_PASSWORD=<redacted> proxmox-backup-client "
+        );
+        assert!(
+            cli.pbs_client(&nas)
+                .to_shell(false, false)
+                .contains("PBS_PASSWORD=nas-token")
+        );
+        assert!(
+            cli.pbs_client(&tenant)
+                .to_shell(false, false)
+                .starts_with("PBS_PASSWORD=tenant-token proxmox-backup-client")
+        );
+    }
+
+    #[test]
+    fn backup_streams_upload_summary_at_configured_priority() {
+        let runner = Arc::new(ScriptedRunner::new().on(
+            " backup ",
+            "a.img: had to backup 1 MiB of 4 MiB (compressed 1 MiB) in 1.00 s\n",
+        ));
+        let pbs = Pbs {
+            repos: Default::default(),
+            keyfile: None,
+            password: None,
+            key_passphrase: None,
+            create_ns: Default::default(),
+            ns: None,
+            backup_id: "id".to_string(),
+        };
+        let cli = PbsCli::new(runner.clone(), Arc::new(pbs));
+        let item = BackupItem {
+            archive: "a.img",
+            device: Path::new("/dev/zd0"),
+        };
+        let opts = BackupOpts {
+            priority: Priority {
+                io: Some(crate::utils::process::IoClass::Idle),
+                nice: None,
+            },
+            ..BackupOpts::default()
+        };
+        let stats = cli.backup(&repo("r:s"), None, "id", &[item], opts).unwrap();
+        assert_eq!(stats[0].size, 4 << 20);
+        assert_eq!(
+            runner.calls(),
+            [
+                "ionice -c3 proxmox-backup-client backup a.img:/dev/zd0 --backup-id id --repository r:s"
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_mapped_archive_device() {
+        let snap = "host/id/2024-05-01T02:00:00Z";
+        let listing = format!(
+            "/dev/loop3:\tnas:store:{snap}:zfs_vm-1-disk-0_raw_aaaa1111.img.fidx\n\
+             (unmapped):\tnas:store:{snap}:zfs_vm-2-disk-0_raw_bbbb2222.img.fidx\n\
+             /dev/loop4:\tnas:store:{snap}:zfs_vm-2-disk-0_raw_bbbb2222.img.fidx\n"
+        );
+        assert_eq!(
+            find_mapping(&listing, snap, "zfs_vm-2-disk-0_raw_bbbb2222.img"),
+            Some(PathBuf::from("/dev/loop4"))
+        );
+        assert_eq!(
+            find_mapping(&listing, snap, "zfs_vm-1-disk-0_raw_aaaa1111.img.fidx"),
+            Some(PathBuf::from("/dev/loop3"))
+        );
+        assert_eq!(
+            find_mapping(
+                &listing,
+                "host/id/2024-05-02T02:00:00Z",
+                "zfs_vm-1-disk-0_raw_aaaa1111.img"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn create_ns_policy_guards_missing_namespaces() {
+        let pbs = |create_ns| Pbs {
+            repos: Default::default(),
+            keyfile: None,
+            password: None,
+            key_passphrase: None,
+            ns: None,
+            create_ns,
+            backup_id: "id".to_string(),
+        };
+        let runner = Arc::new(
+            ScriptedRunner::new()
+                .on("namespace list", "ns/a\n")
+                .on("snapshots", "[]"),
+        );
+        let rs = repo("r:s");
+        let ran = || {
+            runner
+                .calls()
+                .into_iter()
+                .filter(|c| !c.contains("namespace list"))
+                .collect::<Vec<_>>()
+        };
+        let auto = PbsCli::new(runner.clone(), Arc::new(pbs(NsCreate::Auto)));
+        auto.ns_ensure(&rs, "ns/a").unwrap();
+        assert!(ran().is_empty());
+        exec_policy::with_dry_run_enabled(true, || auto.ns_ensure(&rs, "ns/b")).unwrap();
+        assert!(ran()[0].contains("namespace create ns/b"));
+
+        let never = PbsCli::new(runner.clone(), Arc::new(pbs(NsCreate::Never)));
+        let e = never.ns_ensure(&rs, "ns/b").unwrap_err();
+        assert!(e.to_string().contains("create_ns = \"never\""), "{e}");
+        assert!(never.snapshots(&rs, Some("ns/b")).unwrap().is_empty());
+
+        let strict = PbsCli::new(runner.clone(), Arc::new(pbs(NsCreate::RequireExisting)));
+        assert!(strict.snapshots(&rs, Some("ns/a")).unwrap().is_empty());
+        assert!(strict.snapshots(&rs, Some("ns/b")).is_err());
+        assert_eq!(ran().len(), 3);
+    }
+
+    #[test]
+    fn detects_encrypted_keyfiles() {
+        let plain = r#"{"kdf":null,"created":1,"modified":1,"data":"AAAA","fingerprint":"aa:bb"}"#;
+        let enc = r#"{"kdf":{"Scrypt":{"n":65536,"r":8,"p":1,"salt":"AAAA"}},"created":1,"modified":1,"data":"AAAA"}"#;
+        assert!(!keyfile_is_encrypted(plain).unwrap());
+        assert!(keyfile_is_encrypted(enc).unwrap());
+        assert!(keyfile_is_encrypted("not json").is_err());
+    }
+
+    #[test]
+    fn auth_errors_are_tagged() {
+        let e = classify(anyhow::anyhow!(
+            "command failed: proxmox-backup-client snapshots (status 255): Error: authentication failed - invalid credentials"
+        ));
+        assert_eq!(Failure::of(&e), Some(Failure::PbsAuth));
+        let e = classify(anyhow::anyhow!(
+            "command failed: proxmox-backup-client snapshots (status 255)"
+        ));
+        assert_eq!(Failure::of(&e), None);
+    }
+
+    #[test]
+    fn parses_snapshot_files() {
+        let raw = r#"[{"backup-type":"host","backup-id":"id","backup-time":1,"files":[
+            {"filename":"zfs_vm-1-disk-0_raw_abcd1234.img.fidx","size":42,"crypt-mode":"encrypt"},
+            {"filename":"index.json.blob"}]}]"#;
+        let snaps: Vec<PbsSnapshot> = serde_json::from_str(raw).unwrap();
+        let files = &snaps[0].files;
+        assert_eq!(files[0].size, 42);
+        assert_eq!(files[0].crypt_mode.as_deref(), Some("encrypt"));
+        assert_eq!((files[1].size, files[1].crypt_mode.as_deref()), (0, None));
+    }
+
+    #[test]
+    fn parses_index_checksums() {
+        let raw = r#"{"backup-type":"host","backup-id":"id","backup-time":1,
+            "files":[{"filename":"zfs_vm-1-disk-0_raw_abcd1234.img.fidx","size":42,
+            "crypt-mode":"none","csum":"aa11"}],"signature":null,"unprotected":{}}"#;
+        let sums = parse_index_checksums(raw).unwrap();
+        assert_eq!(
+            sums.get("zfs_vm-1-disk-0_raw_abcd1234.img.fidx")
+                .map(String::as_str),
+            Some("aa11")
+        );
+        assert!(parse_index_checksums("{}").is_err());
+
+        assert_eq!(parse_snapshot_stats(raw).unwrap(), None);
+        let raw = r#"{"files":[],"unprotected":{"chunk_upload_stats":{"count":10,
+            "duplicates":7,"size":12582912,"compressed_size":4194304}}}"#;
+        let s = parse_snapshot_stats(raw).unwrap().unwrap();
+        assert_eq!((s.count, s.duplicates), (10, 7));
+        assert_eq!((s.size, s.compressed_size), (12 << 20, 4 << 20));
+    }
+
+    #[test]
+    fn classifies_snapshot_files() {
+        assert_eq!(
+            class_of("zfs_vm-1-disk-0_raw_abcd1234.img.fidx"),
+            FileClass::Archive
+        );
+        assert_eq!(
+            class_of("lvmthin_vm-2_noext_ef567890.img"),
+            FileClass::Archive
+        );
+        assert_eq!(class_of("index.json.blob"), FileClass::Metadata);
+        assert_eq!(class_of("client.log.blob"), FileClass::Metadata);
+        assert_eq!(class_of("pvtools-manifest.conf.blob"), FileClass::Metadata);
+        assert_eq!(class_of("qemu-server.conf.blob"), FileClass::Foreign);
+        assert_eq!(class_of("drive-scsi0.img.fidx"), FileClass::Foreign);
+        assert_eq!(
+            class_of("ceph_vm-1_raw_abcd1234.img.fidx"),
+            FileClass::Foreign
+        );
     }
 }
@@ -1,14 +1,26 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 
 use anyhow::{Context, Result};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::utils::process::{CmdSpec, Pipeline, Runner};
+use crate::{
+    config::{Config, RestoreTarget},
+    state::{StateStore, Versioned},
+    utils::{
+        process::{CmdSpec, Pipeline, Runner},
+        time::current_epoch,
+    },
+};
 
 pub const REQ_BINS: &[&str] = &["pvesh"];
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
 pub enum Storage {
     LvmThin {
         id: String,
@@ -21,6 +33,11 @@ pub enum Storage {
         pool: String,
         content: Vec<String>,
     },
+    Lvm {
+        id: String,
+        vgname: String,
+        content: Vec<String>,
+    },
     Unknown {
         id: String,
         kind: String,
@@ -29,6 +46,70 @@ pub enum Storage {
     },
 }
 
+impl Storage {
+    pub fn id(&self) -> &str {
+        match self {
+            Storage::LvmThin { id, .. }
+            | Storage::ZfsPool { id, .. }
+            | Storage::Lvm { id, .. }
+            | Storage::Unknown { id, .. } => id,
+        }
+    }
+
+    fn set_id(&mut self, new: &str) {
+        let (Storage::LvmThin { id, .. }
+        | Storage::ZfsPool { id, .. }
+        | Storage::Lvm { id, .. }
+        | Storage::Unknown { id, .. }) = self;
+        *id = new.to_string();
+    }
+
+    /// Storage type and the pool/VG it is backed by.
+    /// Whether `other` is a ZFS storage on a dataset below this one's pool.
+    fn contains_dataset_of(&self, other: &Storage) -> bool {
+        match (self, other) {
+            (Storage::ZfsPool { pool, .. }, Storage::ZfsPool { pool: sub, .. }) => sub
+                .strip_prefix(pool.as_str())
+                .is_some_and(|r| r.starts_with('/')),
+            _ => false,
+        }
+    }
+
+    fn key(&self) -> Option<(&'static str, &str)> {
+        match self {
+            Storage::ZfsPool { pool, .. } => Some(("zfspool", pool)),
+            Storage::LvmThin { vgname, .. } => Some(("lvmthin", vgname)),
+            Storage::Lvm { vgname, .. } => Some(("lvm", vgname)),
+            Storage::Unknown { .. } => None,
+        }
+    }
+}
+
+/// One row of `/cluster/resources --type storage`: a storage as seen from one node.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClusterStorage {
+    pub node: String,
+    pub storage: String,
+    #[serde(default)]
+    pub status: String,
+    #[serde(default, deserialize_with = "int_bool")]
+    pub shared: bool,
+}
+
+impl ClusterStorage {
+    #[inline]
+    pub fn is_available(&self) -> bool {
+        self.status == "available"
+    }
+}
+
+fn int_bool<'de, D>(d: D) -> std::result::Result<bool, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(u8::deserialize(d)? != 0)
+}
+
 #[derive(Debug, Deserialize)]
 struct RawStorage {
     #[serde(rename = "type")]
@@ -75,6 +156,15 @@ impl RawStorage {
                     content: content_vec,
                 })
             }
+            "lvm" => {
+                let vgname = get_str("vgname")
+                    .ok_or_else(|| anyhow::anyhow!("storage {id}: missing vgname for type=lvm"))?;
+                Ok(Storage::Lvm {
+                    id,
+                    vgname,
+                    content: content_vec,
+                })
+            }
             "zfspool" => {
                 let pool = get_str("pool").ok_or_else(|| {
                     anyhow::anyhow!("storage {id}: missing pool for type=zfspool")
@@ -97,22 +187,24 @@ impl RawStorage {
 
 pub trait PveshPort: Send + Sync {
     fn get_storage(&self) -> Result<Vec<Storage>>;
+    fn cluster_storage(&self) -> Result<Vec<ClusterStorage>>;
 }
 
 type DynRunner = dyn Runner + Send + Sync;
 
 pub struct PveshCli {
     runner: Arc<DynRunner>,
+    timeout: Duration,
 }
 
 impl PveshCli {
-    pub fn new(runner: Arc<DynRunner>) -> Self {
-        Self { runner }
+    pub fn new(runner: Arc<DynRunner>, timeout: Duration) -> Self {
+        Self { runner, timeout }
     }
 
     #[inline]
     fn pvesh(&self) -> CmdSpec {
-        CmdSpec::new("pvesh")
+        CmdSpec::new("pvesh").with_timeout(self.timeout)
     }
 }
 
@@ -139,4 +231,534 @@ impl PveshPort for PveshCli {
 
         Ok(result)
     }
+
+    fn cluster_storage(&self) -> Result<Vec<ClusterStorage>> {
+        let cmd = self.pvesh().args([
+            "get",
+            "/cluster/resources",
+            "--type",
+            "storage",
+            "--output-format",
+            "json",
+        ]);
+
+        let out = self
+            .runner
+            .run_capture(&Pipeline::new().cmd(cmd))
+            .context("run pvesh get /cluster/resources")?;
+
+        serde_json::from_slice(out.as_bytes()).context("parse PVE cluster resources json")
+    }
+}
+
+/// Queries pvesh at most once per run; a failed query degrades to an empty list.
+pub struct CachedPvesh {
+    inner: Arc<dyn PveshPort>,
+    storages: OnceLock<Vec<Storage>>,
+    cluster: OnceLock<Vec<ClusterStorage>>,
+}
+
+impl CachedPvesh {
+    pub fn new(inner: Arc<dyn PveshPort>) -> Self {
+        Self {
+            inner,
+            storages: OnceLock::new(),
+            cluster: OnceLock::new(),
+        }
+    }
+}
+
+impl PveshPort for CachedPvesh {
+    fn get_storage(&self) -> Result<Vec<Storage>> {
+        let storages = self.storages.get_or_init(|| {
+            self.inner.get_storage().unwrap_or_else(|e| {
+                tracing::warn!("pvesh storage lookup failed, using [pve].storage_map: {e:#}");
+                Vec::new()
+            })
+        });
+        Ok(storages.clone())
+    }
+
+    fn cluster_storage(&self) -> Result<Vec<ClusterStorage>> {
+        let cluster = self.cluster.get_or_init(|| {
+            self.inner.cluster_storage().unwrap_or_else(|e| {
+                tracing::warn!("pvesh cluster lookup failed, assuming local targets: {e:#}");
+                Vec::new()
+            })
+        });
+        Ok(cluster.clone())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+    fetched_at: u64,
+    storages: Vec<Storage>,
+}
+
+impl Versioned for CacheFile {
+    const VERSION: u32 = 1;
+
+    /// Version 0 is the same document without the envelope.
+    fn migrate(_version: u32, data: Value) -> Result<Value> {
+        Ok(data)
+    }
+}
+
+/// Keeps `/storage` output on disk for `ttl`, so most runs never call pvesh for it. A stale
+/// cache is still used when pvesh fails.
+pub struct StorageCache {
+    inner: Arc<dyn PveshPort>,
+    store: StateStore,
+    file: String,
+    ttl: Duration,
+}
+
+impl StorageCache {
+    pub fn new(inner: Arc<dyn PveshPort>, store: StateStore, file: String, ttl: Duration) -> Self {
+        Self {
+            inner,
+            store,
+            file,
+            ttl,
+        }
+    }
+
+    /// Cache file for the host pvesh runs on: `None` is this host.
+    pub fn file_for(host: Option<&str>) -> String {
+        match host {
+            Some(h) => format!("pve-storage-{h}.json"),
+            None => "pve-storage.json".to_string(),
+        }
+    }
+
+    fn read(&self) -> Option<CacheFile> {
+        self.store
+            .load(&self.file)
+            .inspect_err(|e| tracing::warn!("ignoring cached PVE storages: {e:#}"))
+            .ok()
+            .flatten()
+    }
+
+    fn write(&self, storages: &[Storage]) -> Result<()> {
+        self.store.save(
+            &self.file,
+            &CacheFile {
+                fetched_at: current_epoch(),
+                storages: storages.to_vec(),
+            },
+        )
+    }
+}
+
+impl PveshPort for StorageCache {
+    fn get_storage(&self) -> Result<Vec<Storage>> {
+        if self.ttl.is_zero() {
+            return self.inner.get_storage();
+        }
+        let cached = self.read();
+        if let Some(c) = &cached
+            && current_epoch().saturating_sub(c.fetched_at) < self.ttl.as_secs()
+        {
+            return Ok(c.storages.clone());
+        }
+        match self.inner.get_storage() {
+            Ok(storages) => {
+                if let Err(e) = self.write(&storages) {
+                    tracing::warn!("cannot cache PVE storages: {e:#}");
+                }
+                Ok(storages)
+            }
+            Err(e) => match cached {
+                Some(c) => {
+                    tracing::warn!(
+                        "{e:#}; using PVE storages cached {}s ago",
+                        current_epoch().saturating_sub(c.fetched_at)
+                    );
+                    Ok(c.storages)
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    fn cluster_storage(&self) -> Result<Vec<ClusterStorage>> {
+        self.inner.cluster_storage()
+    }
+}
+
+/// Storages of the configured pools and VGs on top of what pvesh reports. Per-source
+/// `storage_map` entries replace the reported ID; pools/VGs pvesh does not know get the
+/// ID from `[pve].storage_map`, else their own name. Without `inner` (`[pve] enabled =
+/// false`) only configured storages exist and every target counts as local.
+pub struct ConfigStorage {
+    inner: Option<Arc<dyn PveshPort>>,
+    /// Each with whether its ID was set explicitly in the source section.
+    configured: Vec<(Storage, bool)>,
+    merged: OnceLock<Vec<Storage>>,
+}
+
+impl ConfigStorage {
+    pub fn new(cfg: &Config, inner: Option<Arc<dyn PveshPort>>) -> Self {
+        let s = &cfg.backup.sources;
+        let no_overrides = BTreeMap::new();
+        let id = |overrides: &BTreeMap<String, String>, name: &str| match overrides.get(name) {
+            Some(id) => (id.clone(), true),
+            None => (
+                cfg.pve
+                    .storage_map
+                    .get(name)
+                    .cloned()
+                    .unwrap_or_else(|| name.to_string()),
+                false,
+            ),
+        };
+        let zfs = |pool: &str| {
+            let (id, explicit) = id(
+                s.zfs.as_ref().map_or(&no_overrides, |z| &z.storage_map),
+                pool,
+            );
+            let st = Storage::ZfsPool {
+                id,
+                pool: pool.to_string(),
+                content: vec!["images".to_string()],
+            };
+            (st, explicit)
+        };
+        let thin = |vg: &str, thinpool: &str| {
+            let overrides = s.lvmthin.as_ref().map_or(&no_overrides, |l| &l.storage_map);
+            let (id, explicit) = id(overrides, vg);
+            let st = Storage::LvmThin {
+                id,
+                vgname: vg.to_string(),
+                thinpool: thinpool.to_string(),
+                content: vec!["images".to_string()],
+            };
+            (st, explicit)
+        };
+        let lvm = |vg: &str| {
+            let (id, explicit) = id(s.lvm.as_ref().map_or(&no_overrides, |l| &l.storage_map), vg);
+            let st = Storage::Lvm {
+                id,
+                vgname: vg.to_string(),
+                content: vec!["images".to_string()],
+            };
+            (st, explicit)
+        };
+
+        let mut all = Vec::new();
+        for t in cfg.restore.targets.values() {
+            all.push(match t {
+                RestoreTarget::Zfs { root, .. } => zfs(root),
+                RestoreTarget::LvmThin { vg, thinpool, .. } => thin(vg, thinpool),
+                RestoreTarget::Lvm { vg, .. } => lvm(vg),
+                // Not a PVE storage.
+                RestoreTarget::Ssh { .. } => continue,
+            });
+        }
+        all.extend(s.zfs.iter().flat_map(|z| z.pools.iter().map(|p| zfs(p))));
+        all.extend(
+            s.lvmthin
+                .iter()
+                .flat_map(|l| l.vgs.iter().map(|vg| thin(vg, ""))),
+        );
+        all.extend(s.lvm.iter().flat_map(|l| l.vgs.iter().map(|vg| lvm(vg))));
+
+        let mut configured: Vec<(Storage, bool)> = Vec::new();
+        for (st, explicit) in all {
+            if !configured.iter().any(|(c, _)| c.key() == st.key()) {
+                configured.push((st, explicit));
+            }
+        }
+        Self {
+            inner,
+            configured,
+            merged: OnceLock::new(),
+        }
+    }
+
+    fn merge(&self, mut found: Vec<Storage>) -> Vec<Storage> {
+        let reported = !found.is_empty();
+        for (c, explicit) in &self.configured {
+            // A dataset under a reported ZFS storage's pool belongs to that storage.
+            let covered = !explicit && found.iter().any(|s| s.contains_dataset_of(c));
+            match found.iter_mut().find(|s| s.key() == c.key()) {
+                Some(s) if *explicit => s.set_id(c.id()),
+                Some(_) => {}
+                None if covered => {}
+                None => {
+                    if reported && let Some((kind, name)) = c.key() {
+                        tracing::warn!(
+                            "no PVE {kind} storage for '{name}'; using storage id '{}'",
+                            c.id()
+                        );
+                    }
+                    found.push(c.clone());
+                }
+            }
+        }
+        found
+    }
+}
+
+impl PveshPort for ConfigStorage {
+    fn get_storage(&self) -> Result<Vec<Storage>> {
+        let merged = self.merged.get_or_init(|| {
+            let found = match &self.inner {
+                Some(p) => p.get_storage().unwrap_or_else(|e| {
+                    tracing::warn!(
+                        "pvesh storage lookup failed, using configured storage ids: {e:#}"
+                    );
+                    Vec::new()
+                }),
+                None => Vec::new(),
+            };
+            self.merge(found)
+        });
+        Ok(merged.clone())
+    }
+
+    fn cluster_storage(&self) -> Result<Vec<ClusterStorage>> {
+        match &self.inner {
+            Some(p) => p.cluster_storage(),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+pub fn fallback_storage_id(
+    storage_map: &BTreeMap<String, String>,
+    name: &str,
+    err: anyhow::Error,
+) -> String {
+    if let Some(id) = storage_map.get(name) {
+        return id.clone();
+    }
+    tracing::warn!("{err:#}; using '{name}' as storage id");
+    name.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use anyhow::{anyhow, bail};
+
+    use super::*;
+
+    struct FlakyPvesh {
+        calls: AtomicUsize,
+    }
+
+    impl PveshPort for FlakyPvesh {
+        fn get_storage(&self) -> Result<Vec<Storage>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            bail!("pvesh timed out")
+        }
+        fn cluster_storage(&self) -> Result<Vec<ClusterStorage>> {
+            bail!("pvesh timed out")
+        }
+    }
+
+    #[test]
+    fn cached_pvesh_queries_once_and_degrades() {
+        let inner = Arc::new(FlakyPvesh {
+            calls: AtomicUsize::new(0),
+        });
+        let cached = CachedPvesh::new(inner.clone());
+
+        assert!(cached.get_storage().unwrap().is_empty());
+        assert!(cached.get_storage().unwrap().is_empty());
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 1);
+    }
+
+    struct FixedPvesh {
+        calls: AtomicUsize,
+        fail: bool,
+    }
+
+    impl PveshPort for FixedPvesh {
+        fn get_storage(&self) -> Result<Vec<Storage>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if self.fail {
+                bail!("pvesh not found");
+            }
+            Ok(vec![Storage::ZfsPool {
+                id: "local-zfs".to_string(),
+                pool: "rpool/data".to_string(),
+                content: vec!["images".to_string()],
+            }])
+        }
+        fn cluster_storage(&self) -> Result<Vec<ClusterStorage>> {
+            Ok(Vec::new())
+        }
+    }
+
+    #[test]
+    fn storage_cache_reuses_fresh_and_falls_back_to_stale() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let store = StateStore::new(&tmp.path().join("state"));
+        let file = StorageCache::file_for(None);
+        let ok = Arc::new(FixedPvesh {
+            calls: AtomicUsize::new(0),
+            fail: false,
+        });
+
+        let cache = StorageCache::new(
+            ok.clone(),
+            store.clone(),
+            file.clone(),
+            Duration::from_secs(300),
+        );
+        assert_eq!(cache.get_storage().unwrap().len(), 1);
+        assert_eq!(cache.get_storage().unwrap().len(), 1);
+        assert_eq!(ok.calls.load(Ordering::SeqCst), 1);
+
+        let broken = Arc::new(FixedPvesh {
+            calls: AtomicUsize::new(0),
+            fail: true,
+        });
+        let stale = StorageCache::new(broken.clone(), store.clone(), file, Duration::from_secs(1));
+        fs::write(
+            store.path(&stale.file),
+            r#"{"fetched_at":1,"storages":[{"type":"lvm","id":"data","vgname":"data","content":[]}]}"#,
+        )
+        .unwrap();
+        assert!(
+            matches!(&stale.get_storage().unwrap()[..], [Storage::Lvm { id, .. }] if id == "data")
+        );
+        assert_eq!(broken.calls.load(Ordering::SeqCst), 1);
+
+        let missing = StorageCache::new(broken, store, "none.json".to_string(), Duration::ZERO);
+        assert!(missing.get_storage().is_err());
+    }
+
+    fn test_config() -> Config {
+        let mut cfg = Config {
+            pbs: crate::config::Pbs {
+                repos: HashMap::new(),
+                keyfile: None,
+                password: None,
+                key_passphrase: None,
+                create_ns: Default::default(),
+                ns: None,
+                backup_id: "id".to_string(),
+            },
+            pve: crate::config::Pve::default(),
+            events: crate::config::Events::default(),
+            log: Default::default(),
+            state: Default::default(),
+            backup: crate::config::Backup::default(),
+            restore: crate::config::Restore::default(),
+            nodes: BTreeMap::new(),
+        };
+        cfg.pve
+            .storage_map
+            .insert("tank".to_string(), "tank-vm".to_string());
+        cfg.restore.targets.insert(
+            "t".to_string(),
+            RestoreTarget::Zfs {
+                root: "tank".to_string(),
+                volblocksize: None,
+                name_template: None,
+            },
+        );
+        cfg.restore.targets.insert(
+            "v".to_string(),
+            RestoreTarget::LvmThin {
+                vg: "pve".to_string(),
+                thinpool: "data".to_string(),
+                lvcreate_args: Vec::new(),
+                name_template: None,
+            },
+        );
+        cfg
+    }
+
+    #[test]
+    fn config_storage_without_pvesh_uses_storage_map() {
+        let cfg = test_config();
+        let offline = ConfigStorage::new(&cfg, None);
+        let storages = offline.get_storage().unwrap();
+        assert!(matches!(&storages[0], Storage::ZfsPool { id, .. } if id == "tank-vm"));
+        assert!(
+            matches!(&storages[1], Storage::LvmThin { id, thinpool, .. } if id == "pve" && thinpool == "data")
+        );
+        assert!(offline.cluster_storage().unwrap().is_empty());
+    }
+
+    #[test]
+    fn source_storage_map_overrides_pvesh() {
+        let mut cfg = test_config();
+        cfg.backup.sources.zfs = Some(crate::config::Zfs {
+            pools: vec!["rpool/data".to_string(), "tank".to_string()],
+            storage_map: BTreeMap::from([("rpool/data".to_string(), "fast".to_string())]),
+            send_pools: Vec::new(),
+            staging_dir: "/var/tmp".into(),
+            snapshot_devices: false,
+            use_existing_snapshot: None,
+        });
+        let pvesh = Arc::new(FixedPvesh {
+            calls: AtomicUsize::new(0),
+            fail: false,
+        });
+
+        let storages = ConfigStorage::new(&cfg, Some(pvesh.clone()))
+            .get_storage()
+            .unwrap();
+        let ids: Vec<&str> = storages.iter().map(Storage::id).collect();
+        // rpool/data is reported as local-zfs but overridden; tank and pve are not PVE storages.
+        assert_eq!(ids, ["fast", "tank-vm", "pve"]);
+        assert_eq!(pvesh.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn sub_datasets_of_a_reported_pool_add_no_storage() {
+        let mut cfg = test_config();
+        cfg.backup.sources.zfs = Some(crate::config::Zfs {
+            pools: vec!["rpool/data/k8s/pvs".to_string()],
+            storage_map: BTreeMap::new(),
+            send_pools: Vec::new(),
+            staging_dir: "/var/tmp".into(),
+            snapshot_devices: false,
+            use_existing_snapshot: None,
+        });
+        let pvesh = Arc::new(FixedPvesh {
+            calls: AtomicUsize::new(0),
+            fail: false,
+        });
+
+        let storages = ConfigStorage::new(&cfg, Some(pvesh)).get_storage().unwrap();
+        let ids: Vec<&str> = storages.iter().map(Storage::id).collect();
+        // tank is a restore target in test_config, not a PVE storage.
+        assert_eq!(ids, ["local-zfs", "tank-vm", "pve"]);
+    }
+
+    #[test]
+    fn parses_cluster_storage_rows() {
+        let json = r#"[
+            {"id":"storage/pve1/local-zfs","node":"pve1","storage":"local-zfs","status":"available","shared":0,"type":"storage"},
+            {"id":"storage/pve2/nfs","node":"pve2","storage":"nfs","status":"unknown","shared":1,"type":"storage"}
+        ]"#;
+        let rows: Vec<ClusterStorage> = serde_json::from_str(json).unwrap();
+        assert_eq!(rows[0].node, "pve1");
+        assert!(rows[0].is_available() && !rows[0].shared);
+        assert!(!rows[1].is_available() && rows[1].shared);
+    }
+
+    #[test]
+    fn fallback_prefers_storage_map() {
+        let map = BTreeMap::from([("tank".to_string(), "local-zfs".to_string())]);
+        assert_eq!(
+            fallback_storage_id(&map, "tank", anyhow!("not found")),
+            "local-zfs"
+        );
+        assert_eq!(
+            fallback_storage_id(&map, "pve", anyhow!("not found")),
+            "pve"
+        );
+    }
 }
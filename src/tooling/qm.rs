@@ -0,0 +1,36 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+
+use crate::utils::process::{CmdSpec, Pipeline, Runner, StdioSpec};
+
+const QM_TIMEOUT: Duration = Duration::from_secs(120);
+
+pub trait QmPort: Send + Sync {
+    /// Adds volumes of `vmid` that its config does not reference yet as `unusedN` disks.
+    fn rescan(&self, vmid: u32) -> Result<()>;
+}
+
+type DynRunner = dyn Runner + Send + Sync;
+
+pub struct QmCli {
+    runner: Arc<DynRunner>,
+}
+
+impl QmCli {
+    pub fn new(runner: Arc<DynRunner>) -> Self {
+        Self { runner }
+    }
+}
+
+impl QmPort for QmCli {
+    fn rescan(&self, vmid: u32) -> Result<()> {
+        let cmd = CmdSpec::new("qm")
+            .args(["rescan", "--vmid", &vmid.to_string()])
+            .with_timeout(QM_TIMEOUT)
+            .stderr(StdioSpec::Inherit);
+        self.runner
+            .run(&Pipeline::new().cmd(cmd))
+            .with_context(|| format!("qm rescan --vmid {vmid}"))
+    }
+}
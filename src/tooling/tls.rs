@@ -0,0 +1,252 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, IsTerminal},
+    net::IpAddr,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    config::Pbs,
+    state::{StateStore, Versioned},
+    ui,
+    utils::process::{CmdSpec, Pipeline, Runner, StdioSpec},
+};
+
+/// Server certificate fingerprints recorded on first use, in the state directory.
+pub const FINGERPRINT_FILE: &str = "pbs-fingerprints.json";
+
+/// Fingerprints by `host:port`.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(transparent)]
+struct KnownServers(BTreeMap<String, String>);
+
+impl Versioned for KnownServers {
+    const VERSION: u32 = 1;
+
+    /// Version 0 is the same map without the envelope.
+    fn migrate(_version: u32, data: Value) -> Result<Value> {
+        Ok(data)
+    }
+}
+
+const DEFAULT_PORT: u16 = 8007;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Pins repos that set neither `fingerprint` nor `trusted_ca` to the certificate fingerprint
+/// recorded in `store` the first time their server was seen, the way ssh treats known_hosts.
+/// An unseen server is recorded with `trust_new`, else after asking on the terminal; without
+/// one it stays unpinned as before. A changed certificate fails the run.
+pub fn pin_fingerprints(
+    pbs: &mut Pbs,
+    runner: &dyn Runner,
+    store: &StateStore,
+    trust_new: bool,
+) -> Result<()> {
+    if std::env::var_os("PBS_FINGERPRINT").is_some() {
+        return Ok(());
+    }
+    let mut names: Vec<String> = pbs
+        .repos
+        .iter()
+        .filter(|(_, r)| r.fingerprint.is_none() && !r.trusted_ca)
+        .map(|(name, _)| name.clone())
+        .collect();
+    if names.is_empty() {
+        return Ok(());
+    }
+    names.sort();
+
+    let known = store
+        .load::<KnownServers>(FINGERPRINT_FILE)?
+        .unwrap_or_default()
+        .0;
+    for name in names {
+        let repo = pbs.repos.get_mut(&name).expect("name taken from repos");
+        let (host, port) = repo_server(&repo.url);
+        let server = authority(&host, port);
+        let seen = match server_fingerprint(runner, &host, port) {
+            Ok(fp) => fp,
+            Err(e) => {
+                tracing::warn!(
+                    "repo '{name}': certificate of {server} not read, not pinned: {e:#}"
+                );
+                continue;
+            }
+        };
+        match known.get(&server) {
+            Some(fp) if fp.eq_ignore_ascii_case(&seen) => {}
+            Some(fp) => bail!(
+                "repo '{name}': the certificate of {server} changed since it was first trusted \
+                 (recorded {fp}, now {seen}); if it was replaced on purpose, set the repo's \
+                 fingerprint or remove {server} from {}",
+                store.path(FINGERPRINT_FILE).display()
+            ),
+            None => {
+                if !trust_new && !io::stdin().is_terminal() {
+                    tracing::warn!(
+                        "repo '{name}': {server} is not pinned yet; pass --trust-new-fingerprint \
+                         to record its certificate fingerprint {seen}"
+                    );
+                    continue;
+                }
+                if !trust_new
+                    && !ui::confirm(&format!(
+                        "repo '{name}': {server} presents certificate fingerprint {seen}. Trust it?"
+                    ))?
+                {
+                    bail!("repo '{name}': certificate fingerprint of {server} not trusted");
+                }
+                store.update(FINGERPRINT_FILE, |k: &mut KnownServers| {
+                    k.0.insert(server.clone(), seen.clone());
+                    Ok(())
+                })?;
+                tracing::info!("repo '{name}': recorded fingerprint {seen} of {server}");
+            }
+        }
+        repo.fingerprint = Some(seen);
+    }
+    Ok(())
+}
+
+/// Host and port of a `[[auth-id@]server[:port]:]datastore` repository string.
+fn repo_server(repo: &str) -> (String, u16) {
+    let Some((rest, _datastore)) = repo.rsplit_once(':') else {
+        return ("localhost".to_string(), DEFAULT_PORT);
+    };
+    let server = rest.rsplit_once('@').map_or(rest, |(_, s)| s);
+    let (host, port) = match server.strip_prefix('[') {
+        Some(v6) => match v6.split_once(']') {
+            Some((h, p)) => (h, p.strip_prefix(':')),
+            None => (v6, None),
+        },
+        None => match server.rsplit_once(':') {
+            Some((h, p)) => (h, Some(p)),
+            None => (server, None),
+        },
+    };
+    let port = port.and_then(|p| p.parse().ok()).unwrap_or(DEFAULT_PORT);
+    (host.to_string(), port)
+}
+
+fn authority(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    }
+}
+
+/// SHA-256 fingerprint of the certificate `host:port` presents, lowercase as PBS shows it.
+fn server_fingerprint(runner: &dyn Runner, host: &str, port: u16) -> Result<String> {
+    let mut connect = CmdSpec::new("openssl")
+        .args(["s_client", "-connect", &authority(host, port)])
+        .stdin(StdioSpec::Null)
+        .stderr(StdioSpec::Null)
+        .with_timeout(CONNECT_TIMEOUT);
+    if host.parse::<IpAddr>().is_err() {
+        connect = connect.args(["-servername", host]);
+    }
+    let pipeline = Pipeline::new()
+        .cmd(connect)
+        .cmd(CmdSpec::new("openssl").args(["x509", "-noout", "-fingerprint", "-sha256"]));
+    let out = runner.run_capture(&pipeline)?;
+    out.lines()
+        .find_map(|l| l.split_once("Fingerprint=").map(|(_, fp)| fp.trim()))
+        .filter(|fp| !fp.is_empty())
+        .map(str::to_ascii_lowercase)
+        .with_context(|| {
+            format!(
+                "no certificate fingerprint in openssl output: {}",
+                out.trim()
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs};
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{config::Repo, utils::process::ScriptedRunner};
+
+    const FP_A: &str = "aa:bb:cc:dd:ee:ff:00:11:22:33:44:55:66:77:88:99:aa:bb:cc:dd:ee:ff:00:11:22:33:44:55:66:77:88:99";
+
+    fn pbs(url: &str) -> Pbs {
+        let repo = Repo {
+            url: url.to_string(),
+            ns: None,
+            fingerprint: None,
+            trusted_ca: false,
+            keyfile: None,
+            password: None,
+            key_passphrase: None,
+        };
+        Pbs {
+            repos: HashMap::from([("nas".to_string(), repo)]),
+            keyfile: None,
+            password: None,
+            key_passphrase: None,
+            ns: None,
+            create_ns: Default::default(),
+            backup_id: "host-backup".to_string(),
+        }
+    }
+
+    #[test]
+    fn repo_server_of_repository_strings() {
+        assert_eq!(
+            repo_server("root@pam!pve@10.10.0.24:nas-store"),
+            ("10.10.0.24".to_string(), 8007)
+        );
+        assert_eq!(
+            repo_server("backup@pbs@pbs.example.com:8443:store"),
+            ("pbs.example.com".to_string(), 8443)
+        );
+        assert_eq!(
+            repo_server("[fd00::2]:store"),
+            ("fd00::2".to_string(), 8007)
+        );
+        assert_eq!(repo_server("store"), ("localhost".to_string(), 8007));
+        assert_eq!(authority("fd00::2", 8007), "[fd00::2]:8007");
+    }
+
+    #[test]
+    fn first_fingerprint_is_recorded_and_a_changed_one_fails() {
+        let dir = TempDir::new().unwrap();
+        let store = StateStore::new(dir.path());
+        let upper = FP_A.to_ascii_uppercase();
+        let runner = ScriptedRunner::new().on("x509", format!("sha256 Fingerprint={upper}\n"));
+
+        let mut cfg = pbs("root@pam@pbs.lan:store");
+        pin_fingerprints(&mut cfg, &runner, &store, true).unwrap();
+        assert_eq!(cfg.repos["nas"].fingerprint.as_deref(), Some(FP_A));
+        assert!(runner.calls()[0].contains("-connect pbs.lan:8007"));
+        assert!(runner.calls()[0].contains("-servername pbs.lan"));
+
+        let mut again = pbs("root@pam@pbs.lan:store");
+        pin_fingerprints(&mut again, &runner, &store, false).unwrap();
+        assert_eq!(again.repos["nas"].fingerprint.as_deref(), Some(FP_A));
+
+        let other = FP_A.replacen("aa", "ab", 1);
+        let mitm = ScriptedRunner::new().on("x509", format!("SHA256 Fingerprint={other}\n"));
+        let err =
+            pin_fingerprints(&mut pbs("root@pam@pbs.lan:store"), &mitm, &store, true).unwrap_err();
+        assert!(format!("{err:#}").contains("changed"), "{err:#}");
+
+        // Files from before the state store are read as they are.
+        fs::write(
+            store.path(FINGERPRINT_FILE),
+            format!(r#"{{"pbs.lan:8007": "{FP_A}"}}"#),
+        )
+        .unwrap();
+        let mut legacy = pbs("root@pam@pbs.lan:store");
+        pin_fingerprints(&mut legacy, &runner, &store, false).unwrap();
+        assert_eq!(legacy.repos["nas"].fingerprint.as_deref(), Some(FP_A));
+    }
+}
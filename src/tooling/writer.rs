@@ -0,0 +1,385 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, bail};
+
+use crate::{
+    config::WriteOverride,
+    utils::{
+        exec_policy::{self, is_dry_run},
+        process::{CmdSpec, Pipeline, Runner},
+        signal,
+    },
+};
+
+pub const DEFAULT_BLOCK_SIZE: usize = 4 << 20;
+/// Buffer, length and offset alignment that O_DIRECT writes need.
+const DIRECT_ALIGN: usize = 4096;
+const PROGRESS_EVERY: Duration = Duration::from_secs(30);
+const MIB: u64 = 1 << 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteOpts {
+    pub block_size: usize,
+    pub direct: bool,
+    /// Seek over all-zero blocks instead of writing them.
+    pub sparse: bool,
+    pub fsync: bool,
+}
+
+impl Default for WriteOpts {
+    fn default() -> Self {
+        Self {
+            block_size: DEFAULT_BLOCK_SIZE,
+            direct: true,
+            sparse: false,
+            fsync: true,
+        }
+    }
+}
+
+impl WriteOpts {
+    pub fn with_override(mut self, o: &WriteOverride) -> Result<Self> {
+        if let Some(bs) = &o.bs {
+            self.block_size = parse_block_size(bs)?;
+        }
+        if let Some(direct) = o.direct {
+            self.direct = direct;
+        }
+        if let Some(sparse) = o.sparse {
+            self.sparse = sparse;
+        }
+        if let Some(fsync) = o.fsync {
+            self.fsync = fsync;
+        }
+        Ok(self)
+    }
+}
+
+/// dd-style size above zero: `512`, `64K`, `4M`, `1G` (powers of 1024).
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (num, mult) = match s.as_bytes().last() {
+        Some(b'k' | b'K') => (&s[..s.len() - 1], 1 << 10),
+        Some(b'M') => (&s[..s.len() - 1], 1 << 20),
+        Some(b'G') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    let n: u64 = num
+        .parse()
+        .with_context(|| format!("invalid size '{s}': expected e.g. 64K, 16M, 1G"))?;
+    match n.checked_mul(mult) {
+        Some(v) if v > 0 => Ok(v),
+        _ => bail!("invalid size '{s}'"),
+    }
+}
+
+/// Block size as [`parse_size`] reads it.
+pub fn parse_block_size(s: &str) -> Result<usize> {
+    usize::try_from(parse_size(s)?).with_context(|| format!("block size '{s}' is too large"))
+}
+
+/// Consumes archive streams: the stdout of a `proxmox-backup-client restore ... -` command.
+pub trait WriterPort: Send + Sync {
+    /// Writes the stream onto the existing `target`, which is never truncated.
+    fn write(&self, src: CmdSpec, target: &Path, opts: &WriteOpts) -> Result<()>;
+    /// Reads the stream to the end and throws it away.
+    fn discard(&self, src: CmdSpec) -> Result<()>;
+    /// Fails unless the stream is `len` bytes long and equals the start of `device`.
+    fn compare(&self, src: CmdSpec, device: &Path, len: u64) -> Result<()>;
+}
+
+/// Writes streams from inside pvtools, so only devices on this host can be targets.
+pub struct NativeWriter {
+    runner: Arc<dyn Runner + Send + Sync>,
+}
+
+impl NativeWriter {
+    pub fn new(runner: Arc<dyn Runner + Send + Sync>) -> Self {
+        Self { runner }
+    }
+}
+
+impl WriterPort for NativeWriter {
+    fn write(&self, src: CmdSpec, target: &Path, opts: &WriteOpts) -> Result<()> {
+        if is_dry_run() {
+            // Shown as the equivalent dd pipeline, so an emitted script can run it.
+            let dd = super::dd::to_file_cmd(target, opts);
+            exec_policy::skip(&Pipeline::new().cmd(src).cmd(dd).render());
+            return Ok(());
+        }
+        self.runner
+            .run_stream(&Pipeline::new().cmd(src), &mut |r| {
+                let stats = write_stream(r, target, opts)?;
+                tracing::info!(
+                    "{}: {} MiB written, {} MiB of zero blocks skipped",
+                    target.display(),
+                    stats.written / MIB,
+                    stats.skipped / MIB
+                );
+                Ok(())
+            })
+            .with_context(|| format!("write {}", target.display()))
+    }
+
+    fn discard(&self, src: CmdSpec) -> Result<()> {
+        self.runner.run_stream(&Pipeline::new().cmd(src), &mut |r| {
+            io::copy(r, &mut io::sink()).context("read archive stream")?;
+            Ok(())
+        })
+    }
+
+    fn compare(&self, src: CmdSpec, device: &Path, len: u64) -> Result<()> {
+        if is_dry_run() {
+            let cmp = super::dd::compare_cmd(device, len);
+            exec_policy::skip(&Pipeline::new().cmd(src).cmd(cmp).render());
+            return Ok(());
+        }
+        let mut dev = File::open(device).with_context(|| format!("open {}", device.display()))?;
+        self.runner
+            .run_stream(&Pipeline::new().cmd(src), &mut |r| {
+                compare_stream(r, &mut dev, len)
+            })
+            .with_context(|| format!("compare with {}", device.display()))
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct WriteStats {
+    pub written: u64,
+    pub skipped: u64,
+}
+
+pub fn write_stream(src: &mut dyn Read, target: &Path, opts: &WriteOpts) -> Result<WriteStats> {
+    if opts.direct && !opts.block_size.is_multiple_of(DIRECT_ALIGN) {
+        bail!(
+            "block size {} is not a multiple of {DIRECT_ALIGN}, required for direct I/O",
+            opts.block_size
+        );
+    }
+    let mut oo = OpenOptions::new();
+    oo.write(true);
+    if opts.direct {
+        oo.custom_flags(libc::O_DIRECT);
+    }
+    let mut file = oo
+        .open(target)
+        .with_context(|| format!("open {}", target.display()))?;
+
+    let mut raw = vec![0u8; opts.block_size + DIRECT_ALIGN];
+    let off = raw.as_ptr().align_offset(DIRECT_ALIGN);
+    let buf = &mut raw[off..off + opts.block_size];
+
+    let mut stats = WriteStats::default();
+    let mut direct = opts.direct;
+    let mut last_report = Instant::now();
+    loop {
+        signal::check()?;
+        let n = read_full(src, buf).context("read archive stream")?;
+        if n == 0 {
+            break;
+        }
+        let block = &buf[..n];
+        if opts.sparse && block.iter().all(|&b| b == 0) {
+            file.seek(SeekFrom::Current(n as i64))
+                .with_context(|| format!("seek in {}", target.display()))?;
+            stats.skipped += n as u64;
+        } else {
+            if direct && !n.is_multiple_of(DIRECT_ALIGN) {
+                clear_direct(&file)?;
+                direct = false;
+            }
+            file.write_all(block)
+                .with_context(|| format!("write {}", target.display()))?;
+            stats.written += n as u64;
+        }
+        if last_report.elapsed() >= PROGRESS_EVERY {
+            tracing::info!(
+                "{}: {} MiB done",
+                target.display(),
+                (stats.written + stats.skipped) / MIB
+            );
+            last_report = Instant::now();
+        }
+        if n < buf.len() {
+            break;
+        }
+    }
+
+    // Skipped trailing blocks leave a regular file short; devices have a fixed size.
+    let end = stats.written + stats.skipped;
+    let meta = file.metadata()?;
+    if meta.is_file() && meta.len() < end {
+        file.set_len(end)
+            .with_context(|| format!("extend {}", target.display()))?;
+    }
+    if opts.fsync {
+        file.sync_all()
+            .with_context(|| format!("fsync {}", target.display()))?;
+    }
+    Ok(stats)
+}
+
+fn compare_stream(src: &mut dyn Read, dev: &mut dyn Read, len: u64) -> Result<()> {
+    let mut want = vec![0u8; DEFAULT_BLOCK_SIZE];
+    let mut have = vec![0u8; DEFAULT_BLOCK_SIZE];
+    let mut pos = 0u64;
+    loop {
+        signal::check()?;
+        let n = read_full(src, &mut want).context("read archive stream")?;
+        if n == 0 {
+            break;
+        }
+        let m = read_full(dev, &mut have[..n]).context("read device")?;
+        if let Some(i) = (0..m).find(|&i| want[i] != have[i]) {
+            bail!("differs at byte {}", pos + i as u64);
+        }
+        if m < n {
+            bail!("device ends at byte {}, before the archive", pos + m as u64);
+        }
+        pos += n as u64;
+    }
+    if len > 0 && pos != len {
+        bail!("archive stream has {pos} bytes, expected {len}");
+    }
+    Ok(())
+}
+
+/// Fills `buf` unless the stream ends first; returns the number of bytes read.
+fn read_full(r: &mut dyn Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(k) => n += k,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+/// O_DIRECT needs aligned lengths, so the final short block goes through the page cache.
+fn clear_direct(file: &File) -> Result<()> {
+    let fd = file.as_raw_fd();
+    // SAFETY: fcntl on a descriptor we own; only the O_DIRECT status flag changes.
+    let ok = unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        flags >= 0 && libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_DIRECT) >= 0
+    };
+    if !ok {
+        return Err(io::Error::last_os_error()).context("clear O_DIRECT for the final block");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn opts(block_size: usize, sparse: bool) -> WriteOpts {
+        WriteOpts {
+            block_size,
+            direct: false,
+            sparse,
+            fsync: false,
+        }
+    }
+
+    #[test]
+    fn parses_dd_style_block_sizes() {
+        assert_eq!(parse_block_size("512").unwrap(), 512);
+        assert_eq!(parse_block_size("64k").unwrap(), 64 << 10);
+        assert_eq!(parse_block_size("16M").unwrap(), 16 << 20);
+        for bad in ["", "0", "16MB", "M", "-1K"] {
+            assert!(parse_block_size(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn sparse_write_skips_zero_blocks_and_keeps_size() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("disk.img");
+        fs::write(&path, b"").unwrap();
+
+        let mut data = vec![0u8; 10];
+        data[..4].copy_from_slice(b"head");
+        let stats = write_stream(&mut data.as_slice(), &path, &opts(4, true)).unwrap();
+        assert_eq!(
+            stats,
+            WriteStats {
+                written: 4,
+                skipped: 6
+            }
+        );
+        assert_eq!(fs::read(&path).unwrap(), data);
+    }
+
+    #[test]
+    fn write_never_truncates_target() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("disk.img");
+        fs::write(&path, b"0123456789").unwrap();
+
+        write_stream(&mut b"ab".as_slice(), &path, &opts(4, false)).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"ab23456789");
+        assert!(
+            write_stream(
+                &mut b"ab".as_slice(),
+                &tmp.path().join("none"),
+                &opts(4, false)
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn compare_reports_first_difference() {
+        let dev = b"abcdefgh-tail";
+        assert!(compare_stream(&mut b"abcdefgh".as_slice(), &mut dev.as_slice(), 8).is_ok());
+        let err = compare_stream(&mut b"abcXefgh".as_slice(), &mut dev.as_slice(), 8).unwrap_err();
+        assert_eq!(err.to_string(), "differs at byte 3");
+        assert!(compare_stream(&mut b"abcdefgh-tail!".as_slice(), &mut dev.as_slice(), 0).is_err());
+        assert!(compare_stream(&mut b"abcd".as_slice(), &mut dev.as_slice(), 8).is_err());
+    }
+
+    #[test]
+    fn dry_run_renders_dd_pipeline_with_redacted_secrets() {
+        let writer = NativeWriter::new(Arc::new(crate::utils::process::ProcessRunner::new()));
+        let src = CmdSpec::new("proxmox-backup-client")
+            .args(["restore", "snap", "a.img", "-"])
+            .env(
+                "PBS_PASSWORD",
+                crate::utils::process::EnvValue::Secret("hunter2".to_string()),
+            );
+        let ((), lines) = exec_policy::with_script(|| {
+            exec_policy::with_dry_run_enabled(true, || {
+                writer
+                    .write(src, Path::new("/dev/zvol/tank/a"), &opts(4096, true))
+                    .unwrap()
+            })
+        });
+        assert_eq!(lines.len(), 1);
+        assert!(
+            lines[0].starts_with("PBS_PASSWORD=<redacted> "),
+            "{}",
+            lines[0]
+        );
+        assert!(
+            lines[0]
+                .ends_with("| dd of=/dev/zvol/tank/a bs=4096 conv=notrunc,sparse status=progress"),
+            "{}",
+            lines[0]
+        );
+    }
+}
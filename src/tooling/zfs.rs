@@ -1,20 +1,63 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, anyhow};
 
-use crate::utils::process::{CmdSpec, Pipeline, Runner, StdioSpec};
+use crate::utils::process::{CmdSpec, Pipeline, Priority, Runner, StdioSpec};
 
-pub const REQ_BINS: &[&str] = &["zfs"];
+pub const REQ_BINS: &[&str] = &["zfs", "zpool"];
+
+const ZFS_TIMEOUT: Duration = Duration::from_secs(300);
 
 pub trait ZfsPort: Send + Sync {
     fn list_volumes(&self, pool: &str) -> Result<Vec<ZfsVolume>>;
+    /// Filesystem datasets below `pool`, without `pool` itself.
+    fn list_filesystems(&self, pool: &str) -> Result<Vec<ZfsVolume>>;
+    /// Snapshots below `pool`, oldest first.
+    fn list_snapshots(&self, pool: &str) -> Result<Vec<String>>;
     fn guid_map(&self, pool: &str) -> Result<HashMap<String, String>>;
+    /// Zvols of `pool` whose snapshots get device nodes (`snapdev=visible`).
+    fn visible_snapdevs(&self, pool: &str) -> Result<HashSet<String>>;
     fn snapshot(&self, snap: &str) -> Result<()>;
     fn clone_readonly_dev(&self, snap: &str, clone: &str) -> Result<()>;
     fn destroy_recursive(&self, target: &str) -> Result<()>;
+    fn rename(&self, from: &str, to: &str) -> Result<()>;
+    /// Bytes written to `dataset` since its snapshot `snap` (short name); `None` if there is
+    /// no such snapshot.
+    fn written_since(&self, dataset: &str, snap: &str) -> Result<Option<u64>>;
     fn assert_dataset_exists(&self, dataset: &str) -> Result<()>;
     fn dataset_mountpoint(&self, dataset: &str) -> Result<Option<String>>;
-    fn create_zvol(&self, dataset: &str, size_bytes: u64) -> anyhow::Result<()>;
+    /// Local value of the user property `prop` of `dataset`; `None` if it is unset.
+    fn user_property(&self, dataset: &str, prop: &str) -> Result<Option<String>>;
+    /// Sets the user property `prop` of `dataset`, or clears it with `None`.
+    fn set_user_property(&self, dataset: &str, prop: &str, value: Option<&str>) -> Result<()>;
+    /// Creates a zvol of `size_bytes`, with `volblocksize` if given.
+    fn create_zvol(
+        &self,
+        dataset: &str,
+        size_bytes: u64,
+        volblocksize: Option<&str>,
+    ) -> anyhow::Result<()>;
+    /// Creates the filesystem `dataset` and any missing ancestors; a no-op if it exists.
+    fn ensure_dataset(&self, dataset: &str) -> Result<()>;
+    fn pool_status(&self, pool: &str) -> Result<String>;
+    fn pool_usage(&self, pool: &str) -> Result<String>;
+    /// Estimated size of the stream `send_to_file` writes for `snap`.
+    fn send_size(&self, snap: &str) -> Result<u64>;
+    /// Writes a full `zfs send` stream of `snap` to `path`, keeping blocks compressed.
+    fn send_to_file(
+        &self,
+        snap: &str,
+        path: &Path,
+        priority: Priority,
+        timeout: Option<Duration>,
+    ) -> Result<()>;
+    /// Receives the stream `src` writes into the new dataset `dataset`, unmounted.
+    fn receive(&self, src: CmdSpec, dataset: &str) -> Result<()>;
 }
 
 type DynRunner = dyn Runner + Send + Sync;
@@ -30,7 +73,12 @@ impl ZfsCli {
 
     #[inline]
     fn zfs(&self) -> CmdSpec {
-        CmdSpec::new("zfs")
+        CmdSpec::new("zfs").with_timeout(ZFS_TIMEOUT)
+    }
+
+    #[inline]
+    fn zpool(&self) -> CmdSpec {
+        CmdSpec::new("zpool").with_timeout(ZFS_TIMEOUT)
     }
 }
 
@@ -38,19 +86,22 @@ impl ZfsCli {
 pub struct ZfsVolume {
     pub name: String,
     pub origin: Option<String>,
+    /// Bytes: `volsize` of a zvol, `referenced` of a filesystem.
+    pub size: Option<u64>,
 }
 
-impl ZfsPort for ZfsCli {
-    fn list_volumes(&self, pool: &str) -> Result<Vec<ZfsVolume>> {
+impl ZfsCli {
+    fn list_datasets(&self, pool: &str, kind: &str) -> Result<Vec<ZfsVolume>> {
         let cmd = self
             .zfs()
             .args([
                 "list",
                 "-H",
+                "-p",
                 "-t",
-                "volume",
+                kind,
                 "-o",
-                "name,origin",
+                "name,origin,volsize,referenced",
                 "-r",
                 pool,
             ])
@@ -73,6 +124,8 @@ impl ZfsPort for ZfsCli {
                 Some(x) => x,
                 None => continue,
             };
+            // volsize for zvols; filesystems have none and are sent with what they reference.
+            let size = it.find_map(|s| s.parse().ok());
 
             volumes.push(ZfsVolume {
                 name: name.to_string(),
@@ -81,11 +134,54 @@ impl ZfsPort for ZfsCli {
                 } else {
                     Some(origin.to_string())
                 },
+                size,
             })
         }
 
         Ok(volumes)
     }
+}
+
+impl ZfsPort for ZfsCli {
+    fn list_volumes(&self, pool: &str) -> Result<Vec<ZfsVolume>> {
+        self.list_datasets(pool, "volume")
+    }
+
+    fn list_filesystems(&self, pool: &str) -> Result<Vec<ZfsVolume>> {
+        let mut out = self.list_datasets(pool, "filesystem")?;
+        out.retain(|v| v.name != pool);
+        Ok(out)
+    }
+
+    fn list_snapshots(&self, pool: &str) -> Result<Vec<String>> {
+        let cmd = self
+            .zfs()
+            .args([
+                "list",
+                "-H",
+                "-t",
+                "snapshot",
+                "-o",
+                "name",
+                "-s",
+                "createtxg",
+                "-r",
+                pool,
+            ])
+            .stdout(StdioSpec::Pipe);
+
+        let out = self
+            .runner
+            .run_capture(&Pipeline::new().cmd(cmd))
+            .with_context(|| format!("zfs list snapshots for pool {pool}"))?;
+
+        Ok(out
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(str::to_string)
+            .collect())
+    }
 
     fn guid_map(&self, pool: &str) -> Result<HashMap<String, String>> {
         let cmd = self
@@ -112,6 +208,34 @@ impl ZfsPort for ZfsCli {
         Ok(map)
     }
 
+    fn visible_snapdevs(&self, pool: &str) -> Result<HashSet<String>> {
+        let cmd = self
+            .zfs()
+            .args([
+                "get",
+                "-H",
+                "-o",
+                "name,value",
+                "-t",
+                "volume",
+                "snapdev",
+                "-r",
+                pool,
+            ])
+            .stdout(StdioSpec::Pipe)
+            .stderr(StdioSpec::Null);
+        let out = self
+            .runner
+            .run_capture(&Pipeline::new().cmd(cmd))
+            .with_context(|| format!("zfs get snapdev -r {pool}"))?;
+        Ok(out
+            .lines()
+            .filter_map(|l| l.split_once('\t'))
+            .filter(|(_, v)| v.trim() == "visible")
+            .map(|(ds, _)| ds.to_string())
+            .collect())
+    }
+
     fn snapshot(&self, snap: &str) -> Result<()> {
         let cmd = self
             .zfs()
@@ -151,6 +275,53 @@ impl ZfsPort for ZfsCli {
             .with_context(|| format!("zfs destroy -r {target}"))
     }
 
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let cmd = self
+            .zfs()
+            .args(["rename", from, to])
+            .stderr(StdioSpec::Inherit);
+        self.runner
+            .run(&Pipeline::new().cmd(cmd))
+            .with_context(|| format!("zfs rename {from} -> {to}"))
+    }
+
+    fn written_since(&self, dataset: &str, snap: &str) -> Result<Option<u64>> {
+        let full = format!("{dataset}@{snap}");
+        let exists = self
+            .zfs()
+            .args(["list", "-H", "-t", "snapshot", "-o", "name", &full])
+            .stdout(StdioSpec::Pipe)
+            .stderr(StdioSpec::Null);
+        if self
+            .runner
+            .run_capture(&Pipeline::new().cmd(exists))
+            .is_err()
+        {
+            return Ok(None);
+        }
+        let cmd = self
+            .zfs()
+            .args([
+                "get",
+                "-Hp",
+                "-o",
+                "value",
+                &format!("written@{snap}"),
+                dataset,
+            ])
+            .stdout(StdioSpec::Pipe)
+            .stderr(StdioSpec::Pipe);
+        let out = self
+            .runner
+            .run_capture(&Pipeline::new().cmd(cmd))
+            .with_context(|| format!("zfs get written@{snap} {dataset}"))?;
+        let written = out
+            .trim()
+            .parse()
+            .with_context(|| format!("parse written@{snap} of {dataset}: '{}'", out.trim()))?;
+        Ok(Some(written))
+    }
+
     fn assert_dataset_exists(&self, dataset: &str) -> Result<()> {
         let cmd = self
             .zfs()
@@ -183,12 +354,155 @@ impl ZfsPort for ZfsCli {
         })
     }
 
-    fn create_zvol(&self, dataset: &str, size_bytes: u64) -> Result<()> {
+    fn user_property(&self, dataset: &str, prop: &str) -> Result<Option<String>> {
         let cmd = self
             .zfs()
-            .args(["create", "-V", &size_bytes.to_string(), dataset]);
+            .args(["get", "-H", "-s", "local", "-o", "value", prop, dataset])
+            .stdout(StdioSpec::Pipe)
+            .stderr(StdioSpec::Pipe);
+
+        let out = self
+            .runner
+            .run_capture(&Pipeline::new().cmd(cmd))
+            .with_context(|| format!("zfs get {prop} {dataset}"))?;
+
+        Ok(match out.trim() {
+            "" | "-" => None,
+            value => Some(value.to_string()),
+        })
+    }
+
+    fn set_user_property(&self, dataset: &str, prop: &str, value: Option<&str>) -> Result<()> {
+        let cmd = match value {
+            Some(value) => self
+                .zfs()
+                .args(["set", &format!("{prop}={value}"), dataset]),
+            None => self.zfs().args(["inherit", prop, dataset]),
+        }
+        .stderr(StdioSpec::Inherit);
+        self.runner
+            .run(&Pipeline::new().cmd(cmd))
+            .with_context(|| format!("zfs set {prop} {dataset}"))
+    }
+
+    fn create_zvol(
+        &self,
+        dataset: &str,
+        size_bytes: u64,
+        volblocksize: Option<&str>,
+    ) -> Result<()> {
+        let mut cmd = self.zfs().args(["create", "-V", &size_bytes.to_string()]);
+        if let Some(bs) = volblocksize {
+            cmd = cmd.args(["-o", &format!("volblocksize={bs}")]);
+        }
+        let cmd = cmd.arg(dataset);
         self.runner
             .run(&Pipeline::new().cmd(cmd))
             .with_context(|| format!("zfs create -V {} {}", size_bytes, dataset))
     }
+
+    fn ensure_dataset(&self, dataset: &str) -> Result<()> {
+        let cmd = self
+            .zfs()
+            .args(["create", "-p", dataset])
+            .stderr(StdioSpec::Inherit);
+        self.runner
+            .run(&Pipeline::new().cmd(cmd))
+            .with_context(|| format!("zfs create -p {dataset}"))
+    }
+
+    fn pool_status(&self, pool: &str) -> Result<String> {
+        let cmd = self
+            .zpool()
+            .args(["status", "-P", pool])
+            .stdout(StdioSpec::Pipe)
+            .stderr(StdioSpec::Null);
+
+        self.runner
+            .run_capture(&Pipeline::new().cmd(cmd))
+            .with_context(|| format!("zpool status {pool}"))
+    }
+
+    fn pool_usage(&self, pool: &str) -> Result<String> {
+        let cmd = self
+            .zpool()
+            .args(["list", "-H", "-o", "name,size,alloc,free,cap,frag", pool])
+            .stdout(StdioSpec::Pipe)
+            .stderr(StdioSpec::Null);
+
+        self.runner
+            .run_capture(&Pipeline::new().cmd(cmd))
+            .map(|s| s.trim().to_string())
+            .with_context(|| format!("zpool list {pool}"))
+    }
+
+    fn send_size(&self, snap: &str) -> Result<u64> {
+        let cmd = self
+            .zfs()
+            .args(["send", "-nP", "-L", "-e", "-c", snap])
+            .stdout(StdioSpec::Pipe)
+            .stderr(StdioSpec::Pipe);
+        let out = self
+            .runner
+            .run_capture(&Pipeline::new().cmd(cmd))
+            .with_context(|| format!("zfs send -nP {snap}"))?;
+        parse_send_size(&out).ok_or_else(|| {
+            anyhow!(
+                "no size in zfs send -nP output for {snap}: '{}'",
+                out.trim()
+            )
+        })
+    }
+
+    fn send_to_file(
+        &self,
+        snap: &str,
+        path: &Path,
+        priority: Priority,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        // Through sh, so the file lands on the host that runs zfs, local or over ssh; exec, so
+        // a timeout stops zfs itself.
+        let mut cmd = CmdSpec::new("sh")
+            .args([
+                "-c",
+                "exec zfs send -L -e -c \"$1\" > \"$2\"",
+                "sh",
+                snap,
+                &path.display().to_string(),
+            ])
+            .priority(priority);
+        if let Some(t) = timeout {
+            cmd = cmd.with_timeout(t);
+        }
+        self.runner
+            .run(&Pipeline::new().cmd(cmd))
+            .with_context(|| format!("zfs send {snap} > {}", path.display()))
+    }
+
+    fn receive(&self, src: CmdSpec, dataset: &str) -> Result<()> {
+        let recv = CmdSpec::new("zfs").args(["receive", "-u", dataset]);
+        self.runner
+            .run(&Pipeline::new().cmd(src).cmd(recv))
+            .with_context(|| format!("zfs receive {dataset}"))
+    }
+}
+
+/// The `size` line of `zfs send -nP` output.
+fn parse_send_size(out: &str) -> Option<u64> {
+    out.lines()
+        .find_map(|l| l.strip_prefix("size"))
+        .and_then(|s| s.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_send_size_estimate() {
+        let out = "full\ttank/vm-1-disk-0@pvtools-1\t1234\nsize\t1234\n";
+        assert_eq!(parse_send_size(out), Some(1234));
+        assert_eq!(parse_send_size("full\ttank@x\n"), None);
+    }
 }
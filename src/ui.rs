@@ -1,6 +1,24 @@
+use std::{
+    io::{self, BufRead, IsTerminal, Write},
+    os::fd::AsRawFd,
+};
+
+use anyhow::{Result, bail};
 use prettytable::{Cell, Row, Table};
 
-use crate::{utils::time::fmt_utc, volume::Volume};
+use crate::{
+    commands::{
+        backup::{Candidate, NodeResult, VolumeOutcome, VolumeStatus},
+        cleanup::Leftover,
+        diff::{ArchiveDiff, Change},
+        restore::{ArchiveResult, RestoreImpact, Route, RuleCheck},
+    },
+    history::RunRecord,
+    manifest::{BackupManifest, CarriedArchive},
+    tooling::pbs::{PbsFile, SnapshotStats, UploadStats, snapshot_path},
+    utils::{signal, time::fmt_utc},
+    volume::Volume,
+};
 
 pub fn log_pbs_info(repo: &str, ns: Option<&str>, backup_id: &str, ts: Option<u64>) {
     let ns_disp = ns.unwrap_or("<root>");
@@ -21,41 +39,698 @@ pub fn log_pbs_info(repo: &str, ns: Option<&str>, backup_id: &str, ts: Option<u6
 pub fn log_archives(vols: &[Volume]) {
     let mut table = Table::new();
 
-    table.set_titles(Row::new(vec![Cell::new("Storage"), Cell::new("VM Disk")]));
+    table.set_titles(Row::new(vec![
+        Cell::new("Storage"),
+        Cell::new("VM Disk"),
+        Cell::new("Size"),
+    ]));
 
     for v in vols {
-        table.add_row(Row::new(vec![Cell::new(&v.storage), Cell::new(&v.disk)]));
+        table.add_row(Row::new(vec![
+            Cell::new(&v.storage),
+            Cell::new(&v.disk),
+            Cell::new(&v.size_bytes.map_or("-".to_string(), human_size)),
+        ]));
+    }
+
+    table.printstd();
+
+    let total: u64 = vols.iter().filter_map(|v| v.size_bytes).sum();
+    let unknown = vols.iter().filter(|v| v.size_bytes.is_none()).count();
+    if unknown > 0 {
+        tracing::info!(
+            "{} volumes, {} total ({unknown} of unknown size)",
+            vols.len(),
+            human_size(total)
+        );
+    } else {
+        tracing::info!("{} volumes, {} total", vols.len(), human_size(total));
+    }
+}
+
+/// The "restore impact" section of a `restore run --dry-run`.
+pub fn log_restore_impact(i: &RestoreImpact) {
+    let secs = i.secs();
+    let eta = match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    };
+    tracing::info!("Restore impact:");
+    tracing::info!(
+        "  {} archive(s), {} to transfer{}",
+        i.archives,
+        human_size(i.bytes),
+        if i.unknown > 0 {
+            format!(" ({} of unknown size)", i.unknown)
+        } else {
+            String::new()
+        }
+    );
+    tracing::info!("  ~{eta} at an assumed {}/s", human_size(i.rate));
+    tracing::info!(
+        "  {} device(s) created, {} existing overwritten",
+        i.created,
+        i.overwritten
+    );
+}
+
+/// `512 B`, `4.0 MiB`, `1.5 TiB`: binary units, as PBS reports sizes.
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut n = bytes as f64;
+    let mut unit = 0;
+    while n >= 1024.0 && unit < UNITS.len() - 1 {
+        n /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{n:.1} {}", UNITS[unit])
+    }
+}
+
+/// Restore targets with whether each already existed and is about to be overwritten.
+pub fn log_restore_plan(plan: &[(&Volume, bool)]) {
+    let mut table = Table::new();
+    table.set_titles(Row::new(vec![
+        Cell::new("Storage"),
+        Cell::new("VM Disk"),
+        Cell::new("Device"),
+        Cell::new("Action"),
+    ]));
+
+    for (v, overwrites) in plan {
+        table.add_row(Row::new(vec![
+            Cell::new(&v.storage),
+            Cell::new(&v.disk),
+            Cell::new(&v.device.display().to_string()),
+            Cell::new(if *overwrites { "OVERWRITE" } else { "create" }),
+        ]));
+    }
+
+    table.printstd();
+}
+
+/// Asks a yes/no question on the terminal; anything but y/yes is a no.
+pub fn confirm(question: &str) -> Result<bool> {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        bail!("{question} Refusing to continue without a terminal; pass --yes to confirm");
+    }
+    eprint!("{question} [y/N] ");
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    stdin.lock().read_line(&mut answer)?;
+    signal::check()?;
+    Ok(matches!(
+        answer.trim().to_ascii_lowercase().as_str(),
+        "y" | "yes"
+    ))
+}
+
+/// Reads a line from the terminal with echo turned off.
+pub fn prompt_secret(question: &str) -> Result<String> {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        bail!("cannot prompt without a terminal");
+    }
+    eprint!("{question} ");
+    io::stderr().flush()?;
+
+    let fd = stdin.as_raw_fd();
+    // SAFETY: termios is plain data; tcgetattr fills it in before it is read.
+    let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+    let echo_off = unsafe { libc::tcgetattr(fd, &mut saved) } == 0 && {
+        let mut quiet = saved;
+        quiet.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &quiet) == 0 }
+    };
+    let mut answer = String::new();
+    let read = stdin.lock().read_line(&mut answer);
+    if echo_off {
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &saved) };
+    }
+    eprintln!();
+    read?;
+    signal::check()?;
+    Ok(answer.trim_end_matches(['\r', '\n']).to_string())
+}
+
+pub fn log_archive_diff(diffs: &[ArchiveDiff]) {
+    let size = |s: Option<u64>| s.map_or_else(|| "-".to_string(), |s| s.to_string());
+    let changed: Vec<&ArchiveDiff> = diffs
+        .iter()
+        .filter(|d| d.change != Change::Unchanged)
+        .collect();
+    if changed.is_empty() {
+        tracing::info!("no archive changes ({} unchanged)", diffs.len());
+        return;
     }
 
+    let mut table = Table::new();
+    table.set_titles(Row::new(vec![
+        Cell::new("Archive"),
+        Cell::new("Change"),
+        Cell::new("Old size"),
+        Cell::new("New size"),
+    ]));
+    for d in &changed {
+        let change = match d.change {
+            Change::Added => "added",
+            Change::Removed => "REMOVED",
+            Change::Changed => "changed",
+            Change::Unchanged => "unchanged",
+        };
+        table.add_row(Row::new(vec![
+            Cell::new(&d.archive),
+            Cell::new(change),
+            Cell::new(&size(d.old_size)),
+            Cell::new(&size(d.new_size)),
+        ]));
+    }
     table.printstd();
+
+    let count = |c: Change| diffs.iter().filter(|d| d.change == c).count();
+    tracing::info!(
+        "{} added, {} removed, {} changed, {} unchanged",
+        count(Change::Added),
+        count(Change::Removed),
+        count(Change::Changed),
+        count(Change::Unchanged)
+    );
 }
 
-pub fn log_pbs_archives(archives: Vec<String>) {
-    if archives.is_empty() {
+/// Rows are `(archive, provider, target)`.
+pub fn log_pbs_archives(rows: &[(&PbsFile, String, &str)]) {
+    if rows.is_empty() {
         tracing::info!("<no archives>");
     } else {
         let mut table = Table::new();
-        table.set_titles(Row::new(vec![Cell::new("File")]));
+        table.set_titles(Row::new(vec![
+            Cell::new("File"),
+            Cell::new("Size"),
+            Cell::new("Crypt"),
+            Cell::new("Provider"),
+            Cell::new("Target"),
+        ]));
 
-        for r in archives {
-            table.add_row(Row::new(vec![Cell::new(&r)]));
+        for (file, provider, target) in rows {
+            table.add_row(Row::new(vec![
+                Cell::new(&file.filename),
+                Cell::new(&file.size.to_string()),
+                Cell::new(file.crypt_mode.as_deref().unwrap_or("-")),
+                Cell::new(provider),
+                Cell::new(target),
+            ]));
         }
 
         table.printstd();
     }
 }
 
-pub fn log_snapshots(snapshots: Vec<Vec<String>>) {
+pub fn log_foreign_files(files: &[&str]) {
+    if files.is_empty() {
+        tracing::info!("<no foreign files>");
+        return;
+    }
+    tracing::info!("files not created by pvtools (ignored by restore and copy):");
+    let mut table = Table::new();
+    table.set_titles(Row::new(vec![Cell::new("Foreign file")]));
+
+    for f in files {
+        table.add_row(Row::new(vec![Cell::new(f)]));
+    }
+
+    table.printstd();
+}
+
+/// Rows are `[time, files]`, or `[group, time, files]` with `with_group`.
+pub fn log_snapshots(snapshots: Vec<Vec<String>>, with_group: bool) {
     if snapshots.is_empty() {
         tracing::info!("<no snapshots>");
     } else {
         let mut table = Table::new();
-        table.set_titles(Row::new(vec![Cell::new("Time (UTC)"), Cell::new("Files")]));
+        let mut titles = vec![Cell::new("Time (UTC)"), Cell::new("Files")];
+        if with_group {
+            titles.insert(0, Cell::new("Group"));
+        }
+        table.set_titles(Row::new(titles));
 
         for r in snapshots {
-            table.add_row(Row::new(vec![Cell::new(&r[0]), Cell::new(&r[1])]));
+            table.add_row(Row::new(r.iter().map(|c| Cell::new(c)).collect()));
+        }
+
+        table.printstd();
+    }
+}
+
+pub fn log_manifest(m: &BackupManifest) {
+    let created = fmt_utc(m.created).unwrap_or_else(|_| m.created.to_string());
+    tracing::info!("Manifest v{} recorded at {created}", m.version);
+
+    if m.storage.is_empty() {
+        tracing::info!("<no storage status recorded>");
+    }
+    for s in &m.storage {
+        println!("=== {} {}: {}", s.provider, s.source, s.command);
+        match (&s.output, &s.error) {
+            (Some(out), _) => println!("{}", out.trim_end()),
+            (None, Some(err)) => println!("<failed: {err}>"),
+            (None, None) => println!("<empty>"),
+        }
+    }
+
+    if !m.claims.is_empty() {
+        let mut table = Table::new();
+        table.set_titles(Row::new(vec![Cell::new("PVC"), Cell::new("Archive")]));
+        for c in &m.claims {
+            table.add_row(Row::new(vec![
+                Cell::new(&format!("{}/{}", c.namespace, c.name)),
+                Cell::new(&c.archive),
+            ]));
+        }
+        table.printstd();
+    }
+
+    if !m.carried.is_empty() {
+        let mut table = Table::new();
+        table.set_titles(Row::new(vec![
+            Cell::new("Unchanged archive"),
+            Cell::new("Held by snapshot"),
+        ]));
+        for c in &m.carried {
+            table.add_row(Row::new(vec![
+                Cell::new(&c.archive),
+                Cell::new(&carried_snapshot(&m.backup_id, c)),
+            ]));
+        }
+        table.printstd();
+    }
+}
+
+/// Archives a `--changed-only` backup left out of its snapshot, with the one holding them.
+pub fn log_carried(backup_id: &str, carried: &[CarriedArchive]) {
+    if carried.is_empty() {
+        return;
+    }
+    tracing::warn!(
+        "{} archive(s) were unchanged at this backup and are NOT in this snapshot; restore them \
+         from the snapshot that holds them:",
+        carried.len()
+    );
+    for c in carried {
+        tracing::warn!(
+            "  {}  --snapshot {}",
+            c.archive,
+            carried_snapshot(backup_id, c)
+        );
+    }
+}
+
+fn carried_snapshot(backup_id: &str, c: &CarriedArchive) -> String {
+    snapshot_path(backup_id, c.backup_time).unwrap_or_else(|_| c.backup_time.to_string())
+}
+
+pub fn log_leftovers(leftovers: &[Leftover]) {
+    if leftovers.is_empty() {
+        tracing::info!("<no leftover pvtools snapshots>");
+        return;
+    }
+    let mut table = Table::new();
+    table.set_titles(Row::new(vec![
+        Cell::new("Type"),
+        Cell::new("Name"),
+        Cell::new("Created (UTC)"),
+    ]));
+
+    for l in leftovers {
+        let created = fmt_utc(l.created).unwrap_or_else(|_| l.created.to_string());
+        table.add_row(Row::new(vec![
+            Cell::new(l.kind.label()),
+            Cell::new(&l.name),
+            Cell::new(&created),
+        ]));
+    }
+
+    table.printstd();
+}
+
+pub fn log_restore_results(results: &[ArchiveResult], total: usize) {
+    let mut table = Table::new();
+    table.set_titles(Row::new(vec![
+        Cell::new("Archive"),
+        Cell::new("Target"),
+        Cell::new("Time"),
+        Cell::new("Result"),
+    ]));
+
+    for r in results {
+        let status = match &r.error {
+            None => "ok".to_string(),
+            Some(e) => format!("FAILED: {e}"),
+        };
+        table.add_row(Row::new(vec![
+            Cell::new(&r.archive),
+            Cell::new(&r.device),
+            Cell::new(&format!("{}s", r.secs)),
+            Cell::new(&status),
+        ]));
+    }
+
+    table.printstd();
+
+    let ok = results.iter().filter(|r| r.error.is_none()).count();
+    let skipped = total - results.len();
+    if skipped > 0 {
+        tracing::info!("{ok} ok, {} failed, {skipped} skipped", results.len() - ok);
+    } else {
+        tracing::info!("{ok} ok, {} failed", results.len() - ok);
+    }
+}
+
+pub fn log_upload_stats(stats: &[UploadStats]) {
+    let mib = |b: u64| format!("{:.1} MiB", b as f64 / (1u64 << 20) as f64);
+    let mut table = Table::new();
+    table.set_titles(Row::new(vec![
+        Cell::new("Archive"),
+        Cell::new("Read"),
+        Cell::new("Uploaded"),
+        Cell::new("Compressed"),
+        Cell::new("Dedup"),
+        Cell::new("Time"),
+        Cell::new("Rate"),
+    ]));
+
+    for s in stats {
+        let rate = if s.secs > 0.0 {
+            format!("{}/s", mib((s.size as f64 / s.secs) as u64))
+        } else {
+            "-".to_string()
+        };
+        table.add_row(Row::new(vec![
+            Cell::new(&s.archive),
+            Cell::new(&mib(s.size)),
+            Cell::new(&mib(s.uploaded)),
+            Cell::new(&mib(s.compressed)),
+            Cell::new(&reused(s.size, s.uploaded)),
+            Cell::new(&format!("{:.1}s", s.secs)),
+            Cell::new(&rate),
+        ]));
+    }
+
+    table.printstd();
+}
+
+/// Share of `total` that did not need uploading.
+fn reused(total: u64, new: u64) -> String {
+    if total == 0 {
+        return "-".to_string();
+    }
+    format!(
+        "{:.0}%",
+        total.saturating_sub(new) as f64 * 100.0 / total as f64
+    )
+}
+
+pub fn log_snapshot_stats(s: &SnapshotStats) {
+    let mib = |b: u64| format!("{:.1} MiB", b as f64 / (1u64 << 20) as f64);
+    tracing::info!(
+        "Snapshot: {} chunks, {} already stored ({} deduplicated); {} new, {} after compression",
+        s.count,
+        s.duplicates,
+        reused(s.count, s.count.saturating_sub(s.duplicates)),
+        mib(s.size),
+        mib(s.compressed_size)
+    );
+}
+
+pub fn log_backup_summary(outcomes: &[VolumeOutcome]) {
+    let mib = |b: Option<u64>| {
+        b.map_or("-".to_string(), |b| {
+            format!("{:.1} MiB", b as f64 / (1u64 << 20) as f64)
+        })
+    };
+    let mut table = Table::new();
+    table.set_titles(Row::new(vec![
+        Cell::new("Volume"),
+        Cell::new("Archive"),
+        Cell::new("Disk size"),
+        Cell::new("Read"),
+        Cell::new("Stored"),
+        Cell::new("Time"),
+        Cell::new("Status"),
+        Cell::new("Reason"),
+    ]));
+
+    for o in outcomes {
+        let status = match o.status {
+            VolumeStatus::Ok => "ok",
+            VolumeStatus::Failed => "FAILED",
+            VolumeStatus::Skipped => "skipped",
+        };
+        table.add_row(Row::new(vec![
+            Cell::new(&format!("{}/{}", o.storage, o.disk)),
+            Cell::new(&o.archive),
+            Cell::new(&o.size_bytes.map_or("-".to_string(), human_size)),
+            Cell::new(&mib(o.size)),
+            Cell::new(&mib(o.stored)),
+            Cell::new(&o.secs.map_or("-".to_string(), |s| format!("{s:.1}s"))),
+            Cell::new(status),
+            Cell::new(o.reason.as_deref().unwrap_or("")),
+        ]));
+    }
+
+    table.printstd();
+
+    let count = |s: VolumeStatus| outcomes.iter().filter(|o| o.status == s).count();
+    tracing::info!(
+        "{} ok, {} failed, {} skipped",
+        count(VolumeStatus::Ok),
+        count(VolumeStatus::Failed),
+        count(VolumeStatus::Skipped)
+    );
+}
+
+pub fn log_history(runs: &[RunRecord]) {
+    if runs.is_empty() {
+        tracing::info!("<no runs recorded>");
+        return;
+    }
+    let mut table = Table::new();
+    table.set_titles(Row::new(vec![
+        Cell::new("Started (UTC)"),
+        Cell::new("Command"),
+        Cell::new("Backup ID"),
+        Cell::new("Repo"),
+        Cell::new("Time"),
+        Cell::new("Archives"),
+        Cell::new("Result"),
+    ]));
+
+    for r in runs {
+        let started = fmt_utc(r.started).unwrap_or_else(|_| r.started.to_string());
+        let mut result = match &r.error {
+            None if r.ok => "ok".to_string(),
+            None => "FAILED".to_string(),
+            Some(e) => format!("FAILED: {e}"),
+        };
+        for f in &r.failures {
+            result.push_str(&format!("\n  {f}"));
         }
+        table.add_row(Row::new(vec![
+            Cell::new(&started),
+            Cell::new(&r.command),
+            Cell::new(&r.backup_id),
+            Cell::new(&r.repo),
+            Cell::new(&format!("{}s", r.secs)),
+            Cell::new(&r.archives.to_string()),
+            Cell::new(&result),
+        ]));
+    }
 
+    table.printstd();
+}
+
+pub fn log_node_results(results: &[NodeResult], total: usize) {
+    let mut table = Table::new();
+    table.set_titles(Row::new(vec![
+        Cell::new("Node"),
+        Cell::new("Backup ID"),
+        Cell::new("Time"),
+        Cell::new("Result"),
+    ]));
+
+    for r in results {
+        let status = match &r.error {
+            None => "ok".to_string(),
+            Some(e) => format!("FAILED: {e}"),
+        };
+        table.add_row(Row::new(vec![
+            Cell::new(&r.node),
+            Cell::new(&r.backup_id),
+            Cell::new(&format!("{}s", r.secs)),
+            Cell::new(&status),
+        ]));
+    }
+
+    table.printstd();
+
+    let ok = results.iter().filter(|r| r.error.is_none()).count();
+    let skipped = total - results.len();
+    if skipped > 0 {
+        tracing::info!(
+            "{ok} node(s) ok, {} failed, {skipped} skipped",
+            results.len() - ok
+        );
+    } else {
+        tracing::info!("{ok} node(s) ok, {} failed", results.len() - ok);
+    }
+}
+
+pub fn log_rollbacks(rollbacks: &[(String, String)]) {
+    if rollbacks.is_empty() {
+        return;
+    }
+    tracing::info!("safety snapshots taken; to undo a restore run:");
+    let mut table = Table::new();
+    table.set_titles(Row::new(vec![Cell::new("Target"), Cell::new("Rollback")]));
+
+    for (target, cmd) in rollbacks {
+        table.add_row(Row::new(vec![Cell::new(target), Cell::new(cmd)]));
+    }
+
+    table.printstd();
+}
+
+pub fn log_remote_archives(remote: &[(&str, &str, String)]) {
+    tracing::error!("target storage is not available on this node:");
+    let mut table = Table::new();
+    table.set_titles(Row::new(vec![
+        Cell::new("Archive"),
+        Cell::new("Target"),
+        Cell::new("Node"),
+    ]));
+
+    for (archive, target, node) in remote {
+        table.add_row(Row::new(vec![
+            Cell::new(archive),
+            Cell::new(target),
+            Cell::new(node),
+        ]));
+    }
+
+    table.printstd();
+}
+
+/// Rows are `(archive, target, expected size)` from a `restore run --plan` file.
+pub fn log_restore_mapping(rows: &[(&str, &str, Option<u64>)]) {
+    tracing::info!("restore plan mapping:");
+    let mut table = Table::new();
+    table.set_titles(Row::new(vec![
+        Cell::new("Archive"),
+        Cell::new("Target"),
+        Cell::new("Size"),
+    ]));
+
+    for (archive, target, size) in rows {
+        let size = size.map_or_else(|| "-".to_string(), |s| s.to_string());
+        table.add_row(Row::new(vec![
+            Cell::new(archive),
+            Cell::new(target),
+            Cell::new(&size),
+        ]));
+    }
+
+    table.printstd();
+}
+
+pub fn log_route(archive: &str, provider: &str, checks: &[RuleCheck], route: &Route) {
+    tracing::info!("{archive} (provider {provider}):");
+    if checks.is_empty() {
+        tracing::info!("no [[restore.rules]] for provider {provider}");
+    } else {
+        let mut table = Table::new();
+        table.set_titles(Row::new(vec![
+            Cell::new("Rule"),
+            Cell::new("Archive regex"),
+            Cell::new("Target"),
+            Cell::new("Matches"),
+        ]));
+        for c in checks {
+            table.add_row(Row::new(vec![
+                Cell::new(&format!("#{}", c.index + 1)),
+                Cell::new(c.regex.unwrap_or("<any>")),
+                Cell::new(c.target),
+                Cell::new(if c.matched { "yes" } else { "no" }),
+            ]));
+        }
         table.printstd();
     }
+
+    match route {
+        Route::Pinned(t) => tracing::info!("→ {t} (pinned by the plan)"),
+        Route::Rule {
+            index,
+            regex: Some(re),
+            target,
+        } => tracing::info!("→ {target} (rule #{}, archive_regex '{re}')", index + 1),
+        Route::Rule {
+            index,
+            regex: None,
+            target,
+        } => tracing::info!(
+            "→ {target} (rule #{}, no archive_regex; no regex rule matched)",
+            index + 1
+        ),
+        Route::Default(t) => tracing::info!("→ {t} (no rule matched; [restore] default_target)"),
+        Route::Unrouted => {
+            tracing::warn!("→ <unrouted>: no rule matched and [restore] default_target is unset")
+        }
+    }
+}
+
+pub fn log_unrouted(archives: &[&str]) {
+    tracing::warn!(
+        "{} archive(s) match no restore rule or target and will NOT be restored:",
+        archives.len()
+    );
+    for a in archives {
+        tracing::warn!("  {a}");
+    }
+    tracing::warn!(
+        "add [[restore.rules]] or [restore] default_target (see `restore explain`), or --exclude them"
+    );
+}
+
+pub fn log_candidates(candidates: &[Candidate]) {
+    if candidates.is_empty() {
+        tracing::info!("<no volumes found>");
+        return;
+    }
+    let mut table = Table::new();
+    table.set_titles(Row::new(vec![
+        Cell::new("Provider"),
+        Cell::new("Volume"),
+        Cell::new("Decision"),
+        Cell::new("Reason"),
+        Cell::new("In use"),
+    ]));
+
+    for c in candidates {
+        let (decision, reason) = match &c.rejected {
+            None => ("accept", ""),
+            Some(r) => ("reject", r.as_str()),
+        };
+        table.add_row(Row::new(vec![
+            Cell::new(&c.provider),
+            Cell::new(&c.name),
+            Cell::new(decision),
+            Cell::new(reason),
+            Cell::new(c.in_use.as_deref().unwrap_or("")),
+        ]));
+    }
+
+    table.printstd();
 }
@@ -3,7 +3,12 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use anyhow::{Result, anyhow};
+use anyhow::{Context, Result};
+
+use crate::utils::{
+    failure::Failure,
+    process::{CmdSpec, Pipeline, Runner, StdioSpec},
+};
 
 pub fn ensure_bins<I, S>(bins: I) -> Result<()>
 where
@@ -20,10 +25,34 @@ where
     if missing.is_empty() {
         Ok(())
     } else {
-        Err(anyhow!(
+        Err(Failure::MissingBinary.with(format!(
             "missing required binaries in PATH: {}",
             missing.join(", ")
-        ))
+        )))
+    }
+}
+
+/// Same check as [`ensure_bins`], but on the host `runner` executes on.
+pub fn ensure_remote_bins(runner: &dyn Runner, host: &str, bins: &[&str]) -> Result<()> {
+    let cmd = CmdSpec::new("sh")
+        .args([
+            "-c",
+            r#"for b in "$@"; do command -v "$b" >/dev/null || echo "$b"; done"#,
+            "sh",
+        ])
+        .args(bins.iter().copied())
+        .stderr(StdioSpec::Inherit);
+    let out = runner
+        .run_capture(&Pipeline::new().cmd(cmd))
+        .with_context(|| format!("check required binaries on {host}"))?;
+    let missing: Vec<&str> = out.split_whitespace().collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(Failure::MissingBinary.with(format!(
+            "missing required binaries on {host}: {}",
+            missing.join(", ")
+        )))
     }
 }
 
@@ -0,0 +1,141 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{Context, Result, anyhow, bail};
+
+const DAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+const DAY_MINUTES: u16 = 24 * 60;
+
+/// A weekly window in local time such as `Mon..Fri 08:00-18:00`. Without days it applies
+/// every day; a window that ends at or before its start runs past midnight.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Blackout {
+    src: String,
+    days: [bool; 7],
+    start: u16,
+    end: u16,
+}
+
+impl Blackout {
+    /// `weekday` counts from Monday = 0, `minute` from local midnight.
+    pub fn contains(&self, weekday: usize, minute: u16) -> bool {
+        if self.start < self.end {
+            return self.days[weekday] && (self.start..self.end).contains(&minute);
+        }
+        let yesterday = (weekday + 6) % 7;
+        (self.days[weekday] && minute >= self.start) || (self.days[yesterday] && minute < self.end)
+    }
+}
+
+impl FromStr for Blackout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let (days, times) = match s.rsplit_once(' ') {
+            Some((d, t)) => (parse_days(d.trim())?, t),
+            None => ([true; 7], s),
+        };
+        let (start, end) = times
+            .split_once('-')
+            .ok_or_else(|| anyhow!("expected HH:MM-HH:MM in '{s}'"))?;
+        let start = parse_hhmm(start).with_context(|| format!("bad start in '{s}'"))?;
+        let end = parse_hhmm(end).with_context(|| format!("bad end in '{s}'"))?;
+        if start == end || start == DAY_MINUTES {
+            bail!("empty blackout window '{s}'");
+        }
+        Ok(Self {
+            src: s.to_string(),
+            days,
+            start,
+            end,
+        })
+    }
+}
+
+impl fmt::Display for Blackout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.src)
+    }
+}
+
+/// `Mon`, `Mon..Fri`, `Sat,Sun` or `Fri..Mon` (ranges wrap around the week).
+fn parse_days(s: &str) -> Result<[bool; 7]> {
+    let mut out = [false; 7];
+    for part in s.split(',') {
+        match part.split_once("..") {
+            Some((a, b)) => {
+                let (mut d, last) = (day(a)?, day(b)?);
+                out[d] = true;
+                while d != last {
+                    d = (d + 1) % 7;
+                    out[d] = true;
+                }
+            }
+            None => out[day(part)?] = true,
+        }
+    }
+    Ok(out)
+}
+
+fn day(s: &str) -> Result<usize> {
+    let s = s.trim();
+    DAYS.iter()
+        .position(|d| d.eq_ignore_ascii_case(s))
+        .ok_or_else(|| anyhow!("unknown day '{s}': use Mon, Tue, Wed, Thu, Fri, Sat or Sun"))
+}
+
+/// Minutes since midnight; `24:00` is allowed as the end of a day.
+fn parse_hhmm(s: &str) -> Result<u16> {
+    let (h, m) = s
+        .trim()
+        .split_once(':')
+        .ok_or_else(|| anyhow!("expected HH:MM, got '{s}'"))?;
+    let (h, m): (u16, u16) = (h.parse()?, m.parse()?);
+    if h > 24 || m >= 60 || h * 60 + m > DAY_MINUTES {
+        bail!("time out of range: '{s}'");
+    }
+    Ok(h * 60 + m)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weekday_office_hours() {
+        let w: Blackout = "Mon..Fri 08:00-18:00".parse().unwrap();
+        assert!(w.contains(0, 8 * 60));
+        assert!(w.contains(4, 17 * 60 + 59));
+        assert!(!w.contains(4, 18 * 60));
+        assert!(!w.contains(5, 12 * 60));
+        assert_eq!(w.to_string(), "Mon..Fri 08:00-18:00");
+    }
+
+    #[test]
+    fn overnight_window_spills_into_next_day() {
+        let w: Blackout = "Fri,Sat 22:00-02:00".parse().unwrap();
+        assert!(w.contains(4, 23 * 60));
+        assert!(w.contains(5, 60));
+        assert!(w.contains(6, 60));
+        assert!(!w.contains(4, 60));
+        assert!(!w.contains(6, 23 * 60));
+
+        let daily: Blackout = "00:00-24:00".parse().unwrap();
+        assert!((0..7).all(|d| daily.contains(d, 0) && daily.contains(d, DAY_MINUTES - 1)));
+    }
+
+    #[test]
+    fn rejects_bad_windows() {
+        for bad in [
+            "Mon..Fri",
+            "Mon..Fry 08:00-18:00",
+            "08:00-08:00",
+            "25:00-26:00",
+            "08:60-09:00",
+            "2000:00-08:00",
+            "08:00-65535:00",
+        ] {
+            assert!(bad.parse::<Blackout>().is_err(), "{bad}");
+        }
+    }
+}
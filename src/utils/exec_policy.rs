@@ -1,7 +1,16 @@
-use std::cell::Cell;
+use std::{
+    cell::{Cell, RefCell},
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+};
+
+use anyhow::{Context, Result};
 
 thread_local! {
     static DRY_RUN: Cell<bool> = const { Cell::new(false) };
+    /// Commands dry-run skipped, while [`with_script`] collects them.
+    static SCRIPT: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
 }
 
 pub fn is_dry_run() -> bool {
@@ -23,3 +32,80 @@ pub fn with_dry_run_enabled<R>(enabled: bool, f: impl FnOnce() -> R) -> R {
     let _g = Guard(prev);
     f()
 }
+
+/// Reports a command that dry-run does not execute. `cmd` is its rendered, redacted form.
+pub fn skip(cmd: &str) {
+    tracing::info!("[DRY-RUN] {cmd}");
+    SCRIPT.with(|s| {
+        if let Some(lines) = s.borrow_mut().as_mut() {
+            lines.push(cmd.to_string());
+        }
+    });
+}
+
+/// Runs `f` and returns, in order, every command [`skip`] reported meanwhile.
+pub fn with_script<R>(f: impl FnOnce() -> R) -> (R, Vec<String>) {
+    struct Guard(Option<Vec<String>>);
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            SCRIPT.with(|s| *s.borrow_mut() = self.0.take());
+        }
+    }
+    let _g = Guard(SCRIPT.with(|s| s.borrow_mut().replace(Vec::new())));
+    let res = f();
+    let lines = SCRIPT.with(|s| s.borrow_mut().replace(Vec::new()));
+    (res, lines.unwrap_or_default())
+}
+
+/// Runs `f`; given `path`, also writes the commands dry-run skipped meanwhile there as a script.
+pub fn emitting_script<R>(
+    path: Option<&Path>,
+    title: &str,
+    f: impl FnOnce() -> Result<R>,
+) -> Result<R> {
+    let Some(path) = path else {
+        return f();
+    };
+    let (res, lines) = with_script(f);
+    fs::write(path, render_script(title, &lines))
+        .with_context(|| format!("write script {}", path.display()))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+        .with_context(|| format!("chmod {}", path.display()))?;
+    tracing::info!("wrote {} commands to {}", lines.len(), path.display());
+    res
+}
+
+fn render_script(title: &str, lines: &[String]) -> String {
+    let mut out = format!(
+        "#!/bin/sh\n# {title}\n# Recorded by a dry run; secrets are shown as <redacted>.\nset -e\n\n"
+    );
+    for l in lines {
+        out.push_str(l);
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn script_collects_skipped_commands_in_order() {
+        skip("not recorded");
+        let ((), lines) = with_script(|| {
+            skip("zfs snapshot tank/vm-1@pvtools-1");
+            skip("zfs destroy -r tank/vm-1@pvtools-1");
+        });
+        assert_eq!(
+            lines,
+            [
+                "zfs snapshot tank/vm-1@pvtools-1",
+                "zfs destroy -r tank/vm-1@pvtools-1"
+            ]
+        );
+        let script = render_script("backup run", &lines);
+        assert!(script.starts_with("#!/bin/sh\n# backup run\n"));
+        assert!(script.ends_with("zfs destroy -r tank/vm-1@pvtools-1\n"));
+    }
+}
@@ -0,0 +1,89 @@
+use std::{fmt, io};
+
+/// Failure classes automation can tell apart by exit status. Raised with [`Failure::with`],
+/// or attached to an existing error with `.context(Failure::X)`; [`Failure::of`] finds it again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// Some archives, nodes or leftovers failed; the rest went through.
+    Partial,
+    Config,
+    LockBusy,
+    MissingBinary,
+    PbsAuth,
+    StorageFull,
+}
+
+impl Failure {
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Failure::Partial => 2,
+            Failure::Config => 3,
+            Failure::LockBusy => 4,
+            Failure::MissingBinary => 5,
+            Failure::PbsAuth => 6,
+            Failure::StorageFull => 7,
+        }
+    }
+
+    /// An error that reads as `msg`, with this class underneath.
+    pub fn with<M>(self, msg: M) -> anyhow::Error
+    where
+        M: fmt::Display + Send + Sync + 'static,
+    {
+        anyhow::Error::new(self).context(msg)
+    }
+
+    /// The tagged class anywhere in the chain, else one recognized from a full disk.
+    pub fn of(e: &anyhow::Error) -> Option<Self> {
+        if let Some(f) = e.downcast_ref::<Failure>() {
+            return Some(*f);
+        }
+        let full = e.chain().any(|c| {
+            c.downcast_ref::<io::Error>().is_some_and(|io| {
+                io.kind() == io::ErrorKind::StorageFull || io.raw_os_error() == Some(libc::ENOSPC)
+            }) || c.to_string().contains("No space left on device")
+        });
+        full.then_some(Failure::StorageFull)
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Failure::Partial => "partial failure",
+            Failure::Config => "invalid configuration",
+            Failure::LockBusy => "another run is in progress",
+            Failure::MissingBinary => "missing required binaries",
+            Failure::PbsAuth => "PBS authentication failed",
+            Failure::StorageFull => "storage full",
+        })
+    }
+}
+
+impl std::error::Error for Failure {}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    #[test]
+    fn finds_tag_below_later_context() {
+        let e = Failure::LockBusy
+            .with("another run holds lock: /run/pvtools/a.lock")
+            .context("backup run");
+        assert_eq!(Failure::of(&e), Some(Failure::LockBusy));
+        assert_eq!(Failure::of(&e).unwrap().exit_code(), 4);
+        assert_eq!(Failure::of(&anyhow!("zfs create failed")), None);
+    }
+
+    #[test]
+    fn recognizes_full_disks() {
+        let e = anyhow::Error::new(io::Error::from_raw_os_error(libc::ENOSPC)).context("write");
+        assert_eq!(Failure::of(&e), Some(Failure::StorageFull));
+        let e =
+            anyhow!("command failed: dd (status 1): dd: error writing: No space left on device");
+        assert_eq!(Failure::of(&e), Some(Failure::StorageFull));
+    }
+}
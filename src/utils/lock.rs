@@ -1,14 +1,17 @@
 #[cfg(unix)]
 use std::os::unix::fs::OpenOptionsExt;
 use std::{
+    fmt,
     fs::{self, File, OpenOptions},
     io,
     path::{Path, PathBuf},
 };
 
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result};
 use fs2::FileExt;
 
+use crate::utils::failure::Failure;
+
 pub struct LockGuard {
     file: File,
     path: PathBuf,
@@ -36,13 +39,88 @@ impl LockGuard {
         match file.try_lock_exclusive() {
             Ok(()) => Ok(Self { file, path }),
             Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                bail!("another run holds lock: {}", path.display())
+                Err(Failure::LockBusy.with(format!("another run holds lock: {}", path.display())))
             }
             Err(e) => Err(e).with_context(|| format!("flock {}", path.display())),
         }
     }
 }
 
+/// Something a run mutates or depends on. Runs only exclude each other when they share one.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Resource {
+    Repo(String),
+    ZfsPool(String),
+    Vg(String),
+    /// A `[nodes.<name>]` entry, orchestrated over ssh.
+    Node(String),
+    /// A `[backup.sources.external.<name>]` provider.
+    External(String),
+    /// A host written to over ssh, as by an `ssh` restore target.
+    Host(String),
+}
+
+impl Resource {
+    /// Locks the whole pool, so overlapping datasets of one pool serialize.
+    pub fn zfs(dataset: &str) -> Self {
+        let pool = dataset.split('/').next().unwrap_or(dataset);
+        Resource::ZfsPool(pool.to_string())
+    }
+
+    fn lock_name(&self) -> String {
+        let (kind, id) = match self {
+            Resource::Repo(r) => ("repo", r),
+            Resource::ZfsPool(p) => ("zfs", p),
+            Resource::Vg(vg) => ("vg", vg),
+            Resource::Node(n) => ("node", n),
+            Resource::External(e) => ("external", e),
+            Resource::Host(h) => ("host", h),
+        };
+        // Repos look like user@realm!token@host:store; keep separators distinct.
+        let id: String = id
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        format!("pvtools-{kind}-{id}")
+    }
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Resource::Repo(r) => write!(f, "repo {r}"),
+            Resource::ZfsPool(p) => write!(f, "zfs pool {p}"),
+            Resource::Vg(vg) => write!(f, "volume group {vg}"),
+            Resource::Node(n) => write!(f, "node {n}"),
+            Resource::External(e) => write!(f, "external provider {e}"),
+            Resource::Host(h) => write!(f, "host {h}"),
+        }
+    }
+}
+
+/// All-or-nothing set of per-resource locks, released together on drop.
+#[derive(Debug)]
+pub struct LockSet {
+    _guards: Vec<LockGuard>,
+}
+
+impl LockSet {
+    pub fn try_acquire(resources: impl IntoIterator<Item = Resource>) -> Result<Self> {
+        let mut resources: Vec<Resource> = resources.into_iter().collect();
+        resources.sort();
+        resources.dedup();
+
+        let guards = resources
+            .iter()
+            .map(|r| {
+                LockGuard::try_acquire(&r.lock_name())
+                    .with_context(|| format!("{r} is in use by another pvtools run"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { _guards: guards })
+    }
+}
+
 fn ensure_parent_dir(path: &Path) -> Result<()> {
     if let Some(dir) = path.parent()
         && !dir.exists()
@@ -141,6 +219,35 @@ mod tests {
         assert!(err.contains("another run holds lock"), "err was: {err}");
     }
 
+    #[test]
+    fn lock_set_conflicts_only_on_shared_resource() {
+        let sfx = rand_suffix();
+        let repo_a = Resource::Repo(format!("root@pam@a-{sfx}:store"));
+        let repo_b = Resource::Repo(format!("root@pam@b-{sfx}:store"));
+        let pool = Resource::zfs(&format!("tank{sfx}/k8s"));
+
+        let _backup = LockSet::try_acquire([repo_a.clone(), pool.clone()]).unwrap();
+        let _restore = LockSet::try_acquire([repo_b]).expect("other repo is free");
+
+        let repo_c = Resource::Repo(format!("root@pam@c-{sfx}:store"));
+        let err = LockSet::try_acquire([Resource::zfs(&format!("tank{sfx}")), repo_c])
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("zfs pool"), "err was: {err}");
+    }
+
+    #[test]
+    fn lock_set_releases_partial_acquire() {
+        let sfx = rand_suffix();
+        let vg = Resource::Vg(format!("vg{sfx}"));
+        let repo = Resource::Repo(format!("repo{sfx}"));
+
+        let held = LockSet::try_acquire([vg.clone()]).unwrap();
+        assert!(LockSet::try_acquire([repo.clone(), vg.clone(), repo.clone()]).is_err());
+        drop(held);
+        let _all = LockSet::try_acquire([repo, vg]).expect("repo lock was released");
+    }
+
     #[test]
     fn ensure_parent_dir_creates_missing_dirs() {
         let temp = TempDir::new().unwrap();
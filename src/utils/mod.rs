@@ -1,10 +1,16 @@
 pub mod bins;
+pub mod blackout;
 pub mod exec_policy;
+pub mod failure;
 pub mod lock;
 pub mod process;
+pub mod signal;
+pub mod ssh;
 
 pub mod time {
-    use anyhow::{Context, Result, anyhow};
+    use std::time::Duration;
+
+    use anyhow::{Context, Result, anyhow, bail};
     use time::{OffsetDateTime, UtcOffset, format_description::well_known::Rfc3339};
 
     #[inline]
@@ -22,6 +28,40 @@ pub mod time {
         Ok(dt.format(&Rfc3339)?)
     }
 
+    /// Local weekday (Monday = 0) and minute of the day for a unix timestamp.
+    pub fn local_week_minute(ts: u64) -> Result<(usize, u16)> {
+        let t = libc::time_t::try_from(ts).map_err(|_| anyhow!("unix timestamp out of range"))?;
+        // SAFETY: localtime_r only writes into the zeroed tm we hand it.
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        if unsafe { libc::localtime_r(&t, &mut tm) }.is_null() {
+            bail!("localtime_r failed for {ts}");
+        }
+        Ok((
+            ((tm.tm_wday + 6) % 7) as usize,
+            (tm.tm_hour * 60 + tm.tm_min) as u16,
+        ))
+    }
+
+    pub fn parse_duration(s: &str) -> Result<Duration> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (num, unit) = s.split_at(split);
+        let n: u64 = num
+            .parse()
+            .with_context(|| format!("invalid duration '{s}': expected e.g. 90s, 30m, 6h, 2d"))?;
+        let mult = match unit {
+            "" | "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            other => bail!("invalid duration unit '{other}' in '{s}': use s, m, h or d"),
+        };
+        let secs = n
+            .checked_mul(mult)
+            .ok_or_else(|| anyhow!("duration '{s}' is too large"))?;
+        Ok(Duration::from_secs(secs))
+    }
+
     pub fn parse_rfc3339_to_unix(s: &str) -> Result<u64> {
         let dt = OffsetDateTime::parse(s, &Rfc3339)
             .with_context(|| format!("invalid RFC3339 datetime: {s}"))?
@@ -33,10 +73,30 @@ pub mod time {
 
     #[cfg(test)]
     mod tests {
+        use std::time::Duration;
+
         #[test]
         fn epoch_nonzero() {
             assert!(super::current_epoch() > 1_600_000_000);
         }
+
+        #[test]
+        fn parse_duration_units() {
+            assert_eq!(
+                super::parse_duration("90").unwrap(),
+                Duration::from_secs(90)
+            );
+            assert_eq!(
+                super::parse_duration("30m").unwrap(),
+                Duration::from_secs(1800)
+            );
+            assert_eq!(
+                super::parse_duration("2d").unwrap(),
+                Duration::from_secs(172800)
+            );
+            assert!(super::parse_duration("6w").is_err());
+            assert!(super::parse_duration("h").is_err());
+        }
     }
 }
 
@@ -44,8 +104,170 @@ pub mod naming {
     use std::path::Path;
 
     use anyhow::{Result, anyhow, bail};
+    use serde::{Deserialize, Serialize};
 
     const NO_EXT_SENTINEL: &str = "noext";
+    /// Ends the base name of v2 archives, as in `zfs_vm-1-disk-0_abcd1234.v2.img`.
+    const V2_MARKER: &str = ".v2";
+    pub const PVTOOLS_SUFFIX: &str = "pvtools";
+    pub const KNOWN_PROVIDERS: &[&str] = &["zfs", "lvmthin", "lvm"];
+    /// Extension of `zfs send` stream archives, as in `zfs_<leaf>_zsend_<id>.img`.
+    pub const ZFS_SEND_EXT: &str = "zsend";
+
+    /// Dataset leaf of a `zfs send` stream archive, from its parsed leaf; `None` for images.
+    pub fn send_stream_leaf(leaf: &str) -> Option<&str> {
+        leaf.strip_suffix(ZFS_SEND_EXT)?.strip_suffix('.')
+    }
+
+    /// VM ID of a PVE VM disk name such as `vm-100-disk-0` or `base-100-disk-0.qcow2`.
+    pub fn pve_vmid(disk: &str) -> Option<u32> {
+        let rest = disk
+            .strip_prefix("vm-")
+            .or_else(|| disk.strip_prefix("base-"))?;
+        let (id, _) = rest.split_once('-')?;
+        if id.is_empty() || !id.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        id.parse().ok()
+    }
+
+    /// Variables of a restore target's `name_template`.
+    pub const NAME_TEMPLATE_VARS: &[&str] = &["leaf", "provider", "id", "date", "time", "ts"];
+
+    /// Fills in a restore target's `name_template` for an archive: `{leaf}`, `{provider}` and
+    /// `{id}` of its name, and the snapshot time `ts` as `{date}` (YYYYMMDD, UTC), `{time}`
+    /// (HHMMSS, UTC) or `{ts}` (epoch).
+    pub fn render_name_template(
+        template: &str,
+        provider: &str,
+        leaf: &str,
+        id: &str,
+        ts: u64,
+    ) -> Result<String> {
+        let dt = i64::try_from(ts)
+            .ok()
+            .and_then(|t| time::OffsetDateTime::from_unix_timestamp(t).ok())
+            .ok_or_else(|| anyhow!("snapshot time {ts} out of range"))?;
+        let mut out = String::with_capacity(template.len() + leaf.len());
+        let mut rest = template;
+        while let Some(open) = rest.find('{') {
+            out.push_str(&rest[..open]);
+            let close = rest[open..]
+                .find('}')
+                .ok_or_else(|| anyhow!("unclosed '{{' in name template '{template}'"))?;
+            let var = &rest[open + 1..open + close];
+            match var {
+                "leaf" => out.push_str(leaf),
+                "provider" => out.push_str(provider),
+                "id" => out.push_str(id),
+                "date" => out.push_str(&format!(
+                    "{:04}{:02}{:02}",
+                    dt.year(),
+                    u8::from(dt.month()),
+                    dt.day()
+                )),
+                "time" => out.push_str(&format!(
+                    "{:02}{:02}{:02}",
+                    dt.hour(),
+                    dt.minute(),
+                    dt.second()
+                )),
+                "ts" => out.push_str(&ts.to_string()),
+                other => bail!(
+                    "unknown variable {{{other}}} in name template '{template}'; use {}",
+                    NAME_TEMPLATE_VARS
+                        .iter()
+                        .map(|v| format!("{{{v}}}"))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            }
+            rest = &rest[open + close + 1..];
+        }
+        out.push_str(rest);
+        if out.is_empty() || out.contains('/') || out.contains('}') {
+            bail!("name template '{template}' gives the invalid name '{out}'");
+        }
+        Ok(out)
+    }
+
+    pub fn prerestore_suffix(ts: u64) -> String {
+        format!("{PVTOOLS_SUFFIX}-prerestore-{ts}")
+    }
+
+    /// Creation time encoded in a `<name>-pvtools-<ts>` clone or `<ds>@pvtools-<ts>` snapshot.
+    pub fn pvtools_leftover_ts(name: &str) -> Option<u64> {
+        let (head, ts) = name.rsplit_once('-')?;
+        if ts.is_empty() || !ts.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let head = head.strip_suffix(PVTOOLS_SUFFIX)?;
+        if !(head.ends_with('-') || head.ends_with('@')) || head.len() < 2 {
+            return None;
+        }
+        ts.parse().ok()
+    }
+    /// Format of the archive names a backup writes; [`parse_archive_name`] reads both.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum NameScheme {
+        /// `<provider>_<stem>_<ext>_<id>.img`
+        #[default]
+        V1,
+        /// `<provider>_<leaf>_<id>.v2.img`, with every `_` of the leaf doubled.
+        V2,
+    }
+
+    impl NameScheme {
+        pub fn archive_name(self, provider: &str, leaf: &str, id: &str) -> Result<String> {
+            match self {
+                NameScheme::V1 => create_archive_name(provider, leaf, id),
+                NameScheme::V2 => create_archive_name_v2(provider, leaf, id),
+            }
+        }
+    }
+
+    /// v2 keeps the leaf whole, so leaves like `vm_1_raw` or `a.b_c` round-trip exactly.
+    pub fn create_archive_name_v2(provider: &str, leaf: &str, id: &str) -> Result<String> {
+        if provider.is_empty() || provider.contains('_') {
+            bail!("invalid provider name for an archive: {provider}");
+        }
+        if leaf.is_empty() || leaf.contains('/') {
+            bail!("invalid leaf: {leaf}");
+        }
+        if id.is_empty() || !id.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            bail!("invalid archive id: {id}");
+        }
+        Ok(format!(
+            "{provider}_{}_{id}{V2_MARKER}.img",
+            leaf.replace('_', "__")
+        ))
+    }
+
+    fn parse_archive_name_v2(name: &str, base: &str) -> Result<(String, String, String)> {
+        let (provider, rest) = base
+            .split_once('_')
+            .ok_or_else(|| anyhow!("invalid archive name: {name}"))?;
+        let (escaped, id) = rest
+            .rsplit_once('_')
+            .ok_or_else(|| anyhow!("invalid archive name: {name}"))?;
+        if provider.is_empty() || escaped.is_empty() || id.is_empty() {
+            bail!("invalid archive name: {name}");
+        }
+        if !id.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            bail!("invalid archive id in {name}");
+        }
+        let mut leaf = String::with_capacity(escaped.len());
+        let mut chars = escaped.chars();
+        while let Some(c) = chars.next() {
+            if c == '_' && chars.next() != Some('_') {
+                bail!("invalid archive name (unescaped '_'): {name}");
+            }
+            leaf.push(c);
+        }
+        Ok((provider.to_string(), leaf, id.to_string()))
+    }
+
     pub fn create_archive_name(provider: &str, leaf: &str, id: &str) -> Result<String> {
         let path = Path::new(leaf);
 
@@ -63,21 +285,25 @@ pub mod naming {
     }
 
     pub fn parse_archive_name(name: &str) -> Result<(String, String, String)> {
-        let mut base = name;
-        if base.ends_with(".fidx") {
-            base = &base[..base.len() - 5];
-        }
-        if base.ends_with(".img") {
-            base = &base[..base.len() - 4];
+        let base = name.strip_suffix(".fidx").unwrap_or(name);
+        let Some(base) = base.strip_suffix(".img") else {
+            bail!("invalid archive name (not an .img): {name}");
+        };
+        if let Some(v2) = base.strip_suffix(V2_MARKER) {
+            return parse_archive_name_v2(name, v2);
         }
 
         let parts: Vec<&str> = base.split('_').collect();
-        if parts.len() < 4 {
+        if parts.len() < 4 || parts.iter().any(|p| p.is_empty()) {
             bail!("invalid archive name: {name}");
         }
 
         let provider = parts[0].to_string();
-        let id = parts.last().unwrap().to_string();
+        let id = parts[parts.len() - 1];
+        if !id.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            bail!("invalid archive id in {name}");
+        }
+        let id = id.to_string();
         let ext = parts[parts.len() - 2];
         let stem = parts[1..parts.len() - 2].join("_");
 
@@ -128,6 +354,22 @@ pub mod naming {
             assert_eq!(id, "cafebabe");
         }
 
+        #[test]
+        fn pve_vmid_of_disk_names() {
+            assert_eq!(pve_vmid("vm-100-disk-0"), Some(100));
+            assert_eq!(pve_vmid("base-101-disk-1.qcow2"), Some(101));
+            assert_eq!(pve_vmid("vm-9999-pvc-data.raw"), Some(9999));
+            for bad in [
+                "vm-100",
+                "vm--disk-0",
+                "vm-1a-disk-0",
+                "subvol-100-disk-0",
+                "data",
+            ] {
+                assert_eq!(pve_vmid(bad), None, "{bad}");
+            }
+        }
+
         #[test]
         fn parse_fidx() {
             let archive = "zfs_vm-1000-data_raw_12345678.img.fidx";
@@ -147,6 +389,91 @@ pub mod naming {
             assert_eq!(id, "deadbeef");
         }
 
+        #[test]
+        fn parse_rejects_malformed_names() {
+            for bad in [
+                "client.log.blob",
+                "zfs_vm-1_raw_abcd.didx",
+                "zfs__raw_abcd.img",
+                "zfs_vm-1_raw_.img",
+                "zfs_vm-1_raw_ab/cd.img",
+                "zfs_raw_abcd.img",
+                "_vm-1_raw_abcd.img.fidx",
+            ] {
+                assert!(parse_archive_name(bad).is_err(), "{bad} should not parse");
+            }
+        }
+
+        #[test]
+        fn leftover_ts_matches_pvtools_names_only() {
+            assert_eq!(
+                pvtools_leftover_ts("tank/vm-1-disk-0@pvtools-1700000000"),
+                Some(1700000000)
+            );
+            assert_eq!(
+                pvtools_leftover_ts("tank/vm-1-disk-0-pvtools-1700000000"),
+                Some(1700000000)
+            );
+            assert_eq!(pvtools_leftover_ts("vm-1-disk-0-pvtools-12"), Some(12));
+            assert_eq!(pvtools_leftover_ts("tank/vm-1-disk-0@autosnap-1"), None);
+            assert_eq!(pvtools_leftover_ts("vm-1-pvtools-abc"), None);
+            assert_eq!(pvtools_leftover_ts("pvtools-123"), None);
+            assert_eq!(
+                pvtools_leftover_ts(&format!("tank/vm-1@{}", prerestore_suffix(5))),
+                None
+            );
+        }
+
+        #[test]
+        fn v2_roundtrips_leaves_v1_mangles() {
+            for leaf in ["vm_1_raw", "vm.1_raw", "_x_", "a.noext", "vm-1-disk-0"] {
+                let archive = NameScheme::V2
+                    .archive_name("zfs", leaf, "abcd1234")
+                    .unwrap();
+                let (prov, parsed, id) = parse_archive_name(&archive).unwrap();
+                assert_eq!(
+                    (prov.as_str(), parsed.as_str(), id.as_str()),
+                    ("zfs", leaf, "abcd1234")
+                );
+            }
+            assert_eq!(
+                create_archive_name_v2("lvm", "vm_1_raw", "ab12").unwrap(),
+                "lvm_vm__1__raw_ab12.v2.img"
+            );
+            let v1 = create_archive_name("zfs", "vm.1_raw", "abcd1234").unwrap();
+            assert_ne!(parse_archive_name(&v1).unwrap().1, "vm.1_raw");
+
+            for bad in ["zfs_vm_1_ab12.v2.img", "zfs__ab12.v2.img", "zfs_vm_.v2.img"] {
+                assert!(parse_archive_name(bad).is_err(), "{bad} should not parse");
+            }
+            assert!(create_archive_name_v2("my_san", "a", "1").is_err());
+        }
+
+        #[test]
+        fn renders_name_templates() {
+            assert_eq!(
+                render_name_template(
+                    "restored-{leaf}-{date}",
+                    "zfs",
+                    "vm-1-disk-0",
+                    "ab12",
+                    1_757_017_516
+                )
+                .unwrap(),
+                "restored-vm-1-disk-0-20250904"
+            );
+            assert_eq!(
+                render_name_template("{provider}-{id}-{time}-{ts}", "lvm", "x", "ab12", 0).unwrap(),
+                "lvm-ab12-000000-0"
+            );
+            for bad in ["{leaf", "{disk}", "{leaf}/x", ""] {
+                assert!(
+                    render_name_template(bad, "zfs", "x", "1", 0).is_err(),
+                    "{bad} should not render"
+                );
+            }
+        }
+
         #[test]
         fn roundtrip_with_underscores_in_leaf() {
             let archive = create_archive_name("zfs", "vm_100-backup.v1.raw", "abcd1234").unwrap();
@@ -1,12 +1,19 @@
 use std::{
+    cell::Cell,
     collections::HashMap,
+    fmt,
+    io::{self, Read},
     path::PathBuf,
-    process::{Child, Command, Stdio},
+    process::{Child, ChildStdout, Command, ExitStatus, Stdio},
+    str::FromStr,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, Result, anyhow, bail};
 
-use crate::utils::exec_policy;
+use crate::utils::{exec_policy, signal};
 
 #[derive(Clone, Debug)]
 pub enum EnvValue {
@@ -19,19 +26,86 @@ pub enum StdioSpec {
     Inherit,
     Null,
     Pipe,
+    /// For stderr: into the stdout pipe of `run_stream`, so the sink sees both. Inherited
+    /// by the other runs.
+    Stdout,
 }
 
 impl StdioSpec {
     #[inline]
     fn to_stdio(&self) -> Stdio {
         match self {
-            StdioSpec::Inherit => Stdio::inherit(),
+            StdioSpec::Inherit | StdioSpec::Stdout => Stdio::inherit(),
             StdioSpec::Null => Stdio::null(),
             StdioSpec::Pipe => Stdio::piped(),
         }
     }
 }
 
+/// `ionice` scheduling class.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoClass {
+    Idle,
+    /// Level 0 (highest) to 7.
+    BestEffort(u8),
+}
+
+impl FromStr for IoClass {
+    type Err = anyhow::Error;
+
+    /// `idle`, or `best-effort` with an optional `:<0-7>` level (default 7).
+    fn from_str(s: &str) -> Result<Self> {
+        let (class, level) = match s.split_once(':') {
+            Some((c, l)) => (c, Some(l)),
+            None => (s, None),
+        };
+        match (class, level) {
+            ("idle", None) => Ok(Self::Idle),
+            ("best-effort", None) => Ok(Self::BestEffort(7)),
+            ("best-effort", Some(l)) => match l.parse::<u8>() {
+                Ok(n) if n <= 7 => Ok(Self::BestEffort(n)),
+                _ => bail!("best-effort level must be 0..=7, got '{l}'"),
+            },
+            _ => bail!("expected 'idle' or 'best-effort[:0-7]', got '{s}'"),
+        }
+    }
+}
+
+impl fmt::Display for IoClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Idle => f.write_str("idle"),
+            Self::BestEffort(n) => write!(f, "best-effort:{n}"),
+        }
+    }
+}
+
+/// CPU and IO scheduling for a command, applied by starting it under `ionice`/`nice`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Priority {
+    pub io: Option<IoClass>,
+    /// `nice` adjustment, -20..=19.
+    pub nice: Option<i8>,
+}
+
+impl Priority {
+    /// Wrapper argv the command is appended to; empty when nothing is set.
+    fn wrapper(&self) -> Vec<String> {
+        let mut out = Vec::new();
+        match self.io {
+            Some(IoClass::Idle) => out.extend(["ionice".into(), "-c3".into()]),
+            Some(IoClass::BestEffort(n)) => {
+                out.extend(["ionice".into(), "-c2".into(), "-n".into(), n.to_string()])
+            }
+            None => {}
+        }
+        if let Some(n) = self.nice {
+            out.extend(["nice".into(), "-n".into(), n.to_string()]);
+        }
+        out
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct CmdSpec {
     program: String,
@@ -41,6 +115,9 @@ pub struct CmdSpec {
     stdout: StdioSpec,
     stderr: StdioSpec,
     cwd: Option<PathBuf>,
+    timeout: Option<Duration>,
+    step_timeout: Option<Duration>,
+    priority: Priority,
 }
 
 impl CmdSpec {
@@ -54,6 +131,9 @@ impl CmdSpec {
             stdout: StdioSpec::Inherit,
             stderr: StdioSpec::Inherit,
             cwd: None,
+            timeout: None,
+            step_timeout: None,
+            priority: Priority::default(),
         }
     }
 
@@ -111,8 +191,34 @@ impl CmdSpec {
         self
     }
 
+    #[must_use]
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Under `run_stream`, ends the command once `timeout` passes without the sink calling
+    /// [`step_done`], e.g. per archive of a multi-archive upload.
+    #[must_use]
+    pub fn with_step_timeout(mut self, timeout: Duration) -> Self {
+        self.step_timeout = Some(timeout);
+        self
+    }
+
+    #[must_use]
+    pub fn priority(mut self, p: Priority) -> Self {
+        self.priority = p;
+        self
+    }
+
     pub fn render(&self) -> String {
-        let prog = sh_quote(&self.program);
+        let mut words = self.priority.wrapper();
+        words.push(self.program.clone());
+        let prog = words
+            .iter()
+            .map(|w| sh_quote(w))
+            .collect::<Vec<_>>()
+            .join(" ");
         let args: Vec<String> = self.args.iter().map(|a| sh_quote(a)).collect();
         let mut env_prefix = String::new();
         for (k, v) in &self.envs {
@@ -123,8 +229,41 @@ impl CmdSpec {
         }
         format!("{}{} {}", env_prefix, prog, args.join(" "))
     }
+    /// Shell form with real env values and `/dev/null` redirects, for running via a remote shell.
+    pub(crate) fn to_shell(&self, first: bool, last: bool) -> String {
+        let mut parts: Vec<String> = self
+            .envs
+            .iter()
+            .map(|(k, v)| match v {
+                EnvValue::Plain(val) | EnvValue::Secret(val) => format!("{k}={}", sh_quote(val)),
+            })
+            .collect();
+        parts.extend(self.priority.wrapper().iter().map(|w| sh_quote(w)));
+        parts.push(sh_quote(&self.program));
+        parts.extend(self.args.iter().map(|a| sh_quote(a)));
+        if first {
+            parts.push("</dev/null".into());
+        }
+        if last && matches!(self.stdout, StdioSpec::Null) {
+            parts.push(">/dev/null".into());
+        }
+        match self.stderr {
+            StdioSpec::Null => parts.push("2>/dev/null".into()),
+            StdioSpec::Stdout if last => parts.push("2>&1".into()),
+            _ => {}
+        }
+        parts.join(" ")
+    }
+
     fn to_command(&self, bin: &str) -> Command {
-        let mut cmd = Command::new(bin);
+        let mut cmd = match self.priority.wrapper().split_first() {
+            Some((wrapper, wargs)) => {
+                let mut c = Command::new(wrapper);
+                c.args(wargs).arg(bin);
+                c
+            }
+            None => Command::new(bin),
+        };
         cmd.args(&self.args);
         for (k, v) in &self.envs {
             match v {
@@ -165,6 +304,33 @@ impl Pipeline {
         self.cmds.is_empty()
     }
 
+    /// Shortest timeout of any stage; the whole pipeline is bounded by it.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.cmds.iter().filter_map(|c| c.timeout).min()
+    }
+
+    /// Shortest step timeout of any stage.
+    pub fn step_timeout(&self) -> Option<Duration> {
+        self.cmds.iter().filter_map(|c| c.step_timeout).min()
+    }
+
+    /// Whether any stage asked for its stderr to be captured into the error on failure.
+    pub(crate) fn pipes_stderr(&self) -> bool {
+        self.cmds
+            .iter()
+            .any(|c| matches!(c.stderr, StdioSpec::Pipe))
+    }
+
+    pub(crate) fn to_shell(&self) -> String {
+        let n = self.cmds.len();
+        self.cmds
+            .iter()
+            .enumerate()
+            .map(|(i, c)| c.to_shell(i == 0, i + 1 == n))
+            .collect::<Vec<_>>()
+            .join(" | ")
+    }
+
     pub fn render(&self) -> String {
         self.cmds
             .iter()
@@ -172,11 +338,41 @@ impl Pipeline {
             .collect::<Vec<_>>()
             .join(" | ")
     }
+
+    /// The tool whose log target the pipeline's commands are logged under, see [`debug_exec`].
+    pub(crate) fn tool(&self) -> Option<&'static str> {
+        self.cmds.iter().find_map(|c| match c.program.as_str() {
+            "proxmox-backup-client" => Some("pbs"),
+            "zfs" | "zpool" => Some("zfs"),
+            p if p.starts_with("lv") || p.starts_with("vg") => Some("lvm"),
+            _ => None,
+        })
+    }
 }
 
+/// Logs a command line at debug level. PBS, zfs and lvm commands go to the targets
+/// `pvtools::utils::process::{pbs,zfs,lvm}`, so `[log] filters` can trace one tool alone;
+/// filters on `pvtools::utils::process` still cover them.
+macro_rules! debug_exec {
+    ($pipeline:expr, $($arg:tt)+) => {
+        match $pipeline.tool() {
+            Some("pbs") => tracing::debug!(target: "pvtools::utils::process::pbs", $($arg)+),
+            Some("zfs") => tracing::debug!(target: "pvtools::utils::process::zfs", $($arg)+),
+            Some("lvm") => tracing::debug!(target: "pvtools::utils::process::lvm", $($arg)+),
+            _ => tracing::debug!(target: "pvtools::utils::process", $($arg)+),
+        }
+    };
+}
+pub(crate) use debug_exec;
+
+/// Consumer of a command's stdout while the command runs.
+pub type StreamSink<'a> = dyn FnMut(&mut dyn Read) -> Result<()> + 'a;
+
 pub trait Runner: Send + Sync {
     fn run(&self, pipeline: &Pipeline) -> Result<()>;
     fn run_capture(&self, pipeline: &Pipeline) -> Result<String>;
+    /// Hands stdout to `sink` instead of buffering it; a failing sink terminates the command.
+    fn run_stream(&self, pipeline: &Pipeline, sink: &mut StreamSink<'_>) -> Result<()>;
 }
 
 #[derive(Default, Clone)]
@@ -202,10 +398,11 @@ impl ProcessRunner {
 impl Runner for ProcessRunner {
     fn run(&self, pipeline: &Pipeline) -> Result<()> {
         if exec_policy::is_dry_run() {
-            tracing::info!("[DRY-RUN] {}", pipeline.render());
+            exec_policy::skip(&pipeline.render());
             return Ok(());
         }
-        tracing::debug!("exec: {}", pipeline.render());
+        signal::check()?;
+        debug_exec!(pipeline, "exec: {}", pipeline.render());
 
         let n = pipeline.len();
         if n == 0 {
@@ -251,10 +448,9 @@ impl Runner for ProcessRunner {
             children.push(child);
         }
 
-        for (i, mut child) in children.into_iter().enumerate() {
-            let status = child
-                .wait()
-                .with_context(|| format!("wait for stage {}: {}", i, pipeline.render()))?;
+        let statuses = wait_all(&mut children, pipeline.timeout())
+            .with_context(|| format!("wait for {}", pipeline.render()))?;
+        for status in statuses {
             if !status.success() {
                 bail!("command failed: {} with {status}", pipeline.render());
             }
@@ -263,7 +459,8 @@ impl Runner for ProcessRunner {
     }
 
     fn run_capture(&self, pipeline: &Pipeline) -> Result<String> {
-        tracing::debug!("exec(capture): {}", pipeline.render());
+        signal::check()?;
+        debug_exec!(pipeline, "exec(capture): {}", pipeline.render());
 
         if pipeline.len() != 1 {
             bail!(
@@ -279,24 +476,295 @@ impl Runner for ProcessRunner {
         cmd.stderr(spec.stderr.to_stdio());
         cmd.stdin(spec.stdin.to_stdio());
 
-        let out = cmd
-            .output()
+        let mut child = cmd
+            .spawn()
             .with_context(|| format!("run {}", spec.render()))?;
-        if out.status.success() {
-            Ok(String::from_utf8_lossy(&out.stdout).to_string())
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| anyhow!("stdout piping not available"))?;
+        let reader = drain(stdout);
+        let stderr = child.stderr.take().map(drain);
+
+        let status = wait_all(std::slice::from_mut(&mut child), spec.timeout)
+            .with_context(|| format!("run {}", spec.render()))?
+            .remove(0);
+        let out = reader
+            .join()
+            .map_err(|_| anyhow!("stdout reader panicked"))?
+            .with_context(|| format!("read stdout of {}", spec.render()))?;
+
+        if status.success() {
+            Ok(String::from_utf8_lossy(&out).to_string())
+        } else {
+            bail!(
+                "command failed: {} (status {}){}",
+                spec.render(),
+                status,
+                stderr_suffix(stderr)
+            );
+        }
+    }
+
+    fn run_stream(&self, pipeline: &Pipeline, sink: &mut StreamSink<'_>) -> Result<()> {
+        if exec_policy::is_dry_run() {
+            exec_policy::skip(&pipeline.render());
+            return Ok(());
+        }
+        signal::check()?;
+        debug_exec!(pipeline, "exec(stream): {}", pipeline.render());
+
+        if pipeline.len() != 1 {
+            bail!(
+                "stream only works with single command, got {}",
+                pipeline.len()
+            );
+        }
+        let spec = &pipeline.cmds[0];
+        let bin = self.resolve_bin(&spec.program);
+        let mut cmd = spec.to_command(bin);
+        cmd.stdin(spec.stdin.to_stdio());
+
+        let status = if matches!(spec.stderr, StdioSpec::Stdout) {
+            let (reader, writer) = io::pipe().context("create output pipe")?;
+            cmd.stdout(writer.try_clone().context("clone output pipe")?);
+            cmd.stderr(writer);
+            let mut child = cmd
+                .spawn()
+                .with_context(|| format!("run {}", spec.render()))?;
+            // Our copies of the write end must go, or the reader never sees EOF.
+            drop(cmd);
+            stream_output(&mut child, reader, sink, spec.timeout, spec.step_timeout)
         } else {
-            bail!("command failed: {} (status {})", spec.render(), out.status);
+            cmd.stdout(Stdio::piped());
+            cmd.stderr(spec.stderr.to_stdio());
+            let mut child = cmd
+                .spawn()
+                .with_context(|| format!("run {}", spec.render()))?;
+            stream_child(&mut child, sink, spec.timeout, spec.step_timeout)
+        }
+        .with_context(|| format!("run {}", spec.render()))?;
+        if !status.success() {
+            bail!("command failed: {} (status {})", spec.render(), status);
         }
+        Ok(())
     }
 }
 
-fn sh_quote(s: &str) -> String {
+/// Feeds the child's stdout to `sink`, then waits for it. The pipe is closed before waiting
+/// so a sink that stops early cannot leave the child blocked on a full pipe.
+pub(crate) fn stream_child(
+    child: &mut Child,
+    sink: &mut StreamSink<'_>,
+    timeout: Option<Duration>,
+    step: Option<Duration>,
+) -> Result<ExitStatus> {
+    let stdout: ChildStdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| anyhow!("stdout piping not available"))?;
+    stream_output(child, stdout, sink, timeout, step)
+}
+
+fn stream_output<R: Read>(
+    child: &mut Child,
+    mut out: R,
+    sink: &mut StreamSink<'_>,
+    timeout: Option<Duration>,
+    step: Option<Duration>,
+) -> Result<ExitStatus> {
+    let watchdog = Watchdog::start(child.id(), timeout, step);
+    let outer = STEP.replace(watchdog.as_ref().map(|w| w.tx.clone()));
+    let res = sink(&mut out);
+    STEP.set(outer);
+    drop(out);
+    match watchdog.and_then(Watchdog::fired) {
+        Some(Fired::Total(t)) => {
+            terminate(std::slice::from_mut(child));
+            bail!("timed out after {}s", t.as_secs_f32());
+        }
+        Some(Fired::Step(t)) => {
+            terminate(std::slice::from_mut(child));
+            return Err(StepTimedOut(t).into());
+        }
+        None => {}
+    }
+    if let Err(e) = res {
+        terminate(std::slice::from_mut(child));
+        return Err(e);
+    }
+    Ok(wait_all(std::slice::from_mut(child), timeout)?.remove(0))
+}
+
+/// A streamed command ran past its step timeout; see [`CmdSpec::with_step_timeout`].
+#[derive(Debug)]
+pub struct StepTimedOut(pub Duration);
+
+impl fmt::Display for StepTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no step finished within {}s", self.0.as_secs_f32())
+    }
+}
+
+impl std::error::Error for StepTimedOut {}
+
+thread_local! {
+    /// Watchdog of the stream whose sink runs on this thread.
+    static STEP: Cell<Option<mpsc::Sender<Tick>>> = const { Cell::new(None) };
+}
+
+/// Restarts the step clock of the stream the calling sink reads.
+pub fn step_done() {
+    let tx = STEP.take();
+    if let Some(tx) = &tx {
+        let _ = tx.send(Tick::Step);
+    }
+    STEP.set(tx);
+}
+
+enum Tick {
+    Step,
+    Stop,
+}
+
+enum Fired {
+    Total(Duration),
+    Step(Duration),
+}
+
+/// Sends SIGTERM to `pid` once `timeout` passes, or `step` passes without a [`step_done`],
+/// unless stopped first: a sink blocked on the child's output cannot watch the deadline itself.
+struct Watchdog {
+    tx: mpsc::Sender<Tick>,
+    handle: thread::JoinHandle<Option<Fired>>,
+}
+
+impl Watchdog {
+    fn start(pid: u32, timeout: Option<Duration>, step: Option<Duration>) -> Option<Self> {
+        if timeout.is_none() && step.is_none() {
+            return None;
+        }
+        let (tx, rx) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let started = Instant::now();
+            let mut step_started = started;
+            loop {
+                let total_end = timeout.map(|t| started + t);
+                let step_end = step.map(|t| step_started + t);
+                let end = total_end.into_iter().chain(step_end).min()?;
+                match rx.recv_timeout(end.saturating_duration_since(Instant::now())) {
+                    Ok(Tick::Step) => step_started = Instant::now(),
+                    Ok(Tick::Stop) | Err(mpsc::RecvTimeoutError::Disconnected) => return None,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        sigterm_pid(pid);
+                        return Some(if Some(end) == total_end {
+                            Fired::Total(timeout?)
+                        } else {
+                            Fired::Step(step?)
+                        });
+                    }
+                }
+            }
+        });
+        Some(Self { tx, handle })
+    }
+
+    /// Stops the watchdog; which limit it had already terminated the child for, if any.
+    fn fired(self) -> Option<Fired> {
+        let _ = self.tx.send(Tick::Stop);
+        self.handle.join().ok().flatten()
+    }
+}
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const TERM_GRACE: Duration = Duration::from_secs(5);
+
+pub(crate) fn wait_all(
+    children: &mut [Child],
+    timeout: Option<Duration>,
+) -> Result<Vec<ExitStatus>> {
+    let deadline = timeout.map(|t| Instant::now() + t);
+    let mut statuses: Vec<Option<ExitStatus>> = vec![None; children.len()];
+    loop {
+        for (c, st) in children.iter_mut().zip(statuses.iter_mut()) {
+            if st.is_none() {
+                *st = c.try_wait()?;
+            }
+        }
+        if statuses.iter().all(Option::is_some) {
+            return Ok(statuses.into_iter().flatten().collect());
+        }
+        if let Err(e) = signal::check() {
+            terminate(children);
+            return Err(e);
+        }
+        if let (Some(deadline), Some(timeout)) = (deadline, timeout)
+            && Instant::now() >= deadline
+        {
+            terminate(children);
+            bail!("timed out after {}s", timeout.as_secs_f32());
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// SIGTERM every still-running child, then SIGKILL whatever survives the grace period.
+fn terminate(children: &mut [Child]) {
+    for c in children.iter_mut() {
+        if let Ok(None) = c.try_wait() {
+            send_sigterm(c);
+        }
+    }
+
+    let deadline = Instant::now() + TERM_GRACE;
+    while Instant::now() < deadline {
+        if children
+            .iter_mut()
+            .all(|c| !matches!(c.try_wait(), Ok(None)))
+        {
+            return;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    for c in children.iter_mut() {
+        if let Ok(None) = c.try_wait() {
+            tracing::warn!("pid {} ignored SIGTERM, killing", c.id());
+            let _ = c.kill();
+            let _ = c.wait();
+        }
+    }
+}
+
+#[cfg(unix)]
+fn send_sigterm(c: &mut Child) {
+    sigterm_pid(c.id());
+}
+
+#[cfg(unix)]
+fn sigterm_pid(pid: u32) {
+    if let Ok(pid) = libc::pid_t::try_from(pid) {
+        // SAFETY: plain kill(2) on a pid we spawned and have not reaped yet.
+        unsafe {
+            libc::kill(pid, libc::SIGTERM);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn send_sigterm(c: &mut Child) {
+    let _ = c.kill();
+}
+
+#[cfg(not(unix))]
+fn sigterm_pid(_pid: u32) {}
+
+pub(crate) fn sh_quote(s: &str) -> String {
     if s.is_empty() {
         return "''".into();
     }
-    if !s
-        .bytes()
-        .any(|b| b == b' ' || b == b'\'' || b == b'"' || b == b'\\')
+    if s.bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b"_-./=:,@%+".contains(&b))
     {
         return s.to_string();
     }
@@ -312,6 +780,94 @@ fn sh_quote(s: &str) -> String {
     out
 }
 
+/// Reads a piped child stream to the end on its own thread.
+pub(crate) fn drain<R: Read + Send + 'static>(mut r: R) -> thread::JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        r.read_to_end(&mut buf)?;
+        Ok(buf)
+    })
+}
+
+/// `": <last stderr lines>"` for a failed command whose stderr was captured, else empty.
+pub(crate) fn stderr_suffix(stderr: Option<thread::JoinHandle<io::Result<Vec<u8>>>>) -> String {
+    let Some(Ok(Ok(buf))) = stderr.map(|h| h.join()) else {
+        return String::new();
+    };
+    let text = String::from_utf8_lossy(&buf);
+    let lines: Vec<&str> = text
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .collect();
+    match lines.len() {
+        0 => String::new(),
+        n => format!(": {}", lines[n.saturating_sub(3)..].join(" / ")),
+    }
+}
+
+/// [`Runner`] double for tests: records every pipeline as [`Pipeline::render`] shows it,
+/// secrets redacted, and answers the first scripted pattern found in that rendering.
+/// Unscripted commands succeed with empty output.
+#[cfg(any(test, feature = "test-support"))]
+#[derive(Default)]
+pub struct ScriptedRunner {
+    replies: Vec<(String, std::result::Result<String, String>)>,
+    calls: std::sync::Mutex<Vec<String>>,
+}
+
+#[cfg(any(test, feature = "test-support"))]
+impl ScriptedRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answers pipelines whose rendering contains `pattern` with `stdout`.
+    #[must_use]
+    pub fn on(mut self, pattern: impl Into<String>, stdout: impl Into<String>) -> Self {
+        self.replies.push((pattern.into(), Ok(stdout.into())));
+        self
+    }
+
+    /// Fails pipelines whose rendering contains `pattern` with `message`.
+    #[must_use]
+    pub fn fail(mut self, pattern: impl Into<String>, message: impl Into<String>) -> Self {
+        self.replies.push((pattern.into(), Err(message.into())));
+        self
+    }
+
+    /// Renderings of every pipeline run so far, in order.
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    fn answer(&self, pipeline: &Pipeline) -> Result<String> {
+        let cmd = pipeline.render();
+        self.calls.lock().unwrap().push(cmd.clone());
+        match self.replies.iter().find(|(p, _)| cmd.contains(p.as_str())) {
+            Some((_, Ok(out))) => Ok(out.clone()),
+            Some((_, Err(msg))) => bail!("command failed: {cmd}: {msg}"),
+            None => Ok(String::new()),
+        }
+    }
+}
+
+#[cfg(any(test, feature = "test-support"))]
+impl Runner for ScriptedRunner {
+    fn run(&self, pipeline: &Pipeline) -> Result<()> {
+        self.answer(pipeline).map(drop)
+    }
+
+    fn run_capture(&self, pipeline: &Pipeline) -> Result<String> {
+        self.answer(pipeline)
+    }
+
+    fn run_stream(&self, pipeline: &Pipeline, sink: &mut StreamSink<'_>) -> Result<()> {
+        let out = self.answer(pipeline)?;
+        sink(&mut out.as_bytes())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -336,6 +892,29 @@ mod tests {
         assert_eq!(sh_quote("don't"), "'don'\\''t'");
     }
 
+    #[test]
+    fn sh_quote_shell_metachars() {
+        assert_eq!(sh_quote("a;rm -rf /"), "'a;rm -rf /'");
+        assert_eq!(sh_quote("$HOME"), "'$HOME'");
+        assert_eq!(sh_quote("of=/dev/zvol/tank/vm-1"), "of=/dev/zvol/tank/vm-1");
+    }
+
+    #[test]
+    fn pipeline_to_shell_keeps_secrets_and_redirects() {
+        let pipeline = Pipeline::new()
+            .cmd(
+                CmdSpec::new("pbs")
+                    .arg("restore")
+                    .env("PBS_PASSWORD", EnvValue::Secret("p w".into()))
+                    .stderr(StdioSpec::Null),
+            )
+            .cmd(CmdSpec::new("dd").arg("of=/dev/x").stdout(StdioSpec::Null));
+        assert_eq!(
+            pipeline.to_shell(),
+            "PBS_PASSWORD='p w' pbs restore </dev/null 2>/dev/null | dd of=/dev/x >/dev/null"
+        );
+    }
+
     #[test]
     fn cmd_spec_render() {
         let cmd = CmdSpec::new("ls").arg("-l").arg("file name");
@@ -350,6 +929,79 @@ mod tests {
         assert_eq!(cmd.render(), "VAR=value SECRET=<redacted> cmd ");
     }
 
+    #[test]
+    fn priority_wraps_the_command() {
+        assert_eq!("idle".parse::<IoClass>().unwrap(), IoClass::Idle);
+        assert_eq!(
+            "best-effort:2".parse::<IoClass>().unwrap(),
+            IoClass::BestEffort(2)
+        );
+        assert!("best-effort:8".parse::<IoClass>().is_err());
+        assert!("realtime".parse::<IoClass>().is_err());
+
+        let cmd = CmdSpec::new("zfs")
+            .arg("send")
+            .env("A", EnvValue::Plain("1".into()))
+            .priority(Priority {
+                io: Some(IoClass::Idle),
+                nice: Some(10),
+            });
+        assert_eq!(cmd.render(), "A=1 ionice -c3 nice -n 10 zfs send");
+        assert_eq!(
+            Pipeline::new().cmd(cmd).to_shell(),
+            "A=1 ionice -c3 nice -n 10 zfs send </dev/null"
+        );
+
+        let niceness = |p: Priority| -> i32 {
+            let out = ProcessRunner::new()
+                .run_capture(&Pipeline::new().cmd(CmdSpec::new("nice").priority(p)))
+                .unwrap();
+            out.trim().parse().unwrap()
+        };
+        let base = niceness(Priority::default());
+        let lowered = niceness(Priority {
+            io: None,
+            nice: Some(5),
+        });
+        assert_eq!(lowered, (base + 5).min(19));
+    }
+
+    #[test]
+    fn scripted_runner_records_and_answers() {
+        let runner = ScriptedRunner::new()
+            .on("zfs list", "tank/vm-1\n")
+            .fail("zfs destroy", "dataset is busy");
+        let list = Pipeline::new().cmd(CmdSpec::new("zfs").args(["list", "-H"]));
+        assert_eq!(runner.run_capture(&list).unwrap(), "tank/vm-1\n");
+        let destroy = Pipeline::new().cmd(CmdSpec::new("zfs").args(["destroy", "tank/x"]));
+        let e = runner.run(&destroy).unwrap_err();
+        assert!(e.to_string().contains("dataset is busy"), "{e}");
+
+        let restore = Pipeline::new()
+            .cmd(
+                CmdSpec::new("pbs")
+                    .arg("restore")
+                    .env("PBS_PASSWORD", EnvValue::Secret("pw".into())),
+            )
+            .cmd(CmdSpec::new("dd").arg("of=/dev/x"));
+        let mut seen = String::new();
+        runner
+            .run_stream(&restore, &mut |r| {
+                r.read_to_string(&mut seen)?;
+                Ok(())
+            })
+            .unwrap();
+        assert!(seen.is_empty());
+        assert_eq!(
+            runner.calls(),
+            [
+                "zfs list -H",
+                "zfs destroy tank/x",
+                "PBS_PASSWORD=<redacted> pbs restore | dd of=/dev/x",
+            ]
+        );
+    }
+
     #[test]
     fn pipeline_render() {
         let pipeline = Pipeline::new()
@@ -358,6 +1010,108 @@ mod tests {
         assert_eq!(pipeline.render(), "cat file | grep pattern");
     }
 
+    #[test]
+    fn pipeline_timeout_is_shortest_stage() {
+        let pipeline = Pipeline::new()
+            .cmd(CmdSpec::new("a").with_timeout(Duration::from_secs(30)))
+            .cmd(CmdSpec::new("b"))
+            .cmd(CmdSpec::new("c").with_timeout(Duration::from_secs(5)));
+        assert_eq!(pipeline.timeout(), Some(Duration::from_secs(5)));
+        assert_eq!(Pipeline::new().cmd(CmdSpec::new("a")).timeout(), None);
+    }
+
+    #[test]
+    fn run_kills_hung_command() {
+        let runner = ProcessRunner::new();
+        let started = Instant::now();
+        let err = runner
+            .run(
+                &Pipeline::new().cmd(
+                    CmdSpec::new("sleep")
+                        .arg("10")
+                        .with_timeout(Duration::from_millis(200)),
+                ),
+            )
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("timed out"), "err was: {err:#}");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn run_stream_merges_stderr_and_times_out() {
+        let runner = ProcessRunner::new();
+        let mut out = String::new();
+        runner
+            .run_stream(
+                &Pipeline::new().cmd(
+                    CmdSpec::new("sh")
+                        .args(["-c", "echo out; echo err >&2"])
+                        .stderr(StdioSpec::Stdout),
+                ),
+                &mut |r| Ok(r.read_to_string(&mut out).map(|_| ())?),
+            )
+            .unwrap();
+        assert_eq!(out, "out\nerr\n");
+
+        let started = Instant::now();
+        let err = runner
+            .run_stream(
+                &Pipeline::new().cmd(
+                    CmdSpec::new("sleep")
+                        .arg("10")
+                        .with_timeout(Duration::from_millis(200)),
+                ),
+                &mut |r| Ok(io::copy(r, &mut io::sink()).map(|_| ())?),
+            )
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("timed out"), "err was: {err:#}");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn run_stream_step_timeout_restarts_on_step_done() {
+        let runner = ProcessRunner::new();
+        let stream = |script: &str| {
+            let cmd = CmdSpec::new("sh")
+                .args(["-c", script])
+                .with_step_timeout(Duration::from_millis(300));
+            runner.run_stream(&Pipeline::new().cmd(cmd), &mut |r| {
+                let mut r = io::BufReader::new(r);
+                let mut line = String::new();
+                while io::BufRead::read_line(&mut r, &mut line)? > 0 {
+                    step_done();
+                    line.clear();
+                }
+                Ok(())
+            })
+        };
+
+        stream("for i in 1 2 3 4 5; do echo $i; sleep 0.1; done").unwrap();
+
+        let started = Instant::now();
+        let err = stream("echo 1; exec sleep 10").unwrap_err();
+        assert!(
+            err.chain().any(|e| e.is::<StepTimedOut>()),
+            "err was: {err:#}"
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn run_capture_with_timeout_ok() {
+        let runner = ProcessRunner::new();
+        let out = runner
+            .run_capture(
+                &Pipeline::new().cmd(
+                    CmdSpec::new("echo")
+                        .arg("hi")
+                        .with_timeout(Duration::from_secs(5)),
+                ),
+            )
+            .unwrap();
+        assert_eq!(out.trim(), "hi");
+    }
+
     #[test]
     fn pipeline_empty() {
         let pipeline = Pipeline::new();
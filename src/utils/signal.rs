@@ -0,0 +1,113 @@
+use std::{
+    cell::Cell,
+    fmt,
+    sync::atomic::{AtomicI32, Ordering},
+};
+
+use anyhow::Result;
+
+static PENDING: AtomicI32 = AtomicI32::new(0);
+
+thread_local! {
+    static SHIELDED: Cell<bool> = const { Cell::new(false) };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interrupted(pub i32);
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "interrupted by {}", signal_name(self.0))
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+#[cfg(unix)]
+extern "C" fn on_signal(sig: libc::c_int) {
+    PENDING.store(sig, Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+pub fn install() -> Result<()> {
+    use anyhow::bail;
+
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    for sig in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: the handler only stores into an atomic, which is async-signal-safe.
+        let prev = unsafe { libc::signal(sig, handler) };
+        if prev == libc::SIG_ERR {
+            bail!("install handler for {}", signal_name(sig));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn install() -> Result<()> {
+    Ok(())
+}
+
+pub fn interrupted() -> Option<i32> {
+    match PENDING.load(Ordering::SeqCst) {
+        0 => None,
+        sig => Some(sig),
+    }
+}
+
+/// Fails with [`Interrupted`] once a signal arrived, unless running inside [`shielded`].
+pub fn check() -> Result<()> {
+    if SHIELDED.with(|c| c.get()) {
+        return Ok(());
+    }
+    match interrupted() {
+        Some(sig) => Err(Interrupted(sig).into()),
+        None => Ok(()),
+    }
+}
+
+/// Runs `f` with interruption checks disabled so cleanup commands can finish.
+pub fn shielded<R>(f: impl FnOnce() -> R) -> R {
+    struct Guard(bool);
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            SHIELDED.with(|c| c.set(self.0));
+        }
+    }
+    let prev = SHIELDED.with(|c| c.replace(true));
+    let _g = Guard(prev);
+    f()
+}
+
+pub fn exit_code(sig: i32) -> u8 {
+    u8::try_from(128 + sig).unwrap_or(u8::MAX)
+}
+
+fn signal_name(sig: i32) -> String {
+    match sig {
+        2 => "SIGINT".to_string(),
+        15 => "SIGTERM".to_string(),
+        n => format!("signal {n}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_code_follows_shell_convention() {
+        assert_eq!(exit_code(2), 130);
+        assert_eq!(exit_code(15), 143);
+    }
+
+    #[test]
+    fn shielded_restores_previous_state() {
+        shielded(|| {
+            assert!(SHIELDED.with(|c| c.get()));
+            shielded(|| {});
+            assert!(SHIELDED.with(|c| c.get()));
+        });
+        assert!(!SHIELDED.with(|c| c.get()));
+    }
+}
@@ -0,0 +1,239 @@
+use std::{
+    io::Write,
+    process::{Child, Command, Stdio},
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+
+use crate::{
+    config::Ssh,
+    utils::{
+        exec_policy,
+        process::{
+            CmdSpec, Pipeline, Runner, StreamSink, debug_exec, drain, sh_quote, stderr_suffix,
+            stream_child, wait_all,
+        },
+        signal,
+    },
+};
+
+pub const REQ_BINS: &[&str] = &["ssh"];
+
+/// Runs each pipeline on a remote host as a `bash -s` script fed over ssh's stdin,
+/// which keeps env values such as `PBS_PASSWORD` off both command lines.
+pub struct SshRunner {
+    ssh: Ssh,
+}
+
+impl SshRunner {
+    pub fn new(ssh: Ssh) -> Self {
+        Self { ssh }
+    }
+
+    #[inline]
+    pub fn host(&self) -> &str {
+        &self.ssh.host
+    }
+
+    pub fn destination(&self) -> String {
+        match &self.ssh.user {
+            Some(u) => format!("{u}@{}", self.ssh.host),
+            None => self.ssh.host.clone(),
+        }
+    }
+
+    fn login_args(&self) -> Vec<String> {
+        let mut args = vec!["-T".to_string(), "-o".into(), "BatchMode=yes".into()];
+        if let Some(port) = self.ssh.port {
+            args.extend(["-p".to_string(), port.to_string()]);
+        }
+        if let Some(id) = &self.ssh.identity_file {
+            args.extend(["-i".to_string(), id.display().to_string()]);
+        }
+        args.push(self.destination());
+        args
+    }
+
+    fn ssh_args(&self) -> Vec<String> {
+        let mut args = self.login_args();
+        args.push("bash -s".into());
+        args
+    }
+
+    /// Local `ssh` command running `cmd` on the host, so a local pipeline can end there.
+    /// `cmd` must not carry secrets: its command line is visible on both hosts.
+    pub fn command(&self, cmd: &CmdSpec) -> CmdSpec {
+        CmdSpec::new("ssh")
+            .args(self.login_args())
+            .arg(cmd.to_shell(false, true))
+    }
+
+    fn script(pipeline: &Pipeline) -> String {
+        format!("set -o pipefail\n{}\n", pipeline.to_shell())
+    }
+
+    fn spawn(&self, pipeline: &Pipeline, stdout: Stdio) -> Result<Child> {
+        if pipeline.is_empty() {
+            bail!("empty pipeline");
+        }
+        let stderr = if pipeline.pipes_stderr() {
+            Stdio::piped()
+        } else {
+            Stdio::inherit()
+        };
+        let mut child = Command::new("ssh")
+            .args(self.ssh_args())
+            .stdin(Stdio::piped())
+            .stdout(stdout)
+            .stderr(stderr)
+            .spawn()
+            .with_context(|| format!("spawn ssh {}", self.destination()))?;
+
+        let mut stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| anyhow!("ssh stdin not available"))?;
+        stdin
+            .write_all(Self::script(pipeline).as_bytes())
+            .with_context(|| format!("send script to {}", self.destination()))?;
+        Ok(child)
+    }
+
+    fn wait(&self, child: &mut Child, pipeline: &Pipeline) -> Result<()> {
+        let status = wait_all(std::slice::from_mut(child), pipeline.timeout())
+            .with_context(|| format!("wait for {} on {}", pipeline.render(), self.ssh.host))?
+            .remove(0);
+        if !status.success() {
+            bail!(
+                "command failed on {}: {} with {status}",
+                self.ssh.host,
+                pipeline.render()
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Runner for SshRunner {
+    fn run(&self, pipeline: &Pipeline) -> Result<()> {
+        if exec_policy::is_dry_run() {
+            exec_policy::skip(&format!(
+                "ssh {} {}",
+                self.ssh.host,
+                sh_quote(&pipeline.render())
+            ));
+            return Ok(());
+        }
+        signal::check()?;
+        debug_exec!(pipeline, "exec on {}: {}", self.ssh.host, pipeline.render());
+
+        let mut child = self.spawn(pipeline, Stdio::inherit())?;
+        self.wait(&mut child, pipeline)
+    }
+
+    fn run_capture(&self, pipeline: &Pipeline) -> Result<String> {
+        signal::check()?;
+        debug_exec!(
+            pipeline,
+            "exec(capture) on {}: {}",
+            self.ssh.host,
+            pipeline.render()
+        );
+
+        let mut child = self.spawn(pipeline, Stdio::piped())?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| anyhow!("stdout piping not available"))?;
+        let reader = drain(stdout);
+        let stderr = child.stderr.take().map(drain);
+
+        let res = self.wait(&mut child, pipeline);
+        let out = reader
+            .join()
+            .map_err(|_| anyhow!("stdout reader panicked"))?
+            .with_context(|| format!("read stdout of {}", pipeline.render()))?;
+        match res {
+            Ok(()) => Ok(String::from_utf8_lossy(&out).to_string()),
+            Err(e) => Err(anyhow!("{e:#}{}", stderr_suffix(stderr))),
+        }
+    }
+
+    fn run_stream(&self, pipeline: &Pipeline, sink: &mut StreamSink<'_>) -> Result<()> {
+        if exec_policy::is_dry_run() {
+            exec_policy::skip(&format!(
+                "ssh {} {}",
+                self.ssh.host,
+                sh_quote(&pipeline.render())
+            ));
+            return Ok(());
+        }
+        signal::check()?;
+        debug_exec!(
+            pipeline,
+            "exec(stream) on {}: {}",
+            self.ssh.host,
+            pipeline.render()
+        );
+
+        let mut child = self.spawn(pipeline, Stdio::piped())?;
+        let status = stream_child(
+            &mut child,
+            sink,
+            pipeline.timeout(),
+            pipeline.step_timeout(),
+        )
+        .with_context(|| format!("run {} on {}", pipeline.render(), self.ssh.host))?;
+        if !status.success() {
+            bail!(
+                "command failed on {}: {} with {status}",
+                self.ssh.host,
+                pipeline.render()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::utils::process::CmdSpec;
+
+    #[test]
+    fn ssh_args_include_login_settings() {
+        let runner = SshRunner::new(Ssh {
+            host: "pve2".to_string(),
+            user: Some("root".to_string()),
+            identity_file: Some(PathBuf::from("/etc/pvtools/id_ed25519")),
+            port: Some(2222),
+        });
+        assert_eq!(
+            runner.ssh_args(),
+            vec![
+                "-T",
+                "-o",
+                "BatchMode=yes",
+                "-p",
+                "2222",
+                "-i",
+                "/etc/pvtools/id_ed25519",
+                "root@pve2",
+                "bash -s",
+            ]
+        );
+    }
+
+    #[test]
+    fn script_fails_on_any_pipeline_stage() {
+        let pipeline = Pipeline::new()
+            .cmd(CmdSpec::new("zfs").args(["list", "-H"]))
+            .cmd(CmdSpec::new("grep").arg("vm-1"));
+        assert_eq!(
+            SshRunner::script(&pipeline),
+            "set -o pipefail\nzfs list -H </dev/null | grep vm-1\n"
+        );
+    }
+}
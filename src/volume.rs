@@ -13,6 +13,8 @@ pub struct Volume {
     pub disk: String,
     pub archive: String,
     pub device: PathBuf,
+    /// Size of the volume as discovery saw it; `None` where the source does not report one.
+    pub size_bytes: Option<u64>,
     pub meta: Option<Arc<dyn Any + Send + Sync>>,
 }
 